@@ -0,0 +1,341 @@
+//! ITU-T G.711 mu-law (PCMU) and A-law (PCMA)
+//!
+//! Bit-exact with the G.191 reference implementation: encoding
+//! truncates the 16-bit input to 14 (mu-law) or 13 (A-law) bits, and
+//! decoding returns the segment mid-point scaled back up to 16 bits.
+
+use crate::codec::{Codec, Error};
+use crate::sip::sdp::{self, Negotiated};
+
+pub const SAMPLE_RATE: u32 = 8000;
+
+/// Samples in a 20 ms frame
+pub const FRAME_SAMPLES: usize = 160;
+
+/// Mu-law bias, in 16-bit scale
+const ULAW_BIAS: i32 = 0x84;
+/// Mu-law clipping level, in 14-bit scale
+const ULAW_CLIP: i32 = 8159;
+
+/// Segment end points, mu-law in 14-bit scale (biased), A-law in 13-bit
+const ULAW_SEG_END: [i32; 8] = [0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF, 0x1FFF];
+const ALAW_SEG_END: [i32; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Law {
+    MuLaw,
+    ALaw,
+}
+
+impl Law {
+    pub fn encode(self, sample: i16) -> u8 {
+        match self {
+            Law::MuLaw => linear_to_ulaw(sample),
+            Law::ALaw => linear_to_alaw(sample),
+        }
+    }
+
+    pub fn decode(self, code: u8) -> i16 {
+        match self {
+            Law::MuLaw => ulaw_to_linear(code),
+            Law::ALaw => alaw_to_linear(code),
+        }
+    }
+
+    /// RFC 3551 static payload type
+    pub fn payload_type(self) -> u8 {
+        match self {
+            Law::MuLaw => 0,
+            Law::ALaw => 8,
+        }
+    }
+}
+
+/// G.711 with a fixed packetization interval
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct G711 {
+    law: Law,
+    frame_samples: usize,
+}
+
+impl G711 {
+    /// `ptime` in milliseconds
+    pub fn new(law: Law, ptime: u32) -> Self {
+        G711 {
+            law,
+            frame_samples: (SAMPLE_RATE * ptime / 1000) as usize,
+        }
+    }
+
+    /// Codec for the negotiated media, `None` when it's not G.711
+    pub fn from_media(media: &Negotiated) -> Option<Self> {
+        let law = match media.codec {
+            sdp::Codec::Pcmu => Law::MuLaw,
+            sdp::Codec::Pcma => Law::ALaw,
+            sdp::Codec::TelephoneEvent => return None,
+        };
+        Some(G711::new(law, media.ptime))
+    }
+
+    pub fn law(&self) -> Law {
+        self.law
+    }
+}
+
+impl Codec for G711 {
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn frame_samples(&self) -> usize {
+        self.frame_samples
+    }
+
+    fn frame_bytes(&self) -> usize {
+        // One byte per sample
+        self.frame_samples
+    }
+
+    fn payload_type(&self) -> u8 {
+        self.law.payload_type()
+    }
+
+    fn encode(&mut self, pcm: &[i16], out: &mut [u8]) -> Result<usize, Error> {
+        if out.len() < pcm.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        for (o, s) in out.iter_mut().zip(pcm.iter()) {
+            *o = self.law.encode(*s);
+        }
+        Ok(pcm.len())
+    }
+
+    fn decode(&mut self, data: &[u8], pcm: &mut [i16]) -> Result<usize, Error> {
+        if pcm.len() < data.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        for (s, c) in pcm.iter_mut().zip(data.iter()) {
+            *s = self.law.decode(*c);
+        }
+        Ok(data.len())
+    }
+}
+
+fn segment(value: i32, ends: &[i32; 8]) -> usize {
+    ends.iter().position(|end| value <= *end).unwrap_or(8)
+}
+
+pub fn linear_to_ulaw(sample: i16) -> u8 {
+    let mut value = i32::from(sample) >> 2;
+    let mask = if value < 0 {
+        value = -value;
+        0x7F
+    } else {
+        0xFF
+    };
+    if value > ULAW_CLIP {
+        value = ULAW_CLIP;
+    }
+    value += ULAW_BIAS >> 2;
+
+    let seg = segment(value, &ULAW_SEG_END);
+    if seg >= 8 {
+        return 0x7F ^ mask;
+    }
+    let code = ((seg as i32) << 4) | ((value >> (seg + 1)) & 0x0F);
+    (code as u8) ^ mask
+}
+
+pub fn ulaw_to_linear(code: u8) -> i16 {
+    let code = i32::from(!code);
+    let t = (((code & 0x0F) << 3) + ULAW_BIAS) << ((code & 0x70) >> 4);
+    let value = if code & 0x80 != 0 {
+        ULAW_BIAS - t
+    } else {
+        t - ULAW_BIAS
+    };
+    value as i16
+}
+
+pub fn linear_to_alaw(sample: i16) -> u8 {
+    let mut value = i32::from(sample) >> 3;
+    let mask = if value >= 0 {
+        0xD5
+    } else {
+        value = -value - 1;
+        0x55
+    };
+
+    let seg = segment(value, &ALAW_SEG_END);
+    if seg >= 8 {
+        return 0x7F ^ mask;
+    }
+    let mut code = (seg as i32) << 4;
+    if seg < 2 {
+        code |= (value >> 1) & 0x0F;
+    } else {
+        code |= (value >> seg) & 0x0F;
+    }
+    (code as u8) ^ mask
+}
+
+pub fn alaw_to_linear(code: u8) -> i16 {
+    let code = i32::from(code ^ 0x55);
+    let mut t = (code & 0x0F) << 4;
+    let seg = (code & 0x70) >> 4;
+    match seg {
+        0 => t += 8,
+        1 => t += 0x108,
+        _ => t = (t + 0x108) << (seg - 1),
+    }
+    let value = if code & 0x80 != 0 { t } else { -t };
+    value as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decoder outputs of G.711 tables 1 and 2, scaled to 16 bits as in G.191
+    const ULAW_DECODE: [(u8, i16); 13] = [
+        (0x00, -32124),
+        (0x0F, -16764),
+        (0x10, -15996),
+        (0x3F, -1980),
+        (0x70, -120),
+        (0x7E, -8),
+        (0x7F, 0),
+        (0x80, 32124),
+        (0x8F, 16764),
+        (0xC0, 1884),
+        (0xF0, 120),
+        (0xFE, 8),
+        (0xFF, 0),
+    ];
+
+    const ALAW_DECODE: [(u8, i16); 12] = [
+        (0x00, -5504),
+        (0x0F, -6784),
+        (0x2A, -32256),
+        (0x54, -24),
+        (0x55, -8),
+        (0x7F, -848),
+        (0x80, 5504),
+        (0x8F, 6784),
+        (0xAA, 32256),
+        (0xD4, 24),
+        (0xD5, 8),
+        (0xFF, 848),
+    ];
+
+    // G.191 encoder outputs
+    const ULAW_ENCODE: [(i16, u8); 16] = [
+        (0, 0xFF),
+        (1, 0xFF),
+        (-1, 0x7E),
+        (4, 0xFE),
+        (-8, 0x7E),
+        (100, 0xF2),
+        (-100, 0x72),
+        (1000, 0xCE),
+        (-1000, 0x4E),
+        (8159, 0x9F),
+        (16000, 0x90),
+        (-16000, 0x10),
+        (32124, 0x80),
+        (32767, 0x80),
+        (-32767, 0x00),
+        (-32768, 0x00),
+    ];
+
+    const ALAW_ENCODE: [(i16, u8); 16] = [
+        (0, 0xD5),
+        (1, 0xD5),
+        (-1, 0x55),
+        (8, 0xD5),
+        (-8, 0x55),
+        (100, 0xD3),
+        (-100, 0x53),
+        (1000, 0xFA),
+        (-1000, 0x7A),
+        (8159, 0x8A),
+        (16000, 0xBA),
+        (-16000, 0x3A),
+        (32124, 0xAA),
+        (32767, 0xAA),
+        (-32767, 0x2A),
+        (-32768, 0x2A),
+    ];
+
+    #[test]
+    fn ulaw_vectors() {
+        for (code, linear) in ULAW_DECODE.iter() {
+            assert_eq!(ulaw_to_linear(*code), *linear, "code {:#X}", code);
+        }
+        for (linear, code) in ULAW_ENCODE.iter() {
+            assert_eq!(linear_to_ulaw(*linear), *code, "linear {}", linear);
+        }
+    }
+
+    #[test]
+    fn alaw_vectors() {
+        for (code, linear) in ALAW_DECODE.iter() {
+            assert_eq!(alaw_to_linear(*code), *linear, "code {:#X}", code);
+        }
+        for (linear, code) in ALAW_ENCODE.iter() {
+            assert_eq!(linear_to_alaw(*linear), *code, "linear {}", linear);
+        }
+    }
+
+    #[test]
+    fn every_code_round_trips() {
+        for code in 0..=255u8 {
+            // Mu-law has two zeros, both encode as positive zero
+            let expected = if code == 0x7F { 0xFF } else { code };
+            assert_eq!(linear_to_ulaw(ulaw_to_linear(code)), expected);
+            assert_eq!(linear_to_alaw(alaw_to_linear(code)), code);
+        }
+    }
+
+    #[test]
+    fn monotonic() {
+        let mut prev_u = i16::min_value();
+        let mut prev_a = i16::min_value();
+        for s in i16::min_value()..=i16::max_value() {
+            let u = ulaw_to_linear(linear_to_ulaw(s));
+            let a = alaw_to_linear(linear_to_alaw(s));
+            assert!(u >= prev_u && a >= prev_a, "sample {}", s);
+            prev_u = u;
+            prev_a = a;
+        }
+    }
+
+    #[test]
+    fn codec_frames() {
+        let mut c = G711::new(Law::ALaw, 20);
+        assert_eq!(c.sample_rate(), 8000);
+        assert_eq!(c.frame_samples(), FRAME_SAMPLES);
+        assert_eq!(c.frame_bytes(), FRAME_SAMPLES);
+        assert_eq!(c.payload_type(), 8);
+
+        let pcm = [0i16, 8, -8, 32767];
+        let mut encoded = [0; 4];
+        assert_eq!(c.encode(&pcm, &mut encoded), Ok(4));
+        assert_eq!(encoded, [0xD5, 0xD5, 0x55, 0xAA]);
+        let mut decoded = [0; 4];
+        assert_eq!(c.decode(&encoded, &mut decoded), Ok(4));
+        assert_eq!(decoded, [8, 8, -8, 32256]);
+        assert_eq!(
+            c.encode(&pcm, &mut encoded[..3]),
+            Err(Error::BufferTooSmall)
+        );
+        assert_eq!(
+            c.decode(&encoded, &mut decoded[..3]),
+            Err(Error::BufferTooSmall)
+        );
+
+        let c = G711::new(Law::MuLaw, 30);
+        assert_eq!(c.frame_samples(), 240);
+        assert_eq!(c.payload_type(), 0);
+    }
+}
@@ -0,0 +1,42 @@
+//! Audio codecs for the media path
+//!
+//! Linear PCM is 16-bit signed, mono.
+
+pub mod g711;
+pub mod plc;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Output buffer too small for the input
+    BufferTooSmall,
+}
+
+pub trait Codec {
+    /// Linear samples per second
+    fn sample_rate(&self) -> u32;
+
+    /// Linear samples in one frame (packetization interval)
+    fn frame_samples(&self) -> usize;
+
+    /// Encoded bytes of one frame
+    fn frame_bytes(&self) -> usize;
+
+    /// RTP payload type
+    fn payload_type(&self) -> u8;
+
+    /// Encodes linear samples into `out`, returns the bytes written
+    fn encode(&mut self, pcm: &[i16], out: &mut [u8]) -> Result<usize, Error>;
+
+    /// Decodes into linear samples, returns the samples written
+    fn decode(&mut self, data: &[u8], pcm: &mut [i16]) -> Result<usize, Error>;
+}
+
+/// Fills in for frames lost on the way
+pub trait Concealment {
+    /// A frame was received and decoded into `pcm`, which may be
+    /// delayed or smoothed to join up with concealed audio
+    fn received(&mut self, pcm: &mut [i16]);
+
+    /// A frame is missing, fill `pcm` with a substitute
+    fn conceal(&mut self, pcm: &mut [i16]);
+}
@@ -0,0 +1,462 @@
+//! Packet loss concealment
+//!
+//! `Plc` follows ITU-T G.711 Appendix I: the pitch period of the last
+//! good audio is found by normalized cross-correlation, and missing
+//! frames are synthesized by repeating it, one more period for each of
+//! the first three 10 ms of the loss. Joins are overlap-added, the
+//! output fades out by 20% per 10 ms after the first 10 ms and is
+//! silent after 60 ms. Received audio is delayed by 3.75 ms so the
+//! start of a loss can be smoothed.
+//!
+//! Works on 8 kHz linear PCM, in frames of any length.
+
+use crate::codec::Concealment;
+
+/// Shortest pitch period, 200 Hz
+const PITCH_MIN: usize = 40;
+/// Longest pitch period, 66.7 Hz
+const PITCH_MAX: usize = 120;
+const PITCH_DIFF: usize = PITCH_MAX - PITCH_MIN;
+
+/// Longest overlap-add at the start of a loss, a quarter of the
+/// longest pitch period. Also the output delay.
+pub const DELAY: usize = PITCH_MAX >> 2;
+
+/// Three pitch periods plus the overlap
+const HISTORY_LEN: usize = PITCH_MAX * 3 + DELAY;
+
+/// Correlation window
+const CORR_LEN: usize = 160;
+const CORR_BUF_LEN: usize = CORR_LEN + PITCH_MAX;
+/// Floor on the window energy, so silence doesn't look periodic
+const CORR_MIN_POWER: f32 = 250.0;
+/// Decimation of the coarse pitch search
+const NDEC: usize = 2;
+
+/// Concealment works in 10 ms steps
+const STEP: usize = 80;
+/// Overlap added at the end of a loss for each 10 ms lost after the first
+const END_OVERLAP_INCR: usize = 32;
+/// Attenuation per 10 ms
+const ATTEN_FACTOR: f32 = 0.2;
+const ATTEN_INCR: f32 = ATTEN_FACTOR / STEP as f32;
+/// 10 ms steps after which the output is silent
+const MAX_ERASED: usize = 5;
+
+/// Plays silence in place of missing frames
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct Silence;
+
+impl Concealment for Silence {
+    fn received(&mut self, _pcm: &mut [i16]) {}
+
+    fn conceal(&mut self, pcm: &mut [i16]) {
+        for s in pcm.iter_mut() {
+            *s = 0;
+        }
+    }
+}
+
+/// G.711 Appendix I pitch repetition
+pub struct Plc {
+    /// Audio up to the latest frame, the last `DELAY` samples not yet
+    /// played out
+    history: [i16; HISTORY_LEN],
+    /// History at the start of the loss, the pitch periods are taken
+    /// from the end of it
+    pitch_buf: [f32; HISTORY_LEN],
+    /// The last quarter period before it was overlap-added
+    last_quarter: [f32; DELAY],
+    /// 10 ms steps concealed in the current loss
+    erased: usize,
+    pitch: usize,
+    overlap: usize,
+    /// Periods being repeated, from the end of `pitch_buf`
+    pitch_len: usize,
+    /// Playback position within the repeated periods
+    offset: usize,
+}
+
+impl Default for Plc {
+    fn default() -> Self {
+        Plc {
+            history: [0; HISTORY_LEN],
+            pitch_buf: [0.0; HISTORY_LEN],
+            last_quarter: [0.0; DELAY],
+            erased: 0,
+            pitch: 0,
+            overlap: 0,
+            pitch_len: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl Plc {
+    pub fn new() -> Self {
+        Plc::default()
+    }
+
+    /// Pitch period of the current loss, in samples
+    pub fn pitch(&self) -> Option<usize> {
+        if self.erased == 0 {
+            None
+        } else {
+            Some(self.pitch)
+        }
+    }
+
+    fn received_step(&mut self, pcm: &mut [i16]) {
+        if self.erased != 0 {
+            // Longer losses need longer overlaps to smooth the return
+            let len = (self.overlap + (self.erased - 1) * END_OVERLAP_INCR)
+                .min(STEP)
+                .min(pcm.len());
+            let mut synth = [0; STEP];
+            self.synthesize(&mut synth[..len]);
+
+            let gain = (1.0 - (self.erased - 1) as f32 * ATTEN_FACTOR).max(0.0);
+            let incr = 1.0 / len as f32;
+            let mut lw = (1.0 - incr) * gain;
+            let mut rw = incr;
+            for (s, f) in pcm.iter_mut().zip(synth[..len].iter()) {
+                *s = to_i16(lw * f32::from(*f) + rw * f32::from(*s));
+                lw -= incr * gain;
+                rw += incr;
+            }
+            self.erased = 0;
+        }
+        self.save(pcm);
+    }
+
+    fn conceal_step(&mut self, pcm: &mut [i16]) {
+        if self.erased == 0 {
+            for (p, h) in self.pitch_buf.iter_mut().zip(self.history.iter()) {
+                *p = f32::from(*h);
+            }
+            self.pitch = self.find_pitch();
+            self.overlap = self.pitch >> 2;
+            let tail = HISTORY_LEN - self.overlap;
+            self.last_quarter[..self.overlap].copy_from_slice(&self.pitch_buf[tail..]);
+            self.offset = 0;
+            self.pitch_len = self.pitch;
+            self.blend_tail();
+            // Not played out yet, thanks to the delay
+            for (h, p) in self.history[tail..]
+                .iter_mut()
+                .zip(self.pitch_buf[tail..].iter())
+            {
+                *h = to_i16(*p);
+            }
+            self.synthesize(pcm);
+        } else if self.erased < 3 {
+            // Carry on the previous periods while adding another
+            let mut prev = [0; DELAY];
+            let offset = self.offset;
+            self.synthesize(&mut prev[..self.overlap]);
+            self.offset = offset;
+            while self.offset > self.pitch {
+                self.offset -= self.pitch;
+            }
+            self.pitch_len += self.pitch;
+            self.blend_tail();
+            self.synthesize(pcm);
+
+            let len = self.overlap.min(pcm.len());
+            let incr = 1.0 / len as f32;
+            let mut lw = 1.0 - incr;
+            let mut rw = incr;
+            for (s, p) in pcm.iter_mut().zip(prev[..len].iter()) {
+                *s = to_i16(lw * f32::from(*p) + rw * f32::from(*s));
+                lw -= incr;
+                rw += incr;
+            }
+            self.attenuate(pcm);
+        } else if self.erased > MAX_ERASED {
+            for s in pcm.iter_mut() {
+                *s = 0;
+            }
+        } else {
+            self.synthesize(pcm);
+            self.attenuate(pcm);
+        }
+        self.erased += 1;
+        self.save(pcm);
+    }
+
+    /// Appends to the history and replaces `pcm` with the delayed audio
+    fn save(&mut self, pcm: &mut [i16]) {
+        let len = pcm.len();
+        self.history.copy_within(len.., 0);
+        self.history[HISTORY_LEN - len..].copy_from_slice(pcm);
+        let start = HISTORY_LEN - len - DELAY;
+        pcm.copy_from_slice(&self.history[start..start + len]);
+    }
+
+    /// Next samples of the repeated periods
+    fn synthesize(&mut self, out: &mut [i16]) {
+        let start = HISTORY_LEN - self.pitch_len;
+        for s in out.iter_mut() {
+            *s = to_i16(self.pitch_buf[start + self.offset]);
+            self.offset += 1;
+            if self.offset == self.pitch_len {
+                self.offset = 0;
+            }
+        }
+    }
+
+    /// Overlap-adds the quarter period before the repeated periods onto
+    /// their end, so they loop without a click
+    fn blend_tail(&mut self) {
+        let start = HISTORY_LEN - self.pitch_len - self.overlap;
+        let tail = HISTORY_LEN - self.overlap;
+        let incr = 1.0 / self.overlap as f32;
+        let mut lw = 1.0 - incr;
+        let mut rw = incr;
+        for i in 0..self.overlap {
+            self.pitch_buf[tail + i] = lw * self.last_quarter[i] + rw * self.pitch_buf[start + i];
+            lw -= incr;
+            rw += incr;
+        }
+    }
+
+    fn attenuate(&self, pcm: &mut [i16]) {
+        let mut gain = 1.0 - (self.erased - 1) as f32 * ATTEN_FACTOR;
+        for s in pcm.iter_mut() {
+            *s = to_i16(f32::from(*s) * gain.max(0.0));
+            gain -= ATTEN_INCR;
+        }
+    }
+
+    /// Lag with the best normalized correlation between the end of the
+    /// history and the audio before it
+    fn find_pitch(&self) -> usize {
+        let buf = &self.pitch_buf;
+        let l = HISTORY_LEN - CORR_LEN;
+        let correlate = |r: usize, step: usize| {
+            (0..CORR_LEN)
+                .step_by(step)
+                .fold(0.0, |c, i| c + buf[r + i] * buf[l + i])
+        };
+
+        // Coarse search, every other sample and lag
+        let mut r = HISTORY_LEN - CORR_BUF_LEN;
+        let mut energy = (0..CORR_LEN)
+            .step_by(NDEC)
+            .fold(0.0, |e, i| e + buf[r + i] * buf[r + i]);
+        let mut best = score(correlate(r, NDEC), energy);
+        let mut best_match = 0;
+        for j in (NDEC..=PITCH_DIFF).step_by(NDEC) {
+            energy += buf[r + CORR_LEN] * buf[r + CORR_LEN] - buf[r] * buf[r];
+            r += NDEC;
+            let s = score(correlate(r, NDEC), energy);
+            if s >= best {
+                best = s;
+                best_match = j;
+            }
+        }
+
+        // Fine search around the coarse match
+        let first = best_match.saturating_sub(NDEC - 1);
+        let last = (best_match + NDEC - 1).min(PITCH_DIFF);
+        let mut r = HISTORY_LEN - CORR_BUF_LEN + first;
+        let mut energy = (0..CORR_LEN).fold(0.0, |e, i| e + buf[r + i] * buf[r + i]);
+        let mut best = score(correlate(r, 1), energy);
+        let mut best_match = first;
+        for j in first + 1..=last {
+            energy += buf[r + CORR_LEN] * buf[r + CORR_LEN] - buf[r] * buf[r];
+            r += 1;
+            let s = score(correlate(r, 1), energy);
+            if s > best {
+                best = s;
+                best_match = j;
+            }
+        }
+
+        PITCH_MAX - best_match
+    }
+}
+
+impl Concealment for Plc {
+    fn received(&mut self, pcm: &mut [i16]) {
+        for step in pcm.chunks_mut(STEP) {
+            self.received_step(step);
+        }
+    }
+
+    fn conceal(&mut self, pcm: &mut [i16]) {
+        for step in pcm.chunks_mut(STEP) {
+            self.conceal_step(step);
+        }
+    }
+}
+
+/// Orders the same as the correlation normalized by the square root of
+/// the energy, without the square root
+fn score(corr: f32, energy: f32) -> f32 {
+    let magnitude = if corr < 0.0 { -corr } else { corr };
+    corr * magnitude / energy.max(CORR_MIN_POWER)
+}
+
+fn to_i16(value: f32) -> i16 {
+    if value >= f32::from(i16::max_value()) {
+        i16::max_value()
+    } else if value <= f32::from(i16::min_value()) {
+        i16::min_value()
+    } else {
+        value as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: usize = 160;
+    const AMPLITUDE: f32 = 10000.0;
+
+    /// Periodic test signal, `harmonics` of a `period` samples long
+    /// fundamental with falling levels
+    fn signal(n: usize, period: usize, harmonics: usize) -> i16 {
+        // Bhaskara's sine approximation, no libm here
+        fn sin(turns: f32) -> f32 {
+            let x = turns - (turns as i32) as f32;
+            let (x, sign) = if x < 0.5 { (x, 1.0) } else { (x - 0.5, -1.0) };
+            let d = 180.0 * x;
+            sign * 4.0 * d * (180.0 - d) / (40500.0 - d * (180.0 - d))
+        }
+        let mut v = 0.0;
+        for h in 1..=harmonics {
+            v += sin((n * h) as f32 / period as f32) / h as f32;
+        }
+        (AMPLITUDE * v / harmonics as f32) as i16
+    }
+
+    /// Runs frames through the PLC, losing those in `lost`
+    fn run(
+        plc: &mut Plc,
+        frames: usize,
+        lost: &[usize],
+        input: &dyn Fn(usize) -> i16,
+    ) -> [i16; 4000] {
+        let mut out = [0; 4000];
+        for f in 0..frames {
+            let pcm = &mut out[f * FRAME..(f + 1) * FRAME];
+            if lost.contains(&f) {
+                plc.conceal(pcm);
+            } else {
+                for (i, s) in pcm.iter_mut().enumerate() {
+                    *s = input(f * FRAME + i);
+                }
+                plc.received(pcm);
+            }
+        }
+        out
+    }
+
+    fn largest_step(pcm: &[i16]) -> i32 {
+        pcm.windows(2)
+            .map(|w| (i32::from(w[1]) - i32::from(w[0])).abs())
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn received_audio_is_delayed() {
+        let mut plc = Plc::new();
+        let input = |n| signal(n, 57, 1);
+        let out = run(&mut plc, 10, &[], &input);
+        assert_eq!(out[..DELAY].iter().all(|s| *s == 0), true);
+        for n in DELAY..10 * FRAME {
+            assert_eq!(out[n], input(n - DELAY));
+        }
+        assert_eq!(plc.pitch(), None);
+    }
+
+    #[test]
+    fn finds_the_pitch() {
+        for period in [40, 57, 80, 100, 120].iter() {
+            let mut plc = Plc::new();
+            let input = |n| signal(n, *period, 3);
+            run(&mut plc, 5, &[4], &input);
+            // Whole multiples repeat just as well
+            let pitch = plc.pitch().unwrap();
+            assert_eq!(pitch % *period, 0, "period {} pitch {}", period, pitch);
+        }
+    }
+
+    #[test]
+    fn conceals_a_lost_frame() {
+        let input = |n| signal(n, 50, 3);
+        let mut plc = Plc::new();
+        let out = run(&mut plc, 20, &[10], &input);
+        let mut silence = Plc::new();
+        let muted = run(&mut silence, 20, &[], &|n| {
+            if n / FRAME == 10 {
+                0
+            } else {
+                input(n)
+            }
+        });
+
+        // Close to what was lost, far closer than silence
+        let lost = 10 * FRAME + DELAY..11 * FRAME + DELAY;
+        let error = |pcm: &[i16]| {
+            lost.clone().fold(0.0, |e, n| {
+                let d = f32::from(pcm[n]) - f32::from(input(n - DELAY));
+                e + d * d
+            })
+        };
+        assert!(error(&out) * 20.0 < error(&muted));
+        // The first 10 ms isn't attenuated
+        for n in lost.clone().take(STEP) {
+            let d = i32::from(out[n]) - i32::from(input(n - DELAY));
+            assert!(d.abs() < 200, "sample {} off by {}", n, d);
+        }
+
+        // No clicks going into or out of the loss
+        let steady = largest_step(&out[..10 * FRAME]);
+        assert!(largest_step(&out[..20 * FRAME]) <= steady + steady / 10);
+    }
+
+    #[test]
+    fn long_loss_fades_out() {
+        let input = |n| signal(n, 73, 2);
+        let mut plc = Plc::new();
+        let lost = [5, 6, 7, 8, 9, 10];
+        let out = run(&mut plc, 20, &lost, &input);
+
+        // Silent after 60 ms
+        let silent = 5 * FRAME + DELAY + 6 * STEP;
+        assert_eq!(
+            out[silent..11 * FRAME + DELAY].iter().all(|s| *s == 0),
+            true
+        );
+        // Each 10 ms quieter than the one before
+        let peak = |n: usize| {
+            out[n..n + STEP]
+                .iter()
+                .map(|s| i32::from(*s).abs())
+                .max()
+                .unwrap()
+        };
+        for step in 1..6 {
+            let start = 5 * FRAME + DELAY + step * STEP;
+            assert!(peak(start) < peak(start - STEP), "step {}", step);
+        }
+
+        // Fades back in without a click
+        let steady = largest_step(&out[..5 * FRAME]);
+        assert!(largest_step(&out[..20 * FRAME]) <= steady + steady / 10);
+        for n in 13 * FRAME..20 * FRAME {
+            assert_eq!(out[n], input(n - DELAY));
+        }
+    }
+
+    #[test]
+    fn silence_is_concealed_with_silence() {
+        let mut plc = Plc::new();
+        let out = run(&mut plc, 4, &[1, 2], &|_| 0);
+        assert_eq!(out[..4 * FRAME].iter().all(|s| *s == 0), true);
+    }
+}
@@ -19,7 +19,7 @@ impl Default for EventBufferMode {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct EventBuffer {
     mode: EventBufferMode,
     buffer: Storage,
@@ -8,7 +8,7 @@ use crate::time::{Duration, Instant};
 use keypad::{keypad_new, keypad_struct, KeypadInput};
 
 const DEBOUNCE_DURATION: Duration = Duration::from_millis(25);
-const LONGPRESS_DURATION: Duration = Duration::from_secs(1);
+pub const LONGPRESS_DURATION: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum KeypadEvent {
@@ -26,6 +26,7 @@ impl KeypadEvent {
 
 pub struct Keypad<INNER: KeypadDecomp> {
     states: KeyStateMatrix,
+    held: Duration,
     inner: INNER,
 }
 
@@ -86,10 +87,17 @@ where
                 [KeyState::new('7'), KeyState::new('8'), KeyState::new('9')],
                 [KeyState::new('*'), KeyState::new('0'), KeyState::new('#')],
             ],
+            held: Duration::from_secs(0),
             inner,
         }
     }
 
+    /// How long the key of the last event was held down, i.e. the
+    /// DTMF duration
+    pub fn held(&self) -> Duration {
+        self.held
+    }
+
     pub fn read(&mut self, time: &Instant) -> Option<KeypadEvent> {
         let keys = self.inner.decompose();
         for (row_index, row) in keys.iter().enumerate() {
@@ -100,6 +108,7 @@ where
                 let long_pressed = self.states[row_index][col_index].long_pressed(time);
                 if changed && prev_pressed {
                     let c = self.states[row_index][col_index].key();
+                    self.held = *time - self.states[row_index][col_index].last_db;
 
                     // Clear all of the states, not tracking multi-key presses
                     for s in self.states.iter_mut().flat_map(|r| r.iter_mut()) {
@@ -309,6 +318,7 @@ mod tests {
         keypad.inner = inner_clr;
         let t = t_0 + (DEBOUNCE_DURATION + Duration::from_millis(1));
         assert_eq!(keypad.read(&t), Some(KeypadEvent::KeyPress('1')));
+        assert_eq!(keypad.held(), DEBOUNCE_DURATION + Duration::from_millis(1));
 
         let t = t_0 + (DEBOUNCE_DURATION + Duration::from_millis(2));
         assert_eq!(keypad.read(&t), None);
@@ -359,6 +369,7 @@ mod tests {
         keypad.inner = inner_clr;
         let t = t_0 + LONGPRESS_DURATION;
         assert_eq!(keypad.read(&t), Some(KeypadEvent::LongPress('1')));
+        assert_eq!(keypad.held(), LONGPRESS_DURATION);
 
         let t = t_0 + (LONGPRESS_DURATION + Duration::from_millis(1));
         assert_eq!(keypad.read(&t), None);
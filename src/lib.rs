@@ -2,13 +2,18 @@
 
 pub extern crate stm32f4xx_hal as hal;
 
+pub mod codec;
 pub mod display;
 pub mod keypad;
 pub mod logger;
 pub mod net;
 pub mod phone_number;
 pub mod phone_state;
+pub mod random;
 pub mod rtc;
+pub mod rtp;
+pub mod sip;
 pub mod sync;
 pub mod sys_clock;
 pub mod time;
+pub mod tones;
@@ -3,24 +3,69 @@
 #![feature(core_intrinsics)]
 
 use core::cell::Cell;
+use core::fmt::Write;
 use cortex_m::interrupt::Mutex;
 use cortex_m_rt::ExceptionFrame;
 use cortex_m_rt::{entry, exception};
+use lib::codec::g711::{FRAME_SAMPLES as G711_FRAME_SAMPLES, G711};
+use lib::codec::plc::Plc;
+use lib::hal::i2c::I2c;
 use lib::hal::prelude::*;
 use lib::hal::serial::{config::Config, Serial};
 use lib::hal::stm32::{self, interrupt};
+use lib::keypad::{Keypad, KeypadInner};
 use lib::logger::Logger;
-use lib::net::eth::{Eth, NEIGHBOR_CACHE_SIZE, SOCKET_BUFFER_SIZE};
+use lib::net::eth::{Eth, NEIGHBOR_CACHE_SIZE};
+use lib::net::routes::MAX_ROUTES;
+use lib::net::socket_pool::{self, Owner, SocketPool, UdpHandle, MAX_SOCKETS};
+use lib::net::{dhcp, dns, sntp};
+use lib::phone_state::{PhoneCommand, PhoneEvent, PhoneStateMachine};
+use lib::random::XorShift32;
+use lib::rtc::format::{DateStyle, HourStyle};
+use lib::rtc::{DateTimeFormat, Rtc, TimeZone};
+use lib::rtp::session::Session;
+use lib::sip::registration;
+use lib::sip::sdp::{self, Codec, LocalMedia};
+use lib::sip::user_agent::UserAgent;
 use lib::sys_clock::SysClock;
-use log::{debug, info, LevelFilter};
+use lib::tones::{DtmfDetector, Region, ToneGenerator};
+use log::{debug, info, warn, LevelFilter};
 use smoltcp::iface::{EthernetInterfaceBuilder, NeighborCache, Routes};
-use smoltcp::socket::{SocketSet, TcpSocket, TcpSocketBuffer};
-use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address};
+use smoltcp::socket::SocketSet;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr};
 
 mod panic_handler;
 
 const SRC_MAC: [u8; 6] = [0x02, 0x00, 0x05, 0x06, 0x07, 0x08];
-const SRC_IP: [u8; 4] = [192, 168, 1, 39];
+/// Static address used when DHCP doesn't come up with a lease
+const FALLBACK_IP: [u8; 4] = [192, 168, 1, 39];
+const FALLBACK_PREFIX_LEN: u8 = 24;
+const FALLBACK_GATEWAY: [u8; 4] = [192, 168, 1, 1];
+/// Destination network, prefix length and gateway of routes other
+/// than the default
+const STATIC_ROUTES: [([u8; 4], u8, [u8; 4]); 0] = [];
+/// Host name or address of the NTP server, DHCP's is used without one
+const NTP_SERVER: Option<&str> = None;
+/// POSIX TZ string for the displayed time
+const TIME_ZONE: &str = "EST5EDT,M3.2.0,M11.1.0";
+const TIME_FORMAT: DateTimeFormat = DateTimeFormat {
+    date: DateStyle::WeekdayMonthDay,
+    hours: HourStyle::H12,
+    seconds: false,
+};
+
+/// Host name or address, a name's `_sip._udp.` SRV records are tried
+/// first (RFC 3263)
+const SIP_REGISTRAR: &str = "192.168.1.2";
+const SIP_DOMAIN: &str = "192.168.1.2";
+const SIP_USERNAME: &str = "1001";
+const SIP_PASSWORD: &str = "1001";
+
+const RTP_PORT: u16 = 10000;
+/// Audio frame interval when there's no RTP session
+const AUDIO_PTIME: u32 = 20;
+const TONE_REGION: Region = Region::NorthAmerica;
+const CODECS: [Codec; 3] = [Codec::Pcmu, Codec::Pcma, Codec::TelephoneEvent];
 
 static GLOBAL_LOGGER: Logger = Logger::new();
 
@@ -60,9 +105,19 @@ fn main() -> ! {
     log::set_logger(&GLOBAL_LOGGER).unwrap();
     log::set_max_level(LevelFilter::Trace);
 
+    debug!("Setup RTC");
+    let gpiob = dp.GPIOB.split();
+    let scl = gpiob.pb8.into_alternate_af4().set_open_drain();
+    let sda = gpiob.pb9.into_alternate_af4().set_open_drain();
+    let i2c = I2c::i2c1(dp.I2C1, (scl, sda), 100.khz(), clocks);
+    let mut rtc = Rtc::new(i2c);
+    let time_zone = TimeZone::parse(TIME_ZONE).unwrap_or_else(|e| {
+        warn!("Time zone {} not used {:?}", TIME_ZONE, e);
+        TimeZone::utc()
+    });
+
     debug!("Setup Ethernet");
     let gpioa = dp.GPIOA.split();
-    let gpiob = dp.GPIOB.split();
     let gpioc = dp.GPIOC.split();
     let gpiog = dp.GPIOG.split();
     stm32_eth::setup_pins(
@@ -82,14 +137,13 @@ fn main() -> ! {
     eth.enable_interrupt(&mut cp.NVIC);
 
     debug!("Setup IP stack");
-    let ip = Ipv4Address::from_bytes(&SRC_IP);
     let mac = EthernetAddress::from_bytes(&SRC_MAC);
-    info!("{}, {}", ip, mac);
-    let ip_addr = IpCidr::new(ip.into(), 24);
-    let mut ip_addrs = [ip_addr];
+    info!("{}", mac);
+    // Unconfigured until the DHCP client has a lease or falls back
+    let mut ip_addrs = [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)];
     let mut neighbor_storage = [None; NEIGHBOR_CACHE_SIZE];
     let neighbor_cache = NeighborCache::new(&mut neighbor_storage[..]);
-    let mut routes_storage = [];
+    let mut routes_storage = [None; MAX_ROUTES];
     let routes = Routes::new(&mut routes_storage[..]);
     let iface = EthernetInterfaceBuilder::new(&mut eth)
         .ethernet_addr(mac.into())
@@ -98,26 +152,97 @@ fn main() -> ! {
         .routes(routes)
         .finalize();
 
-    // TODO - only 1 socket for now, move this to the Eth area
-    let mut sockets_storage = [None];
-    let mut sockets = SocketSet::new(&mut sockets_storage[..]);
-
-    let tcp_server_socket = {
-        static mut RX_BUFFER: [u8; SOCKET_BUFFER_SIZE] = [0; SOCKET_BUFFER_SIZE];
-        static mut TX_BUFFER: [u8; SOCKET_BUFFER_SIZE] = [0; SOCKET_BUFFER_SIZE];
-        TcpSocket::new(
-            TcpSocketBuffer::new(unsafe { &mut RX_BUFFER[..] }),
-            TcpSocketBuffer::new(unsafe { &mut TX_BUFFER[..] }),
-        )
+    let mut sockets_storage: [_; MAX_SOCKETS] = Default::default();
+    let sockets = SocketSet::new(&mut sockets_storage[..]);
+    let pool = {
+        static mut BUFFERS: socket_pool::Buffers = socket_pool::Buffers::new();
+        SocketPool::new(sockets, unsafe { &mut BUFFERS })
     };
+    let mut eth = Eth::new(iface, pool);
+    // Can't fail, the pool has a UDP socket for each of these
+    let sip_handle = eth.allocate_udp(Owner::Sip).unwrap();
+    let rtp_handle = eth.allocate_udp(Owner::Rtp).unwrap();
+    let rtcp_handle = eth.allocate_udp(Owner::Rtcp).unwrap();
+    let dhcp_handle = eth.allocate_udp(Owner::Dhcp).unwrap();
+    let dns_handle = eth.allocate_udp(Owner::Dns).unwrap();
+    let ntp_handle = eth.allocate_udp(Owner::Ntp).unwrap();
+    for (addr, prefix_len, gateway) in STATIC_ROUTES.iter() {
+        let cidr = Ipv4Cidr::new(Ipv4Address::from_bytes(addr), *prefix_len);
+        if let Err(e) = eth.add_route(cidr, Ipv4Address::from_bytes(gateway)) {
+            warn!("Route to {} not added {:?}", cidr, e);
+        }
+    }
 
-    let server_handle = sockets.add(tcp_server_socket);
+    let mut sys_clock = SysClock::new(cp.SYST, clocks);
 
-    let mut eth = Eth::new(iface, sockets);
+    // TODO - seed from the RNG peripheral
+    let seed = u32::from_be_bytes([SRC_MAC[2], SRC_MAC[3], SRC_MAC[4], SRC_MAC[5]]);
+    let mut rng = XorShift32::new(seed);
+    let mut dhcp = dhcp::Client::new(
+        dhcp_handle,
+        mac,
+        Some(dhcp::Config {
+            address: Ipv4Cidr::new(Ipv4Address::from_bytes(&FALLBACK_IP), FALLBACK_PREFIX_LEN),
+            router: Some(Ipv4Address::from_bytes(&FALLBACK_GATEWAY)),
+            dns_servers: heapless::Vec::new(),
+            ntp_server: None,
+        }),
+        &mut rng,
+    );
+    let mut resolver = dns::Resolver::new(dns_handle, &mut rng);
+    let mut sntp = sntp::Client::new(ntp_handle, None, &mut rng);
+    let mut user_agent = UserAgent::new(
+        registration::Config {
+            // Set once resolved
+            registrar: IpEndpoint::new(
+                IpAddress::Ipv4(Ipv4Address::UNSPECIFIED),
+                registration::DEFAULT_PORT,
+            ),
+            domain: SIP_DOMAIN,
+            username: SIP_USERNAME,
+            password: SIP_PASSWORD,
+            // Registers once DHCP sets the address
+            local_addr: Ipv4Address::UNSPECIFIED,
+            local_port: registration::DEFAULT_PORT,
+            expires: registration::DEFAULT_EXPIRES,
+        },
+        LocalMedia {
+            addr: Ipv4Address::UNSPECIFIED,
+            port: RTP_PORT,
+            codecs: &CODECS,
+            ptime: sdp::DEFAULT_PTIME,
+        },
+        sip_handle,
+        &mut rng,
+    );
 
-    let mut sys_clock = SysClock::new(cp.SYST, clocks);
+    debug!("Setup keypad");
+    let mut keypad = Keypad::new(KeypadInner::new(
+        gpiob.pb0.into_pull_up_input(),
+        gpiob.pb1.into_pull_up_input(),
+        gpiob.pb2.into_pull_up_input(),
+        gpiob.pb3.into_pull_up_input(),
+        gpiob.pb4.into_open_drain_output(),
+        gpiob.pb5.into_open_drain_output(),
+        gpiob.pb6.into_open_drain_output(),
+    ));
+
+    let mut phone = PhoneStateMachine::new(sys_clock.now());
+    let mut rtp: Option<Session> = None;
 
     let mut last_sec = 0;
+    let mut last_frame = 0;
+    let mut plc = Plc::new();
+    let mut tones = ToneGenerator::new(TONE_REGION);
+    let mut dtmf = DtmfDetector::new();
+    handle_phone_event(
+        &mut phone,
+        &mut user_agent,
+        &mut rtp,
+        &mut tones,
+        PhoneEvent::TimeFormat(TIME_FORMAT),
+        sys_clock.now(),
+    );
     loop {
         let ms: u64 = cortex_m::interrupt::free(|cs| GLOBAL_SYST_MS.borrow(cs).get());
         sys_clock.set_time(ms);
@@ -129,14 +254,306 @@ fn main() -> ! {
             eth.poll(time);
         }
 
+        match eth.poll_dhcp(&mut dhcp, time) {
+            Ok(true) => {
+                if let Some(config) = dhcp.config() {
+                    info!("IP address {}", config.address);
+                    user_agent.set_local_addr(config.address.address(), time);
+                    resolver.set_servers(&config.dns_servers, time);
+                } else {
+                    warn!("IP address lost");
+                }
+                for route in eth.routes().iter() {
+                    info!("Route {} via {}", route.cidr, route.gateway);
+                }
+            }
+            Ok(false) => (),
+            Err(e) => warn!("DHCP error {:?}", e),
+        }
+        if let Err(e) = resolver.poll(eth.sockets(), time) {
+            warn!("DNS error {:?}", e);
+        }
+        match sntp.poll(eth.sockets(), time) {
+            Ok(Some(sample)) => debug!(
+                "NTP offset {} us, delay {} us",
+                sample.offset,
+                sample.delay.as_micros()
+            ),
+            Ok(None) => (),
+            Err(e) => warn!("NTP error {:?}", e),
+        }
+        if let Some(dt) = sntp.rtc_update(time) {
+            match rtc.set(&dt, time) {
+                Ok(()) => info!("RTC set to {}", dt),
+                Err(e) => warn!("RTC not set {:?}", e),
+            }
+        }
+
+        match user_agent.poll(eth.sockets(), time) {
+            Ok(events) => {
+                for event in events {
+                    if let PhoneEvent::Registration(status) = event {
+                        info!("SIP registration: {}", status);
+                    }
+                    handle_phone_event(
+                        &mut phone,
+                        &mut user_agent,
+                        &mut rtp,
+                        &mut tones,
+                        event,
+                        time,
+                    );
+                }
+            }
+            Err(e) => warn!("SIP error {:?}", e),
+        }
+        if let Some(key) = keypad.read(&time) {
+            let event = PhoneEvent::Keypad(key, keypad.held());
+            handle_phone_event(
+                &mut phone,
+                &mut user_agent,
+                &mut rtp,
+                &mut tones,
+                event,
+                time,
+            );
+        }
+        update_rtp_session(
+            &mut rtp,
+            &user_agent,
+            &mut eth,
+            (rtp_handle, rtcp_handle),
+            time,
+            &mut rng,
+        );
+        if let Some(session) = rtp.as_mut() {
+            if let Err(e) = session.receive(eth.sockets(), time) {
+                warn!("RTP error {:?}", e);
+            }
+            if let Err(e) = session.poll(eth.sockets(), time) {
+                warn!("RTP error {:?}", e);
+            }
+        }
+        let ptime = rtp.as_ref().map_or(AUDIO_PTIME, |s| s.media().ptime.max(1));
+        let frame = time.as_millis() / u128::from(ptime);
+        if frame != last_frame {
+            play_audio(rtp.as_mut(), &mut plc, &mut dtmf, &mut tones, ptime, time);
+            last_frame = frame;
+        }
+        // Send whatever the user agent and RTP session queued
+        eth.poll(time);
+
         let sec = time.as_secs();
         if sec != last_sec {
             info!("{}", lib::time::DisplayableInstant::from(time));
+            if let Some(config) = dhcp.config() {
+                match resolve_registrar(&mut resolver, time) {
+                    Ok(Some(registrar)) => user_agent.set_registrar(registrar, time),
+                    Ok(None) => (),
+                    Err(e) => warn!("Registrar {} not resolved {:?}", SIP_REGISTRAR, e),
+                }
+                let server = match NTP_SERVER {
+                    Some(host) => resolver.query_a(host, time).unwrap_or_else(|e| {
+                        warn!("NTP server {} not resolved {:?}", host, e);
+                        None
+                    }),
+                    None => config.ntp_server,
+                };
+                if server.is_some() {
+                    sntp.set_server(server, time);
+                }
+            }
+            // The RTC keeps time until NTP has it
+            let now = sntp
+                .now(time)
+                .or_else(|| rtc.now(time))
+                .and_then(|utc| time_zone.to_local(&utc));
+            if let Some(now) = now {
+                handle_phone_event(
+                    &mut phone,
+                    &mut user_agent,
+                    &mut rtp,
+                    &mut tones,
+                    PhoneEvent::SystemTime(now),
+                    time,
+                );
+            }
+            if let Some(stats) = rtp.as_ref().map(|s| *s.jitter_buffer().stats()) {
+                handle_phone_event(
+                    &mut phone,
+                    &mut user_agent,
+                    &mut rtp,
+                    &mut tones,
+                    PhoneEvent::MediaStats(stats),
+                    time,
+                );
+            }
+            if let Some(quality) = rtp.as_ref().and_then(|s| s.quality()) {
+                handle_phone_event(
+                    &mut phone,
+                    &mut user_agent,
+                    &mut rtp,
+                    &mut tones,
+                    PhoneEvent::CallQuality(quality),
+                    time,
+                );
+            }
+            handle_phone_event(
+                &mut phone,
+                &mut user_agent,
+                &mut rtp,
+                &mut tones,
+                PhoneEvent::Tick,
+                time,
+            );
             last_sec = sec;
         }
     }
 }
 
+/// Registrar's address, from the first SRV target or else the host's
+/// own address on the default port. None while the lookups are
+/// outstanding.
+fn resolve_registrar(
+    resolver: &mut dns::Resolver,
+    time: lib::time::Instant,
+) -> Result<Option<IpEndpoint>, dns::Error> {
+    let endpoint = |addr: Ipv4Address, port| IpEndpoint::new(addr.into(), port);
+    if let Ok(addr) = SIP_REGISTRAR.parse() {
+        return Ok(Some(endpoint(addr, registration::DEFAULT_PORT)));
+    }
+    let mut service = dns::Name::new();
+    if write!(service, "_sip._udp.{}", SIP_REGISTRAR).is_ok() {
+        match resolver.query_srv(&service, time) {
+            Ok(Some(records)) => {
+                let srv = &records[0];
+                let addr = resolver.query_a(&srv.target, time)?;
+                return Ok(addr.map(|addr| endpoint(addr, srv.port)));
+            }
+            Ok(None) => return Ok(None),
+            Err(dns::Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    let addr = resolver.query_a(SIP_REGISTRAR, time)?;
+    Ok(addr.map(|addr| endpoint(addr, registration::DEFAULT_PORT)))
+}
+
+fn handle_phone_event(
+    phone: &mut PhoneStateMachine,
+    user_agent: &mut UserAgent,
+    rtp: &mut Option<Session>,
+    tones: &mut ToneGenerator,
+    event: PhoneEvent,
+    time: lib::time::Instant,
+) {
+    match phone.handle(event, time) {
+        Ok(out) => {
+            if out.state.is_some() {
+                debug!("Phone state: {:?}", phone.state());
+            }
+            let mut failed = None;
+            for cmd in out.commands {
+                match cmd {
+                    PhoneCommand::PlayTone(tone) => tones.start(tone, time),
+                    PhoneCommand::StopTone => tones.stop(),
+                    _ => (),
+                }
+                if let PhoneCommand::SendDtmf(digit, held) = cmd {
+                    match rtp.as_mut() {
+                        Some(session) => {
+                            if let Err(e) = session.send_dtmf(digit, held) {
+                                warn!("DTMF {} failed {:?}", digit, e);
+                            }
+                        }
+                        None => warn!("DTMF {} without media", digit),
+                    }
+                }
+                match user_agent.handle_command(cmd, time) {
+                    Ok(Some(event)) => failed = Some(event),
+                    Ok(None) => (),
+                    Err(e) => warn!("Phone command {:?} failed {:?}", cmd, e),
+                }
+            }
+            // i.e. the call couldn't be placed, back to the phone
+            if let Some(event) = failed {
+                handle_phone_event(phone, user_agent, rtp, tones, event, time);
+            }
+        }
+        Err(e) => warn!("Phone event error {:?}", e),
+    }
+}
+
+/// Follows the user agent's negotiated media with the RTP session
+fn update_rtp_session(
+    rtp: &mut Option<Session>,
+    user_agent: &UserAgent,
+    eth: &mut Eth,
+    handles: (UdpHandle, UdpHandle),
+    time: lib::time::Instant,
+    rng: &mut XorShift32,
+) {
+    match (user_agent.media(), rtp.as_mut()) {
+        (Some(media), Some(session)) => {
+            if media != session.media() {
+                info!("RTP session updated {:?}", media);
+                session.update(*media);
+            }
+        }
+        (Some(media), None) => {
+            info!("RTP session started {:?}", media);
+            *rtp = Some(Session::new(handles.0, handles.1, RTP_PORT, *media, rng));
+        }
+        (None, Some(session)) => {
+            let stats = session.receiver().stats();
+            info!(
+                "RTP session ended, received {} lost {}",
+                stats.received,
+                session.receiver().lost()
+            );
+            if let Some(q) = session.quality() {
+                info!("Call quality {:?}", q);
+            }
+            if let Err(e) = session.bye(eth.sockets(), time) {
+                warn!("RTCP BYE failed {:?}", e);
+            }
+            // Get the BYE out before the socket goes
+            eth.poll(time);
+            session.close(eth.sockets());
+            *rtp = None;
+        }
+        (None, None) => (),
+    }
+}
+
+/// Produces one frame of handset audio: the call's, with any tone on top
+fn play_audio(
+    rtp: Option<&mut Session>,
+    plc: &mut Plc,
+    dtmf: &mut DtmfDetector,
+    tones: &mut ToneGenerator,
+    ptime: u32,
+    time: lib::time::Instant,
+) {
+    let mut pcm = [0; 2 * G711_FRAME_SAMPLES];
+    let samples = ((lib::tones::SAMPLE_RATE * ptime / 1000) as usize).min(pcm.len());
+    if let Some(session) = rtp {
+        if let Some(mut codec) = G711::from_media(session.media()) {
+            let played = session
+                .jitter_buffer_mut()
+                .play_out(&mut codec, plc, &mut pcm);
+            if let Err(e) = played {
+                warn!("RTP playout error {:?}", e);
+            }
+            dtmf.process(&pcm[..samples], |event| {
+                info!("In-band DTMF {:?}", event);
+            });
+        }
+    }
+    tones.mix(time, &mut pcm[..samples]);
+    // TODO - play out
+}
+
 #[exception]
 fn SysTick() {
     cortex_m::interrupt::free(|cs| {
@@ -0,0 +1,474 @@
+//! DHCPv4 messages (RFC 2131, options from RFC 2132)
+//!
+//! Only the options the client asks for are kept when parsing, the
+//! rest are skipped. Option overloading of the `sname` and `file`
+//! fields isn't supported.
+
+use core::convert::TryInto;
+use heapless::consts::U3;
+use heapless::Vec;
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+/// Fixed fields and the magic cookie
+pub const HEADER_LEN: usize = 240;
+
+/// BOOTP relays and some servers drop anything shorter
+pub const MIN_LEN: usize = 300;
+
+/// Largest message sent or received, the minimum every host takes
+pub const MAX_LEN: usize = 576;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const BOOT_REQUEST: u8 = 1;
+const BOOT_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS_SERVERS: u8 = 6;
+const OPT_NTP_SERVERS: u8 = 42;
+const OPT_REQUESTED_ADDR: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPT_MAX_MESSAGE_SIZE: u8 = 57;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_CLIENT_ID: u8 = 61;
+const OPT_END: u8 = 255;
+
+/// Options asked of the server
+const PARAMETER_REQUEST_LIST: [u8; 7] = [
+    OPT_SUBNET_MASK,
+    OPT_ROUTER,
+    OPT_DNS_SERVERS,
+    OPT_NTP_SERVERS,
+    OPT_LEASE_TIME,
+    OPT_RENEWAL_TIME,
+    OPT_REBINDING_TIME,
+];
+
+pub type MaxDnsServers = U3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Shorter than the fixed fields, or an option runs off the end
+    Truncated,
+    /// Not a DHCP message, BOOTP or otherwise
+    InvalidMagicCookie,
+    /// Not Ethernet hardware addresses
+    InvalidHardwareType,
+    /// Missing or unknown message type option
+    InvalidMessageType,
+    /// Emit buffer too small for the message
+    BufferTooSmall,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => return None,
+        })
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Decline => 4,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Release => 7,
+            MessageType::Inform => 8,
+        }
+    }
+
+    /// Sent by clients, the rest by servers
+    pub fn is_request(self) -> bool {
+        match self {
+            MessageType::Offer | MessageType::Ack | MessageType::Nak => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Message {
+    pub message_type: MessageType,
+    /// Transaction ID, picked by the client
+    pub xid: u32,
+    /// Seconds since the client started acquiring or renewing
+    pub secs: u16,
+    /// Ask the server to broadcast its replies
+    pub broadcast: bool,
+    /// `ciaddr`, the client's address while bound, renewing or rebinding
+    pub client_addr: Ipv4Address,
+    /// `yiaddr`, the address offered or assigned
+    pub your_addr: Ipv4Address,
+    pub client_hw_addr: EthernetAddress,
+    pub server_id: Option<Ipv4Address>,
+    pub requested_addr: Option<Ipv4Address>,
+    pub subnet_mask: Option<Ipv4Address>,
+    /// First router only
+    pub router: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address, MaxDnsServers>,
+    /// First NTP server only
+    pub ntp_server: Option<Ipv4Address>,
+    /// In seconds
+    pub lease_time: Option<u32>,
+    /// T1, in seconds
+    pub renewal_time: Option<u32>,
+    /// T2, in seconds
+    pub rebinding_time: Option<u32>,
+}
+
+impl Message {
+    /// Client message with the fixed fields set and no options
+    pub fn new(message_type: MessageType, xid: u32, client_hw_addr: EthernetAddress) -> Self {
+        Message {
+            message_type,
+            xid,
+            secs: 0,
+            broadcast: false,
+            client_addr: Ipv4Address::UNSPECIFIED,
+            your_addr: Ipv4Address::UNSPECIFIED,
+            client_hw_addr,
+            server_id: None,
+            requested_addr: None,
+            subnet_mask: None,
+            router: None,
+            dns_servers: Vec::new(),
+            ntp_server: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if data[236..240] != MAGIC_COOKIE {
+            return Err(Error::InvalidMagicCookie);
+        }
+        if data[1] != HTYPE_ETHERNET || data[2] != 6 {
+            return Err(Error::InvalidHardwareType);
+        }
+        let mut msg = Message::new(
+            MessageType::Discover,
+            read_u32(&data[4..8]),
+            EthernetAddress::from_bytes(&data[28..34]),
+        );
+        msg.secs = u16::from_be_bytes([data[8], data[9]]);
+        msg.broadcast = u16::from_be_bytes([data[10], data[11]]) & FLAG_BROADCAST != 0;
+        msg.client_addr = Ipv4Address::from_bytes(&data[12..16]);
+        msg.your_addr = Ipv4Address::from_bytes(&data[16..20]);
+
+        let mut message_type = None;
+        let mut options = &data[HEADER_LEN..];
+        while let Some((&code, rest)) = options.split_first() {
+            if code == OPT_END {
+                break;
+            }
+            if code == OPT_PAD {
+                options = rest;
+                continue;
+            }
+            let (&len, rest) = rest.split_first().ok_or(Error::Truncated)?;
+            let len = usize::from(len);
+            if rest.len() < len {
+                return Err(Error::Truncated);
+            }
+            let (value, rest) = rest.split_at(len);
+            options = rest;
+            let addr = if len >= 4 {
+                Some(Ipv4Address::from_bytes(&value[..4]))
+            } else {
+                None
+            };
+            let seconds = if len == 4 {
+                Some(read_u32(value))
+            } else {
+                None
+            };
+            match code {
+                OPT_MESSAGE_TYPE if len == 1 => message_type = MessageType::from_u8(value[0]),
+                OPT_SUBNET_MASK => msg.subnet_mask = addr,
+                OPT_ROUTER => msg.router = addr,
+                OPT_DNS_SERVERS => {
+                    for server in value.chunks_exact(4) {
+                        if msg
+                            .dns_servers
+                            .push(Ipv4Address::from_bytes(server))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+                OPT_NTP_SERVERS => msg.ntp_server = addr,
+                OPT_REQUESTED_ADDR => msg.requested_addr = addr,
+                OPT_SERVER_ID => msg.server_id = addr,
+                OPT_LEASE_TIME => msg.lease_time = seconds,
+                OPT_RENEWAL_TIME => msg.renewal_time = seconds,
+                OPT_REBINDING_TIME => msg.rebinding_time = seconds,
+                _ => (),
+            }
+        }
+        msg.message_type = message_type.ok_or(Error::InvalidMessageType)?;
+        // A request in a reply, or the other way around
+        let op = if msg.message_type.is_request() {
+            BOOT_REQUEST
+        } else {
+            BOOT_REPLY
+        };
+        if data[0] != op {
+            return Err(Error::InvalidMessageType);
+        }
+        Ok(msg)
+    }
+
+    /// Writes the message to `buf`, padded to `MIN_LEN`, returns its
+    /// length
+    pub fn emit(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < MIN_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        for b in buf[..HEADER_LEN].iter_mut() {
+            *b = 0;
+        }
+        buf[0] = if self.message_type.is_request() {
+            BOOT_REQUEST
+        } else {
+            BOOT_REPLY
+        };
+        buf[1] = HTYPE_ETHERNET;
+        buf[2] = 6;
+        buf[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        buf[8..10].copy_from_slice(&self.secs.to_be_bytes());
+        if self.broadcast {
+            buf[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+        }
+        buf[12..16].copy_from_slice(self.client_addr.as_bytes());
+        buf[16..20].copy_from_slice(self.your_addr.as_bytes());
+        buf[28..34].copy_from_slice(self.client_hw_addr.as_bytes());
+        buf[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut options = Options {
+            buf: &mut buf[HEADER_LEN..],
+            len: 0,
+        };
+        options.push(OPT_MESSAGE_TYPE, &[self.message_type.as_u8()])?;
+        if self.message_type.is_request() {
+            let mut client_id = [HTYPE_ETHERNET; 7];
+            client_id[1..].copy_from_slice(self.client_hw_addr.as_bytes());
+            options.push(OPT_CLIENT_ID, &client_id)?;
+            options.push(OPT_MAX_MESSAGE_SIZE, &(MAX_LEN as u16).to_be_bytes())?;
+        }
+        options.push_addr(OPT_REQUESTED_ADDR, self.requested_addr)?;
+        options.push_addr(OPT_SERVER_ID, self.server_id)?;
+        options.push_addr(OPT_SUBNET_MASK, self.subnet_mask)?;
+        options.push_addr(OPT_ROUTER, self.router)?;
+        if !self.dns_servers.is_empty() {
+            let mut servers = [0; 12];
+            for (s, addr) in servers.chunks_mut(4).zip(self.dns_servers.iter()) {
+                s.copy_from_slice(addr.as_bytes());
+            }
+            options.push(OPT_DNS_SERVERS, &servers[..4 * self.dns_servers.len()])?;
+        }
+        options.push_addr(OPT_NTP_SERVERS, self.ntp_server)?;
+        options.push_seconds(OPT_LEASE_TIME, self.lease_time)?;
+        options.push_seconds(OPT_RENEWAL_TIME, self.renewal_time)?;
+        options.push_seconds(OPT_REBINDING_TIME, self.rebinding_time)?;
+        if self.message_type == MessageType::Discover || self.message_type == MessageType::Request {
+            options.push(OPT_PARAMETER_REQUEST_LIST, &PARAMETER_REQUEST_LIST)?;
+        }
+        options.push(OPT_END, &[])?;
+
+        let end = HEADER_LEN + options.len;
+        let len = end.max(MIN_LEN);
+        for b in buf[end..len].iter_mut() {
+            *b = OPT_PAD;
+        }
+        Ok(len)
+    }
+}
+
+struct Options<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Options<'a> {
+    /// The end option has no length
+    fn push(&mut self, code: u8, value: &[u8]) -> Result<(), Error> {
+        let len = if code == OPT_END { 1 } else { 2 + value.len() };
+        let buf = self
+            .buf
+            .get_mut(self.len..self.len + len)
+            .ok_or(Error::BufferTooSmall)?;
+        buf[0] = code;
+        if code != OPT_END {
+            buf[1] = value.len() as u8;
+            buf[2..].copy_from_slice(value);
+        }
+        self.len += len;
+        Ok(())
+    }
+
+    fn push_addr(&mut self, code: u8, addr: Option<Ipv4Address>) -> Result<(), Error> {
+        match addr {
+            Some(a) => self.push(code, a.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    fn push_seconds(&mut self, code: u8, seconds: Option<u32>) -> Result<(), Error> {
+        match seconds {
+            Some(s) => self.push(code, &s.to_be_bytes()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Callers have checked the length
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+
+    fn ack() -> Message {
+        let mut msg = Message::new(MessageType::Ack, 0x1234_5678, MAC);
+        msg.your_addr = Ipv4Address::new(10, 0, 0, 50);
+        msg.server_id = Some(Ipv4Address::new(10, 0, 0, 1));
+        msg.subnet_mask = Some(Ipv4Address::new(255, 255, 255, 0));
+        msg.router = Some(Ipv4Address::new(10, 0, 0, 1));
+        msg.dns_servers.push(Ipv4Address::new(10, 0, 0, 2)).unwrap();
+        msg.dns_servers.push(Ipv4Address::new(8, 8, 8, 8)).unwrap();
+        msg.ntp_server = Some(Ipv4Address::new(10, 0, 0, 3));
+        msg.lease_time = Some(3600);
+        msg.renewal_time = Some(1800);
+        msg.rebinding_time = Some(3150);
+        msg
+    }
+
+    #[test]
+    fn emit_parse() {
+        let mut buf = [0; MAX_LEN];
+        let msg = ack();
+        let len = msg.emit(&mut buf).unwrap();
+        assert_eq!(len, MIN_LEN);
+        assert_eq!(buf[0], BOOT_REPLY);
+        assert_eq!(&buf[236..243], &[99, 130, 83, 99, OPT_MESSAGE_TYPE, 1, 5]);
+        assert_eq!(Message::parse(&buf[..len]), Ok(msg));
+
+        let mut msg = Message::new(MessageType::Request, 7, MAC);
+        msg.broadcast = true;
+        msg.secs = 3;
+        msg.requested_addr = Some(Ipv4Address::new(10, 0, 0, 50));
+        msg.server_id = Some(Ipv4Address::new(10, 0, 0, 1));
+        let len = msg.emit(&mut buf).unwrap();
+        assert_eq!(buf[0], BOOT_REQUEST);
+        assert_eq!(&buf[10..12], &[0x80, 0]);
+        assert_eq!(&buf[243..252], &[OPT_CLIENT_ID, 7, 1, 2, 0, 0, 0, 0, 1]);
+        assert_eq!(Message::parse(&buf[..len]), Ok(msg));
+
+        assert_eq!(
+            Message::new(MessageType::Discover, 1, MAC).emit(&mut buf[..299]),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        let mut buf = [0; MAX_LEN];
+        let len = Message::new(MessageType::Offer, 9, MAC)
+            .emit(&mut buf)
+            .unwrap();
+        // Replace the end with padding, an unknown option, 4 DNS
+        // servers and two routers
+        let options: &[u8] = &[
+            0, 0, 12, 3, b'a', b'b', b'c', 6, 16, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4,
+            3, 8, 10, 0, 0, 1, 10, 0, 0, 254, 255,
+        ];
+        buf[243..243 + options.len()].copy_from_slice(options);
+        let msg = Message::parse(&buf[..len]).unwrap();
+        assert_eq!(msg.message_type, MessageType::Offer);
+        assert_eq!(
+            &msg.dns_servers[..],
+            &[
+                Ipv4Address::new(1, 1, 1, 1),
+                Ipv4Address::new(2, 2, 2, 2),
+                Ipv4Address::new(3, 3, 3, 3)
+            ]
+        );
+        assert_eq!(msg.router, Some(Ipv4Address::new(10, 0, 0, 1)));
+        assert_eq!(msg.lease_time, None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut buf = [0; MAX_LEN];
+        let len = ack().emit(&mut buf).unwrap();
+        assert_eq!(Message::parse(&buf[..239]), Err(Error::Truncated));
+
+        let mut bad = buf;
+        bad[236] = 0;
+        assert_eq!(Message::parse(&bad[..len]), Err(Error::InvalidMagicCookie));
+
+        let mut bad = buf;
+        bad[1] = 6;
+        assert_eq!(Message::parse(&bad[..len]), Err(Error::InvalidHardwareType));
+
+        // Option length past the end
+        let mut bad = buf;
+        bad[len - 2] = OPT_ROUTER;
+        bad[len - 1] = 4;
+        for b in bad[243..len - 2].iter_mut() {
+            *b = OPT_PAD;
+        }
+        assert_eq!(Message::parse(&bad[..len]), Err(Error::Truncated));
+
+        // No message type, or an ACK from a client
+        let mut bad = buf;
+        bad[240] = OPT_PAD;
+        assert_eq!(Message::parse(&bad[..len]), Err(Error::InvalidMessageType));
+        let mut bad = buf;
+        bad[0] = BOOT_REQUEST;
+        assert_eq!(Message::parse(&bad[..len]), Err(Error::InvalidMessageType));
+    }
+}
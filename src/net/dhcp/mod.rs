@@ -0,0 +1,882 @@
+//! DHCPv4 client (RFC 2131)
+//!
+//! Leases an address over a UDP socket on the client port, renews it
+//! with the server at T1 and rebinds with any server at T2. The
+//! interface's first `ip_addrs` slot and the default route follow the
+//! lease, and a static fallback config is applied if no lease turns up
+//! in time (discovery carries on in the background).
+//!
+//! Requests sent before an address is configured ask for broadcast
+//! replies, smoltcp drops unicast to an address the interface doesn't
+//! have yet.
+
+pub mod message;
+
+pub use crate::net::dhcp::message::{MaxDnsServers, Message, MessageType};
+
+use crate::net::dhcp::message::{CLIENT_PORT, MAX_LEN, SERVER_PORT};
+use crate::net::routes;
+use crate::net::socket_pool::UdpHandle;
+use crate::random::{RandomSource, XorShift32};
+use crate::time::{Duration, Instant};
+use heapless::Vec;
+use log::{debug, info, warn};
+use smoltcp::iface::EthernetInterface;
+use smoltcp::phy::Device;
+use smoltcp::socket::{SocketSet, UdpSocket};
+use smoltcp::wire::{EthernetAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr};
+
+/// First retransmission timeout, doubled up to `MAX_TIMEOUT`
+pub const INITIAL_TIMEOUT: Duration = Duration::from_secs(4);
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(64);
+
+/// Unanswered REQUESTs before starting over with a DISCOVER
+pub const MAX_REQUESTS: u8 = 4;
+
+/// Shortest wait between REQUESTs while renewing or rebinding
+pub const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Discovering for this long without a lease applies the fallback
+pub const FALLBACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Used when the server doesn't send a (valid) subnet mask
+pub const DEFAULT_PREFIX_LEN: u8 = 24;
+
+/// Lease time option value of a lease that never expires
+const INFINITE_LEASE: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    Message(message::Error),
+    Socket(smoltcp::Error),
+    Routes(routes::Error),
+}
+
+impl From<message::Error> for Error {
+    fn from(e: message::Error) -> Self {
+        Error::Message(e)
+    }
+}
+
+impl From<routes::Error> for Error {
+    fn from(e: routes::Error) -> Self {
+        Error::Routes(e)
+    }
+}
+
+impl From<smoltcp::Error> for Error {
+    fn from(e: smoltcp::Error) -> Self {
+        Error::Socket(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum State {
+    /// About to start discovery
+    Init,
+    /// DISCOVER sent, waiting for an offer
+    Selecting,
+    /// Offer taken, REQUEST sent to its server
+    Requesting,
+    /// Lease held
+    Bound,
+    /// Past T1, extending the lease with its server
+    Renewing,
+    /// Past T2, extending the lease with any server
+    Rebinding,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State::Init
+    }
+}
+
+/// Network configuration, from a lease or the static fallback
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Config {
+    pub address: Ipv4Cidr,
+    pub router: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address, MaxDnsServers>,
+    pub ntp_server: Option<Ipv4Address>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Lease {
+    server: Ipv4Address,
+    /// T1
+    renew_at: Instant,
+    /// T2
+    rebind_at: Instant,
+    expires_at: Instant,
+}
+
+pub struct Client {
+    handle: UdpHandle,
+    mac: EthernetAddress,
+    fallback: Option<Config>,
+    rng: XorShift32,
+    state: State,
+    xid: u32,
+    /// Start of the current exchange, for the `secs` field and the
+    /// fallback
+    started: Instant,
+    next_transmit: Instant,
+    timeout: Duration,
+    requests: u8,
+    /// Server and address of the offer being requested
+    offer: Option<(Ipv4Address, Ipv4Address)>,
+    lease: Option<Lease>,
+    config: Option<Config>,
+    /// Config changed since it was last applied to the interface
+    changed: bool,
+}
+
+impl Client {
+    /// `handle` is a UDP socket in the interface's socket set, it gets
+    /// bound to the client port. `mac` is the interface's address.
+    pub fn new<R: RandomSource>(
+        handle: UdpHandle,
+        mac: EthernetAddress,
+        fallback: Option<Config>,
+        rng: &mut R,
+    ) -> Self {
+        Client {
+            handle,
+            mac,
+            fallback,
+            rng: XorShift32::new(rng.next_u32()),
+            state: State::Init,
+            xid: 0,
+            started: Instant::from_secs(0),
+            next_transmit: Instant::from_secs(0),
+            timeout: INITIAL_TIMEOUT,
+            requests: 0,
+            offer: None,
+            lease: None,
+            config: None,
+            changed: false,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Configuration in use, if any
+    pub fn config(&self) -> Option<&Config> {
+        self.config.as_ref()
+    }
+
+    pub fn socket_handle(&self) -> UdpHandle {
+        self.handle
+    }
+
+    /// Receives replies, runs the lease timers and sends any
+    /// outstanding message. Applies a changed config to the
+    /// interface's first address slot and default route, and returns
+    /// true when it did.
+    pub fn poll<DeviceT>(
+        &mut self,
+        iface: &mut EthernetInterface<'_, '_, '_, DeviceT>,
+        sockets: &mut SocketSet,
+        time: Instant,
+    ) -> Result<bool, Error>
+    where
+        DeviceT: for<'d> Device<'d>,
+    {
+        {
+            let mut socket = sockets.get::<UdpSocket>(self.handle.into());
+            if !socket.is_open() {
+                socket.bind(CLIENT_PORT)?;
+            }
+
+            while let Ok((data, from)) = socket.recv() {
+                match Message::parse(data) {
+                    Ok(msg) => {
+                        if !self.handle_reply(&msg, time) {
+                            debug!("Ignoring DHCP message from {}", from);
+                        }
+                    }
+                    Err(e) => warn!("Bad DHCP message from {}: {:?}", from, e),
+                }
+            }
+
+            self.poll_timers(time);
+            if let Some((msg, remote)) = self.poll_transmit(time) {
+                let mut buf = [0; MAX_LEN];
+                let len = msg.emit(&mut buf)?;
+                socket.send_slice(&buf[..len], remote)?;
+            }
+        }
+
+        if !self.changed {
+            return Ok(false);
+        }
+        configure(iface, self.config.as_ref())?;
+        self.changed = false;
+        Ok(true)
+    }
+
+    /// Returns false if the message isn't a reply for us in this state
+    fn handle_reply(&mut self, msg: &Message, time: Instant) -> bool {
+        if msg.xid != self.xid || msg.client_hw_addr != self.mac {
+            return false;
+        }
+        match (self.state, msg.message_type) {
+            (State::Selecting, MessageType::Offer) => {
+                let server = match msg.server_id {
+                    Some(s) if msg.your_addr.is_unicast() => s,
+                    _ => return false,
+                };
+                debug!("DHCP offer of {} from {}", msg.your_addr, server);
+                self.offer = Some((server, msg.your_addr));
+                self.state = State::Requesting;
+                self.requests = 0;
+                self.timeout = INITIAL_TIMEOUT;
+                self.next_transmit = time;
+                true
+            }
+            (State::Requesting, MessageType::Ack) => {
+                if msg.server_id != self.offer.map(|(server, _)| server) {
+                    return false;
+                }
+                self.bind(msg, time)
+            }
+            (State::Renewing, MessageType::Ack) | (State::Rebinding, MessageType::Ack) => {
+                self.bind(msg, time)
+            }
+            (State::Requesting, MessageType::Nak)
+            | (State::Renewing, MessageType::Nak)
+            | (State::Rebinding, MessageType::Nak) => {
+                warn!("DHCP NAK from {:?}", msg.server_id);
+                self.restart(time);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn bind(&mut self, msg: &Message, time: Instant) -> bool {
+        let server = match msg.server_id.or_else(|| self.lease.map(|l| l.server)) {
+            Some(s) if msg.your_addr.is_unicast() => s,
+            _ => return false,
+        };
+
+        let lease = seconds(msg.lease_time.unwrap_or(INFINITE_LEASE));
+        let rebind = msg
+            .rebinding_time
+            .map(seconds)
+            .filter(|t| *t <= lease)
+            .unwrap_or(lease * 7 / 8);
+        let renew = msg
+            .renewal_time
+            .map(seconds)
+            .filter(|t| *t <= rebind)
+            .unwrap_or(lease / 2)
+            .min(rebind);
+        self.lease = Some(Lease {
+            server,
+            renew_at: time + renew,
+            rebind_at: time + rebind,
+            expires_at: time + lease,
+        });
+
+        let prefix_len = msg
+            .subnet_mask
+            .and_then(prefix_len)
+            .unwrap_or(DEFAULT_PREFIX_LEN);
+        let config = Config {
+            address: Ipv4Cidr::new(msg.your_addr, prefix_len),
+            router: msg.router,
+            dns_servers: msg.dns_servers.clone(),
+            ntp_server: msg.ntp_server,
+        };
+        if self.config.as_ref() != Some(&config) {
+            info!(
+                "DHCP lease of {} from {} for {} s",
+                config.address,
+                server,
+                lease.as_secs()
+            );
+            self.config = Some(config);
+            self.changed = true;
+        }
+        self.offer = None;
+        self.state = State::Bound;
+        true
+    }
+
+    /// Drops any lease and goes back to discovery
+    fn restart(&mut self, time: Instant) {
+        if self.lease.take().is_some() {
+            self.config = None;
+            self.changed = true;
+        }
+        self.offer = None;
+        self.state = State::Init;
+        self.next_transmit = time;
+    }
+
+    fn poll_timers(&mut self, time: Instant) {
+        match (self.state, self.lease) {
+            (State::Init, _) => {
+                self.xid = self.rng.next_u32();
+                self.started = time;
+                self.requests = 0;
+                self.timeout = INITIAL_TIMEOUT;
+                self.next_transmit = time;
+                self.state = State::Selecting;
+            }
+            (State::Selecting, _)
+                if self.config.is_none() && time >= self.started + FALLBACK_TIMEOUT =>
+            {
+                if let Some(fallback) = self.fallback.clone() {
+                    warn!("No DHCP lease, falling back to {}", fallback.address);
+                    self.config = Some(fallback);
+                    self.changed = true;
+                }
+            }
+            (State::Bound, Some(lease)) if time >= lease.renew_at => {
+                debug!("DHCP renewing");
+                self.xid = self.rng.next_u32();
+                self.started = time;
+                self.next_transmit = time;
+                self.state = State::Renewing;
+            }
+            (State::Renewing, Some(lease)) if time >= lease.rebind_at => {
+                debug!("DHCP rebinding");
+                self.next_transmit = time;
+                self.state = State::Rebinding;
+            }
+            (State::Rebinding, Some(lease)) if time >= lease.expires_at => {
+                warn!("DHCP lease expired");
+                self.restart(time);
+            }
+            _ => (),
+        }
+    }
+
+    /// Message due in this state, and where to send it
+    fn poll_transmit(&mut self, time: Instant) -> Option<(Message, IpEndpoint)> {
+        if time < self.next_transmit {
+            return None;
+        }
+        let broadcast = IpEndpoint::new(Ipv4Address::BROADCAST.into(), SERVER_PORT);
+        let mut msg = match self.state {
+            State::Selecting => Message::new(MessageType::Discover, self.xid, self.mac),
+            State::Requesting => {
+                if self.requests >= MAX_REQUESTS {
+                    warn!("DHCP request timed out");
+                    self.restart(time);
+                    return None;
+                }
+                self.requests += 1;
+                // Can't fail, only requesting with an offer
+                let (server, addr) = self.offer.unwrap();
+                let mut msg = Message::new(MessageType::Request, self.xid, self.mac);
+                msg.server_id = Some(server);
+                msg.requested_addr = Some(addr);
+                msg
+            }
+            State::Renewing | State::Rebinding => {
+                let mut msg = Message::new(MessageType::Request, self.xid, self.mac);
+                msg.client_addr = self
+                    .config
+                    .as_ref()
+                    .map_or(Ipv4Address::UNSPECIFIED, |c| c.address.address());
+                msg
+            }
+            State::Init | State::Bound => return None,
+        };
+        msg.secs = time
+            .checked_sub(self.started)
+            .map_or(0, |d| d.as_secs().min(u64::from(u16::max_value())) as u16);
+        msg.broadcast = msg.client_addr.is_unspecified();
+
+        let remote = match (self.state, self.lease) {
+            (State::Renewing, Some(lease)) => {
+                self.next_transmit = time + half_remaining(lease.rebind_at, time);
+                IpEndpoint::new(lease.server.into(), SERVER_PORT)
+            }
+            (State::Rebinding, Some(lease)) => {
+                self.next_transmit = time + half_remaining(lease.expires_at, time);
+                broadcast
+            }
+            _ => {
+                // Randomized by +/- 1 s
+                let jitter = Duration::from_millis(u64::from(self.rng.next_u32() % 2000));
+                self.next_transmit = time + self.timeout + jitter - Duration::from_secs(1);
+                self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+                broadcast
+            }
+        };
+        Some((msg, remote))
+    }
+}
+
+/// Writes the config's address to the interface's first address slot,
+/// 0.0.0.0/0 without one, and replaces the default route
+fn configure<DeviceT>(
+    iface: &mut EthernetInterface<'_, '_, '_, DeviceT>,
+    config: Option<&Config>,
+) -> Result<(), Error>
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    let address = config.map_or(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0), |c| c.address);
+    iface.update_ip_addrs(|addrs| {
+        if let Some(addr) = addrs.iter_mut().next() {
+            *addr = IpCidr::Ipv4(address);
+        }
+    });
+
+    routes::set_default_gateway(iface, config.and_then(|c| c.router))?;
+    Ok(())
+}
+
+/// RFC 2131 waits half the time left until T2 (or the lease expiring)
+fn half_remaining(until: Instant, time: Instant) -> Duration {
+    (until.checked_sub(time).unwrap_or_default() / 2).max(MIN_RENEW_INTERVAL)
+}
+
+fn seconds(s: u32) -> Duration {
+    Duration::from_secs(u64::from(s))
+}
+
+/// Prefix length of a contiguous subnet mask
+fn prefix_len(mask: Ipv4Address) -> Option<u8> {
+    let mask = u32::from_be_bytes(mask.0);
+    let ones = (!mask).leading_zeros();
+    if mask.count_ones() == ones {
+        Some(ones as u8)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::loopback::{Loopback, MAC};
+    use smoltcp::socket::SocketHandle;
+    use smoltcp::wire::IpAddress;
+    const SERVER: Ipv4Address = Ipv4Address([10, 0, 0, 1]);
+    const LEASED: Ipv4Address = Ipv4Address([10, 0, 0, 50]);
+    const LEASE_TIME: u32 = 3600;
+
+    type Iface<'a> = EthernetInterface<'a, 'a, 'a, Loopback>;
+
+    fn fallback() -> Config {
+        Config {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 1, 39), 24),
+            router: None,
+            dns_servers: Vec::new(),
+            ntp_server: None,
+        }
+    }
+
+    /// Client and a bound `server` on a loopback interface holding the
+    /// server's address
+    macro_rules! setup {
+        ($iface:ident, $sockets:ident, $client:ident, $server:ident, $fallback:expr, $seed:expr) => {
+            crate::loopback!(
+                $iface,
+                $sockets,
+                [
+                    IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0),
+                    IpCidr::new(SERVER.into(), 24),
+                ],
+                1,
+                {
+                    client_handle: [2; 1024],
+                    server_handle: [2; 1024],
+                }
+            );
+            let $server = Server {
+                handle: server_handle,
+            };
+            let mut $client = Client::new(
+                UdpHandle::from_socket(client_handle),
+                MAC,
+                $fallback,
+                &mut XorShift32::new($seed),
+            );
+            // Bind the server
+            assert_eq!($server.recv(&mut $sockets), None);
+        };
+    }
+
+    /// Scripted server on the same interface
+    struct Server {
+        handle: SocketHandle,
+    }
+
+    impl Server {
+        fn recv(&self, sockets: &mut SocketSet) -> Option<Message> {
+            let mut socket = sockets.get::<UdpSocket>(self.handle);
+            if !socket.is_open() {
+                socket.bind(SERVER_PORT).unwrap();
+            }
+            let msg = socket
+                .recv()
+                .ok()
+                .map(|(data, _)| Message::parse(data).unwrap());
+            assert_eq!(socket.can_recv(), false);
+            msg
+        }
+
+        fn reply(&self, sockets: &mut SocketSet, request: &Message, message_type: MessageType) {
+            let mut msg = Message::new(message_type, request.xid, request.client_hw_addr);
+            if message_type != MessageType::Nak {
+                msg.your_addr = LEASED;
+                msg.subnet_mask = Some(Ipv4Address::new(255, 255, 255, 0));
+                msg.router = Some(SERVER);
+                msg.dns_servers.push(SERVER).unwrap();
+                msg.ntp_server = Some(Ipv4Address::new(10, 0, 0, 2));
+                msg.lease_time = Some(LEASE_TIME);
+            }
+            msg.server_id = Some(SERVER);
+            let mut buf = [0; MAX_LEN];
+            let len = msg.emit(&mut buf).unwrap();
+            let remote = IpEndpoint::new(Ipv4Address::BROADCAST.into(), CLIENT_PORT);
+            let mut socket = sockets.get::<UdpSocket>(self.handle);
+            socket.send_slice(&buf[..len], remote).unwrap();
+        }
+    }
+
+    /// Delivers anything the server sent, polls the client and returns
+    /// whether the config changed and what the server got
+    fn step(
+        client: &mut Client,
+        server: &Server,
+        iface: &mut Iface,
+        sockets: &mut SocketSet,
+        time: Instant,
+    ) -> (bool, Option<Message>) {
+        let _ = iface.poll(sockets, smoltcp::time::Instant::from_millis(0));
+        let changed = client.poll(iface, sockets, time).unwrap();
+        let _ = iface.poll(sockets, smoltcp::time::Instant::from_millis(0));
+        (changed, server.recv(sockets))
+    }
+
+    /// Offers, and acks the request for, the address
+    fn accept(
+        client: &mut Client,
+        server: &Server,
+        iface: &mut Iface,
+        sockets: &mut SocketSet,
+        discover: &Message,
+        time: Instant,
+    ) {
+        assert_eq!(discover.message_type, MessageType::Discover);
+        server.reply(sockets, discover, MessageType::Offer);
+        let (_, request) = step(client, server, iface, sockets, time);
+        let request = request.unwrap();
+        assert_eq!(request.message_type, MessageType::Request);
+        server.reply(sockets, &request, MessageType::Ack);
+        assert_eq!(step(client, server, iface, sockets, time), (true, None));
+        assert_eq!(client.state(), State::Bound);
+    }
+
+    /// Runs discover, offer, request and ack at `time`
+    fn lease(
+        client: &mut Client,
+        server: &Server,
+        iface: &mut Iface,
+        sockets: &mut SocketSet,
+        time: Instant,
+    ) {
+        let (_, discover) = step(client, server, iface, sockets, time);
+        accept(client, server, iface, sockets, &discover.unwrap(), time);
+    }
+
+    #[test]
+    fn lease_renew_rebind_expire() {
+        setup!(iface, sockets, client, server, Some(fallback()), 1);
+        let s = |secs| Instant::from_secs(secs);
+
+        let (changed, discover) = step(&mut client, &server, &mut iface, &mut sockets, s(0));
+        assert_eq!(changed, false);
+        let discover = discover.unwrap();
+        assert_eq!(discover.message_type, MessageType::Discover);
+        assert_eq!(discover.client_hw_addr, MAC);
+        assert_eq!(discover.broadcast, true);
+        assert_eq!(discover.client_addr, Ipv4Address::UNSPECIFIED);
+        assert_eq!(client.state(), State::Selecting);
+
+        // Offers for someone else
+        let mut other = discover.clone();
+        other.xid = discover.xid.wrapping_add(1);
+        server.reply(&mut sockets, &other, MessageType::Offer);
+        let mut other = discover.clone();
+        other.client_hw_addr = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+        server.reply(&mut sockets, &other, MessageType::Offer);
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, s(1)),
+            (false, None)
+        );
+        assert_eq!(client.state(), State::Selecting);
+
+        server.reply(&mut sockets, &discover, MessageType::Offer);
+        let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, s(2));
+        let request = request.unwrap();
+        assert_eq!(client.state(), State::Requesting);
+        assert_eq!(request.message_type, MessageType::Request);
+        assert_eq!(request.xid, discover.xid);
+        assert_eq!(request.secs, 2);
+        assert_eq!(request.requested_addr, Some(LEASED));
+        assert_eq!(request.server_id, Some(SERVER));
+
+        server.reply(&mut sockets, &request, MessageType::Ack);
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, s(2)),
+            (true, None)
+        );
+        assert_eq!(client.state(), State::Bound);
+        let config = client.config().unwrap();
+        assert_eq!(config.address, Ipv4Cidr::new(LEASED, 24));
+        assert_eq!(config.router, Some(SERVER));
+        assert_eq!(&config.dns_servers[..], &[SERVER]);
+        assert_eq!(config.ntp_server, Some(Ipv4Address::new(10, 0, 0, 2)));
+        assert_eq!(iface.ip_addrs()[0], IpCidr::new(LEASED.into(), 24));
+        assert_eq!(routes::default_gateway(&mut iface), Some(SERVER));
+
+        // Renew with the server at T1
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, s(1801)),
+            (false, None)
+        );
+        let (_, renew) = step(&mut client, &server, &mut iface, &mut sockets, s(1802));
+        let renew = renew.unwrap();
+        assert_eq!(client.state(), State::Renewing);
+        assert_eq!(renew.message_type, MessageType::Request);
+        assert_eq!(renew.client_addr, LEASED);
+        assert_eq!(renew.broadcast, false);
+        assert_eq!(renew.requested_addr, None);
+        assert_eq!(renew.server_id, None);
+        server.reply(&mut sockets, &renew, MessageType::Ack);
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, s(1803)),
+            (false, None)
+        );
+        assert_eq!(client.state(), State::Bound);
+
+        // Unanswered, retransmits at half the time left to T2 then
+        // rebinds, new lease is from 1803 s
+        let (_, renew) = step(&mut client, &server, &mut iface, &mut sockets, s(3603));
+        assert_eq!(renew.unwrap().client_addr, LEASED);
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, s(4277)),
+            (false, None)
+        );
+        let (_, renew) = step(&mut client, &server, &mut iface, &mut sockets, s(4278));
+        assert_eq!(renew.unwrap().client_addr, LEASED);
+        let (_, rebind) = step(&mut client, &server, &mut iface, &mut sockets, s(4953));
+        let rebind = rebind.unwrap();
+        assert_eq!(client.state(), State::Rebinding);
+        assert_eq!(rebind.client_addr, LEASED);
+        assert_eq!(rebind.secs, 1350);
+
+        // Expired, back to discovery without an address
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, s(5403)),
+            (true, None)
+        );
+        assert_eq!(client.state(), State::Init);
+        assert_eq!(client.config(), None);
+        assert_eq!(
+            iface.ip_addrs()[0],
+            IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0)
+        );
+        assert_eq!(routes::default_gateway(&mut iface), None);
+        lease(&mut client, &server, &mut iface, &mut sockets, s(5404));
+        assert_eq!(iface.ip_addrs()[0], IpCidr::new(LEASED.into(), 24));
+    }
+
+    #[test]
+    fn nak_and_request_timeout() {
+        setup!(iface, sockets, client, server, None, 2);
+        let s = |secs| Instant::from_secs(secs);
+
+        // NAK'd request starts over with a new transaction
+        let (_, discover) = step(&mut client, &server, &mut iface, &mut sockets, s(0));
+        let discover = discover.unwrap();
+        server.reply(&mut sockets, &discover, MessageType::Offer);
+        let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, s(0));
+        server.reply(&mut sockets, &request.unwrap(), MessageType::Nak);
+        let (changed, rediscover) = step(&mut client, &server, &mut iface, &mut sockets, s(1));
+        let rediscover = rediscover.unwrap();
+        assert_eq!(changed, false);
+        assert_eq!(client.state(), State::Selecting);
+        assert_eq!(rediscover.message_type, MessageType::Discover);
+        assert_ne!(rediscover.xid, discover.xid);
+
+        // Unanswered requests back off, then start over
+        server.reply(&mut sockets, &rediscover, MessageType::Offer);
+        let mut requests = 0;
+        let mut secs = 1;
+        let discover = loop {
+            let (_, msg) = step(&mut client, &server, &mut iface, &mut sockets, s(secs));
+            match msg {
+                Some(m) if m.message_type == MessageType::Request => requests += 1,
+                Some(m) => break m,
+                None => (),
+            }
+            secs += 1;
+        };
+        assert_eq!(requests, MAX_REQUESTS);
+        // 4 + 8 + 16 + 32 s, +/- 1 s each
+        assert!((58..=67).contains(&secs), "{}", secs);
+        assert_eq!(client.config(), None);
+
+        // NAK while renewing drops the lease
+        accept(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            &discover,
+            s(secs),
+        );
+        assert_eq!(routes::default_gateway(&mut iface), Some(SERVER));
+        let (_, renew) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            s(secs + 1800),
+        );
+        server.reply(&mut sockets, &renew.unwrap(), MessageType::Nak);
+        let (changed, discover) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            s(secs + 1801),
+        );
+        assert_eq!(changed, true);
+        assert_eq!(discover.unwrap().message_type, MessageType::Discover);
+        assert_eq!(client.config(), None);
+        assert_eq!(
+            iface.ip_addrs()[0],
+            IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0)
+        );
+        assert_eq!(routes::default_gateway(&mut iface), None);
+    }
+
+    #[test]
+    fn configure_retried() {
+        setup!(iface, sockets, client, server, None, 4);
+        let s = |secs| Instant::from_secs(secs);
+
+        // No room for the default route
+        let static_route = Ipv4Cidr::new(Ipv4Address::new(10, 1, 0, 0), 16);
+        routes::add(&mut iface, static_route, SERVER).unwrap();
+        let (_, discover) = step(&mut client, &server, &mut iface, &mut sockets, s(0));
+        server.reply(&mut sockets, &discover.unwrap(), MessageType::Offer);
+        let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, s(0));
+        server.reply(&mut sockets, &request.unwrap(), MessageType::Ack);
+        for _ in 0..2 {
+            let _ = iface.poll(&mut sockets, smoltcp::time::Instant::from_millis(0));
+            assert_eq!(
+                client.poll(&mut iface, &mut sockets, s(1)),
+                Err(Error::Routes(routes::Error::Full))
+            );
+        }
+        assert_eq!(client.state(), State::Bound);
+
+        // Applied once there's room
+        assert_eq!(routes::remove(&mut iface, static_route), true);
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, s(2)),
+            (true, None)
+        );
+        assert_eq!(routes::default_gateway(&mut iface), Some(SERVER));
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, s(3)),
+            (false, None)
+        );
+    }
+
+    #[test]
+    fn fallback_then_lease() {
+        setup!(iface, sockets, client, server, Some(fallback()), 3);
+
+        // Discover at 0, 4, 12 and 28 s, each randomized by +/- 1 s
+        let mut discovers: Vec<u64, heapless::consts::U8> = Vec::new();
+        for secs in 0..30 {
+            let (changed, msg) = step(
+                &mut client,
+                &server,
+                &mut iface,
+                &mut sockets,
+                Instant::from_secs(secs),
+            );
+            assert_eq!(changed, false);
+            if let Some(msg) = msg {
+                assert_eq!(msg.message_type, MessageType::Discover);
+                assert_eq!(u64::from(msg.secs), secs);
+                discovers.push(secs).unwrap();
+            }
+        }
+        assert!(discovers.len() >= 3, "{:?}", discovers);
+        assert_eq!(discovers[0], 0);
+        assert!((3..=5).contains(&discovers[1]), "{:?}", discovers);
+        assert!((10..=14).contains(&discovers[2]), "{:?}", discovers);
+        assert_eq!(client.config(), None);
+
+        let (changed, _) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            Instant::from_secs(30),
+        );
+        assert_eq!(changed, true);
+        assert_eq!(client.state(), State::Selecting);
+        assert_eq!(client.config(), Some(&fallback()));
+        assert_eq!(
+            iface.ip_addrs()[0],
+            IpCidr::new(IpAddress::v4(192, 168, 1, 39), 24)
+        );
+        assert_eq!(routes::default_gateway(&mut iface), None);
+
+        // Still discovering, a lease replaces the fallback
+        let mut secs = 31;
+        let discover = loop {
+            let (_, msg) = step(
+                &mut client,
+                &server,
+                &mut iface,
+                &mut sockets,
+                Instant::from_secs(secs),
+            );
+            if let Some(msg) = msg {
+                break msg;
+            }
+            secs += 1;
+        };
+        assert_eq!(discover.client_addr, Ipv4Address::UNSPECIFIED);
+        accept(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            &discover,
+            Instant::from_secs(secs),
+        );
+        assert_eq!(client.config().unwrap().address, Ipv4Cidr::new(LEASED, 24));
+        assert_eq!(iface.ip_addrs()[0], IpCidr::new(LEASED.into(), 24));
+        assert_eq!(routes::default_gateway(&mut iface), Some(SERVER));
+    }
+
+    #[test]
+    fn subnet_masks() {
+        assert_eq!(prefix_len(Ipv4Address::new(255, 255, 255, 0)), Some(24));
+        assert_eq!(prefix_len(Ipv4Address::new(255, 255, 240, 0)), Some(20));
+        assert_eq!(prefix_len(Ipv4Address::new(255, 255, 255, 255)), Some(32));
+        assert_eq!(prefix_len(Ipv4Address::new(0, 0, 0, 0)), Some(0));
+        assert_eq!(prefix_len(Ipv4Address::new(255, 0, 255, 0)), None);
+    }
+}
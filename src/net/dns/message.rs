@@ -0,0 +1,517 @@
+//! DNS messages (RFC 1035, SRV from RFC 2782)
+//!
+//! Queries carry a single question with recursion desired. Responses
+//! are parsed in place, records are handed out one at a time with
+//! compressed names expanded. Names longer than `MaxNameLen` are
+//! rejected rather than truncated.
+
+use core::convert::TryInto;
+use heapless::consts::U64;
+use heapless::String;
+use smoltcp::wire::Ipv4Address;
+
+pub const HEADER_LEN: usize = 12;
+
+/// Largest response over UDP without EDNS
+pub const MAX_LEN: usize = 512;
+
+/// Longest name kept, in presentation format without the trailing dot
+pub type MaxNameLen = U64;
+
+pub type Name = String<MaxNameLen>;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const OPCODE_MASK: u16 = 0x7800;
+const RCODE_MASK: u16 = 0x000F;
+
+const CLASS_IN: u16 = 1;
+
+/// Longest label and longest encoded name
+const MAX_LABEL_LEN: usize = 63;
+const MAX_ENCODED_NAME_LEN: usize = 255;
+
+/// Compression pointers followed in one name before giving up
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Ends in the middle of the header, a name or a record
+    Truncated,
+    /// Empty or overlong label, reserved label type or not ASCII
+    InvalidName,
+    /// Longer than `MaxNameLen`
+    NameTooLong,
+    /// Too many compression pointers, most likely a loop
+    PointerLoop,
+    /// A query, or a response without a question
+    NotResponse,
+    /// Record data doesn't match its type
+    InvalidRecord,
+    /// Emit buffer too small for the query
+    BufferTooSmall,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Type {
+    A,
+    Cname,
+    Srv,
+    Other(u16),
+}
+
+impl From<u16> for Type {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Type::A,
+            5 => Type::Cname,
+            33 => Type::Srv,
+            _ => Type::Other(value),
+        }
+    }
+}
+
+impl From<Type> for u16 {
+    fn from(value: Type) -> Self {
+        match value {
+            Type::A => 1,
+            Type::Cname => 5,
+            Type::Srv => 33,
+            Type::Other(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Rcode {
+    NoError,
+    FormatError,
+    ServerFailure,
+    /// NXDOMAIN, the name doesn't exist
+    NameError,
+    NotImplemented,
+    Refused,
+    Other(u8),
+}
+
+impl From<u8> for Rcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Rcode::NoError,
+            1 => Rcode::FormatError,
+            2 => Rcode::ServerFailure,
+            3 => Rcode::NameError,
+            4 => Rcode::NotImplemented,
+            5 => Rcode::Refused,
+            _ => Rcode::Other(value),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// Empty for "." (service not available)
+    pub target: Name,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RecordData {
+    A(Ipv4Address),
+    Cname(Name),
+    Srv(Srv),
+    Other(Type),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    pub section: Section,
+    pub name: Name,
+    /// In seconds
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+/// Writes a recursive query for `name`, returns its length
+pub fn emit_query(id: u16, name: &str, qtype: Type, buf: &mut [u8]) -> Result<usize, Error> {
+    if buf.len() < HEADER_LEN {
+        return Err(Error::BufferTooSmall);
+    }
+    buf[0..2].copy_from_slice(&id.to_be_bytes());
+    buf[2..4].copy_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // One question, no records
+    buf[4..6].copy_from_slice(&1_u16.to_be_bytes());
+    for b in buf[6..HEADER_LEN].iter_mut() {
+        *b = 0;
+    }
+    let mut len = HEADER_LEN + emit_name(name, &mut buf[HEADER_LEN..])?;
+    let question = buf.get_mut(len..len + 4).ok_or(Error::BufferTooSmall)?;
+    question[0..2].copy_from_slice(&u16::from(qtype).to_be_bytes());
+    question[2..4].copy_from_slice(&CLASS_IN.to_be_bytes());
+    len += 4;
+    Ok(len)
+}
+
+/// Uncompressed wire format of `name`, a trailing dot is optional
+fn emit_name(name: &str, buf: &mut [u8]) -> Result<usize, Error> {
+    let name = name.trim_end_matches('.');
+    let mut len = 0;
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LEN || !label.is_ascii() {
+                return Err(Error::InvalidName);
+            }
+            let b = buf
+                .get_mut(len..len + 1 + label.len())
+                .ok_or(Error::BufferTooSmall)?;
+            b[0] = label.len() as u8;
+            b[1..].copy_from_slice(label.as_bytes());
+            len += 1 + label.len();
+        }
+    }
+    *buf.get_mut(len).ok_or(Error::BufferTooSmall)? = 0;
+    len += 1;
+    if len > MAX_ENCODED_NAME_LEN {
+        return Err(Error::InvalidName);
+    }
+    Ok(len)
+}
+
+/// Reads the name at `pos`, returns it and where the data after it
+/// starts
+fn read_name(data: &[u8], mut pos: usize) -> Result<(Name, usize), Error> {
+    let mut name = Name::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *data.get(pos).ok_or(Error::Truncated)?;
+        match len & 0xC0 {
+            0xC0 => {
+                let low = *data.get(pos + 1).ok_or(Error::Truncated)?;
+                if end.is_none() {
+                    end = Some(pos + 2);
+                }
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(Error::PointerLoop);
+                }
+                pos = usize::from(u16::from_be_bytes([len & 0x3F, low]));
+            }
+            0x00 if len == 0 => return Ok((name, end.unwrap_or(pos + 1))),
+            0x00 => {
+                let label = data
+                    .get(pos + 1..pos + 1 + usize::from(len))
+                    .ok_or(Error::Truncated)?;
+                if !label.iter().all(|c| c.is_ascii_graphic() && *c != b'.') {
+                    return Err(Error::InvalidName);
+                }
+                if !name.is_empty() {
+                    name.push('.').map_err(|_| Error::NameTooLong)?;
+                }
+                // Can't fail, checked it's ASCII
+                let label = core::str::from_utf8(label).unwrap();
+                name.push_str(label).map_err(|_| Error::NameTooLong)?;
+                pos += 1 + usize::from(len);
+            }
+            _ => return Err(Error::InvalidName),
+        }
+    }
+}
+
+/// Case-insensitive name comparison, ignoring a trailing dot
+pub fn name_eq(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Response<'a> {
+    data: &'a [u8],
+    pub id: u16,
+    pub rcode: Rcode,
+    /// Didn't fit in a UDP message, the records are incomplete
+    pub truncated: bool,
+    answers: u16,
+    authorities: u16,
+    additionals: u16,
+    /// Start of the answer section
+    records_pos: usize,
+    qname_pos: usize,
+    qtype: Type,
+}
+
+impl<'a> Response<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        let flags = read_u16(&data[2..]);
+        let questions = read_u16(&data[4..]);
+        if flags & FLAG_RESPONSE == 0 || flags & OPCODE_MASK != 0 || questions != 1 {
+            return Err(Error::NotResponse);
+        }
+        let (_, pos) = read_name(data, HEADER_LEN)?;
+        let question = data.get(pos..pos + 4).ok_or(Error::Truncated)?;
+        Ok(Response {
+            data,
+            id: read_u16(data),
+            rcode: Rcode::from((flags & RCODE_MASK) as u8),
+            truncated: flags & FLAG_TRUNCATED != 0,
+            answers: read_u16(&data[6..]),
+            authorities: read_u16(&data[8..]),
+            additionals: read_u16(&data[10..]),
+            records_pos: pos + 4,
+            qname_pos: HEADER_LEN,
+            qtype: Type::from(read_u16(question)),
+        })
+    }
+
+    /// Name and type asked about
+    pub fn question(&self) -> Result<(Name, Type), Error> {
+        let (name, _) = read_name(self.data, self.qname_pos)?;
+        Ok((name, self.qtype))
+    }
+
+    /// Records of every section, in order
+    pub fn records(&self) -> Records<'a> {
+        Records {
+            data: self.data,
+            pos: self.records_pos,
+            counts: [self.answers, self.authorities, self.additionals],
+            section: 0,
+        }
+    }
+}
+
+/// Iterator over the records of a response, stops after the first error
+pub struct Records<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Left to read in each section
+    counts: [u16; 3],
+    section: usize,
+}
+
+impl<'a> Records<'a> {
+    fn read(&mut self, section: Section) -> Result<Record, Error> {
+        let (name, pos) = read_name(self.data, self.pos)?;
+        let fixed = self.data.get(pos..pos + 10).ok_or(Error::Truncated)?;
+        let rtype = Type::from(read_u16(fixed));
+        let ttl = read_u32(&fixed[4..]);
+        let rdlen = usize::from(read_u16(&fixed[8..]));
+        let start = pos + 10;
+        let rdata = self
+            .data
+            .get(start..start + rdlen)
+            .ok_or(Error::Truncated)?;
+        let data = match rtype {
+            Type::A if rdlen == 4 => RecordData::A(Ipv4Address::from_bytes(rdata)),
+            Type::Cname => {
+                let (cname, end) = read_name(self.data, start)?;
+                if end != start + rdlen {
+                    return Err(Error::InvalidRecord);
+                }
+                RecordData::Cname(cname)
+            }
+            Type::Srv if rdlen > 6 => {
+                let (target, end) = read_name(self.data, start + 6)?;
+                if end != start + rdlen {
+                    return Err(Error::InvalidRecord);
+                }
+                RecordData::Srv(Srv {
+                    priority: read_u16(rdata),
+                    weight: read_u16(&rdata[2..]),
+                    port: read_u16(&rdata[4..]),
+                    target,
+                })
+            }
+            Type::A | Type::Srv => return Err(Error::InvalidRecord),
+            Type::Other(_) => RecordData::Other(rtype),
+        };
+        self.pos = start + rdlen;
+        // Only IPv4 records are of the IN class
+        Ok(Record {
+            section,
+            name,
+            // RFC 2181, a TTL with the top bit set is zero
+            ttl: if ttl > 0x7FFF_FFFF { 0 } else { ttl },
+            data,
+        })
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.section < self.counts.len() && self.counts[self.section] == 0 {
+            self.section += 1;
+        }
+        let section = match self.section {
+            0 => Section::Answer,
+            1 => Section::Authority,
+            2 => Section::Additional,
+            _ => return None,
+        };
+        self.counts[self.section] -= 1;
+        let record = self.read(section);
+        if record.is_err() {
+            self.section = self.counts.len();
+        }
+        Some(record)
+    }
+}
+
+// Callers have checked the length
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_be_bytes(data[..2].try_into().unwrap())
+}
+
+// Callers have checked the length
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Response to an SRV query for _sip._udp.example.com with two
+    /// targets, one address in the additional section and compressed
+    /// names throughout
+    pub(crate) const SRV_RESPONSE: [u8; 126] = [
+        0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 1, //
+        // _sip._udp.example.com SRV IN
+        4, b'_', b's', b'i', b'p', 4, b'_', b'u', b'd', b'p', 7, b'e', b'x', b'a', b'm', b'p', b'l',
+        b'e', 3, b'c', b'o', b'm', 0, 0, 33, 0, 1, //
+        // _sip._udp.example.com (pointer to 12), TTL 300,
+        // 10 60 5060 sip1.example.com
+        0xC0, 12, 0, 33, 0, 1, 0, 0, 1, 0x2C, 0, 13, 0, 10, 0, 60, 0x13, 0xC4, 4, b's', b'i', b'p',
+        b'1', 0xC0, 22, //
+        // 20 0 5080 sip2.example.com, TTL 600
+        0xC0, 12, 0, 33, 0, 1, 0, 0, 2, 0x58, 0, 13, 0, 20, 0, 0, 0x13, 0xD8, 4, b's', b'i', b'p',
+        b'2', 0xC0, 22, //
+        // sip1.example.com (pointer to the first target) A 10.0.0.5,
+        // TTL 60
+        0xC0, 57, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 5, //
+        // Trailing padding is ignored
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn query() {
+        let mut buf = [0; MAX_LEN];
+        let len = emit_query(0xBEEF, "sip.example.com.", Type::A, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[
+                0xBE, 0xEF, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, b's', b'i', b'p', 7, b'e', b'x', b'a',
+                b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1
+            ][..]
+        );
+        let len = emit_query(1, "_sip._udp.example.com", Type::Srv, &mut buf).unwrap();
+        assert_eq!(&buf[len - 4..len], &[0, 33, 0, 1]);
+
+        assert_eq!(
+            emit_query(1, "a..b", Type::A, &mut buf),
+            Err(Error::InvalidName)
+        );
+        let mut long = [b'a'; 64];
+        long[63] = b'.';
+        let long = core::str::from_utf8(&long).unwrap();
+        assert_eq!(emit_query(1, long, Type::A, &mut buf), Ok(12 + 64 + 1 + 4));
+        let long = core::str::from_utf8(&[b'a'; 64]).unwrap();
+        assert_eq!(
+            emit_query(1, long, Type::A, &mut buf),
+            Err(Error::InvalidName)
+        );
+        assert_eq!(
+            emit_query(1, "example.com", Type::A, &mut buf[..20]),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn srv_response() {
+        let response = Response::parse(&SRV_RESPONSE).unwrap();
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.rcode, Rcode::NoError);
+        assert_eq!(response.truncated, false);
+        let (name, qtype) = response.question().unwrap();
+        assert_eq!(name.as_str(), "_sip._udp.example.com");
+        assert_eq!(qtype, Type::Srv);
+
+        let mut records = response.records();
+        let r = records.next().unwrap().unwrap();
+        assert_eq!(r.section, Section::Answer);
+        assert_eq!(r.name.as_str(), "_sip._udp.example.com");
+        assert_eq!(r.ttl, 300);
+        match r.data {
+            RecordData::Srv(srv) => {
+                assert_eq!((srv.priority, srv.weight, srv.port), (10, 60, 5060));
+                assert_eq!(srv.target.as_str(), "sip1.example.com");
+            }
+            d => panic!("{:?}", d),
+        }
+        let r = records.next().unwrap().unwrap();
+        assert_eq!(r.ttl, 600);
+        match r.data {
+            RecordData::Srv(srv) => assert_eq!(srv.target.as_str(), "sip2.example.com"),
+            d => panic!("{:?}", d),
+        }
+        let r = records.next().unwrap().unwrap();
+        assert_eq!(r.section, Section::Additional);
+        assert_eq!(r.name.as_str(), "sip1.example.com");
+        assert_eq!(r.data, RecordData::A(Ipv4Address::new(10, 0, 0, 5)));
+        assert_eq!(records.next(), None);
+
+        assert_eq!(name_eq("SIP1.Example.com.", "sip1.example.com"), true);
+        assert_eq!(name_eq("sip1.example.co", "sip1.example.com"), false);
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(Response::parse(&SRV_RESPONSE[..11]), Err(Error::Truncated));
+        assert_eq!(Response::parse(&SRV_RESPONSE[..36]), Err(Error::Truncated));
+
+        // A query
+        let mut bad = SRV_RESPONSE;
+        bad[2] = 0x01;
+        assert_eq!(Response::parse(&bad), Err(Error::NotResponse));
+
+        // Record cut short
+        let response = Response::parse(&SRV_RESPONSE[..80]).unwrap();
+        let mut records = response.records();
+        assert_eq!(records.next().map(|r| r.is_ok()), Some(true));
+        assert_eq!(records.next(), Some(Err(Error::Truncated)));
+        assert_eq!(records.next(), None);
+
+        // Pointer to itself
+        let mut bad = SRV_RESPONSE;
+        bad[40] = 39;
+        let response = Response::parse(&bad).unwrap();
+        assert_eq!(response.records().next(), Some(Err(Error::PointerLoop)));
+
+        // Reserved label type
+        let mut bad = SRV_RESPONSE;
+        bad[39] = 0x40;
+        let response = Response::parse(&bad).unwrap();
+        assert_eq!(response.records().next(), Some(Err(Error::InvalidName)));
+
+        // SRV rdlength doesn't cover the target
+        let mut bad = SRV_RESPONSE;
+        bad[50] = 12;
+        let response = Response::parse(&bad).unwrap();
+        assert_eq!(response.records().next(), Some(Err(Error::InvalidRecord)));
+    }
+}
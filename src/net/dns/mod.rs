@@ -0,0 +1,933 @@
+//! DNS stub resolver
+//!
+//! Looks up A and SRV records with the configured recursive servers
+//! over a UDP socket. Lookups don't block, `query_a` and `query_srv`
+//! return `Ok(None)` while the query is outstanding and are asked
+//! again until there's an answer or an error. `poll` does the sending,
+//! receiving and retrying, round robin across the servers.
+//!
+//! Answers are cached for their TTL, including the addresses an SRV
+//! response brings along in its additional section. SRV targets are
+//! ordered once per answer (RFC 2782), so repeated lookups agree on
+//! which target to try first.
+
+pub mod message;
+
+pub use crate::net::dns::message::{Name, Srv, Type};
+
+use crate::net::dns::message::{name_eq, Rcode, RecordData, Response, Section, MAX_LEN};
+use crate::net::socket_pool::UdpHandle;
+use crate::random::{RandomSource, XorShift32};
+use crate::time::{Duration, Instant};
+use core::str::FromStr;
+use heapless::consts::{U3, U4};
+use heapless::Vec;
+use log::{debug, warn};
+use smoltcp::socket::{SocketSet, UdpSocket};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+pub const DNS_PORT: u16 = 53;
+
+/// Wait for a response before asking the next server
+pub const RETRY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Each server is asked this many times before the query times out
+pub const ATTEMPTS_PER_SERVER: u8 = 2;
+
+/// Answers are kept at least this long, so a zero TTL still gets to
+/// the caller
+pub const MIN_TTL: Duration = Duration::from_secs(5);
+pub const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Failed queries report their error for this long before the name is
+/// asked about again
+pub const NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+/// Source ports are picked from the dynamic range
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+pub type MaxServers = U3;
+pub type MaxCacheEntries = U4;
+pub type MaxQueries = U4;
+pub type MaxSrvRecords = U4;
+
+/// SRV targets in the order to try them
+pub type SrvRecords = Vec<Srv, MaxSrvRecords>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    Message(message::Error),
+    Socket(smoltcp::Error),
+    /// No servers configured
+    NoServers,
+    /// Every query slot is in use
+    TooManyQueries,
+    /// Name doesn't exist or has no records of the type
+    NotFound,
+    /// Every server refused or failed the query
+    ServerFailure,
+    /// No response from any server
+    Timeout,
+}
+
+impl From<message::Error> for Error {
+    fn from(e: message::Error) -> Self {
+        Error::Message(e)
+    }
+}
+
+impl From<smoltcp::Error> for Error {
+    fn from(e: smoltcp::Error) -> Self {
+        Error::Socket(e)
+    }
+}
+
+// Only a few of these, in the fixed size cache
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Answer {
+    A(Ipv4Address),
+    Srv(SrvRecords),
+}
+
+impl Answer {
+    fn qtype(&self) -> Type {
+        match self {
+            Answer::A(_) => Type::A,
+            Answer::Srv(_) => Type::Srv,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    name: Name,
+    answer: Answer,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct Query {
+    name: Name,
+    qtype: Type,
+    id: u16,
+    /// Sent so far, across all servers
+    attempts: u8,
+    next_transmit: Instant,
+    /// Reported when the attempts run out
+    error: Error,
+    /// Set once the query has failed
+    failed_until: Option<Instant>,
+}
+
+pub struct Resolver {
+    handle: UdpHandle,
+    rng: XorShift32,
+    servers: Vec<Ipv4Address, MaxServers>,
+    cache: Vec<CacheEntry, MaxCacheEntries>,
+    queries: Vec<Query, MaxQueries>,
+}
+
+impl Resolver {
+    /// `handle` is a UDP socket in the interface's socket set, it gets
+    /// bound to a random source port
+    pub fn new<R: RandomSource>(handle: UdpHandle, rng: &mut R) -> Self {
+        Resolver {
+            handle,
+            rng: XorShift32::new(rng.next_u32()),
+            servers: Vec::new(),
+            cache: Vec::new(),
+            queries: Vec::new(),
+        }
+    }
+
+    pub fn socket_handle(&self) -> UdpHandle {
+        self.handle
+    }
+
+    pub fn servers(&self) -> &[Ipv4Address] {
+        &self.servers
+    }
+
+    /// Replaces the servers (i.e. from a new DHCP lease), outstanding
+    /// queries start over with them and failures are forgotten. Servers
+    /// past `MaxServers` are ignored.
+    pub fn set_servers(&mut self, servers: &[Ipv4Address], time: Instant) {
+        self.servers.clear();
+        for server in servers.iter().filter(|s| s.is_unicast()) {
+            if self.servers.push(*server).is_err() {
+                break;
+            }
+        }
+        let mut i = 0;
+        while i < self.queries.len() {
+            let query = &mut self.queries[i];
+            if query.failed_until.is_some() {
+                self.queries.swap_remove(i);
+            } else {
+                query.attempts = 0;
+                query.error = Error::Timeout;
+                query.next_transmit = time;
+                i += 1;
+            }
+        }
+    }
+
+    /// Address of `name`, which can also be a dotted-quad address
+    pub fn query_a(&mut self, name: &str, time: Instant) -> Result<Option<Ipv4Address>, Error> {
+        if let Ok(addr) = Ipv4Address::from_str(name) {
+            return Ok(Some(addr));
+        }
+        match self.lookup(name, Type::A, time)? {
+            Some(Answer::A(addr)) => Ok(Some(*addr)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Targets of the service `name` (i.e. `_sip._udp.example.com`), in
+    /// the order to try them. `Error::NotFound` if the service isn't
+    /// offered.
+    pub fn query_srv(&mut self, name: &str, time: Instant) -> Result<Option<SrvRecords>, Error> {
+        match self.lookup(name, Type::Srv, time)? {
+            Some(Answer::Srv(records)) => Ok(Some(records.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Drops every cached answer
+    pub fn flush(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Receives responses and sends any query that's due
+    pub fn poll(&mut self, sockets: &mut SocketSet, time: Instant) -> Result<(), Error> {
+        let mut socket = sockets.get::<UdpSocket>(self.handle.into());
+        if !socket.is_open() {
+            let ports = u32::from(u16::max_value() - EPHEMERAL_PORT_START) + 1;
+            let port = EPHEMERAL_PORT_START + (self.rng.next_u32() % ports) as u16;
+            socket.bind(port)?;
+        }
+
+        while let Ok((data, from)) = socket.recv() {
+            let known = from.port == DNS_PORT
+                && self
+                    .servers
+                    .iter()
+                    .any(|s| IpAddress::Ipv4(*s) == from.addr);
+            if !known {
+                debug!("Ignoring DNS message from {}", from);
+                continue;
+            }
+            match Response::parse(data) {
+                Ok(response) => {
+                    if !self.handle_response(&response, time) {
+                        debug!("Ignoring DNS message from {}", from);
+                    }
+                }
+                Err(e) => warn!("Bad DNS message from {}: {:?}", from, e),
+            }
+        }
+
+        self.expire(time);
+        let max_attempts = self.servers.len() * usize::from(ATTEMPTS_PER_SERVER);
+        let mut buf = [0; MAX_LEN];
+        for query in self.queries.iter_mut() {
+            if query.failed_until.is_some() || time < query.next_transmit {
+                continue;
+            }
+            if self.servers.is_empty() {
+                query.error = Error::NoServers;
+            }
+            if usize::from(query.attempts) >= max_attempts {
+                warn!("DNS query for {} failed {:?}", query.name, query.error);
+                query.failed_until = Some(time + NEGATIVE_TTL);
+                continue;
+            }
+            let server = self.servers[usize::from(query.attempts) % self.servers.len()];
+            query.attempts += 1;
+            query.next_transmit = time + RETRY_TIMEOUT;
+            let len = message::emit_query(query.id, &query.name, query.qtype, &mut buf)?;
+            socket.send_slice(&buf[..len], IpEndpoint::new(server.into(), DNS_PORT))?;
+        }
+        Ok(())
+    }
+
+    /// Cached answer, or the outcome of the query for it, starting one
+    /// if there isn't one yet
+    fn lookup(&mut self, name: &str, qtype: Type, time: Instant) -> Result<Option<&Answer>, Error> {
+        self.expire(time);
+        if let Some(index) = self
+            .cache
+            .iter()
+            .position(|e| e.answer.qtype() == qtype && name_eq(&e.name, name))
+        {
+            return Ok(Some(&self.cache[index].answer));
+        }
+        if let Some(query) = self
+            .queries
+            .iter()
+            .find(|q| q.qtype == qtype && name_eq(&q.name, name))
+        {
+            return match query.failed_until {
+                Some(_) => Err(query.error),
+                None => Ok(None),
+            };
+        }
+
+        if self.servers.is_empty() {
+            return Err(Error::NoServers);
+        }
+        // Checks the name can be sent
+        message::emit_query(0, name, qtype, &mut [0; MAX_LEN])?;
+        let mut qname = Name::new();
+        qname
+            .push_str(name.trim_end_matches('.'))
+            .map_err(|_| message::Error::NameTooLong)?;
+        let query = Query {
+            name: qname,
+            qtype,
+            id: self.rng.next_u32() as u16,
+            attempts: 0,
+            next_transmit: time,
+            error: Error::Timeout,
+            failed_until: None,
+        };
+        self.queries
+            .push(query)
+            .map_err(|_| Error::TooManyQueries)?;
+        Ok(None)
+    }
+
+    /// Returns false if the response isn't for an outstanding query
+    fn handle_response(&mut self, response: &Response, time: Instant) -> bool {
+        let (qname, qtype) = match response.question() {
+            Ok(q) => q,
+            Err(_) => return false,
+        };
+        let index = match self.queries.iter().position(|q| {
+            q.failed_until.is_none()
+                && q.id == response.id
+                && q.qtype == qtype
+                && name_eq(&q.name, &qname)
+        }) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        match response.rcode {
+            Rcode::NoError => (),
+            Rcode::NameError => {
+                self.fail(index, Error::NotFound, time);
+                return true;
+            }
+            rcode => {
+                // Straight on to the next server
+                debug!("DNS {:?} for {}", rcode, qname);
+                let query = &mut self.queries[index];
+                query.error = Error::ServerFailure;
+                query.next_transmit = time;
+                return true;
+            }
+        }
+
+        // Answers follow any CNAME chain, so their names aren't checked.
+        // Additional addresses are only taken for this response's SRV
+        // targets, which come before them.
+        let mut addr = None;
+        let mut srv_records = SrvRecords::new();
+        let mut ttl = u32::max_value();
+        for record in response.records() {
+            let record = match record {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Bad DNS record for {}: {:?}", qname, e);
+                    break;
+                }
+            };
+            match (record.section, record.data) {
+                (Section::Answer, RecordData::A(a)) if qtype == Type::A && addr.is_none() => {
+                    addr = Some(a);
+                    ttl = ttl.min(record.ttl);
+                }
+                // "." means the service isn't offered
+                (Section::Answer, RecordData::Srv(srv))
+                    if qtype == Type::Srv && !srv.target.is_empty() =>
+                {
+                    // Any past `MaxSrvRecords` are dropped
+                    let _ = srv_records.push(srv);
+                    ttl = ttl.min(record.ttl);
+                }
+                (Section::Additional, RecordData::A(a)) => {
+                    let name = record.name;
+                    if srv_records.iter().any(|srv| name_eq(&srv.target, &name)) {
+                        self.insert(name, Answer::A(a), record.ttl, time);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let answer = match addr {
+            Some(addr) => Answer::A(addr),
+            None if !srv_records.is_empty() => {
+                order_srv(&mut srv_records, &mut self.rng);
+                Answer::Srv(srv_records)
+            }
+            None => {
+                self.fail(index, Error::NotFound, time);
+                return true;
+            }
+        };
+        let query = self.queries.swap_remove(index);
+        self.insert(query.name, answer, ttl, time);
+        true
+    }
+
+    fn fail(&mut self, index: usize, error: Error, time: Instant) {
+        let query = &mut self.queries[index];
+        debug!("DNS query for {} failed {:?}", query.name, error);
+        query.error = error;
+        query.failed_until = Some(time + NEGATIVE_TTL);
+    }
+
+    /// Caches an answer, replacing any for the same name and type or
+    /// else the one that expires first
+    fn insert(&mut self, name: Name, answer: Answer, ttl: u32, time: Instant) {
+        let ttl = Duration::from_secs(u64::from(ttl))
+            .max(MIN_TTL)
+            .min(MAX_TTL);
+        let entry = CacheEntry {
+            name,
+            answer,
+            expires_at: time + ttl,
+        };
+        if let Some(e) = self
+            .cache
+            .iter_mut()
+            .find(|e| e.answer.qtype() == entry.answer.qtype() && name_eq(&e.name, &entry.name))
+        {
+            *e = entry;
+            return;
+        }
+        if let Err(entry) = self.cache.push(entry) {
+            // Can't fail, the cache is full
+            let oldest = self.cache.iter_mut().min_by_key(|e| e.expires_at).unwrap();
+            *oldest = entry;
+        }
+    }
+
+    /// Drops expired answers and failures
+    fn expire(&mut self, time: Instant) {
+        let mut i = 0;
+        while i < self.cache.len() {
+            if self.cache[i].expires_at <= time {
+                self.cache.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        let mut i = 0;
+        while i < self.queries.len() {
+            match self.queries[i].failed_until {
+                Some(until) if until <= time => {
+                    self.queries.swap_remove(i);
+                }
+                _ => i += 1,
+            }
+        }
+    }
+}
+
+/// Orders by priority, and within a priority by repeated weighted
+/// random picks, zero weights standing a small chance (RFC 2782)
+fn order_srv<R: RandomSource>(records: &mut SrvRecords, rng: &mut R) {
+    // Zero weights go first, the pick below relies on it
+    records.sort_unstable_by_key(|r| (r.priority, r.weight != 0));
+    let mut start = 0;
+    while start < records.len() {
+        let priority = records[start].priority;
+        let end = start
+            + records[start..]
+                .iter()
+                .take_while(|r| r.priority == priority)
+                .count();
+        for i in start..end {
+            let total: u32 = records[i..end].iter().map(|r| u32::from(r.weight)).sum();
+            let pick = rng.next_u32() % (total + 1);
+            let mut sum = 0;
+            // Can't fail, the running sum gets to the total
+            let chosen = records[i..end]
+                .iter()
+                .position(|r| {
+                    sum += u32::from(r.weight);
+                    sum >= pick
+                })
+                .unwrap();
+            // Keeps the rest in order, zero weights still first
+            records[i..=i + chosen].rotate_right(1);
+        }
+        start = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::dns::message::tests::SRV_RESPONSE;
+    use crate::net::loopback::Loopback;
+    use smoltcp::iface::EthernetInterface;
+    use smoltcp::socket::SocketHandle;
+    use smoltcp::wire::IpCidr;
+
+    const CLIENT: Ipv4Address = Ipv4Address([10, 0, 0, 50]);
+    const SERVER_1: Ipv4Address = Ipv4Address([10, 0, 0, 1]);
+    const SERVER_2: Ipv4Address = Ipv4Address([10, 0, 0, 2]);
+    const HOST: &str = "sip.example.com";
+    const HOST_ADDR: Ipv4Address = Ipv4Address([10, 0, 0, 7]);
+    const SERVICE: &str = "_sip._udp.example.com";
+
+    type Iface<'a> = EthernetInterface<'a, 'a, 'a, Loopback>;
+
+    struct Packet {
+        buf: [u8; MAX_LEN],
+        len: usize,
+        from: IpEndpoint,
+    }
+
+    impl Packet {
+        fn id(&self) -> u16 {
+            u16::from_be_bytes([self.buf[0], self.buf[1]])
+        }
+
+        fn assert_query(&self, name: &str, qtype: Type) {
+            let mut expected = [0; MAX_LEN];
+            let len = message::emit_query(self.id(), name, qtype, &mut expected).unwrap();
+            assert_eq!(&self.buf[..self.len], &expected[..len]);
+        }
+
+        /// Response with the query's header and question
+        fn response(&self, rcode: u8) -> Packet {
+            let mut response = Packet { ..*self };
+            response.buf[2] = 0x81;
+            response.buf[3] = 0x80 | rcode;
+            response
+        }
+
+        /// Response with an A record for the question's name
+        fn answer_a(&self, addr: Ipv4Address, ttl: u32) -> Packet {
+            let mut response = self.response(0);
+            response.buf[7] = 1;
+            let record = &mut response.buf[self.len..self.len + 16];
+            record[..6].copy_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+            record[6..10].copy_from_slice(&ttl.to_be_bytes());
+            record[10..12].copy_from_slice(&[0, 4]);
+            record[12..].copy_from_slice(addr.as_bytes());
+            response.len += 16;
+            response
+        }
+    }
+
+    /// Scripted server on the same interface
+    struct Server {
+        handle: SocketHandle,
+        addr: Ipv4Address,
+    }
+
+    impl Server {
+        fn recv(&self, sockets: &mut SocketSet) -> Option<Packet> {
+            let mut socket = sockets.get::<UdpSocket>(self.handle);
+            if !socket.is_open() {
+                socket
+                    .bind(IpEndpoint::new(self.addr.into(), DNS_PORT))
+                    .unwrap();
+            }
+            let packet = socket.recv().ok().map(|(data, from)| {
+                let mut packet = Packet {
+                    buf: [0; MAX_LEN],
+                    len: data.len(),
+                    from,
+                };
+                packet.buf[..data.len()].copy_from_slice(data);
+                packet
+            });
+            assert_eq!(socket.can_recv(), false);
+            packet
+        }
+
+        fn send(&self, sockets: &mut SocketSet, data: &[u8], to: IpEndpoint) {
+            let mut socket = sockets.get::<UdpSocket>(self.handle);
+            socket.send_slice(data, to).unwrap();
+        }
+
+        fn reply(&self, sockets: &mut SocketSet, response: &Packet, query: &Packet) {
+            self.send(sockets, &response.buf[..response.len], query.from);
+        }
+    }
+
+    /// Delivers anything the servers sent, polls the resolver and
+    /// returns what each server got
+    fn step(
+        resolver: &mut Resolver,
+        servers: &[Server; 2],
+        iface: &mut Iface,
+        sockets: &mut SocketSet,
+        time: Instant,
+    ) -> (Option<Packet>, Option<Packet>) {
+        let _ = iface.poll(sockets, smoltcp::time::Instant::from_millis(0));
+        resolver.poll(sockets, time).unwrap();
+        let _ = iface.poll(sockets, smoltcp::time::Instant::from_millis(0));
+        (servers[0].recv(sockets), servers[1].recv(sockets))
+    }
+
+    macro_rules! setup {
+        ($iface:ident, $sockets:ident, $resolver:ident, $servers:ident) => {
+            crate::loopback!(
+                $iface,
+                $sockets,
+                [
+                    IpCidr::new(CLIENT.into(), 24),
+                    IpCidr::new(SERVER_1.into(), 24),
+                    IpCidr::new(SERVER_2.into(), 24),
+                ],
+                1,
+                {
+                    client_handle: [2; 1024],
+                    server_1_handle: [2; 1024],
+                    server_2_handle: [2; 1024],
+                }
+            );
+            let $servers = [
+                Server {
+                    handle: server_1_handle,
+                    addr: SERVER_1,
+                },
+                Server {
+                    handle: server_2_handle,
+                    addr: SERVER_2,
+                },
+            ];
+            let mut $resolver =
+                Resolver::new(UdpHandle::from_socket(client_handle), &mut XorShift32::new(1));
+            // Bind the servers
+            assert_eq!($servers[0].recv(&mut $sockets).is_none(), true);
+            assert_eq!($servers[1].recv(&mut $sockets).is_none(), true);
+        };
+    }
+
+    #[test]
+    fn a_lookup_and_cache() {
+        setup!(iface, sockets, resolver, servers);
+        let s = |secs| Instant::from_secs(secs);
+
+        assert_eq!(resolver.query_a(HOST, s(0)), Err(Error::NoServers));
+        assert_eq!(
+            resolver.query_a("10.1.2.3", s(0)),
+            Ok(Some(Ipv4Address::new(10, 1, 2, 3)))
+        );
+        resolver.set_servers(&[SERVER_1], s(0));
+        assert_eq!(resolver.query_a(HOST, s(0)), Ok(None));
+        assert_eq!(resolver.query_a(HOST, s(0)), Ok(None));
+
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(0));
+        let query = query.unwrap();
+        query.assert_query(HOST, Type::A);
+        assert_eq!(query.from.addr, IpAddress::Ipv4(CLIENT));
+        assert!(query.from.port >= EPHEMERAL_PORT_START);
+
+        // Wrong server, wrong ID, then the answer
+        servers[1].reply(&mut sockets, &query.answer_a(SERVER_2, 30), &query);
+        let mut wrong_id = query.answer_a(SERVER_2, 30);
+        wrong_id.buf[1] ^= 0xFF;
+        servers[0].reply(&mut sockets, &wrong_id, &query);
+        let (none, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(1));
+        assert_eq!(none.is_none(), true);
+        assert_eq!(resolver.query_a(HOST, s(1)), Ok(None));
+        servers[0].reply(&mut sockets, &query.answer_a(HOST_ADDR, 30), &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(1));
+        assert_eq!(resolver.query_a(HOST, s(1)), Ok(Some(HOST_ADDR)));
+
+        // Cached until the TTL runs out
+        assert_eq!(
+            resolver.query_a("SIP.Example.com.", s(30)),
+            Ok(Some(HOST_ADDR))
+        );
+        let (none, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(30));
+        assert_eq!(none.is_none(), true);
+        assert_eq!(resolver.query_a(HOST, s(31)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(31));
+        let query = query.unwrap();
+        query.assert_query(HOST, Type::A);
+
+        // Zero TTL is kept long enough to be picked up
+        servers[0].reply(&mut sockets, &query.answer_a(HOST_ADDR, 0), &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(31));
+        assert_eq!(resolver.query_a(HOST, s(35)), Ok(Some(HOST_ADDR)));
+        assert_eq!(resolver.query_a(HOST, s(36)), Ok(None));
+
+        resolver.flush();
+        assert_eq!(resolver.query_a("other.example.com", s(36)), Ok(None));
+        assert_eq!(resolver.query_a("a.example.com", s(36)), Ok(None));
+        assert_eq!(resolver.query_a("b.example.com", s(36)), Ok(None));
+        assert_eq!(
+            resolver.query_a("c.example.com", s(36)),
+            Err(Error::TooManyQueries)
+        );
+        assert_eq!(
+            resolver.query_a("bad..example.com", s(36)),
+            Err(Error::Message(message::Error::InvalidName))
+        );
+    }
+
+    #[test]
+    fn retries_across_servers() {
+        setup!(iface, sockets, resolver, servers);
+        let s = |secs| Instant::from_secs(secs);
+        resolver.set_servers(&[SERVER_1, SERVER_2], s(0));
+        assert_eq!(resolver.servers(), &[SERVER_1, SERVER_2]);
+
+        // Unanswered, alternates between the servers then times out
+        assert_eq!(resolver.query_a(HOST, s(0)), Ok(None));
+        let mut sent = [(0, 0); 4];
+        let mut count = 0;
+        for secs in 0..8 {
+            match step(&mut resolver, &servers, &mut iface, &mut sockets, s(secs)) {
+                (Some(q), None) | (None, Some(q)) => {
+                    q.assert_query(HOST, Type::A);
+                    sent[count] = (secs, u32::from(q.from.port));
+                    count += 1;
+                }
+                (None, None) => (),
+                _ => panic!("sent to both servers"),
+            }
+            assert_eq!(resolver.query_a(HOST, s(secs)), Ok(None));
+        }
+        assert_eq!(count, 4);
+        assert_eq!([sent[0].0, sent[1].0, sent[2].0, sent[3].0], [0, 2, 4, 6]);
+        let (none_1, none_2) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(8));
+        assert_eq!(none_1.is_none() && none_2.is_none(), true);
+        assert_eq!(resolver.query_a(HOST, s(8)), Err(Error::Timeout));
+        assert_eq!(resolver.query_a(HOST, s(12)), Err(Error::Timeout));
+
+        // Failures move straight on to the next server
+        assert_eq!(resolver.query_a(HOST, s(13)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(13));
+        let query = query.unwrap();
+        servers[0].reply(&mut sockets, &query.response(2), &query);
+        let (_, query) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(13));
+        let query = query.unwrap();
+        servers[1].reply(&mut sockets, &query.response(5), &query);
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(13));
+        let query = query.unwrap();
+        servers[0].reply(&mut sockets, &query.response(2), &query);
+        let (_, query) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(13));
+        let query = query.unwrap();
+        servers[1].reply(&mut sockets, &query.response(2), &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(13));
+        assert_eq!(resolver.query_a(HOST, s(13)), Err(Error::ServerFailure));
+
+        // New servers forget the failure, a late answer still counts
+        resolver.set_servers(&[SERVER_2], s(14));
+        assert_eq!(resolver.query_a(HOST, s(14)), Ok(None));
+        let (_, query) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(14));
+        let query = query.unwrap();
+        let (_, retry) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(16));
+        assert_eq!(retry.unwrap().id(), query.id());
+        servers[1].reply(&mut sockets, &query.answer_a(HOST_ADDR, 60), &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(17));
+        assert_eq!(resolver.query_a(HOST, s(17)), Ok(Some(HOST_ADDR)));
+    }
+
+    #[test]
+    fn not_found() {
+        setup!(iface, sockets, resolver, servers);
+        let s = |secs| Instant::from_secs(secs);
+        resolver.set_servers(&[SERVER_1, SERVER_2], s(0));
+
+        // NXDOMAIN isn't asked of the other server
+        assert_eq!(resolver.query_a(HOST, s(0)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(0));
+        let query = query.unwrap();
+        servers[0].reply(&mut sockets, &query.response(3), &query);
+        assert_eq!(
+            step(&mut resolver, &servers, &mut iface, &mut sockets, s(0))
+                .1
+                .is_none(),
+            true
+        );
+        assert_eq!(resolver.query_a(HOST, s(0)), Err(Error::NotFound));
+
+        // No records of the type
+        assert_eq!(resolver.query_srv(SERVICE, s(1)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(1));
+        let query = query.unwrap();
+        query.assert_query(SERVICE, Type::Srv);
+        servers[0].reply(&mut sockets, &query.response(0), &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(1));
+        assert_eq!(resolver.query_srv(SERVICE, s(1)), Err(Error::NotFound));
+
+        // Asked again once the failure expires
+        assert_eq!(resolver.query_a(HOST, s(4)), Err(Error::NotFound));
+        assert_eq!(resolver.query_a(HOST, s(5)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(5));
+        query.unwrap().assert_query(HOST, Type::A);
+    }
+
+    #[test]
+    fn srv_lookup() {
+        setup!(iface, sockets, resolver, servers);
+        let s = |secs| Instant::from_secs(secs);
+        resolver.set_servers(&[SERVER_1], s(0));
+
+        assert_eq!(resolver.query_srv(SERVICE, s(0)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(0));
+        let query = query.unwrap();
+        query.assert_query(SERVICE, Type::Srv);
+        let mut response = Packet {
+            buf: [0; MAX_LEN],
+            len: SRV_RESPONSE.len(),
+            from: query.from,
+        };
+        response.buf[..SRV_RESPONSE.len()].copy_from_slice(&SRV_RESPONSE);
+        response.buf[..2].copy_from_slice(&query.id().to_be_bytes());
+        servers[0].reply(&mut sockets, &response, &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(0));
+
+        let records = resolver.query_srv(SERVICE, s(0)).unwrap().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (
+                records[0].priority,
+                records[0].port,
+                records[0].target.as_str()
+            ),
+            (10, 5060, "sip1.example.com")
+        );
+        assert_eq!(
+            (
+                records[1].priority,
+                records[1].port,
+                records[1].target.as_str()
+            ),
+            (20, 5080, "sip2.example.com")
+        );
+
+        // The additional address is cached for its own TTL
+        assert_eq!(
+            resolver.query_a("sip1.example.com", s(59)),
+            Ok(Some(Ipv4Address::new(10, 0, 0, 5)))
+        );
+        assert_eq!(resolver.query_a("sip1.example.com", s(60)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(60));
+        let query = query.unwrap();
+        query.assert_query("sip1.example.com", Type::A);
+        let addr = Ipv4Address::new(10, 0, 0, 6);
+        servers[0].reply(&mut sockets, &query.answer_a(addr, 600), &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(60));
+        assert_eq!(resolver.query_a("sip1.example.com", s(60)), Ok(Some(addr)));
+
+        // Shortest TTL of the answers
+        assert_eq!(resolver.query_srv(SERVICE, s(299)), Ok(Some(records)));
+        assert_eq!(resolver.query_srv(SERVICE, s(300)), Ok(None));
+
+        // Service not available
+        let mut response = Packet { ..response };
+        response.buf[3] = 0x80;
+        response.buf[7] = 1;
+        response.buf[11] = 0;
+        response.buf[49..51].copy_from_slice(&[0, 7]);
+        response.buf[57] = 0;
+        response.len = 58;
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(300));
+        let query = query.unwrap();
+        response.buf[..2].copy_from_slice(&query.id().to_be_bytes());
+        servers[0].reply(&mut sockets, &response, &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(300));
+        assert_eq!(resolver.query_srv(SERVICE, s(300)), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn additional_records_for_srv_targets() {
+        setup!(iface, sockets, resolver, servers);
+        let s = |secs| Instant::from_secs(secs);
+        resolver.set_servers(&[SERVER_1], s(0));
+
+        // Not asked about
+        assert_eq!(resolver.query_a(HOST, s(0)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(0));
+        let query = query.unwrap();
+        let mut response = query.answer_a(HOST_ADDR, 60);
+        response.buf[11] = 1;
+        // sip1.example.com (pointer to example.com) A 10.0.0.66, TTL 60
+        let record = [
+            4, b's', b'i', b'p', b'1', 0xC0, 16, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 66,
+        ];
+        response.buf[response.len..response.len + record.len()].copy_from_slice(&record);
+        response.len += record.len();
+        servers[0].reply(&mut sockets, &response, &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(0));
+        assert_eq!(resolver.query_a(HOST, s(0)), Ok(Some(HOST_ADDR)));
+
+        // Only the SRV targets of the same response
+        assert_eq!(resolver.query_srv(SERVICE, s(1)), Ok(None));
+        let (query, _) = step(&mut resolver, &servers, &mut iface, &mut sockets, s(1));
+        let query = query.unwrap();
+        let mut response = Packet {
+            buf: [0; MAX_LEN],
+            len: 105,
+            from: query.from,
+        };
+        response.buf[..105].copy_from_slice(&SRV_RESPONSE[..105]);
+        response.buf[..2].copy_from_slice(&query.id().to_be_bytes());
+        response.buf[11] = 3;
+        let records = [
+            // evil.example.com (pointer to example.com) A 10.0.0.66
+            4, b'e', b'v', b'i', b'l', 0xC0, 22, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 66,
+            // sip2.example.com (pointer to the second target) A 10.0.0.6
+            0xC0, 82, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 6,
+        ];
+        response.buf[105..105 + records.len()].copy_from_slice(&records);
+        response.len += records.len();
+        servers[0].reply(&mut sockets, &response, &query);
+        step(&mut resolver, &servers, &mut iface, &mut sockets, s(1));
+        assert_eq!(resolver.query_srv(SERVICE, s(1)).unwrap().is_some(), true);
+        assert_eq!(
+            resolver.query_a("sip1.example.com", s(1)),
+            Ok(Some(Ipv4Address::new(10, 0, 0, 5)))
+        );
+        assert_eq!(
+            resolver.query_a("sip2.example.com", s(1)),
+            Ok(Some(Ipv4Address::new(10, 0, 0, 6)))
+        );
+        assert_eq!(resolver.query_a("evil.example.com", s(1)), Ok(None));
+    }
+
+    #[test]
+    fn srv_order() {
+        let srv = |priority, weight, port| Srv {
+            priority,
+            weight,
+            port,
+            target: Name::from("sip.example.com"),
+        };
+        let mut rng = XorShift32::new(1);
+        let mut first = [0; 3];
+        for _ in 0..1000 {
+            let mut records = SrvRecords::new();
+            records.push(srv(20, 30, 1)).unwrap();
+            records.push(srv(20, 0, 2)).unwrap();
+            records.push(srv(20, 70, 3)).unwrap();
+            records.push(srv(10, 0, 4)).unwrap();
+            order_srv(&mut records, &mut rng);
+            assert_eq!(records[0].port, 4);
+            assert_eq!(records[1..].iter().all(|r| r.priority == 20), true);
+            let mut ports = [records[1].port, records[2].port, records[3].port];
+            first[usize::from(ports[0]) - 1] += 1;
+            ports.sort_unstable();
+            assert_eq!(ports, [1, 2, 3]);
+        }
+        // 30%, ~1% and 70% of the time
+        assert!((250..=350).contains(&first[0]), "{:?}", first);
+        assert!(first[1] <= 30, "{:?}", first);
+        assert!((650..=750).contains(&first[2]), "{:?}", first);
+    }
+}
@@ -1,28 +1,87 @@
+use crate::net::dhcp;
+use crate::net::routes::{self, RouteTable};
+use crate::net::socket_pool::{self, IcmpHandle, Owner, SocketPool, TcpHandle, UdpHandle};
 use crate::time::Instant;
 use smoltcp::iface::EthernetInterface;
-use smoltcp::socket::SocketSet;
+use smoltcp::socket::{SocketHandle, SocketSet};
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
 
 pub const NEIGHBOR_CACHE_SIZE: usize = 32;
 pub const SOCKET_BUFFER_SIZE: usize = 2048;
 
-// TODO - socket management
-
-pub struct Eth<'a, 'b, 'c, 'd, 'e, 'f, 'rx, 'tx, 'r> {
+pub struct Eth<'a, 'b, 'c, 'd, 'e, 'rx, 'tx, 'r> {
     iface: EthernetInterface<'a, 'b, 'c, &'r mut stm32_eth::Eth<'rx, 'tx>>,
-    sockets: SocketSet<'d, 'e, 'f>,
+    pool: SocketPool<'d, 'e>,
 }
 
-impl<'a, 'b, 'c, 'd, 'e, 'f, 'rx, 'tx, 'r> Eth<'a, 'b, 'c, 'd, 'e, 'f, 'rx, 'tx, 'r> {
+impl<'a, 'b, 'c, 'd, 'e, 'rx, 'tx, 'r> Eth<'a, 'b, 'c, 'd, 'e, 'rx, 'tx, 'r> {
     pub fn new(
         iface: EthernetInterface<'a, 'b, 'c, &'r mut stm32_eth::Eth<'rx, 'tx>>,
-        sockets: SocketSet<'d, 'e, 'f>,
+        pool: SocketPool<'d, 'e>,
     ) -> Self {
-        Eth { iface, sockets }
+        Eth { iface, pool }
+    }
+
+    pub fn sockets(&mut self) -> &mut SocketSet<'d, 'e, 'e> {
+        self.pool.sockets()
+    }
+
+    pub fn allocate_udp(&mut self, owner: Owner) -> Result<UdpHandle, socket_pool::Error> {
+        self.pool.allocate_udp(owner)
+    }
+
+    pub fn allocate_tcp(&mut self, owner: Owner) -> Result<TcpHandle, socket_pool::Error> {
+        self.pool.allocate_tcp(owner)
+    }
+
+    pub fn allocate_icmp(&mut self, owner: Owner) -> Result<IcmpHandle, socket_pool::Error> {
+        self.pool.allocate_icmp(owner)
+    }
+
+    /// Closes the socket and returns it to the pool
+    pub fn release<H: Into<SocketHandle>>(&mut self, handle: H) -> Result<(), socket_pool::Error> {
+        self.pool.release(handle)
+    }
+
+    /// Runs the DHCP client, which manages the interface's first
+    /// address and the default route
+    pub fn poll_dhcp(
+        &mut self,
+        dhcp: &mut dhcp::Client,
+        time: Instant,
+    ) -> Result<bool, dhcp::Error> {
+        dhcp.poll(&mut self.iface, self.pool.sockets(), time)
+    }
+
+    /// Sets, replaces or (with `None`) removes the default gateway
+    pub fn set_default_gateway(
+        &mut self,
+        gateway: Option<Ipv4Address>,
+    ) -> Result<(), routes::Error> {
+        routes::set_default_gateway(&mut self.iface, gateway)
+    }
+
+    pub fn default_gateway(&mut self) -> Option<Ipv4Address> {
+        routes::default_gateway(&mut self.iface)
+    }
+
+    /// Adds a static route, replacing any route to the same network
+    pub fn add_route(&mut self, cidr: Ipv4Cidr, gateway: Ipv4Address) -> Result<(), routes::Error> {
+        routes::add(&mut self.iface, cidr, gateway)
+    }
+
+    pub fn remove_route(&mut self, cidr: Ipv4Cidr) -> bool {
+        routes::remove(&mut self.iface, cidr)
+    }
+
+    /// Current routing table, for diagnostics
+    pub fn routes(&mut self) -> RouteTable {
+        routes::table(&mut self.iface)
     }
 
     pub fn poll(&mut self, time: Instant) {
         let t = smoltcp::time::Instant::from_millis(time.as_millis() as i64);
-        match self.iface.poll(&mut self.sockets, t) {
+        match self.iface.poll(self.pool.sockets(), t) {
             Ok(true) => (),
             _ => (),
         }
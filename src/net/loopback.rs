@@ -7,6 +7,9 @@ use smoltcp::time::Instant;
 use smoltcp::{Error, Result};
 use typenum::{Sum, Unsigned, U1024, U476};
 
+#[cfg(test)]
+use smoltcp::wire::EthernetAddress;
+
 /// Default MTU size is 1,500 bytes
 /// U1024 + U476 = U1500
 pub type MtuSize = Sum<U1024, U476>;
@@ -15,6 +18,10 @@ const QLEN: usize = 32;
 
 type QElement = Vec<u8, MtuSize>;
 
+/// Hardware address of the `loopback!` test interface
+#[cfg(test)]
+pub const MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+
 #[derive(Debug)]
 pub struct Loopback {
     queue: ArrayVec<[QElement; QLEN]>,
@@ -84,3 +91,50 @@ impl<'a> phy::TxToken for TxToken<'a> {
         result
     }
 }
+
+/// Test fixture: an interface on a `Loopback` device and a socket set
+///
+/// Declares `$iface` with the given addresses and route slots and, when
+/// given, `$sockets` holding one UDP socket per `name: [metadata; payload]`
+/// entry, binding `name` to its handle.
+#[cfg(test)]
+#[macro_export]
+macro_rules! loopback {
+    (@slot $socket:ident) => {
+        None
+    };
+    ($iface:ident, $ip_addrs:expr, $routes:expr) => {
+        let mut ip_addrs = $ip_addrs;
+        let mut neighbor_storage = [None; 8];
+        let mut routes_storage = [None; $routes];
+        let mut $iface = smoltcp::iface::EthernetInterfaceBuilder::new(
+            $crate::net::loopback::Loopback::new(),
+        )
+        .ethernet_addr($crate::net::loopback::MAC)
+        .ip_addrs(&mut ip_addrs[..])
+        .neighbor_cache(smoltcp::iface::NeighborCache::new(&mut neighbor_storage[..]))
+        .routes(smoltcp::iface::Routes::new(&mut routes_storage[..]))
+        .finalize();
+    };
+    ($iface:ident, $sockets:ident, $ip_addrs:expr, $routes:expr,
+     { $($socket:ident: [$meta:expr; $data:expr]),* $(,)* }) => {
+        $crate::loopback!($iface, $ip_addrs, $routes);
+
+        $(
+            let mut rx_meta = [smoltcp::socket::UdpPacketMetadata::EMPTY; $meta];
+            let mut rx_data = [0; $data];
+            let mut tx_meta = [smoltcp::socket::UdpPacketMetadata::EMPTY; $meta];
+            let mut tx_data = [0; $data];
+            let $socket = smoltcp::socket::UdpSocket::new(
+                smoltcp::socket::UdpSocketBuffer::new(&mut rx_meta[..], &mut rx_data[..]),
+                smoltcp::socket::UdpSocketBuffer::new(&mut tx_meta[..], &mut tx_data[..]),
+            );
+        )*
+
+        let mut sockets_storage = [$($crate::loopback!(@slot $socket)),*];
+        let mut $sockets = smoltcp::socket::SocketSet::new(&mut sockets_storage[..]);
+        $(
+            let $socket = $sockets.add($socket);
+        )*
+    };
+}
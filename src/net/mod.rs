@@ -1,2 +1,7 @@
+pub mod dhcp;
+pub mod dns;
 pub mod eth;
 pub mod loopback;
+pub mod routes;
+pub mod sntp;
+pub mod socket_pool;
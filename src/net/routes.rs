@@ -0,0 +1,203 @@
+//! IPv4 routes in the interface's route storage
+//!
+//! The default gateway and a few static routes, set at runtime from
+//! DHCP or the config. Anything on a subnet of the interface's
+//! `ip_addrs` is on-link and needs no route.
+
+use heapless::consts::U4;
+use heapless::Vec;
+use smoltcp::iface::{EthernetInterface, Route as IfaceRoute};
+use smoltcp::phy::Device;
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+
+/// Size of the interface's route storage, the default route and 3
+/// static routes
+pub const MAX_ROUTES: usize = 4;
+
+pub type MaxRoutes = U4;
+
+/// Snapshot of the routing table, most specific route first
+pub type RouteTable = Vec<Route, MaxRoutes>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// No room in the interface's route storage
+    Full,
+    /// Gateway isn't a unicast address
+    InvalidGateway,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Route {
+    /// Destination network, 0.0.0.0/0 for the default route
+    pub cidr: Ipv4Cidr,
+    pub gateway: Ipv4Address,
+}
+
+impl Route {
+    pub fn is_default(&self) -> bool {
+        self.cidr.prefix_len() == 0
+    }
+}
+
+fn default_cidr() -> Ipv4Cidr {
+    Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0)
+}
+
+/// Sets, replaces or (with `None`) removes the default route
+pub fn set_default_gateway<DeviceT>(
+    iface: &mut EthernetInterface<'_, '_, '_, DeviceT>,
+    gateway: Option<Ipv4Address>,
+) -> Result<(), Error>
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    match gateway {
+        Some(gateway) => add(iface, default_cidr(), gateway),
+        None => {
+            remove(iface, default_cidr());
+            Ok(())
+        }
+    }
+}
+
+pub fn default_gateway<DeviceT>(
+    iface: &mut EthernetInterface<'_, '_, '_, DeviceT>,
+) -> Option<Ipv4Address>
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    table(iface)
+        .iter()
+        .find(|r| r.is_default())
+        .map(|r| r.gateway)
+}
+
+/// Adds a route to `cidr` through `gateway`, replacing any route to
+/// the same network
+pub fn add<DeviceT>(
+    iface: &mut EthernetInterface<'_, '_, '_, DeviceT>,
+    cidr: Ipv4Cidr,
+    gateway: Ipv4Address,
+) -> Result<(), Error>
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    if !gateway.is_unicast() {
+        return Err(Error::InvalidGateway);
+    }
+    let mut result = Ok(());
+    iface.routes_mut().update(|routes| {
+        if routes
+            .insert(IpCidr::Ipv4(cidr), IfaceRoute::new_ipv4_gateway(gateway))
+            .is_err()
+        {
+            result = Err(Error::Full);
+        }
+    });
+    result
+}
+
+/// Returns false if there was no route to `cidr`
+pub fn remove<DeviceT>(iface: &mut EthernetInterface<'_, '_, '_, DeviceT>, cidr: Ipv4Cidr) -> bool
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    let mut removed = false;
+    iface.routes_mut().update(|routes| {
+        removed = routes.remove(&IpCidr::Ipv4(cidr)).is_some();
+    });
+    removed
+}
+
+/// Copy of the IPv4 routes, for diagnostics
+pub fn table<DeviceT>(iface: &mut EthernetInterface<'_, '_, '_, DeviceT>) -> RouteTable
+where
+    DeviceT: for<'d> Device<'d>,
+{
+    let mut table = RouteTable::new();
+    iface.routes_mut().update(|routes| {
+        for (cidr, route) in routes.iter() {
+            if let (IpCidr::Ipv4(cidr), IpAddress::Ipv4(gateway)) = (cidr, route.via_router) {
+                let route = Route {
+                    cidr: *cidr,
+                    gateway,
+                };
+                if table.push(route).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    table.sort_unstable_by(|a, b| b.cidr.prefix_len().cmp(&a.cidr.prefix_len()).then(a.cmp(b)));
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_and_static_routes() {
+        crate::loopback!(
+            iface,
+            [IpCidr::new(IpAddress::v4(192, 168, 1, 39), 24)],
+            MAX_ROUTES
+        );
+        let router = Ipv4Address::new(192, 168, 1, 1);
+        let vpn = Ipv4Cidr::new(Ipv4Address::new(10, 8, 0, 0), 16);
+        let vpn_gateway = Ipv4Address::new(192, 168, 1, 2);
+
+        assert_eq!(default_gateway(&mut iface), None);
+        assert_eq!(table(&mut iface).is_empty(), true);
+
+        set_default_gateway(&mut iface, Some(router)).unwrap();
+        assert_eq!(default_gateway(&mut iface), Some(router));
+        add(&mut iface, vpn, vpn_gateway).unwrap();
+        assert_eq!(
+            &table(&mut iface)[..],
+            &[
+                Route {
+                    cidr: vpn,
+                    gateway: vpn_gateway
+                },
+                Route {
+                    cidr: default_cidr(),
+                    gateway: router
+                },
+            ]
+        );
+
+        // Replaced in place, doesn't take another slot
+        let router = Ipv4Address::new(192, 168, 1, 254);
+        set_default_gateway(&mut iface, Some(router)).unwrap();
+        add(&mut iface, vpn, router).unwrap();
+        assert_eq!(table(&mut iface).len(), 2);
+        assert_eq!(default_gateway(&mut iface), Some(router));
+
+        for i in 0..2 {
+            let cidr = Ipv4Cidr::new(Ipv4Address::new(172, 16, i, 0), 24);
+            add(&mut iface, cidr, vpn_gateway).unwrap();
+        }
+        let cidr = Ipv4Cidr::new(Ipv4Address::new(172, 16, 2, 0), 24);
+        assert_eq!(add(&mut iface, cidr, vpn_gateway), Err(Error::Full));
+        assert_eq!(table(&mut iface).len(), MAX_ROUTES);
+        assert_eq!(table(&mut iface)[MAX_ROUTES - 1].is_default(), true);
+
+        assert_eq!(
+            add(&mut iface, cidr, Ipv4Address::BROADCAST),
+            Err(Error::InvalidGateway)
+        );
+        assert_eq!(
+            set_default_gateway(&mut iface, Some(Ipv4Address::UNSPECIFIED)),
+            Err(Error::InvalidGateway)
+        );
+
+        assert_eq!(remove(&mut iface, vpn), true);
+        assert_eq!(remove(&mut iface, vpn), false);
+        set_default_gateway(&mut iface, None).unwrap();
+        assert_eq!(default_gateway(&mut iface), None);
+        assert_eq!(table(&mut iface).len(), 2);
+        add(&mut iface, cidr, vpn_gateway).unwrap();
+    }
+}
@@ -0,0 +1,805 @@
+//! SNTPv4 client (RFC 4330)
+//!
+//! Asks a single server for the time over a UDP socket and keeps the
+//! wall clock as an offset from the monotonic `Instant`. Each response
+//! gives the clock offset and round-trip delay from the four
+//! timestamps (RFC 5905), the two local ones from the current estimate.
+//! The request's transmit timestamp is random, the server echoing it
+//! is what ties a response to the request.
+//!
+//! Time for the RTC is handed out on the first sync, after the clock
+//! steps and every `RTC_UPDATE_INTERVAL`, at the top of a second since
+//! the RTC has no subseconds.
+
+use crate::net::socket_pool::UdpHandle;
+use crate::random::{RandomSource, XorShift32};
+use crate::rtc::DateTime;
+use crate::time::{Duration, Instant};
+use log::{debug, info, warn};
+use smoltcp::socket::{SocketSet, UdpSocket};
+use smoltcp::wire::{IpEndpoint, Ipv4Address};
+
+pub const SERVER_PORT: u16 = 123;
+
+pub const PACKET_LEN: usize = 48;
+
+/// Between requests once synchronized
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1024);
+
+/// Wait for a response before giving up on a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// First wait after a failed request, doubled up to `POLL_INTERVAL`
+pub const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(16);
+
+pub const RTC_UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Offsets this large are a step, the RTC is rewritten straight away
+const STEP_THRESHOLD_US: i64 = 1_000_000;
+
+/// How far into a second the RTC can still be written
+const RTC_WRITE_WINDOW: Duration = Duration::from_millis(100);
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const UNIX_EPOCH: u64 = 2_208_988_800;
+
+const LEAP_ALARM: u8 = 3;
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const MAX_STRATUM: u8 = 15;
+
+/// Source ports are picked from the dynamic range
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    /// Shorter than a header
+    Truncated,
+    /// Not a server response of a version we speak
+    InvalidPacket,
+    /// Server isn't synchronized itself
+    Unsynchronized,
+    /// Server's time is before 1970
+    InvalidTime,
+    /// Server refused, with the kiss code (i.e. `RATE` or `DENY`)
+    KissOfDeath([u8; 4]),
+    /// Emit buffer too small for a packet
+    BufferTooSmall,
+    Socket(smoltcp::Error),
+}
+
+impl From<smoltcp::Error> for Error {
+    fn from(e: smoltcp::Error) -> Self {
+        Error::Socket(e)
+    }
+}
+
+/// NTP timestamp, seconds since 1900 and fractions of a second
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct Timestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl Timestamp {
+    /// Rounds up to the next fraction, so the round trip through
+    /// `to_unix_micros` is exact
+    pub fn from_unix_micros(micros: u64) -> Self {
+        let secs = micros / 1_000_000 + UNIX_EPOCH;
+        let micros = micros % 1_000_000;
+        Timestamp {
+            seconds: secs as u32,
+            fraction: (((micros << 32) + 999_999) / 1_000_000) as u32,
+        }
+    }
+
+    /// Times before 1968 are taken to be in era 1 (from 2036), as
+    /// RFC 4330 suggests. Negative from 1968 to 1970.
+    pub fn to_unix_micros(&self) -> i64 {
+        let era = if self.seconds & 0x8000_0000 == 0 {
+            1 << 32
+        } else {
+            0
+        };
+        let secs = i64::from(self.seconds) + era - UNIX_EPOCH as i64;
+        let micros = ((u64::from(self.fraction) * 1_000_000) >> 32) as i64;
+        secs * 1_000_000 + micros
+    }
+
+    /// UTC, to the second, and 1970 for anything before
+    pub fn to_datetime(&self) -> DateTime {
+        DateTime::from_unix_time(self.to_unix_micros().max(0) as u64 / 1_000_000)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Packet {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    /// 0 for a kiss-o'-death packet
+    pub stratum: u8,
+    /// Kiss code when the stratum is 0
+    pub reference_id: [u8; 4],
+    /// Client's transmit time, echoed by the server
+    pub originate: Timestamp,
+    /// Server's time the request arrived
+    pub receive: Timestamp,
+    /// Server's time the response left
+    pub transmit: Timestamp,
+}
+
+impl Packet {
+    pub fn request(transmit: Timestamp) -> Self {
+        Packet {
+            version: VERSION,
+            mode: MODE_CLIENT,
+            transmit,
+            ..Default::default()
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < PACKET_LEN {
+            return Err(Error::Truncated);
+        }
+        let version = (data[0] >> 3) & 0x07;
+        if version == 0 || version > VERSION {
+            return Err(Error::InvalidPacket);
+        }
+        let mut reference_id = [0; 4];
+        reference_id.copy_from_slice(&data[12..16]);
+        Ok(Packet {
+            leap: data[0] >> 6,
+            version,
+            mode: data[0] & 0x07,
+            stratum: data[1],
+            reference_id,
+            originate: read_timestamp(&data[24..]),
+            receive: read_timestamp(&data[32..]),
+            transmit: read_timestamp(&data[40..]),
+        })
+    }
+
+    /// Returns the length, fields without a member are zero
+    pub fn emit(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < PACKET_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        let buf = &mut buf[..PACKET_LEN];
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        buf[0] = (self.leap << 6) | ((self.version & 0x07) << 3) | (self.mode & 0x07);
+        buf[1] = self.stratum;
+        buf[12..16].copy_from_slice(&self.reference_id);
+        write_timestamp(&mut buf[24..], self.originate);
+        write_timestamp(&mut buf[32..], self.receive);
+        write_timestamp(&mut buf[40..], self.transmit);
+        Ok(PACKET_LEN)
+    }
+}
+
+/// Outcome of a sync
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Sample {
+    /// Correction applied to the clock, in microseconds
+    pub offset: i64,
+    /// Round trip, less the server's processing time
+    pub delay: Duration,
+}
+
+pub struct Client {
+    handle: UdpHandle,
+    rng: XorShift32,
+    server: Option<Ipv4Address>,
+    /// Unix time at `Instant` zero, in microseconds
+    epoch: Option<i64>,
+    /// Transmit timestamp and time of the request waiting on a response
+    pending: Option<(Timestamp, Instant)>,
+    next_request: Instant,
+    retry_interval: Duration,
+    /// Server sent a DENY or RSTR kiss
+    denied: bool,
+    last_sync: Option<Instant>,
+    rtc_updated: Option<Instant>,
+}
+
+impl Client {
+    /// `handle` is a UDP socket in the interface's socket set, it gets
+    /// bound to a random source port
+    pub fn new<R: RandomSource>(
+        handle: UdpHandle,
+        server: Option<Ipv4Address>,
+        rng: &mut R,
+    ) -> Self {
+        Client {
+            handle,
+            rng: XorShift32::new(rng.next_u32()),
+            server,
+            epoch: None,
+            pending: None,
+            next_request: Instant::from_secs(0),
+            retry_interval: MIN_RETRY_INTERVAL,
+            denied: false,
+            last_sync: None,
+            rtc_updated: None,
+        }
+    }
+
+    pub fn socket_handle(&self) -> UdpHandle {
+        self.handle
+    }
+
+    pub fn server(&self) -> Option<Ipv4Address> {
+        self.server
+    }
+
+    /// Changes the server (i.e. from a new DHCP lease), asks it on the
+    /// next poll
+    pub fn set_server(&mut self, server: Option<Ipv4Address>, time: Instant) {
+        if server != self.server {
+            self.server = server;
+            self.pending = None;
+            self.denied = false;
+            self.retry_interval = MIN_RETRY_INTERVAL;
+            self.next_request = time;
+        }
+    }
+
+    /// Time of the last successful sync
+    pub fn last_sync(&self) -> Option<Instant> {
+        self.last_sync
+    }
+
+    /// Unix time, once synchronized
+    pub fn unix_time(&self, time: Instant) -> Option<Duration> {
+        let micros = self.epoch? + time.as_micros() as i64;
+        if micros < 0 {
+            None
+        } else {
+            Some(Duration::from_micros(micros as u64))
+        }
+    }
+
+    /// UTC, once synchronized
+    pub fn now(&self, time: Instant) -> Option<DateTime> {
+        self.unix_time(time)
+            .map(|t| DateTime::from_unix_time(t.as_secs()))
+    }
+
+    /// Time to write to the RTC when it's due, call often so the top
+    /// of a second isn't missed
+    pub fn rtc_update(&mut self, time: Instant) -> Option<DateTime> {
+        let now = self.unix_time(time)?;
+        let due = self
+            .rtc_updated
+            .map_or(true, |at| time >= at + RTC_UPDATE_INTERVAL);
+        if !due || now.subsec_nanos() >= RTC_WRITE_WINDOW.subsec_nanos() {
+            return None;
+        }
+        self.rtc_updated = Some(time);
+        Some(DateTime::from_unix_time(now.as_secs()))
+    }
+
+    /// Receives the response and sends any request that's due. Returns
+    /// the sample when the clock was synchronized.
+    pub fn poll(
+        &mut self,
+        sockets: &mut SocketSet,
+        time: Instant,
+    ) -> Result<Option<Sample>, Error> {
+        let mut socket = sockets.get::<UdpSocket>(self.handle.into());
+        if !socket.is_open() {
+            let ports = u32::from(u16::max_value() - EPHEMERAL_PORT_START) + 1;
+            let port = EPHEMERAL_PORT_START + (self.rng.next_u32() % ports) as u16;
+            socket.bind(port)?;
+        }
+
+        let mut sample = None;
+        let server = self.server.map(|s| IpEndpoint::new(s.into(), SERVER_PORT));
+        while let Ok((data, from)) = socket.recv() {
+            if Some(from) != server {
+                debug!("Ignoring NTP message from {}", from);
+                continue;
+            }
+            match Packet::parse(data).and_then(|p| self.handle_response(&p, time)) {
+                Ok(Some(s)) => sample = Some(s),
+                Ok(None) => debug!("Ignoring NTP message from {}", from),
+                Err(e) => warn!("Bad NTP message from {}: {:?}", from, e),
+            }
+        }
+
+        let server = match server {
+            Some(s) if !self.denied => s,
+            _ => return Ok(sample),
+        };
+        if let Some((_, sent)) = self.pending {
+            if time < sent + REQUEST_TIMEOUT {
+                return Ok(sample);
+            }
+            warn!("NTP request timed out");
+            self.pending = None;
+            self.back_off(time);
+        }
+        if time >= self.next_request {
+            let transmit = Timestamp {
+                seconds: self.rng.next_u32(),
+                fraction: self.rng.next_u32(),
+            };
+            let mut buf = [0; PACKET_LEN];
+            let len = Packet::request(transmit).emit(&mut buf)?;
+            socket.send_slice(&buf[..len], server)?;
+            self.pending = Some((transmit, time));
+        }
+        Ok(sample)
+    }
+
+    /// Returns `None` if the packet isn't the response to our request
+    fn handle_response(&mut self, packet: &Packet, time: Instant) -> Result<Option<Sample>, Error> {
+        let sent = match self.pending {
+            Some((transmit, sent)) if packet.originate == transmit => sent,
+            _ => return Ok(None),
+        };
+        if packet.mode != MODE_SERVER {
+            return Err(Error::InvalidPacket);
+        }
+        self.pending = None;
+        if packet.stratum == 0 {
+            if &packet.reference_id == b"DENY" || &packet.reference_id == b"RSTR" {
+                self.denied = true;
+            }
+            self.back_off(time);
+            return Err(Error::KissOfDeath(packet.reference_id));
+        }
+        if packet.leap == LEAP_ALARM || packet.stratum > MAX_STRATUM || packet.transmit.seconds == 0
+        {
+            self.back_off(time);
+            return Err(Error::Unsynchronized);
+        }
+
+        let t2 = packet.receive.to_unix_micros();
+        let t3 = packet.transmit.to_unix_micros();
+        if t2 < 0 || t3 < 0 {
+            self.back_off(time);
+            return Err(Error::InvalidTime);
+        }
+
+        let epoch = self.epoch.unwrap_or(0);
+        let t1 = epoch + sent.as_micros() as i64;
+        let t4 = epoch + time.as_micros() as i64;
+        let offset = ((t2 - t1) + (t3 - t4)) / 2;
+        let delay = ((t4 - t1) - (t3 - t2)).max(0);
+
+        if self.epoch.is_none() || offset.abs() >= STEP_THRESHOLD_US {
+            info!("NTP clock set to {}", packet.transmit.to_datetime());
+            self.rtc_updated = None;
+        }
+        self.epoch = Some(epoch + offset);
+        self.last_sync = Some(time);
+        self.retry_interval = MIN_RETRY_INTERVAL;
+        self.next_request = time + POLL_INTERVAL;
+        Ok(Some(Sample {
+            offset,
+            delay: Duration::from_micros(delay as u64),
+        }))
+    }
+
+    fn back_off(&mut self, time: Instant) {
+        self.next_request = time + self.retry_interval;
+        self.retry_interval = (self.retry_interval * 2).min(POLL_INTERVAL);
+    }
+}
+
+// Callers have checked the length
+fn read_timestamp(data: &[u8]) -> Timestamp {
+    Timestamp {
+        seconds: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+        fraction: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+    }
+}
+
+// Callers have checked the length
+fn write_timestamp(buf: &mut [u8], timestamp: Timestamp) {
+    buf[..4].copy_from_slice(&timestamp.seconds.to_be_bytes());
+    buf[4..8].copy_from_slice(&timestamp.fraction.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::loopback::Loopback;
+    use smoltcp::iface::EthernetInterface;
+    use smoltcp::socket::SocketHandle;
+    use smoltcp::wire::IpCidr;
+
+    const CLIENT: Ipv4Address = Ipv4Address([10, 0, 0, 50]);
+    const SERVER: Ipv4Address = Ipv4Address([10, 0, 0, 1]);
+    /// 2019-09-29 07:10:15.25 UTC
+    const SERVER_TIME_US: u64 = 1_569_741_015_250_000;
+
+    type Iface<'a> = EthernetInterface<'a, 'a, 'a, Loopback>;
+
+    fn ms(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    /// Scripted server on the same interface
+    struct Server {
+        handle: SocketHandle,
+    }
+
+    impl Server {
+        fn recv(&self, sockets: &mut SocketSet) -> Option<(Packet, IpEndpoint)> {
+            let mut socket = sockets.get::<UdpSocket>(self.handle);
+            if !socket.is_open() {
+                socket
+                    .bind(IpEndpoint::new(SERVER.into(), SERVER_PORT))
+                    .unwrap();
+            }
+            let packet = socket
+                .recv()
+                .ok()
+                .map(|(data, from)| (Packet::parse(data).unwrap(), from));
+            assert_eq!(socket.can_recv(), false);
+            packet
+        }
+
+        fn send(&self, sockets: &mut SocketSet, packet: &Packet, to: IpEndpoint) {
+            let mut buf = [0; PACKET_LEN];
+            let len = packet.emit(&mut buf).unwrap();
+            let mut socket = sockets.get::<UdpSocket>(self.handle);
+            socket.send_slice(&buf[..len], to).unwrap();
+        }
+
+        /// Responds to `request` as a stratum 2 server, `processing`
+        /// after receiving it at `receive_us`
+        fn respond(
+            &self,
+            sockets: &mut SocketSet,
+            request: &(Packet, IpEndpoint),
+            receive_us: u64,
+            processing_us: u64,
+        ) {
+            let response = Packet {
+                version: VERSION,
+                mode: MODE_SERVER,
+                stratum: 2,
+                reference_id: [10, 0, 0, 254],
+                originate: request.0.transmit,
+                receive: Timestamp::from_unix_micros(receive_us),
+                transmit: Timestamp::from_unix_micros(receive_us + processing_us),
+                ..Default::default()
+            };
+            self.send(sockets, &response, request.1);
+        }
+    }
+
+    /// Delivers anything the server sent, polls the client and returns
+    /// its sample and what the server got
+    fn step(
+        client: &mut Client,
+        server: &Server,
+        iface: &mut Iface,
+        sockets: &mut SocketSet,
+        time: Instant,
+    ) -> (Option<Sample>, Option<(Packet, IpEndpoint)>) {
+        let _ = iface.poll(sockets, smoltcp::time::Instant::from_millis(0));
+        let sample = client.poll(sockets, time).unwrap();
+        let _ = iface.poll(sockets, smoltcp::time::Instant::from_millis(0));
+        (sample, server.recv(sockets))
+    }
+
+    macro_rules! setup {
+        ($iface:ident, $sockets:ident, $client:ident, $server:ident) => {
+            crate::loopback!(
+                $iface,
+                $sockets,
+                [
+                    IpCidr::new(CLIENT.into(), 24),
+                    IpCidr::new(SERVER.into(), 24),
+                ],
+                1,
+                {
+                    client_handle: [2; 256],
+                    server_handle: [2; 256],
+                }
+            );
+            let $server = Server {
+                handle: server_handle,
+            };
+            let mut $client = Client::new(
+                UdpHandle::from_socket(client_handle),
+                Some(SERVER),
+                &mut XorShift32::new(1),
+            );
+            // Bind the server
+            assert_eq!($server.recv(&mut $sockets).is_none(), true);
+        };
+    }
+
+    #[test]
+    fn sync_offset_and_delay() {
+        setup!(iface, sockets, client, server);
+        assert_eq!(client.now(ms(0)), None);
+        assert_eq!(client.rtc_update(ms(0)), None);
+
+        let (sample, request) = step(&mut client, &server, &mut iface, &mut sockets, ms(10_000));
+        assert_eq!(sample, None);
+        let request = request.unwrap();
+        assert_eq!(request.0.version, VERSION);
+        assert_eq!(request.0.mode, MODE_CLIENT);
+        assert_ne!(request.0.transmit, Timestamp::default());
+        assert_eq!(request.1.addr, CLIENT.into());
+        assert!(request.1.port >= EPHEMERAL_PORT_START);
+
+        // 100 ms round trip, 1 ms of it in the server
+        server.respond(&mut sockets, &request, SERVER_TIME_US, 1_000);
+        let (sample, _) = step(&mut client, &server, &mut iface, &mut sockets, ms(10_100));
+        let sample = sample.unwrap();
+        assert_eq!(sample.delay, Duration::from_micros(99_000));
+        assert_eq!(sample.offset, SERVER_TIME_US as i64 - 10_049_500);
+        assert_eq!(client.last_sync(), Some(ms(10_100)));
+        assert_eq!(
+            client.unix_time(ms(10_100)),
+            Some(Duration::from_micros(SERVER_TIME_US + 50_500))
+        );
+        let dt = |minute, second| {
+            DateTime::from(ds323x::DateTime {
+                year: 2019,
+                month: 9,
+                day: 29,
+                weekday: 1,
+                hour: ds323x::Hours::H24(7),
+                minute,
+                second,
+            })
+        };
+        assert_eq!(client.now(ms(10_100)), Some(dt(10, 15)));
+        assert_eq!(client.now(ms(10_799)), Some(dt(10, 15)));
+        assert_eq!(client.now(ms(10_800)), Some(dt(10, 16)));
+
+        // RTC is written at the top of the next second, then hourly
+        assert_eq!(client.rtc_update(ms(10_100)), None);
+        assert_eq!(client.rtc_update(ms(10_800)), Some(dt(10, 16)));
+        assert_eq!(client.rtc_update(ms(10_850)), None);
+        assert_eq!(client.rtc_update(ms(11_800)), None);
+        assert_eq!(client.rtc_update(ms(3_610_799)), None);
+        assert_eq!(client.rtc_update(ms(3_610_800)).is_some(), true);
+
+        // Polls again, a step rewrites the RTC straight away
+        let (_, request) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            ms(1_034_099),
+        );
+        assert_eq!(request.is_none(), true);
+        let (_, request) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            ms(1_034_100),
+        );
+        let request = request.unwrap();
+        let server_time = SERVER_TIME_US + 1_024_000_000 + 5_000_000;
+        server.respond(&mut sockets, &request, server_time, 0);
+        let (sample, _) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            ms(1_034_100),
+        );
+        let sample = sample.unwrap();
+        assert_eq!(sample.delay, Duration::from_micros(0));
+        assert_eq!(sample.offset, 5_000_000 - 50_500);
+        assert_eq!(
+            client.unix_time(ms(1_034_100)),
+            Some(Duration::from_micros(server_time))
+        );
+        assert_eq!(client.rtc_update(ms(1_034_849)), None);
+        assert_eq!(client.rtc_update(ms(1_034_850)), Some(dt(27, 25)));
+    }
+
+    #[test]
+    fn rejected_responses() {
+        setup!(iface, sockets, client, server);
+        let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, ms(0));
+        let request = request.unwrap();
+        let response = |leap, mode, stratum, reference_id: &[u8; 4]| Packet {
+            leap,
+            version: VERSION,
+            mode,
+            stratum,
+            reference_id: *reference_id,
+            originate: request.0.transmit,
+            receive: Timestamp::from_unix_micros(SERVER_TIME_US),
+            transmit: Timestamp::from_unix_micros(SERVER_TIME_US),
+        };
+
+        // Not for the request, not from a server
+        let mut other = response(0, MODE_SERVER, 2, b"GPS\0");
+        other.originate.fraction ^= 1;
+        server.send(&mut sockets, &other, request.1);
+        server.send(
+            &mut sockets,
+            &response(0, MODE_CLIENT, 2, b"GPS\0"),
+            request.1,
+        );
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, ms(100)),
+            (None, None)
+        );
+
+        // Unsynchronized server, backs off 16 s
+        server.send(
+            &mut sockets,
+            &response(LEAP_ALARM, MODE_SERVER, 2, b"GPS\0"),
+            request.1,
+        );
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, ms(200)),
+            (None, None)
+        );
+        let (_, none) = step(&mut client, &server, &mut iface, &mut sockets, ms(16_199));
+        assert_eq!(none.is_none(), true);
+        let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, ms(16_200));
+        let request = request.unwrap();
+
+        // Rate kiss, backs off 32 s
+        let kiss = Packet {
+            originate: request.0.transmit,
+            ..response(0, MODE_SERVER, 0, b"RATE")
+        };
+        server.send(&mut sockets, &kiss, request.1);
+        assert_eq!(
+            step(&mut client, &server, &mut iface, &mut sockets, ms(16_300)),
+            (None, None)
+        );
+        let (_, none) = step(&mut client, &server, &mut iface, &mut sockets, ms(48_299));
+        assert_eq!(none.is_none(), true);
+        let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, ms(48_300));
+        let request = request.unwrap();
+
+        // Unanswered, times out and backs off 64 s
+        let (_, none) = step(&mut client, &server, &mut iface, &mut sockets, ms(52_300));
+        assert_eq!(none.is_none(), true);
+        let (_, none) = step(&mut client, &server, &mut iface, &mut sockets, ms(116_299));
+        assert_eq!(none.is_none(), true);
+        let (_, retry) = step(&mut client, &server, &mut iface, &mut sockets, ms(116_300));
+        let retry = retry.unwrap();
+        assert_ne!(retry.0.transmit, request.0.transmit);
+
+        // Denied, stops asking until the server changes
+        let kiss = Packet {
+            originate: retry.0.transmit,
+            ..response(0, MODE_SERVER, 0, b"DENY")
+        };
+        server.send(&mut sockets, &kiss, retry.1);
+        step(&mut client, &server, &mut iface, &mut sockets, ms(116_400));
+        let (_, none) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            ms(10_000_000),
+        );
+        assert_eq!(none.is_none(), true);
+        assert_eq!(client.now(ms(10_000_000)), None);
+        client.set_server(None, ms(10_000_000));
+        let (_, none) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            ms(10_000_000),
+        );
+        assert_eq!(none.is_none(), true);
+        client.set_server(Some(SERVER), ms(10_000_000));
+        let (_, request) = step(
+            &mut client,
+            &server,
+            &mut iface,
+            &mut sockets,
+            ms(10_000_000),
+        );
+        assert_eq!(request.is_some(), true);
+    }
+
+    #[test]
+    fn pre_1970_response() {
+        setup!(iface, sockets, client, server);
+        let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, ms(0));
+        let request = request.unwrap();
+        // 1969-12-31 23:59:59, and 1968-01-20 03:14:08
+        for (seconds, at) in [(UNIX_EPOCH as u32 - 1, 0), (0x8000_0000, 16_100)].iter() {
+            let request = if *at == 0 {
+                request
+            } else {
+                let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, ms(*at));
+                request.unwrap()
+            };
+            let time = Timestamp {
+                seconds: *seconds,
+                fraction: 0,
+            };
+            let response = Packet {
+                version: VERSION,
+                mode: MODE_SERVER,
+                stratum: 2,
+                reference_id: [10, 0, 0, 254],
+                originate: request.0.transmit,
+                receive: time,
+                transmit: time,
+                ..Default::default()
+            };
+            server.send(&mut sockets, &response, request.1);
+            assert_eq!(
+                step(&mut client, &server, &mut iface, &mut sockets, ms(at + 100)),
+                (None, None)
+            );
+            assert_eq!(client.now(ms(at + 100)), None);
+            assert_eq!(client.rtc_update(ms(at + 100)), None);
+        }
+
+        // Backs off like any other bad response
+        let (_, none) = step(&mut client, &server, &mut iface, &mut sockets, ms(48_199));
+        assert_eq!(none.is_none(), true);
+        let (_, request) = step(&mut client, &server, &mut iface, &mut sockets, ms(48_200));
+        assert_eq!(request.is_some(), true);
+    }
+
+    #[test]
+    fn timestamps() {
+        let ts = Timestamp::from_unix_micros(SERVER_TIME_US);
+        assert_eq!(ts.seconds, 3_778_729_815);
+        assert_eq!(ts.fraction, 0x4000_0000);
+        assert_eq!(ts.to_unix_micros(), SERVER_TIME_US as i64);
+        for micros in (0..1_000_000).step_by(7) {
+            let ts = Timestamp::from_unix_micros(SERVER_TIME_US + micros);
+            assert_eq!(ts.to_unix_micros(), (SERVER_TIME_US + micros) as i64);
+        }
+
+        // Era 1 starts 2036-02-07 06:28:16
+        let era_1 = Timestamp::default();
+        assert_eq!(era_1.to_unix_micros(), 2_085_978_496_000_000);
+        assert_eq!(era_1.to_datetime(), DateTime::from_unix_time(2_085_978_496));
+        let end_of_era_0 = Timestamp {
+            seconds: 0xFFFF_FFFF,
+            fraction: 0,
+        };
+        assert_eq!(end_of_era_0.to_unix_micros(), 2_085_978_495_000_000);
+
+        // 1968 to 1970 is before the Unix epoch
+        let start_of_1968 = Timestamp {
+            seconds: 0x8000_0000,
+            fraction: 0,
+        };
+        assert_eq!(start_of_1968.to_unix_micros(), -61_505_152_000_000);
+        assert_eq!(start_of_1968.to_datetime(), DateTime::from_unix_time(0));
+        let before_epoch = Timestamp {
+            seconds: UNIX_EPOCH as u32 - 1,
+            fraction: 0x8000_0000,
+        };
+        assert_eq!(before_epoch.to_unix_micros(), -500_000);
+        assert_eq!(Timestamp::from_unix_micros(0).to_unix_micros(), 0);
+
+        let request = Packet::request(ts);
+        let mut buf = [0xFF; PACKET_LEN + 1];
+        assert_eq!(request.emit(&mut buf), Ok(PACKET_LEN));
+        assert_eq!(buf[0], 0x23);
+        assert_eq!(&buf[1..40], &[0; 39][..]);
+        assert_eq!(Packet::parse(&buf), Ok(request));
+        assert_eq!(Packet::parse(&buf[..PACKET_LEN - 1]), Err(Error::Truncated));
+        assert_eq!(
+            request.emit(&mut buf[..PACKET_LEN - 1]),
+            Err(Error::BufferTooSmall)
+        );
+        buf[0] = 0x03;
+        assert_eq!(Packet::parse(&buf), Err(Error::InvalidPacket));
+    }
+}
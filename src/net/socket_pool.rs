@@ -0,0 +1,331 @@
+//! Fixed pool of sockets with statically allocated buffers
+//!
+//! Every socket is created up front in the `SocketSet`, subsystems
+//! allocate one of the kind they need and get a typed handle back.
+//! Released sockets are closed and go back to the pool.
+
+use crate::net::eth::SOCKET_BUFFER_SIZE;
+use smoltcp::socket::{
+    IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer, SocketHandle, SocketRef, SocketSet,
+    TcpSocket, TcpSocketBuffer, UdpPacketMetadata, UdpSocket, UdpSocketBuffer,
+};
+
+pub const UDP_SOCKETS: usize = 8;
+pub const TCP_SOCKETS: usize = 1;
+pub const ICMP_SOCKETS: usize = 1;
+
+/// Slots the `SocketSet` needs
+pub const MAX_SOCKETS: usize = UDP_SOCKETS + TCP_SOCKETS + ICMP_SOCKETS;
+
+/// Datagrams queued each way on a UDP socket, 20 ms RTP frames need a
+/// few in flight
+pub const UDP_PACKETS: usize = 8;
+
+pub const ICMP_PACKETS: usize = 4;
+pub const ICMP_BUFFER_SIZE: usize = 512;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Every socket of the kind is allocated
+    Exhausted(SocketKind),
+    /// Handle isn't allocated (i.e. released twice)
+    NotAllocated,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SocketKind {
+    Udp,
+    Tcp,
+    Icmp,
+}
+
+/// Subsystem a socket is allocated to
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Owner {
+    Sip,
+    Rtp,
+    Rtcp,
+    Dns,
+    Dhcp,
+    Ntp,
+    Console,
+    Diagnostics,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct UdpHandle(SocketHandle);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TcpHandle(SocketHandle);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct IcmpHandle(SocketHandle);
+
+#[cfg(test)]
+impl UdpHandle {
+    /// Socket a test added to its own `SocketSet`
+    pub(crate) fn from_socket(handle: SocketHandle) -> Self {
+        UdpHandle(handle)
+    }
+}
+
+impl From<UdpHandle> for SocketHandle {
+    fn from(h: UdpHandle) -> Self {
+        h.0
+    }
+}
+
+impl From<TcpHandle> for SocketHandle {
+    fn from(h: TcpHandle) -> Self {
+        h.0
+    }
+}
+
+impl From<IcmpHandle> for SocketHandle {
+    fn from(h: IcmpHandle) -> Self {
+        h.0
+    }
+}
+
+#[derive(Copy, Clone)]
+struct UdpBuffers {
+    rx_metadata: [UdpPacketMetadata; UDP_PACKETS],
+    tx_metadata: [UdpPacketMetadata; UDP_PACKETS],
+    rx: [u8; SOCKET_BUFFER_SIZE],
+    tx: [u8; SOCKET_BUFFER_SIZE],
+}
+
+impl UdpBuffers {
+    const EMPTY: UdpBuffers = UdpBuffers {
+        rx_metadata: [UdpPacketMetadata::EMPTY; UDP_PACKETS],
+        tx_metadata: [UdpPacketMetadata::EMPTY; UDP_PACKETS],
+        rx: [0; SOCKET_BUFFER_SIZE],
+        tx: [0; SOCKET_BUFFER_SIZE],
+    };
+}
+
+#[derive(Copy, Clone)]
+struct TcpBuffers {
+    rx: [u8; SOCKET_BUFFER_SIZE],
+    tx: [u8; SOCKET_BUFFER_SIZE],
+}
+
+impl TcpBuffers {
+    const EMPTY: TcpBuffers = TcpBuffers {
+        rx: [0; SOCKET_BUFFER_SIZE],
+        tx: [0; SOCKET_BUFFER_SIZE],
+    };
+}
+
+#[derive(Copy, Clone)]
+struct IcmpBuffers {
+    rx_metadata: [IcmpPacketMetadata; ICMP_PACKETS],
+    tx_metadata: [IcmpPacketMetadata; ICMP_PACKETS],
+    rx: [u8; ICMP_BUFFER_SIZE],
+    tx: [u8; ICMP_BUFFER_SIZE],
+}
+
+impl IcmpBuffers {
+    const EMPTY: IcmpBuffers = IcmpBuffers {
+        rx_metadata: [IcmpPacketMetadata::EMPTY; ICMP_PACKETS],
+        tx_metadata: [IcmpPacketMetadata::EMPTY; ICMP_PACKETS],
+        rx: [0; ICMP_BUFFER_SIZE],
+        tx: [0; ICMP_BUFFER_SIZE],
+    };
+}
+
+/// Storage for every socket in the pool, meant for a `static mut`
+pub struct Buffers {
+    udp: [UdpBuffers; UDP_SOCKETS],
+    tcp: [TcpBuffers; TCP_SOCKETS],
+    icmp: [IcmpBuffers; ICMP_SOCKETS],
+}
+
+impl Buffers {
+    pub const fn new() -> Self {
+        Buffers {
+            udp: [UdpBuffers::EMPTY; UDP_SOCKETS],
+            tcp: [TcpBuffers::EMPTY; TCP_SOCKETS],
+            icmp: [IcmpBuffers::EMPTY; ICMP_SOCKETS],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    handle: SocketHandle,
+    kind: SocketKind,
+    owner: Option<Owner>,
+}
+
+pub struct SocketPool<'a, 'b> {
+    sockets: SocketSet<'a, 'b, 'b>,
+    slots: [Option<Slot>; MAX_SOCKETS],
+}
+
+impl<'a, 'b> SocketPool<'a, 'b> {
+    /// Creates every socket in `sockets`, which needs room for
+    /// `MAX_SOCKETS`
+    pub fn new(mut sockets: SocketSet<'a, 'b, 'b>, buffers: &'b mut Buffers) -> Self {
+        let mut slots = [None; MAX_SOCKETS];
+        let mut slot = slots.iter_mut();
+        let mut add = |handle, kind| {
+            // Can't fail, one slot per buffer
+            *slot.next().unwrap() = Some(Slot {
+                handle,
+                kind,
+                owner: None,
+            });
+        };
+        for b in buffers.udp.iter_mut() {
+            let socket = UdpSocket::new(
+                UdpSocketBuffer::new(&mut b.rx_metadata[..], &mut b.rx[..]),
+                UdpSocketBuffer::new(&mut b.tx_metadata[..], &mut b.tx[..]),
+            );
+            add(sockets.add(socket), SocketKind::Udp);
+        }
+        for b in buffers.tcp.iter_mut() {
+            let socket = TcpSocket::new(
+                TcpSocketBuffer::new(&mut b.rx[..]),
+                TcpSocketBuffer::new(&mut b.tx[..]),
+            );
+            add(sockets.add(socket), SocketKind::Tcp);
+        }
+        for b in buffers.icmp.iter_mut() {
+            let socket = IcmpSocket::new(
+                IcmpSocketBuffer::new(&mut b.rx_metadata[..], &mut b.rx[..]),
+                IcmpSocketBuffer::new(&mut b.tx_metadata[..], &mut b.tx[..]),
+            );
+            add(sockets.add(socket), SocketKind::Icmp);
+        }
+        SocketPool { sockets, slots }
+    }
+
+    pub fn sockets(&mut self) -> &mut SocketSet<'a, 'b, 'b> {
+        &mut self.sockets
+    }
+
+    pub fn allocate_udp(&mut self, owner: Owner) -> Result<UdpHandle, Error> {
+        self.allocate(SocketKind::Udp, owner).map(UdpHandle)
+    }
+
+    pub fn allocate_tcp(&mut self, owner: Owner) -> Result<TcpHandle, Error> {
+        self.allocate(SocketKind::Tcp, owner).map(TcpHandle)
+    }
+
+    pub fn allocate_icmp(&mut self, owner: Owner) -> Result<IcmpHandle, Error> {
+        self.allocate(SocketKind::Icmp, owner).map(IcmpHandle)
+    }
+
+    /// Closes the socket and returns it to the pool. ICMP sockets keep
+    /// their binding, smoltcp has no way to undo it.
+    pub fn release<H: Into<SocketHandle>>(&mut self, handle: H) -> Result<(), Error> {
+        let handle = handle.into();
+        let slot = self
+            .slots
+            .iter_mut()
+            .flatten()
+            .find(|s| s.handle == handle && s.owner.is_some())
+            .ok_or(Error::NotAllocated)?;
+        match slot.kind {
+            SocketKind::Udp => self.sockets.get::<UdpSocket>(handle).close(),
+            SocketKind::Tcp => self.sockets.get::<TcpSocket>(handle).abort(),
+            SocketKind::Icmp => (),
+        }
+        slot.owner = None;
+        Ok(())
+    }
+
+    pub fn udp(&mut self, handle: UdpHandle) -> SocketRef<UdpSocket<'b, 'b>> {
+        self.sockets.get::<UdpSocket>(handle.0)
+    }
+
+    pub fn tcp(&mut self, handle: TcpHandle) -> SocketRef<TcpSocket<'b>> {
+        self.sockets.get::<TcpSocket>(handle.0)
+    }
+
+    pub fn icmp(&mut self, handle: IcmpHandle) -> SocketRef<IcmpSocket<'b, 'b>> {
+        self.sockets.get::<IcmpSocket>(handle.0)
+    }
+
+    pub fn owner<H: Into<SocketHandle>>(&self, handle: H) -> Option<Owner> {
+        let handle = handle.into();
+        self.slots
+            .iter()
+            .flatten()
+            .find(|s| s.handle == handle)
+            .and_then(|s| s.owner)
+    }
+
+    /// Sockets of the kind left to allocate
+    pub fn available(&self, kind: SocketKind) -> usize {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|s| s.kind == kind && s.owner.is_none())
+            .count()
+    }
+
+    fn allocate(&mut self, kind: SocketKind, owner: Owner) -> Result<SocketHandle, Error> {
+        let slot = self
+            .slots
+            .iter_mut()
+            .flatten()
+            .find(|s| s.kind == kind && s.owner.is_none())
+            .ok_or(Error::Exhausted(kind))?;
+        slot.owner = Some(owner);
+        Ok(slot.handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_release() {
+        let mut buffers = Buffers::new();
+        let mut sockets_storage: [_; MAX_SOCKETS] = Default::default();
+        let mut pool = SocketPool::new(SocketSet::new(&mut sockets_storage[..]), &mut buffers);
+        assert_eq!(pool.available(SocketKind::Udp), UDP_SOCKETS);
+        assert_eq!(pool.available(SocketKind::Tcp), TCP_SOCKETS);
+        assert_eq!(pool.available(SocketKind::Icmp), ICMP_SOCKETS);
+
+        let sip = pool.allocate_udp(Owner::Sip).unwrap();
+        assert_eq!(pool.owner(sip), Some(Owner::Sip));
+        pool.udp(sip).bind(5060).unwrap();
+        for _ in 1..UDP_SOCKETS {
+            let handle = pool.allocate_udp(Owner::Rtp).unwrap();
+            assert_ne!(handle, sip);
+        }
+        assert_eq!(
+            pool.allocate_udp(Owner::Dns),
+            Err(Error::Exhausted(SocketKind::Udp))
+        );
+        assert_eq!(pool.available(SocketKind::Udp), 0);
+
+        // Other kinds come from their own sockets
+        let console = pool.allocate_tcp(Owner::Console).unwrap();
+        assert_eq!(pool.tcp(console).is_open(), false);
+        assert_eq!(
+            pool.allocate_tcp(Owner::Console),
+            Err(Error::Exhausted(SocketKind::Tcp))
+        );
+        let ping = pool.allocate_icmp(Owner::Diagnostics).unwrap();
+        assert_eq!(pool.owner(ping), Some(Owner::Diagnostics));
+
+        // Released closed, and handed out again
+        pool.release(sip).unwrap();
+        assert_eq!(pool.owner(sip), None);
+        assert_eq!(pool.release(sip), Err(Error::NotAllocated));
+        let dns = pool.allocate_udp(Owner::Dns).unwrap();
+        assert_eq!(dns, sip);
+        assert_eq!(pool.owner(dns), Some(Owner::Dns));
+        assert_eq!(pool.udp(dns).is_open(), false);
+
+        pool.release(console).unwrap();
+        pool.release(ping).unwrap();
+        assert_eq!(pool.available(SocketKind::Tcp), TCP_SOCKETS);
+        assert_eq!(pool.available(SocketKind::Icmp), ICMP_SOCKETS);
+    }
+}
@@ -1,11 +1,12 @@
 use crate::display::{Row, RowFormatter, RowStorage};
 use crate::phone_number::PhoneNumber;
-use crate::rtc::DateTime;
+use crate::rtc::{DateTime, DateTimeFormat};
 use core::fmt::{self, Write};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct CallPendingStateData {
     pub system_time: DateTime,
+    pub time_format: DateTimeFormat,
     pub remote: PhoneNumber,
 }
 
@@ -13,6 +14,7 @@ impl Default for CallPendingStateData {
     fn default() -> Self {
         CallPendingStateData {
             system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
             remote: PhoneNumber::default(),
         }
     }
@@ -34,7 +36,11 @@ impl RowFormatter for CallPendingStateData {
                 write!(storage, "{: ^20}", self.remote)?;
             }
             Row::Three => {
-                write!(storage, "{: ^20}", self.system_time)?;
+                write!(
+                    storage,
+                    "{: ^20}",
+                    self.time_format.display(self.system_time)
+                )?;
             }
         }
 
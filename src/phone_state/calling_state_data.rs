@@ -0,0 +1,90 @@
+use crate::display::{Row, RowFormatter, RowStorage};
+use crate::phone_number::PhoneNumber;
+use crate::rtc::{DateTime, DateTimeFormat};
+use core::fmt::{self, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallingStateData {
+    pub system_time: DateTime,
+    pub time_format: DateTimeFormat,
+    pub remote: PhoneNumber,
+    /// Remote end is alerting, ringback is being played
+    pub ringback: bool,
+}
+
+impl Default for CallingStateData {
+    fn default() -> Self {
+        CallingStateData {
+            system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
+            remote: PhoneNumber::default(),
+            ringback: false,
+        }
+    }
+}
+
+impl RowFormatter for CallingStateData {
+    fn format_row(&self, row: Row, storage: &mut RowStorage) -> Result<(), fmt::Error> {
+        storage.clear();
+
+        match row {
+            Row::Zero => {
+                write!(storage, "{: ^20}", "Hang Up to Cancel")?;
+            }
+            Row::One => {
+                if self.ringback {
+                    write!(storage, "{: ^20}", "Ringing")?;
+                } else {
+                    write!(storage, "{: ^20}", "Calling")?;
+                }
+            }
+            Row::Two => {
+                // TODO - alignment doesn't seem to work with format_args here?
+                write!(storage, "{: ^20}", self.remote)?;
+            }
+            Row::Three => {
+                write!(
+                    storage,
+                    "{: ^20}",
+                    self.time_format.display(self.system_time)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::debug;
+
+    fn format_data<T: RowFormatter>(data: &T) {
+        let mut storage = RowStorage::new();
+        debug!("**********************");
+        for row in Row::enumerate() {
+            data.format_row(*row, &mut storage).unwrap();
+            debug!("*{:20}*", storage.as_str());
+            assert!(storage.len() <= 21);
+        }
+        debug!("**********************");
+    }
+
+    #[test]
+    fn default_formatter() {
+        let data = CallingStateData::default();
+        format_data(&data);
+    }
+
+    #[test]
+    fn ringback_formatter() {
+        let data = CallingStateData {
+            system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
+            remote: PhoneNumber::new(222, 333, 4444),
+            ringback: true,
+        };
+        format_data(&data);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::display::{Row, RowFormatter, RowStorage};
+use crate::keypad::EventBuffer;
+use crate::rtc::{DateTime, DateTimeFormat};
+use core::fmt::{self, Write};
+
+/// Max digits shown, the most recent digits are kept when the buffer is
+/// longer than a row
+const MAX_SHOWN_DIGITS: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DialingStateData {
+    pub system_time: DateTime,
+    pub time_format: DateTimeFormat,
+    pub buffer: EventBuffer,
+}
+
+impl Default for DialingStateData {
+    fn default() -> Self {
+        DialingStateData {
+            system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
+            buffer: EventBuffer::new(),
+        }
+    }
+}
+
+impl RowFormatter for DialingStateData {
+    fn format_row(&self, row: Row, storage: &mut RowStorage) -> Result<(), fmt::Error> {
+        storage.clear();
+
+        match row {
+            Row::Zero => {
+                write!(storage, "{: ^20}", "Hold '#' to Dial")?;
+            }
+            Row::One => {
+                write!(storage, "{: ^20}", "Dialing")?;
+            }
+            Row::Two => {
+                let digits = self.buffer.as_str();
+                let start = digits.len().saturating_sub(MAX_SHOWN_DIGITS);
+                write!(storage, "{: ^20}", &digits[start..])?;
+            }
+            Row::Three => {
+                write!(
+                    storage,
+                    "{: ^20}",
+                    self.time_format.display(self.system_time)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::{EventBufferMode, KeypadEvent};
+    use log::debug;
+
+    fn format_data<T: RowFormatter>(data: &T) {
+        let mut storage = RowStorage::new();
+        debug!("**********************");
+        for row in Row::enumerate() {
+            data.format_row(*row, &mut storage).unwrap();
+            debug!("*{:20}*", storage.as_str());
+            assert!(storage.len() <= 21);
+        }
+        debug!("**********************");
+    }
+
+    #[test]
+    fn default_formatter() {
+        let data = DialingStateData::default();
+        format_data(&data);
+    }
+
+    #[test]
+    fn long_buffer_formatter() {
+        let mut data = DialingStateData::default();
+        for c in "0123456789012345678901234567890123456789".chars() {
+            data.buffer
+                .push(EventBufferMode::WaitForUserDial, KeypadEvent::KeyPress(c));
+        }
+        format_data(&data);
+
+        let mut storage = RowStorage::new();
+        data.format_row(Row::Two, &mut storage).unwrap();
+        assert_eq!(storage.as_str(), "01234567890123456789");
+    }
+}
@@ -1,5 +1,6 @@
 use crate::display::{Row, RowFormatter, RowStorage};
-use crate::rtc::DateTime;
+use crate::rtc::{DateTime, DateTimeFormat};
+use crate::sip::registration::Status as RegistrationStatus;
 use core::fmt::{self, Write};
 
 #[derive(Debug, Clone, PartialEq)]
@@ -8,6 +9,9 @@ pub struct IdleStateData {
     pub missed_calls: usize,
 
     pub system_time: DateTime,
+    pub time_format: DateTimeFormat,
+
+    pub registration: RegistrationStatus,
 
     // TODO row storage or heapless::String?
     // row storage is fixed capacity, String could be any and truncated
@@ -19,6 +23,8 @@ impl Default for IdleStateData {
         IdleStateData {
             missed_calls: 0,
             system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
+            registration: RegistrationStatus::default(),
             message: None,
         }
     }
@@ -32,6 +38,8 @@ impl RowFormatter for IdleStateData {
             Row::Zero => {
                 if self.missed_calls != 0 {
                     write!(storage, "{: ^20}", "'*' Next | Clear '#'")?;
+                } else if !self.registration.is_registered() {
+                    write!(storage, "{: ^20}", self.registration)?;
                 } else {
                     write!(storage, "{: ^20}", "")?;
                 }
@@ -54,7 +62,11 @@ impl RowFormatter for IdleStateData {
                 }
             }
             Row::Three => {
-                write!(storage, "{: ^20}", self.system_time)?;
+                write!(
+                    storage,
+                    "{: ^20}",
+                    self.time_format.display(self.system_time)
+                )?;
             }
         }
 
@@ -65,6 +77,7 @@ impl RowFormatter for IdleStateData {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rtc::format::{DateStyle, HourStyle};
     use log::debug;
 
     fn format_data<T: RowFormatter>(data: &T) {
@@ -89,6 +102,8 @@ mod tests {
         let data = IdleStateData {
             missed_calls: 2,
             system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
+            registration: RegistrationStatus::Registered,
             message: None,
         };
         format_data(&data);
@@ -99,8 +114,43 @@ mod tests {
         let data = IdleStateData {
             missed_calls: 0,
             system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
+            registration: RegistrationStatus::Registered,
             message: Some(RowStorage::from("A message")),
         };
         format_data(&data);
     }
+
+    #[test]
+    fn registration_formatter() {
+        let data = IdleStateData {
+            missed_calls: 0,
+            system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
+            registration: RegistrationStatus::Registering,
+            message: None,
+        };
+        format_data(&data);
+        let mut storage = RowStorage::new();
+        data.format_row(Row::Zero, &mut storage).unwrap();
+        assert_eq!(storage.as_str(), "    Registering     ");
+    }
+
+    #[test]
+    fn system_time_formatter() {
+        let mut data = IdleStateData {
+            system_time: DateTime::from_unix_time(1_569_784_215),
+            ..IdleStateData::default()
+        };
+        let mut storage = RowStorage::new();
+        data.format_row(Row::Three, &mut storage).unwrap();
+        assert_eq!(storage.as_str(), "Sun Sep 29 19:10:15 ");
+        data.time_format = DateTimeFormat {
+            date: DateStyle::Iso,
+            hours: HourStyle::H12,
+            seconds: false,
+        };
+        data.format_row(Row::Three, &mut storage).unwrap();
+        assert_eq!(storage.as_str(), " 2019-09-29 7:10 PM ");
+    }
 }
@@ -1,22 +1,31 @@
 use crate::display::{Row, RowFormatter, RowStorage};
 use crate::phone_number::PhoneNumber;
-use crate::rtc::DateTime;
+use crate::rtc::{DateTime, DateTimeFormat};
+use crate::rtp::jitter_buffer::Stats as MediaStats;
+use crate::rtp::quality::Quality;
 use crate::time::{DisplayableInstant, Duration};
 use core::fmt::{self, Write};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct InCallStateData {
     pub system_time: DateTime,
+    pub time_format: DateTimeFormat,
     pub remote: PhoneNumber,
     pub call_duration: Duration,
+    pub media: MediaStats,
+    /// Shown in place of the media counters once known
+    pub quality: Option<Quality>,
 }
 
 impl Default for InCallStateData {
     fn default() -> Self {
         InCallStateData {
             system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
             remote: PhoneNumber::default(),
             call_duration: Duration::default(),
+            media: MediaStats::default(),
+            quality: None,
         }
     }
 }
@@ -38,11 +47,47 @@ impl RowFormatter for InCallStateData {
                     format_args!("Duration {}", DisplayableInstant::from(self.call_duration))
                 )?;
             }
-            Row::Two => {
-                write!(storage, "{: ^20}", "")?;
-            }
+            Row::Two => match self.quality {
+                // MOS is in tenths, round-trip time in milliseconds
+                Some(Quality {
+                    mos,
+                    r_factor,
+                    rtt: Some(rtt),
+                    ..
+                }) => write!(
+                    storage,
+                    "{: ^20}",
+                    format_args!(
+                        "MOS:{}.{} R:{} RTT:{}",
+                        mos / 10,
+                        mos % 10,
+                        r_factor,
+                        rtt.as_millis().min(999)
+                    )
+                )?,
+                Some(Quality { mos, r_factor, .. }) => write!(
+                    storage,
+                    "{: ^20}",
+                    format_args!("MOS:{}.{} R:{}", mos / 10, mos % 10, r_factor)
+                )?,
+                // Lost, late and dropped frames
+                None => write!(
+                    storage,
+                    "{: ^20}",
+                    format_args!(
+                        "Lo:{} La:{} Dr:{}",
+                        capped(self.media.lost),
+                        capped(self.media.late),
+                        capped(self.media.discarded)
+                    )
+                )?,
+            },
             Row::Three => {
-                write!(storage, "{: ^20}", self.system_time)?;
+                write!(
+                    storage,
+                    "{: ^20}",
+                    self.time_format.display(self.system_time)
+                )?;
             }
         }
 
@@ -50,6 +95,11 @@ impl RowFormatter for InCallStateData {
     }
 }
 
+/// Keeps the media counters to 3 digits so the row fits
+fn capped(count: u32) -> u32 {
+    count.min(999)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,4 +121,43 @@ mod tests {
         let data = InCallStateData::default();
         format_data(&data);
     }
+
+    #[test]
+    fn media_stats_fit() {
+        let mut data = InCallStateData::default();
+        data.media.lost = 12;
+        data.media.late = 3;
+        let mut storage = RowStorage::new();
+        data.format_row(Row::Two, &mut storage).unwrap();
+        assert_eq!(storage.as_str().trim(), "Lo:12 La:3 Dr:0");
+
+        data.media.lost = u32::max_value();
+        data.media.late = 1000;
+        data.media.discarded = 999;
+        data.format_row(Row::Two, &mut storage).unwrap();
+        assert_eq!(storage.as_str(), "Lo:999 La:999 Dr:999");
+    }
+
+    #[test]
+    fn call_quality_fits() {
+        let mut data = InCallStateData::default();
+        data.quality = Some(Quality {
+            r_factor: 88,
+            mos: 43,
+            ..Quality::default()
+        });
+        let mut storage = RowStorage::new();
+        data.format_row(Row::Two, &mut storage).unwrap();
+        assert_eq!(storage.as_str().trim(), "MOS:4.3 R:88");
+
+        data.quality = Some(Quality {
+            r_factor: 93,
+            mos: 44,
+            rtt: Some(Duration::from_secs(2)),
+            ..Quality::default()
+        });
+        data.format_row(Row::Two, &mut storage).unwrap();
+        assert_eq!(storage.as_str(), "MOS:4.4 R:93 RTT:999");
+        format_data(&data);
+    }
 }
@@ -1,14 +1,25 @@
 mod call_pending_state_data;
+mod calling_state_data;
+mod dialing_state_data;
 mod idle_state_data;
 mod in_call_state_data;
+mod state_machine;
 
 pub use crate::phone_state::call_pending_state_data::CallPendingStateData;
+pub use crate::phone_state::calling_state_data::CallingStateData;
+pub use crate::phone_state::dialing_state_data::DialingStateData;
 pub use crate::phone_state::idle_state_data::IdleStateData;
 pub use crate::phone_state::in_call_state_data::InCallStateData;
+pub use crate::phone_state::state_machine::{
+    Error, HookState, Output, PhoneCommand, PhoneCommands, PhoneEvent, PhoneStateMachine,
+    RING_TIMEOUT,
+};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum PhoneState {
     Idle,
+    Dialing,
+    Calling,
     CallPending,
     InCall,
 }
@@ -16,6 +27,20 @@ pub enum PhoneState {
 #[derive(Debug, Clone, PartialEq)]
 pub enum PhoneStateData {
     Idle(IdleStateData),
+    Dialing(DialingStateData),
+    Calling(CallingStateData),
     CallPending(CallPendingStateData),
     InCall(InCallStateData),
 }
+
+impl PhoneStateData {
+    pub fn state(&self) -> PhoneState {
+        match self {
+            PhoneStateData::Idle(_) => PhoneState::Idle,
+            PhoneStateData::Dialing(_) => PhoneState::Dialing,
+            PhoneStateData::Calling(_) => PhoneState::Calling,
+            PhoneStateData::CallPending(_) => PhoneState::CallPending,
+            PhoneStateData::InCall(_) => PhoneState::InCall,
+        }
+    }
+}
@@ -0,0 +1,1050 @@
+//! Phone state machine
+//!
+//! Pure, hardware independent driver for the `PhoneState` transitions.
+//! Inputs are `PhoneEvent`s (keypad, hook switch, network and timer),
+//! outputs are the new `PhoneStateData` and a set of `PhoneCommand`s
+//! that the caller is responsible for carrying out.
+
+use crate::keypad::{EventBuffer, EventBufferMode, KeypadEvent};
+use crate::phone_number::PhoneNumber;
+use crate::phone_state::{
+    CallPendingStateData, CallingStateData, DialingStateData, IdleStateData, InCallStateData,
+    PhoneState, PhoneStateData,
+};
+use crate::rtc::{DateTime, DateTimeFormat};
+use crate::rtp::jitter_buffer::Stats as MediaStats;
+use crate::rtp::quality::Quality;
+use crate::sip::registration::Status as RegistrationStatus;
+use crate::time::{Duration, Instant};
+use crate::tones::Tone;
+use heapless::consts::U4;
+use heapless::Vec;
+use log::debug;
+
+/// How long an incoming call rings before it's considered missed
+pub const RING_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// The event isn't valid in the current state
+    InvalidTransition(PhoneState),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum HookState {
+    OnHook,
+    OffHook,
+}
+
+impl Default for HookState {
+    fn default() -> Self {
+        HookState::OnHook
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PhoneEvent {
+    /// Key released, and how long it was held down
+    Keypad(KeypadEvent, Duration),
+    Hook(HookState),
+    /// Remote party is calling us
+    IncomingCall(PhoneNumber),
+    /// Remote party is alerting for our outgoing call (SIP 180 Ringing)
+    RemoteRinging,
+    /// Remote party answered our outgoing call (SIP 200 OK)
+    RemoteAnswered,
+    /// Remote party hung up, cancelled or rejected the call
+    RemoteHangup,
+    /// Our outgoing call couldn't be placed, i.e. the INVITE wasn't sent
+    CallFailed,
+    /// Updated wall clock time for the display
+    SystemTime(DateTime),
+    /// How the display shows the time
+    TimeFormat(DateTimeFormat),
+    /// SIP registration status changed
+    Registration(RegistrationStatus),
+    /// Updated receive statistics of the call's media
+    MediaStats(MediaStats),
+    /// Updated quality estimate of the call, from RTCP
+    CallQuality(Quality),
+    /// Periodic timer tick
+    Tick,
+}
+
+/// Side effects requested by the state machine
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum PhoneCommand {
+    StartRinger,
+    StopRinger,
+    /// Accept the pending incoming call (SIP 200 OK)
+    AnswerCall,
+    /// Decline the pending incoming call (SIP 486 Busy Here)
+    DeclineCall,
+    /// Start an outgoing call (SIP INVITE)
+    PlaceCall(PhoneNumber),
+    /// Abandon the outgoing call before it's answered (SIP CANCEL)
+    CancelCall,
+    /// Terminate the active call (SIP BYE)
+    HangUp,
+    /// Send a DTMF digit to the remote party (RFC 4733), lasting as
+    /// long as the key was held
+    SendDtmf(char, Duration),
+    /// Play a call-progress tone in the handset, replacing any other
+    PlayTone(Tone),
+    StopTone,
+}
+
+pub type PhoneCommands = Vec<PhoneCommand, U4>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    /// The new state data, only present when it changed
+    pub state: Option<PhoneStateData>,
+    pub commands: PhoneCommands,
+}
+
+impl Output {
+    fn new() -> Self {
+        Output {
+            state: None,
+            commands: PhoneCommands::new(),
+        }
+    }
+
+    fn push(&mut self, cmd: PhoneCommand) {
+        // Never more than a few commands per transition
+        self.commands.push(cmd).expect("PhoneCommands full");
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhoneStateMachine {
+    data: PhoneStateData,
+    hook: HookState,
+    system_time: DateTime,
+    time_format: DateTimeFormat,
+    registration: RegistrationStatus,
+    missed_calls: usize,
+    remote: PhoneNumber,
+    ringback: bool,
+    media: MediaStats,
+    quality: Option<Quality>,
+    tone: Option<Tone>,
+    buffer: EventBuffer,
+    entered_at: Instant,
+}
+
+impl PhoneStateMachine {
+    pub fn new(time: Instant) -> Self {
+        PhoneStateMachine {
+            data: PhoneStateData::Idle(IdleStateData::default()),
+            hook: HookState::default(),
+            system_time: DateTime::default(),
+            time_format: DateTimeFormat::default(),
+            registration: RegistrationStatus::default(),
+            missed_calls: 0,
+            remote: PhoneNumber::default(),
+            ringback: false,
+            media: MediaStats::default(),
+            quality: None,
+            tone: None,
+            buffer: EventBuffer::new(),
+            entered_at: time,
+        }
+    }
+
+    pub fn state(&self) -> PhoneState {
+        self.data.state()
+    }
+
+    pub fn data(&self) -> &PhoneStateData {
+        &self.data
+    }
+
+    pub fn hook(&self) -> HookState {
+        self.hook
+    }
+
+    pub fn registration(&self) -> RegistrationStatus {
+        self.registration
+    }
+
+    pub fn missed_calls(&self) -> usize {
+        self.missed_calls
+    }
+
+    /// Last tone started, which may have since ended on its own
+    pub fn tone(&self) -> Option<Tone> {
+        self.tone
+    }
+
+    /// Time elapsed since the current state was entered
+    pub fn time_in_state(&self, time: Instant) -> Duration {
+        time.checked_sub(self.entered_at).unwrap_or_default()
+    }
+
+    pub fn handle(&mut self, event: PhoneEvent, time: Instant) -> Result<Output, Error> {
+        let mut out = Output::new();
+
+        if let PhoneEvent::Hook(hook) = event {
+            self.hook = hook;
+        }
+
+        let next = match &event {
+            PhoneEvent::SystemTime(dt) => {
+                self.system_time = *dt;
+                None
+            }
+            PhoneEvent::TimeFormat(format) => {
+                self.time_format = *format;
+                None
+            }
+            PhoneEvent::Registration(status) => {
+                self.registration = *status;
+                None
+            }
+            PhoneEvent::MediaStats(stats) => {
+                self.media = *stats;
+                None
+            }
+            PhoneEvent::CallQuality(quality) => {
+                self.quality = Some(*quality);
+                None
+            }
+            _ => match self.state() {
+                PhoneState::Idle => self.handle_idle(&event, &mut out)?,
+                PhoneState::Dialing => self.handle_dialing(&event, &mut out)?,
+                PhoneState::Calling => self.handle_calling(&event, &mut out)?,
+                PhoneState::CallPending => self.handle_call_pending(&event, time, &mut out)?,
+                PhoneState::InCall => self.handle_in_call(&event, &mut out)?,
+            },
+        };
+
+        if let Some(state) = next {
+            if state != self.state() {
+                self.entered_at = time;
+            }
+            match state {
+                PhoneState::Idle => {
+                    self.remote = PhoneNumber::default();
+                    self.ringback = false;
+                    self.media = MediaStats::default();
+                    self.quality = None;
+                    self.buffer.clear();
+                }
+                PhoneState::Dialing => self.buffer.clear(),
+                _ => (),
+            }
+            self.data = self.state_data(state, time);
+            out.state = Some(self.data.clone());
+        } else {
+            let data = self.state_data(self.state(), time);
+            if data != self.data {
+                self.data = data;
+                out.state = Some(self.data.clone());
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn handle_idle(
+        &mut self,
+        event: &PhoneEvent,
+        out: &mut Output,
+    ) -> Result<Option<PhoneState>, Error> {
+        match event {
+            PhoneEvent::Keypad(KeypadEvent::KeyPress('#'), _) => {
+                self.missed_calls = 0;
+                Ok(None)
+            }
+            PhoneEvent::Hook(HookState::OffHook) => {
+                self.play_tone(Tone::Dial, out);
+                Ok(Some(PhoneState::Dialing))
+            }
+            PhoneEvent::Hook(HookState::OnHook) => {
+                // Busy after the remote rejected our call
+                self.stop_tone(out);
+                Ok(None)
+            }
+            PhoneEvent::Keypad(_, _) | PhoneEvent::Tick => Ok(None),
+            PhoneEvent::IncomingCall(remote) => {
+                if self.hook == HookState::OffHook {
+                    // Handset is in use, nothing to ring
+                    out.push(PhoneCommand::DeclineCall);
+                    Ok(None)
+                } else {
+                    self.remote = *remote;
+                    out.push(PhoneCommand::StartRinger);
+                    Ok(Some(PhoneState::CallPending))
+                }
+            }
+            _ => Err(Error::InvalidTransition(PhoneState::Idle)),
+        }
+    }
+
+    fn handle_dialing(
+        &mut self,
+        event: &PhoneEvent,
+        out: &mut Output,
+    ) -> Result<Option<PhoneState>, Error> {
+        match event {
+            PhoneEvent::Keypad(key, _) => {
+                // Dial tone until the first digit
+                self.stop_tone(out);
+                if self.buffer.push(EventBufferMode::WaitForUserDial, *key) {
+                    match PhoneNumber::from_utf8(self.buffer.as_str()) {
+                        Ok(remote) => {
+                            self.remote = remote;
+                            out.push(PhoneCommand::PlaceCall(remote));
+                            Ok(Some(PhoneState::Calling))
+                        }
+                        Err(_) => {
+                            debug!("Invalid number '{}'", self.buffer.as_str());
+                            self.buffer.clear();
+                            self.play_tone(Tone::Reorder, out);
+                            Ok(None)
+                        }
+                    }
+                } else {
+                    Ok(None)
+                }
+            }
+            PhoneEvent::Hook(HookState::OnHook) => {
+                self.stop_tone(out);
+                Ok(Some(PhoneState::Idle))
+            }
+            PhoneEvent::IncomingCall(_) => {
+                // Handset is in use, nothing to ring
+                out.push(PhoneCommand::DeclineCall);
+                Ok(None)
+            }
+            PhoneEvent::Hook(_) | PhoneEvent::Tick => Ok(None),
+            _ => Err(Error::InvalidTransition(PhoneState::Dialing)),
+        }
+    }
+
+    fn handle_calling(
+        &mut self,
+        event: &PhoneEvent,
+        out: &mut Output,
+    ) -> Result<Option<PhoneState>, Error> {
+        match event {
+            PhoneEvent::Hook(HookState::OnHook) => {
+                out.push(PhoneCommand::CancelCall);
+                self.stop_tone(out);
+                Ok(Some(PhoneState::Idle))
+            }
+            PhoneEvent::RemoteRinging => {
+                if !self.ringback {
+                    self.play_tone(Tone::Ringback, out);
+                }
+                self.ringback = true;
+                Ok(None)
+            }
+            PhoneEvent::RemoteAnswered => {
+                self.stop_tone(out);
+                Ok(Some(PhoneState::InCall))
+            }
+            PhoneEvent::RemoteHangup => {
+                // Until the handset is hung up
+                self.play_tone(Tone::Busy, out);
+                Ok(Some(PhoneState::Idle))
+            }
+            PhoneEvent::CallFailed => {
+                self.play_tone(Tone::Reorder, out);
+                Ok(Some(PhoneState::Idle))
+            }
+            PhoneEvent::IncomingCall(_) => {
+                // No call waiting support
+                out.push(PhoneCommand::DeclineCall);
+                Ok(None)
+            }
+            // Keys, other hook changes and ticks
+            _ => Ok(None),
+        }
+    }
+
+    fn handle_call_pending(
+        &mut self,
+        event: &PhoneEvent,
+        time: Instant,
+        out: &mut Output,
+    ) -> Result<Option<PhoneState>, Error> {
+        match event {
+            PhoneEvent::Keypad(KeypadEvent::KeyPress('#'), _)
+            | PhoneEvent::Hook(HookState::OffHook) => {
+                out.push(PhoneCommand::StopRinger);
+                out.push(PhoneCommand::AnswerCall);
+                Ok(Some(PhoneState::InCall))
+            }
+            PhoneEvent::Keypad(KeypadEvent::KeyPress('*'), _) => {
+                out.push(PhoneCommand::StopRinger);
+                out.push(PhoneCommand::DeclineCall);
+                Ok(Some(PhoneState::Idle))
+            }
+            PhoneEvent::Keypad(_, _) | PhoneEvent::Hook(_) => Ok(None),
+            PhoneEvent::IncomingCall(_) => {
+                // No call waiting support
+                out.push(PhoneCommand::DeclineCall);
+                Ok(None)
+            }
+            PhoneEvent::RemoteHangup => {
+                self.missed_calls = self.missed_calls.saturating_add(1);
+                out.push(PhoneCommand::StopRinger);
+                Ok(Some(PhoneState::Idle))
+            }
+            PhoneEvent::Tick => {
+                if self.time_in_state(time) >= RING_TIMEOUT {
+                    self.missed_calls = self.missed_calls.saturating_add(1);
+                    out.push(PhoneCommand::StopRinger);
+                    out.push(PhoneCommand::DeclineCall);
+                    Ok(Some(PhoneState::Idle))
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => Err(Error::InvalidTransition(PhoneState::CallPending)),
+        }
+    }
+
+    fn handle_in_call(
+        &mut self,
+        event: &PhoneEvent,
+        out: &mut Output,
+    ) -> Result<Option<PhoneState>, Error> {
+        match event {
+            PhoneEvent::Hook(HookState::OnHook) => {
+                out.push(PhoneCommand::HangUp);
+                self.stop_tone(out);
+                Ok(Some(PhoneState::Idle))
+            }
+            PhoneEvent::RemoteHangup => {
+                self.stop_tone(out);
+                Ok(Some(PhoneState::Idle))
+            }
+            PhoneEvent::IncomingCall(_) => {
+                // No call waiting support, but let the user know
+                out.push(PhoneCommand::DeclineCall);
+                self.play_tone(Tone::CallWaiting, out);
+                Ok(None)
+            }
+            PhoneEvent::Keypad(key, held) => {
+                if self.buffer.push(EventBufferMode::Dtmf, *key) {
+                    out.push(PhoneCommand::SendDtmf(key.as_char(), *held));
+                }
+                Ok(None)
+            }
+            PhoneEvent::Hook(_) | PhoneEvent::Tick => Ok(None),
+            _ => Err(Error::InvalidTransition(PhoneState::InCall)),
+        }
+    }
+
+    fn play_tone(&mut self, tone: Tone, out: &mut Output) {
+        self.tone = Some(tone);
+        out.push(PhoneCommand::PlayTone(tone));
+    }
+
+    fn stop_tone(&mut self, out: &mut Output) {
+        if self.tone.take().is_some() {
+            out.push(PhoneCommand::StopTone);
+        }
+    }
+
+    fn state_data(&self, state: PhoneState, time: Instant) -> PhoneStateData {
+        match state {
+            PhoneState::Idle => {
+                let message = match &self.data {
+                    PhoneStateData::Idle(d) => d.message.clone(),
+                    _ => None,
+                };
+                PhoneStateData::Idle(IdleStateData {
+                    missed_calls: self.missed_calls,
+                    system_time: self.system_time,
+                    time_format: self.time_format,
+                    registration: self.registration,
+                    message,
+                })
+            }
+            PhoneState::Dialing => PhoneStateData::Dialing(DialingStateData {
+                system_time: self.system_time,
+                time_format: self.time_format,
+                buffer: self.buffer.clone(),
+            }),
+            PhoneState::Calling => PhoneStateData::Calling(CallingStateData {
+                system_time: self.system_time,
+                time_format: self.time_format,
+                remote: self.remote,
+                ringback: self.ringback,
+            }),
+            PhoneState::CallPending => PhoneStateData::CallPending(CallPendingStateData {
+                system_time: self.system_time,
+                time_format: self.time_format,
+                remote: self.remote,
+            }),
+            PhoneState::InCall => PhoneStateData::InCall(InCallStateData {
+                system_time: self.system_time,
+                time_format: self.time_format,
+                remote: self.remote,
+                call_duration: self.time_in_state(time),
+                media: self.media,
+                quality: self.quality,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::LONGPRESS_DURATION;
+    use crate::rtc::format::{DateStyle, HourStyle};
+
+    fn remote() -> PhoneNumber {
+        PhoneNumber::new(222, 333, 4444)
+    }
+
+    const HELD: Duration = Duration::from_millis(150);
+
+    fn ms(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn starts_idle() {
+        let sm = PhoneStateMachine::new(ms(0));
+        assert_eq!(sm.state(), PhoneState::Idle);
+        assert_eq!(sm.hook(), HookState::OnHook);
+        assert_eq!(sm.data(), &PhoneStateData::Idle(IdleStateData::default()));
+    }
+
+    #[test]
+    fn answer_and_hang_up() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+
+        let out = sm
+            .handle(PhoneEvent::IncomingCall(remote()), ms(10))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::CallPending);
+        assert_eq!(&out.commands[..], &[PhoneCommand::StartRinger]);
+        match out.state {
+            Some(PhoneStateData::CallPending(d)) => assert_eq!(d.remote, remote()),
+            _ => panic!("Expected CallPending state data"),
+        }
+
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OffHook), ms(20))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::InCall);
+        assert_eq!(
+            &out.commands[..],
+            &[PhoneCommand::StopRinger, PhoneCommand::AnswerCall]
+        );
+
+        let out = sm.handle(PhoneEvent::Tick, ms(2020)).unwrap();
+        match out.state {
+            Some(PhoneStateData::InCall(d)) => {
+                assert_eq!(d.remote, remote());
+                assert_eq!(d.call_duration, Duration::from_secs(2));
+            }
+            _ => panic!("Expected InCall state data"),
+        }
+
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OnHook), ms(3000))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::Idle);
+        assert_eq!(&out.commands[..], &[PhoneCommand::HangUp]);
+        assert_eq!(sm.missed_calls(), 0);
+    }
+
+    #[test]
+    fn answer_with_keypad() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(0))
+            .unwrap();
+        let out = sm
+            .handle(PhoneEvent::Keypad(KeypadEvent::KeyPress('#'), HELD), ms(1))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::InCall);
+        assert_eq!(
+            &out.commands[..],
+            &[PhoneCommand::StopRinger, PhoneCommand::AnswerCall]
+        );
+
+        let out = sm.handle(PhoneEvent::RemoteHangup, ms(2)).unwrap();
+        assert_eq!(sm.state(), PhoneState::Idle);
+        assert_eq!(out.commands.len(), 0);
+    }
+
+    #[test]
+    fn decline() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(0))
+            .unwrap();
+        let out = sm
+            .handle(PhoneEvent::Keypad(KeypadEvent::KeyPress('*'), HELD), ms(1))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::Idle);
+        assert_eq!(
+            &out.commands[..],
+            &[PhoneCommand::StopRinger, PhoneCommand::DeclineCall]
+        );
+        assert_eq!(sm.missed_calls(), 0);
+    }
+
+    #[test]
+    fn missed_calls() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+
+        // Remote cancels
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(0))
+            .unwrap();
+        let out = sm.handle(PhoneEvent::RemoteHangup, ms(1)).unwrap();
+        assert_eq!(&out.commands[..], &[PhoneCommand::StopRinger]);
+        assert_eq!(sm.missed_calls(), 1);
+
+        // Ring timeout
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(100))
+            .unwrap();
+        let t = ms(100) + RING_TIMEOUT - Duration::from_millis(1);
+        let out = sm.handle(PhoneEvent::Tick, t).unwrap();
+        assert_eq!(sm.state(), PhoneState::CallPending);
+        assert_eq!(out.commands.len(), 0);
+        let out = sm.handle(PhoneEvent::Tick, ms(100) + RING_TIMEOUT).unwrap();
+        assert_eq!(sm.state(), PhoneState::Idle);
+        assert_eq!(
+            &out.commands[..],
+            &[PhoneCommand::StopRinger, PhoneCommand::DeclineCall]
+        );
+        assert_eq!(sm.missed_calls(), 2);
+        match sm.data() {
+            PhoneStateData::Idle(d) => assert_eq!(d.missed_calls, 2),
+            _ => panic!("Expected Idle state data"),
+        }
+
+        // '#' clears
+        let out = sm
+            .handle(
+                PhoneEvent::Keypad(KeypadEvent::KeyPress('#'), HELD),
+                ms(40_000),
+            )
+            .unwrap();
+        assert_eq!(sm.missed_calls(), 0);
+        assert!(out.state.is_some());
+    }
+
+    #[test]
+    fn busy_when_off_hook() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(0))
+            .unwrap();
+        let out = sm
+            .handle(PhoneEvent::IncomingCall(remote()), ms(1))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::Dialing);
+        assert_eq!(&out.commands[..], &[PhoneCommand::DeclineCall]);
+    }
+
+    fn dial(sm: &mut PhoneStateMachine, digits: &str) {
+        for c in digits.chars() {
+            let tone = sm.tone();
+            let out = sm
+                .handle(PhoneEvent::Keypad(KeypadEvent::KeyPress(c), HELD), ms(1))
+                .unwrap();
+            assert_eq!(sm.state(), PhoneState::Dialing);
+            if tone.is_some() {
+                assert_eq!(&out.commands[..], &[PhoneCommand::StopTone]);
+            } else {
+                assert_eq!(out.commands.len(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn dialing() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OffHook), ms(0))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::Dialing);
+        assert!(out.state.is_some());
+
+        dial(&mut sm, "222333");
+        match sm.data() {
+            PhoneStateData::Dialing(d) => assert_eq!(d.buffer.as_str(), "222333"),
+            _ => panic!("Expected Dialing state data"),
+        }
+
+        // Hanging up discards the digits
+        sm.handle(PhoneEvent::Hook(HookState::OnHook), ms(2))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::Idle);
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(3))
+            .unwrap();
+        match sm.data() {
+            PhoneStateData::Dialing(d) => assert_eq!(d.buffer.as_str(), ""),
+            _ => panic!("Expected Dialing state data"),
+        }
+    }
+
+    #[test]
+    fn invalid_number_stays_dialing() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(0))
+            .unwrap();
+        dial(&mut sm, "12");
+        let out = sm
+            .handle(
+                PhoneEvent::Keypad(KeypadEvent::LongPress('#'), LONGPRESS_DURATION),
+                ms(2),
+            )
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::Dialing);
+        assert_eq!(&out.commands[..], &[PhoneCommand::PlayTone(Tone::Reorder)]);
+        match sm.data() {
+            PhoneStateData::Dialing(d) => assert_eq!(d.buffer.as_str(), ""),
+            _ => panic!("Expected Dialing state data"),
+        }
+    }
+
+    #[test]
+    fn outgoing_call() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(0))
+            .unwrap();
+        dial(&mut sm, "2223334444");
+
+        let out = sm
+            .handle(
+                PhoneEvent::Keypad(KeypadEvent::LongPress('#'), LONGPRESS_DURATION),
+                ms(2),
+            )
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::Calling);
+        assert_eq!(&out.commands[..], &[PhoneCommand::PlaceCall(remote())]);
+        match out.state {
+            Some(PhoneStateData::Calling(d)) => {
+                assert_eq!(d.remote, remote());
+                assert_eq!(d.ringback, false);
+            }
+            _ => panic!("Expected Calling state data"),
+        }
+
+        let out = sm.handle(PhoneEvent::RemoteRinging, ms(3)).unwrap();
+        assert_eq!(sm.state(), PhoneState::Calling);
+        assert_eq!(&out.commands[..], &[PhoneCommand::PlayTone(Tone::Ringback)]);
+        match out.state {
+            Some(PhoneStateData::Calling(d)) => assert_eq!(d.ringback, true),
+            _ => panic!("Expected Calling state data"),
+        }
+
+        let out = sm.handle(PhoneEvent::RemoteAnswered, ms(1000)).unwrap();
+        assert_eq!(sm.state(), PhoneState::InCall);
+        assert_eq!(&out.commands[..], &[PhoneCommand::StopTone]);
+
+        let out = sm.handle(PhoneEvent::Tick, ms(2000)).unwrap();
+        match out.state {
+            Some(PhoneStateData::InCall(d)) => {
+                assert_eq!(d.remote, remote());
+                assert_eq!(d.call_duration, Duration::from_secs(1));
+            }
+            _ => panic!("Expected InCall state data"),
+        }
+    }
+
+    #[test]
+    fn cancel_outgoing_call() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(0))
+            .unwrap();
+        dial(&mut sm, "5556667777");
+        sm.handle(
+            PhoneEvent::Keypad(KeypadEvent::LongPress('#'), LONGPRESS_DURATION),
+            ms(2),
+        )
+        .unwrap();
+        assert_eq!(sm.state(), PhoneState::Calling);
+
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OnHook), ms(3))
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::Idle);
+        assert_eq!(&out.commands[..], &[PhoneCommand::CancelCall]);
+    }
+
+    #[test]
+    fn call_progress_tones() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OffHook), ms(0))
+            .unwrap();
+        assert_eq!(&out.commands[..], &[PhoneCommand::PlayTone(Tone::Dial)]);
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OnHook), ms(1))
+            .unwrap();
+        assert_eq!(&out.commands[..], &[PhoneCommand::StopTone]);
+        assert_eq!(sm.tone(), None);
+
+        // Rejected outgoing call is busy until hung up
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(2))
+            .unwrap();
+        dial(&mut sm, "2223334444");
+        sm.handle(
+            PhoneEvent::Keypad(KeypadEvent::LongPress('#'), LONGPRESS_DURATION),
+            ms(3),
+        )
+        .unwrap();
+        sm.handle(PhoneEvent::RemoteRinging, ms(4)).unwrap();
+        let out = sm.handle(PhoneEvent::RemoteRinging, ms(5)).unwrap();
+        assert_eq!(out.commands.len(), 0);
+        let out = sm.handle(PhoneEvent::RemoteHangup, ms(6)).unwrap();
+        assert_eq!(sm.state(), PhoneState::Idle);
+        assert_eq!(&out.commands[..], &[PhoneCommand::PlayTone(Tone::Busy)]);
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OnHook), ms(7))
+            .unwrap();
+        assert_eq!(&out.commands[..], &[PhoneCommand::StopTone]);
+
+        // Ringback stops when the call is abandoned
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(8))
+            .unwrap();
+        dial(&mut sm, "2223334444");
+        sm.handle(
+            PhoneEvent::Keypad(KeypadEvent::LongPress('#'), LONGPRESS_DURATION),
+            ms(9),
+        )
+        .unwrap();
+        sm.handle(PhoneEvent::RemoteRinging, ms(10)).unwrap();
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OnHook), ms(11))
+            .unwrap();
+        assert_eq!(
+            &out.commands[..],
+            &[PhoneCommand::CancelCall, PhoneCommand::StopTone]
+        );
+    }
+
+    #[test]
+    fn call_not_placed() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(0))
+            .unwrap();
+        dial(&mut sm, "5556667777");
+        sm.handle(
+            PhoneEvent::Keypad(KeypadEvent::LongPress('#'), LONGPRESS_DURATION),
+            ms(2),
+        )
+        .unwrap();
+
+        let out = sm.handle(PhoneEvent::CallFailed, ms(3)).unwrap();
+        assert_eq!(sm.state(), PhoneState::Idle);
+        assert_eq!(&out.commands[..], &[PhoneCommand::PlayTone(Tone::Reorder)]);
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OnHook), ms(4))
+            .unwrap();
+        assert_eq!(&out.commands[..], &[PhoneCommand::StopTone]);
+    }
+
+    #[test]
+    fn no_call_waiting() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(0))
+            .unwrap();
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(1))
+            .unwrap();
+        let out = sm
+            .handle(
+                PhoneEvent::IncomingCall(PhoneNumber::new(555, 666, 7777)),
+                ms(2),
+            )
+            .unwrap();
+        assert_eq!(sm.state(), PhoneState::InCall);
+        assert_eq!(
+            &out.commands[..],
+            &[
+                PhoneCommand::DeclineCall,
+                PhoneCommand::PlayTone(Tone::CallWaiting)
+            ]
+        );
+        match sm.data() {
+            PhoneStateData::InCall(d) => assert_eq!(d.remote, remote()),
+            _ => panic!("Expected InCall state data"),
+        }
+    }
+
+    #[test]
+    fn dtmf_in_call() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(0))
+            .unwrap();
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(1))
+            .unwrap();
+
+        for (c, held) in "12#*".chars().zip([80, 150, 400, 90].iter()) {
+            let held = Duration::from_millis(*held);
+            let out = sm
+                .handle(PhoneEvent::Keypad(KeypadEvent::KeyPress(c), held), ms(2))
+                .unwrap();
+            assert_eq!(&out.commands[..], &[PhoneCommand::SendDtmf(c, held)]);
+        }
+        let out = sm
+            .handle(
+                PhoneEvent::Keypad(KeypadEvent::LongPress('5'), LONGPRESS_DURATION),
+                ms(3),
+            )
+            .unwrap();
+        assert_eq!(out.commands.len(), 0);
+        assert_eq!(sm.state(), PhoneState::InCall);
+    }
+
+    #[test]
+    fn media_stats_in_call() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(0))
+            .unwrap();
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(1))
+            .unwrap();
+
+        let stats = MediaStats {
+            lost: 4,
+            ..MediaStats::default()
+        };
+        let out = sm.handle(PhoneEvent::MediaStats(stats), ms(2)).unwrap();
+        match out.state {
+            Some(PhoneStateData::InCall(d)) => assert_eq!(d.media, stats),
+            _ => panic!("Expected InCall state data"),
+        }
+        let quality = Quality {
+            mos: 41,
+            ..Quality::default()
+        };
+        let out = sm.handle(PhoneEvent::CallQuality(quality), ms(2)).unwrap();
+        match out.state {
+            Some(PhoneStateData::InCall(d)) => assert_eq!(d.quality, Some(quality)),
+            _ => panic!("Expected InCall state data"),
+        }
+
+        // Cleared for the next call
+        sm.handle(PhoneEvent::Hook(HookState::OnHook), ms(3))
+            .unwrap();
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(4))
+            .unwrap();
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(5))
+            .unwrap();
+        match sm.data() {
+            PhoneStateData::InCall(d) => {
+                assert_eq!(d.media, MediaStats::default());
+                assert_eq!(d.quality, None);
+            }
+            _ => panic!("Expected InCall state data"),
+        }
+    }
+
+    #[test]
+    fn invalid_transitions() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        assert_eq!(
+            sm.handle(PhoneEvent::RemoteHangup, ms(0)),
+            Err(Error::InvalidTransition(PhoneState::Idle))
+        );
+        assert_eq!(sm.state(), PhoneState::Idle);
+
+        // Outgoing call progress doesn't apply to an incoming call
+        sm.handle(PhoneEvent::IncomingCall(remote()), ms(1))
+            .unwrap();
+        assert_eq!(
+            sm.handle(PhoneEvent::RemoteAnswered, ms(2)),
+            Err(Error::InvalidTransition(PhoneState::CallPending))
+        );
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(3))
+            .unwrap();
+        assert_eq!(
+            sm.handle(PhoneEvent::RemoteRinging, ms(4)),
+            Err(Error::InvalidTransition(PhoneState::InCall))
+        );
+        assert_eq!(sm.state(), PhoneState::InCall);
+    }
+
+    #[test]
+    fn system_time_updates() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        let dt = DateTime::from(ds323x::DateTime {
+            year: 2019,
+            month: 1,
+            day: 1,
+            weekday: 3,
+            hour: ds323x::Hours::H24(1),
+            minute: 1,
+            second: 1,
+        });
+        let out = sm.handle(PhoneEvent::SystemTime(dt), ms(0)).unwrap();
+        match out.state {
+            Some(PhoneStateData::Idle(d)) => assert_eq!(d.system_time, dt),
+            _ => panic!("Expected Idle state data"),
+        }
+        let out = sm.handle(PhoneEvent::Tick, ms(1)).unwrap();
+        assert_eq!(out.state, None);
+    }
+
+    #[test]
+    fn time_format_updates() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        let format = DateTimeFormat {
+            date: DateStyle::None,
+            hours: HourStyle::H12,
+            seconds: false,
+        };
+        let out = sm.handle(PhoneEvent::TimeFormat(format), ms(0)).unwrap();
+        match out.state {
+            Some(PhoneStateData::Idle(d)) => assert_eq!(d.time_format, format),
+            _ => panic!("Expected Idle state data"),
+        }
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(1))
+            .unwrap();
+        match sm.data() {
+            PhoneStateData::Dialing(d) => assert_eq!(d.time_format, format),
+            _ => panic!("Expected Dialing state data"),
+        }
+        let out = sm.handle(PhoneEvent::TimeFormat(format), ms(2)).unwrap();
+        assert_eq!(out.state, None);
+    }
+
+    #[test]
+    fn registration_status_shown_when_idle() {
+        let mut sm = PhoneStateMachine::new(ms(0));
+        let out = sm
+            .handle(
+                PhoneEvent::Registration(RegistrationStatus::Registered),
+                ms(0),
+            )
+            .unwrap();
+        assert_eq!(sm.registration(), RegistrationStatus::Registered);
+        match out.state {
+            Some(PhoneStateData::Idle(d)) => {
+                assert_eq!(d.registration, RegistrationStatus::Registered)
+            }
+            _ => panic!("Expected Idle state data"),
+        }
+
+        // Tracked while in other states
+        sm.handle(PhoneEvent::Hook(HookState::OffHook), ms(10))
+            .unwrap();
+        let out = sm
+            .handle(
+                PhoneEvent::Registration(RegistrationStatus::Registering),
+                ms(20),
+            )
+            .unwrap();
+        assert_eq!(out.state, None);
+        let out = sm
+            .handle(PhoneEvent::Hook(HookState::OnHook), ms(30))
+            .unwrap();
+        match out.state {
+            Some(PhoneStateData::Idle(d)) => {
+                assert_eq!(d.registration, RegistrationStatus::Registering)
+            }
+            _ => panic!("Expected Idle state data"),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Random number sources
+//!
+//! Protocol identifiers (SIP branch/tag/Call-ID, RTP SSRC, DNS IDs, etc)
+//! are generated from whatever implements `RandomSource`, on the
+//! hardware that's the RNG peripheral.
+
+pub trait RandomSource {
+    fn next_u32(&mut self) -> u32;
+}
+
+/// Marsaglia xorshift, not cryptographically secure
+#[derive(Debug, Clone)]
+pub struct XorShift32 {
+    state: u32,
+}
+
+impl XorShift32 {
+    pub fn new(seed: u32) -> Self {
+        XorShift32 {
+            // Zero is a fixed point
+            state: if seed == 0 { 0x9E37_79B9 } else { seed },
+        }
+    }
+}
+
+impl RandomSource for XorShift32 {
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_seed() {
+        let mut rng = XorShift32::new(0);
+        assert_ne!(rng.next_u32(), 0);
+    }
+
+    #[test]
+    fn sequence() {
+        let mut a = XorShift32::new(1234);
+        let mut b = XorShift32::new(1234);
+        for _ in 0..100 {
+            let v = a.next_u32();
+            assert_eq!(v, b.next_u32());
+            assert_ne!(v, 0);
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use crate::rtc::DateTimeFormat;
+use crate::time::Duration;
+use core::fmt;
+
+/// A DateTime wrapper over ds323x::DateTime
+///
+/// Unix time and arithmetic take it as UTC, from 1970, and give 24-hour
+/// time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime(ds323x::DateTime);
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// 9999-12-31 23:59:59
+const MAX_UNIX_TIME: u64 = 253_402_300_799;
+
+impl DateTime {
+    /// UTC date and 24-hour time of the seconds since 1970-01-01
+    pub fn from_unix_time(secs: u64) -> Self {
+        let days = secs / SECONDS_PER_DAY;
+        let secs = secs % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime::from(ds323x::DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            weekday: (weekday_from_days(days) + 1) as u8,
+            hour: ds323x::Hours::H24((secs / 3600) as u8),
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        })
+    }
+
+    /// Seconds since 1970-01-01, taking the date and time as UTC. None
+    /// if it's not valid.
+    pub fn to_unix_time(&self) -> Option<u64> {
+        if !self.is_valid() {
+            return None;
+        }
+        let dt = &self.0;
+        let hour = hour24(dt.hour)?;
+
+        let days = days_from_civil(u64::from(dt.year), u64::from(dt.month), u64::from(dt.day));
+
+        Some(
+            days * SECONDS_PER_DAY
+                + u64::from(hour) * 3600
+                + u64::from(dt.minute) * 60
+                + u64::from(dt.second),
+        )
+    }
+
+    /// Whether the fields are in range, the day is in the month and
+    /// the year is from 1970. The weekday field isn't checked, see
+    /// `weekday()`.
+    pub fn is_valid(&self) -> bool {
+        let dt = &self.0;
+        hour24(dt.hour).is_some()
+            && dt.year >= 1970
+            && (1..=12).contains(&dt.month)
+            && dt.day >= 1
+            && dt.day <= days_in_month(u64::from(dt.year), dt.month)
+            && dt.minute <= 59
+            && dt.second <= 59
+    }
+
+    pub fn year(&self) -> u16 {
+        self.0.year
+    }
+
+    /// 1-12
+    pub fn month(&self) -> u8 {
+        self.0.month
+    }
+
+    pub fn day(&self) -> u8 {
+        self.0.day
+    }
+
+    /// 0-23 whether it's held as 12 or 24-hour time, None if out of
+    /// range
+    pub fn hour(&self) -> Option<u8> {
+        hour24(self.0.hour)
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.0.minute
+    }
+
+    pub fn second(&self) -> u8 {
+        self.0.second
+    }
+
+    /// 1 (Sunday) to 7 from the date, as the DS3231 counts. None if
+    /// it's not valid.
+    pub fn weekday(&self) -> Option<u8> {
+        if !self.is_valid() {
+            return None;
+        }
+        let dt = &self.0;
+        let days = days_from_civil(u64::from(dt.year), u64::from(dt.month), u64::from(dt.day));
+        Some(weekday_from_days(days) as u8 + 1)
+    }
+
+    /// The same time with a 0-23 hour, None if the hour is out of range
+    pub fn to_24_hour(&self) -> Option<Self> {
+        let hour = hour24(self.0.hour)?;
+        Some(DateTime(ds323x::DateTime {
+            hour: ds323x::Hours::H24(hour),
+            ..self.0
+        }))
+    }
+
+    /// The same time with a 1-12 AM/PM hour, None if the hour is out of
+    /// range
+    pub fn to_12_hour(&self) -> Option<Self> {
+        let hour = hour24(self.0.hour)?;
+        let hour = match hour {
+            0 => ds323x::Hours::AM(12),
+            1..=11 => ds323x::Hours::AM(hour),
+            12 => ds323x::Hours::PM(12),
+            _ => ds323x::Hours::PM(hour - 12),
+        };
+        Some(DateTime(ds323x::DateTime { hour, ..self.0 }))
+    }
+
+    /// Later by the whole seconds of `duration`. None if it's not valid
+    /// or the result is past year 9999.
+    pub fn checked_add(&self, duration: Duration) -> Option<Self> {
+        let secs = self.to_unix_time()?.checked_add(duration.as_secs())?;
+        if secs > MAX_UNIX_TIME {
+            None
+        } else {
+            Some(DateTime::from_unix_time(secs))
+        }
+    }
+
+    /// Earlier by the whole seconds of `duration`. None if it's not
+    /// valid or the result is before 1970.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        let secs = self.to_unix_time()?.checked_sub(duration.as_secs())?;
+        Some(DateTime::from_unix_time(secs))
+    }
+
+    /// Time since `earlier`. None if it's later or either isn't valid.
+    pub fn duration_since(&self, earlier: &DateTime) -> Option<Duration> {
+        let secs = self.to_unix_time()?.checked_sub(earlier.to_unix_time()?)?;
+        Some(Duration::from_secs(secs))
+    }
+}
+
+pub(crate) fn is_leap_year(year: u64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// 1-31, 0 for a month out of range
+fn days_in_month(year: u64, month: u8) -> u8 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => 0,
+    }
+}
+
+/// Year, month (1-12) and day (1-31) of the days since 1970-01-01
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Years start in March so the leap day is last
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Days since 1970-01-01, the inverse of `civil_from_days`. The year
+/// must be at least 1970.
+pub(crate) fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = year - if month <= 2 { 1 } else { 0 };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// 0 is Sunday
+pub(crate) fn weekday_from_days(days: u64) -> u64 {
+    // 1970-01-01 was a Thursday
+    (days + 4) % 7
+}
+
+/// 0-23, None if out of range
+fn hour24(hour: ds323x::Hours) -> Option<u8> {
+    match hour {
+        ds323x::Hours::AM(h) if (1..=12).contains(&h) => Some(h % 12),
+        ds323x::Hours::PM(h) if (1..=12).contains(&h) => Some(h % 12 + 12),
+        ds323x::Hours::H24(h) if h < 24 => Some(h),
+        _ => None,
+    }
+}
+
+impl From<ds323x::DateTime> for DateTime {
+    fn from(dt: ds323x::DateTime) -> Self {
+        DateTime(dt)
+    }
+}
+
+impl From<DateTime> for ds323x::DateTime {
+    fn from(dt: DateTime) -> Self {
+        dt.0
+    }
+}
+
+impl Default for DateTime {
+    fn default() -> Self {
+        DateTime::from(ds323x::DateTime {
+            year: 0,
+            month: 0,
+            day: 0,
+            weekday: 0,
+            hour: ds323x::Hours::AM(0),
+            minute: 0,
+            second: 0,
+        })
+    }
+}
+
+// Formats like chrono::DateTime "%a %b %e %H:%M:%S", see
+// `DateTimeFormat` for the other styles
+// Example: Sun Sep 29 07:10:15
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&DateTimeFormat::default().display(*self), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use heapless::consts::U32;
+    use heapless::String;
+
+    /// With the weekday from the date
+    fn dt(year: u16, month: u8, day: u8, hour: ds323x::Hours, minute: u8, second: u8) -> DateTime {
+        let mut dt = DateTime(ds323x::DateTime {
+            year,
+            month,
+            day,
+            weekday: 0,
+            hour,
+            minute,
+            second,
+        });
+        dt.0.weekday = dt.weekday().unwrap_or(0);
+        dt
+    }
+
+    fn h24(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        dt(year, month, day, ds323x::Hours::H24(hour), minute, second)
+    }
+
+    fn display(dt: &DateTime) -> String<U32> {
+        let mut s = String::new();
+        write!(s, "{}", dt).unwrap();
+        s
+    }
+
+    #[test]
+    fn datetime_formating() {
+        assert_eq!(display(&DateTime::default()), "--- --- -- --:--:--");
+        // Weekday from the date, not the field
+        let mut sunday = h24(2019, 1, 1, 1, 1, 1);
+        sunday.0.weekday = 1;
+        assert_eq!(display(&sunday), "Tue Jan 01 01:01:01");
+        assert_eq!(
+            display(&dt(2019, 9, 29, ds323x::Hours::PM(7), 10, 15)),
+            "Sun Sep 29 19:10:15"
+        );
+        assert_eq!(
+            display(&dt(2019, 12, 31, ds323x::Hours::AM(12), 0, 0)),
+            "Tue Dec 31 00:00:00"
+        );
+        assert_eq!(display(&h24(2019, 2, 29, 1, 1, 1)), "--- --- -- --:--:--");
+        assert_eq!(
+            display(&dt(2019, 1, 1, ds323x::Hours::AM(13), 1, 1)),
+            "--- --- -- --:--:--"
+        );
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(h24(2019, 9, 29, 7, 10, 15).is_valid(), true);
+        assert_eq!(h24(1970, 1, 1, 0, 0, 0).is_valid(), true);
+        assert_eq!(h24(2019, 12, 31, 23, 59, 59).is_valid(), true);
+        assert_eq!(h24(2020, 2, 29, 0, 0, 0).is_valid(), true);
+        assert_eq!(h24(2000, 2, 29, 0, 0, 0).is_valid(), true);
+        assert_eq!(h24(2100, 2, 29, 0, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 2, 29, 0, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 2, 31, 0, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 4, 31, 0, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 4, 30, 0, 0, 0).is_valid(), true);
+        assert_eq!(h24(1969, 12, 31, 0, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 0, 1, 0, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 13, 1, 0, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 1, 0, 0, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 1, 1, 24, 0, 0).is_valid(), false);
+        assert_eq!(h24(2019, 1, 1, 0, 60, 0).is_valid(), false);
+        assert_eq!(h24(2019, 1, 1, 0, 0, 60).is_valid(), false);
+        assert_eq!(dt(2019, 1, 1, ds323x::Hours::AM(0), 0, 0).is_valid(), false);
+        assert_eq!(dt(2019, 1, 1, ds323x::Hours::PM(12), 0, 0).is_valid(), true);
+        assert_eq!(DateTime::default().is_valid(), false);
+    }
+
+    #[test]
+    fn weekday() {
+        assert_eq!(h24(1970, 1, 1, 0, 0, 0).weekday(), Some(5));
+        assert_eq!(h24(2019, 9, 29, 7, 10, 15).weekday(), Some(1));
+        assert_eq!(h24(2019, 9, 28, 7, 10, 15).weekday(), Some(7));
+        assert_eq!(h24(2020, 2, 29, 0, 0, 0).weekday(), Some(7));
+        assert_eq!(h24(2000, 1, 1, 0, 0, 0).weekday(), Some(7));
+        assert_eq!(h24(2019, 2, 29, 0, 0, 0).weekday(), None);
+        for secs in (0..4_102_444_800).step_by(86_399 * 5 + 17) {
+            let dt = DateTime::from_unix_time(secs);
+            assert_eq!(dt.weekday(), Some(dt.0.weekday));
+        }
+    }
+
+    #[test]
+    fn hour_formats() {
+        let hours = [
+            (0, ds323x::Hours::AM(12)),
+            (1, ds323x::Hours::AM(1)),
+            (11, ds323x::Hours::AM(11)),
+            (12, ds323x::Hours::PM(12)),
+            (13, ds323x::Hours::PM(1)),
+            (23, ds323x::Hours::PM(11)),
+        ];
+        for (hour, am_pm) in hours.iter() {
+            let h24 = h24(2019, 9, 29, *hour, 10, 15);
+            let h12 = dt(2019, 9, 29, *am_pm, 10, 15);
+            assert_eq!(h24.to_12_hour(), Some(h12));
+            assert_eq!(h12.to_24_hour(), Some(h24));
+            assert_eq!(h24.to_24_hour(), Some(h24));
+            assert_eq!(h12.to_12_hour(), Some(h12));
+            assert_eq!(h12.hour(), Some(*hour));
+            assert_eq!(h12.to_unix_time(), h24.to_unix_time());
+        }
+        let bad = dt(2019, 9, 29, ds323x::Hours::PM(13), 10, 15);
+        assert_eq!(bad.hour(), None);
+        assert_eq!(bad.to_24_hour(), None);
+        assert_eq!(bad.to_12_hour(), None);
+        assert_eq!(h24(2019, 9, 29, 24, 0, 0).to_12_hour(), None);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let secs = Duration::from_secs;
+        let start = dt(2019, 12, 31, ds323x::Hours::PM(11), 59, 30);
+        // Across midnight, the new year, sub-second parts dropped
+        assert_eq!(
+            start.checked_add(Duration::from_millis(45_999)),
+            Some(h24(2020, 1, 1, 0, 0, 15))
+        );
+        assert_eq!(
+            h24(2020, 1, 1, 0, 0, 15).duration_since(&start),
+            Some(secs(45))
+        );
+        assert_eq!(start.duration_since(&h24(2020, 1, 1, 0, 0, 15)), None);
+        assert_eq!(start.duration_since(&start), Some(secs(0)));
+        // Leap day
+        assert_eq!(
+            h24(2020, 2, 28, 12, 0, 0).checked_add(secs(86_400)),
+            Some(h24(2020, 2, 29, 12, 0, 0))
+        );
+        assert_eq!(
+            h24(2019, 2, 28, 12, 0, 0).checked_add(secs(86_400)),
+            Some(h24(2019, 3, 1, 12, 0, 0))
+        );
+        assert_eq!(
+            h24(2020, 3, 1, 0, 0, 0).checked_sub(secs(1)),
+            Some(h24(2020, 2, 29, 23, 59, 59))
+        );
+        assert_eq!(
+            h24(2020, 3, 1, 0, 0, 0).duration_since(&h24(2020, 2, 1, 0, 0, 0)),
+            Some(secs(29 * 86_400))
+        );
+
+        assert_eq!(h24(1970, 1, 1, 0, 0, 0).checked_sub(secs(1)), None);
+        assert_eq!(
+            h24(9999, 12, 31, 23, 59, 59).checked_add(secs(0)).is_some(),
+            true
+        );
+        assert_eq!(h24(9999, 12, 31, 23, 59, 59).checked_add(secs(1)), None);
+        assert_eq!(
+            start.checked_add(Duration::from_secs(u64::max_value())),
+            None
+        );
+        assert_eq!(DateTime::default().checked_add(secs(1)), None);
+        assert_eq!(DateTime::default().checked_sub(secs(1)), None);
+        assert_eq!(start.duration_since(&DateTime::default()), None);
+        assert_eq!(DateTime::default().duration_since(&start), None);
+    }
+
+    #[test]
+    fn from_unix_time() {
+        let dt = |year, month, day, weekday, hour, minute, second| {
+            DateTime::from(ds323x::DateTime {
+                year,
+                month,
+                day,
+                weekday,
+                hour: ds323x::Hours::H24(hour),
+                minute,
+                second,
+            })
+        };
+        assert_eq!(DateTime::from_unix_time(0), dt(1970, 1, 1, 5, 0, 0, 0));
+        assert_eq!(
+            DateTime::from_unix_time(1_569_741_015),
+            dt(2019, 9, 29, 1, 7, 10, 15)
+        );
+        // Leap day, end of a leap year, start of the next
+        assert_eq!(
+            DateTime::from_unix_time(951_825_600),
+            dt(2000, 2, 29, 3, 12, 0, 0)
+        );
+        assert_eq!(
+            DateTime::from_unix_time(1_609_459_199),
+            dt(2020, 12, 31, 5, 23, 59, 59)
+        );
+        assert_eq!(
+            DateTime::from_unix_time(1_609_459_200),
+            dt(2021, 1, 1, 6, 0, 0, 0)
+        );
+        // NTP era 1 starts 2036-02-07 06:28:16
+        assert_eq!(
+            DateTime::from_unix_time(2_085_978_496),
+            dt(2036, 2, 7, 5, 6, 28, 16)
+        );
+    }
+
+    #[test]
+    fn to_unix_time() {
+        for secs in (0..4_102_444_800).step_by(86_399 * 7 + 13) {
+            assert_eq!(DateTime::from_unix_time(secs).to_unix_time(), Some(secs));
+        }
+        let dt = |month, day, hour| {
+            DateTime::from(ds323x::DateTime {
+                year: 2019,
+                month,
+                day,
+                weekday: 1,
+                hour,
+                minute: 10,
+                second: 15,
+            })
+        };
+        assert_eq!(
+            dt(9, 29, ds323x::Hours::AM(7)).to_unix_time(),
+            Some(1_569_741_015)
+        );
+        assert_eq!(
+            dt(9, 29, ds323x::Hours::PM(7)).to_unix_time(),
+            Some(1_569_741_015 + 12 * 3600)
+        );
+        assert_eq!(
+            dt(9, 29, ds323x::Hours::AM(12)).to_unix_time(),
+            Some(1_569_741_015 - 7 * 3600)
+        );
+        assert_eq!(dt(9, 29, ds323x::Hours::H24(24)).to_unix_time(), None);
+        assert_eq!(dt(9, 29, ds323x::Hours::PM(0)).to_unix_time(), None);
+        assert_eq!(dt(13, 29, ds323x::Hours::H24(7)).to_unix_time(), None);
+        assert_eq!(dt(9, 0, ds323x::Hours::H24(7)).to_unix_time(), None);
+        assert_eq!(dt(2, 31, ds323x::Hours::H24(7)).to_unix_time(), None);
+        assert_eq!(dt(2, 29, ds323x::Hours::H24(7)).to_unix_time(), None);
+        assert_eq!(dt(4, 31, ds323x::Hours::H24(7)).to_unix_time(), None);
+        assert_eq!(DateTime::default().to_unix_time(), None);
+    }
+}
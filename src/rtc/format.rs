@@ -0,0 +1,308 @@
+//! Date and time styles for the display
+//!
+//! Every style fits a 20 character row. 12-hour time with seconds
+//! doesn't fit next to a date, so the seconds are dropped there.
+
+use crate::rtc::DateTime;
+use core::fmt::{self, Write};
+use heapless::consts::U20;
+use heapless::String;
+
+const DOW_STRINGS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+const MONTH_STRINGS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum HourStyle {
+    /// 19:10
+    H24,
+    /// 7:10 PM
+    H12,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum DateStyle {
+    /// Sun Sep 29
+    WeekdayMonthDay,
+    /// 2019-09-29
+    Iso,
+    /// 29/09/2019
+    DayMonthYear,
+    /// 09/29/2019
+    MonthDayYear,
+    /// Time alone
+    None,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DateTimeFormat {
+    pub date: DateStyle,
+    pub hours: HourStyle,
+    pub seconds: bool,
+}
+
+impl Default for DateTimeFormat {
+    /// Sun Sep 29 19:10:15
+    fn default() -> Self {
+        DateTimeFormat {
+            date: DateStyle::WeekdayMonthDay,
+            hours: HourStyle::H24,
+            seconds: true,
+        }
+    }
+}
+
+impl DateTimeFormat {
+    pub fn display(self, dt: DateTime) -> DisplayableDateTime {
+        DisplayableDateTime { dt, format: self }
+    }
+
+    fn shows_seconds(&self) -> bool {
+        self.seconds && (self.hours == HourStyle::H24 || self.date == DateStyle::None)
+    }
+
+    fn write<W: Write>(&self, dt: &DateTime, w: &mut W) -> fmt::Result {
+        // Callers have checked it's valid
+        let weekday = usize::from(dt.weekday().unwrap_or(1));
+        let hour = dt.hour().unwrap_or(0);
+
+        match self.date {
+            DateStyle::WeekdayMonthDay => write!(
+                w,
+                "{} {} {:02} ",
+                DOW_STRINGS[weekday - 1],
+                MONTH_STRINGS[usize::from(dt.month()) - 1],
+                dt.day()
+            )?,
+            DateStyle::Iso => write!(w, "{}-{:02}-{:02} ", dt.year(), dt.month(), dt.day())?,
+            DateStyle::DayMonthYear => {
+                write!(w, "{:02}/{:02}/{} ", dt.day(), dt.month(), dt.year())?
+            }
+            DateStyle::MonthDayYear => {
+                write!(w, "{:02}/{:02}/{} ", dt.month(), dt.day(), dt.year())?
+            }
+            DateStyle::None => (),
+        }
+
+        match self.hours {
+            HourStyle::H24 => write!(w, "{:02}:{:02}", hour, dt.minute())?,
+            HourStyle::H12 => {
+                let h12 = match hour % 12 {
+                    0 => 12,
+                    h => h,
+                };
+                write!(w, "{}:{:02}", h12, dt.minute())?
+            }
+        }
+        if self.shows_seconds() {
+            write!(w, ":{:02}", dt.second())?;
+        }
+        if self.hours == HourStyle::H12 {
+            w.write_str(if hour < 12 { " AM" } else { " PM" })?;
+        }
+        Ok(())
+    }
+}
+
+/// A `DateTime` in a `DateTimeFormat`, dashes in place of the digits
+/// and names when it's not valid
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DisplayableDateTime {
+    dt: DateTime,
+    format: DateTimeFormat,
+}
+
+impl fmt::Display for DisplayableDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s: String<U20> = String::new();
+        if self.dt.is_valid() {
+            self.format.write(&self.dt, &mut s)?;
+            f.pad(&s)
+        } else {
+            self.format.write(&DateTime::from_unix_time(0), &mut s)?;
+            let mut masked: String<U20> = String::new();
+            for c in s.chars() {
+                let c = if c.is_ascii_alphanumeric() { '-' } else { c };
+                // Can't fail, it's the same length
+                masked.push(c).unwrap();
+            }
+            f.pad(&masked)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::consts::U32;
+
+    const DATES: [DateStyle; 5] = [
+        DateStyle::WeekdayMonthDay,
+        DateStyle::Iso,
+        DateStyle::DayMonthYear,
+        DateStyle::MonthDayYear,
+        DateStyle::None,
+    ];
+
+    fn format(date: DateStyle, hours: HourStyle, seconds: bool) -> DateTimeFormat {
+        DateTimeFormat {
+            date,
+            hours,
+            seconds,
+        }
+    }
+
+    fn display(format: DateTimeFormat, dt: DateTime) -> String<U32> {
+        let mut s = String::new();
+        write!(s, "{}", format.display(dt)).unwrap();
+        s
+    }
+
+    fn dt(hour: ds323x::Hours) -> DateTime {
+        DateTime::from(ds323x::DateTime {
+            year: 2019,
+            month: 9,
+            day: 29,
+            weekday: 1,
+            hour,
+            minute: 5,
+            second: 9,
+        })
+    }
+
+    #[test]
+    fn styles() {
+        let pm = dt(ds323x::Hours::H24(19));
+        let styles = [
+            (
+                DateStyle::WeekdayMonthDay,
+                [
+                    "Sun Sep 29 19:05:09",
+                    "Sun Sep 29 19:05",
+                    "Sun Sep 29 7:05 PM",
+                ],
+            ),
+            (
+                DateStyle::Iso,
+                [
+                    "2019-09-29 19:05:09",
+                    "2019-09-29 19:05",
+                    "2019-09-29 7:05 PM",
+                ],
+            ),
+            (
+                DateStyle::DayMonthYear,
+                [
+                    "29/09/2019 19:05:09",
+                    "29/09/2019 19:05",
+                    "29/09/2019 7:05 PM",
+                ],
+            ),
+            (
+                DateStyle::MonthDayYear,
+                [
+                    "09/29/2019 19:05:09",
+                    "09/29/2019 19:05",
+                    "09/29/2019 7:05 PM",
+                ],
+            ),
+            (DateStyle::None, ["19:05:09", "19:05", "7:05 PM"]),
+        ];
+        for (date, [h24_seconds, h24, h12]) in styles.iter() {
+            assert_eq!(
+                display(format(*date, HourStyle::H24, true), pm),
+                *h24_seconds
+            );
+            assert_eq!(display(format(*date, HourStyle::H24, false), pm), *h24);
+            assert_eq!(display(format(*date, HourStyle::H12, false), pm), *h12);
+            // No room for seconds next to a date
+            if *date != DateStyle::None {
+                assert_eq!(display(format(*date, HourStyle::H12, true), pm), *h12);
+            }
+        }
+        assert_eq!(
+            display(format(DateStyle::None, HourStyle::H12, true), pm),
+            "7:05:09 PM"
+        );
+        assert_eq!(
+            display(DateTimeFormat::default(), pm),
+            "Sun Sep 29 19:05:09"
+        );
+    }
+
+    #[test]
+    fn hours() {
+        let time = format(DateStyle::None, HourStyle::H12, false);
+        let hours = [
+            (ds323x::Hours::H24(0), "12:05 AM", "00:05"),
+            (ds323x::Hours::AM(12), "12:05 AM", "00:05"),
+            (ds323x::Hours::H24(1), "1:05 AM", "01:05"),
+            (ds323x::Hours::AM(11), "11:05 AM", "11:05"),
+            (ds323x::Hours::H24(12), "12:05 PM", "12:05"),
+            (ds323x::Hours::PM(12), "12:05 PM", "12:05"),
+            (ds323x::Hours::PM(1), "1:05 PM", "13:05"),
+            (ds323x::Hours::H24(23), "11:05 PM", "23:05"),
+        ];
+        for (hour, h12, h24) in hours.iter() {
+            assert_eq!(display(time, dt(*hour)), *h12);
+            let time = DateTimeFormat {
+                hours: HourStyle::H24,
+                ..time
+            };
+            assert_eq!(display(time, dt(*hour)), *h24);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let invalid = dt(ds323x::Hours::PM(13));
+        assert_eq!(
+            display(DateTimeFormat::default(), invalid),
+            "--- --- -- --:--:--"
+        );
+        assert_eq!(
+            display(format(DateStyle::Iso, HourStyle::H12, true), invalid),
+            "---------- --:-- --"
+        );
+        assert_eq!(
+            display(
+                format(DateStyle::DayMonthYear, HourStyle::H24, false),
+                invalid
+            ),
+            "--/--/---- --:--"
+        );
+        assert_eq!(
+            display(
+                format(DateStyle::None, HourStyle::H12, true),
+                DateTime::default()
+            ),
+            "--:--:-- --"
+        );
+    }
+
+    #[test]
+    fn fits_a_row() {
+        let mut storage = crate::display::RowStorage::new();
+        for secs in (0..253_402_300_799).step_by(86_399 * 997 + 3_599) {
+            let dt = DateTime::from_unix_time(secs);
+            for date in DATES.iter() {
+                for hours in [HourStyle::H24, HourStyle::H12].iter() {
+                    for seconds in [false, true].iter() {
+                        let format = format(*date, *hours, *seconds);
+                        storage.clear();
+                        write!(storage, "{: ^20}", format.display(dt)).unwrap();
+                        assert_eq!(storage.len(), 20);
+                        assert_eq!(storage.trim(), display(format, dt).as_str());
+                    }
+                }
+            }
+        }
+
+        storage.clear();
+        let format = format(DateStyle::None, HourStyle::H12, false);
+        write!(storage, "{: ^20}", format.display(dt(ds323x::Hours::PM(7)))).unwrap();
+        assert_eq!(storage.as_str(), "      7:05 PM       ");
+    }
+}
@@ -0,0 +1,293 @@
+//! Real-time clock
+//!
+//! The DS3231 on the I2C bus keeps UTC across power cycles on its
+//! backup battery. `Rtc` reads and sets it through the `ds323x`
+//! driver, and treats the time as invalid once the oscillator has
+//! stopped (first power up, flat battery) until it's set again.
+//!
+//! The display loop takes the time from `now()`, which only goes to the
+//! bus every `REFRESH_INTERVAL` (sooner after a failed read) and counts
+//! the seconds in between on the monotonic clock.
+
+mod date_time;
+pub mod format;
+pub mod time_zone;
+
+pub use crate::rtc::date_time::DateTime;
+pub use crate::rtc::format::DateTimeFormat;
+pub use crate::rtc::time_zone::TimeZone;
+
+use crate::time::{Duration, Instant};
+use core::fmt;
+use ds323x::interface::I2cInterface;
+use ds323x::{ic, Ds323x};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use log::warn;
+
+/// Between reads of the chip in `now()`
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Before `now()` tries again after a failed read
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error<E> {
+    Bus(E),
+    /// Date or time the chip can't hold, or garbage read from it
+    InvalidDateTime,
+    /// Oscillator stopped since the time was last set
+    Stopped,
+}
+
+impl<E> From<ds323x::Error<E, ()>> for Error<E> {
+    fn from(e: ds323x::Error<E, ()>) -> Self {
+        match e {
+            ds323x::Error::Comm(e) => Error::Bus(e),
+            ds323x::Error::Pin(()) | ds323x::Error::InvalidInputData => Error::InvalidDateTime,
+        }
+    }
+}
+
+pub struct Rtc<I2C> {
+    rtc: Ds323x<I2cInterface<I2C>, ic::DS3231>,
+    /// Oscillator-stop flag was seen, until the time is set
+    stopped: bool,
+    /// Unix time last read or set, and when
+    cached: Option<(u64, Instant)>,
+    next_read: Instant,
+}
+
+impl<I2C, E> Rtc<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: fmt::Debug,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Rtc {
+            rtc: Ds323x::new_ds3231(i2c),
+            stopped: false,
+            cached: None,
+            next_read: Instant::from_secs(0),
+        }
+    }
+
+    pub fn destroy(self) -> I2C {
+        self.rtc.destroy_ds3231()
+    }
+
+    /// False once the oscillator has stopped, until the time is set
+    pub fn is_valid(&self) -> bool {
+        !self.stopped
+    }
+
+    /// Reads the time from the chip
+    pub fn read(&mut self, time: Instant) -> Result<DateTime, Error<E>> {
+        // Pushed out to the refresh once the chip has answered
+        self.next_read = time + RETRY_INTERVAL;
+        if self.rtc.has_been_stopped()? {
+            if !self.stopped {
+                warn!("RTC oscillator stopped, time is invalid");
+            }
+            self.stopped = true;
+            self.cached = None;
+            self.next_read = time + REFRESH_INTERVAL;
+            return Err(Error::Stopped);
+        }
+        let dt = DateTime::from(self.rtc.get_datetime()?);
+        let secs = dt.to_unix_time().ok_or(Error::InvalidDateTime)?;
+        self.cached = Some((secs, time));
+        self.next_read = time + REFRESH_INTERVAL;
+        Ok(dt)
+    }
+
+    /// Sets the chip's time (UTC), which makes it valid again
+    pub fn set(&mut self, dt: &DateTime, time: Instant) -> Result<(), Error<E>> {
+        let secs = dt.to_unix_time().ok_or(Error::InvalidDateTime)?;
+        self.rtc.set_datetime(&(*dt).into())?;
+        self.rtc.clear_has_been_stopped_flag()?;
+        self.stopped = false;
+        self.cached = Some((secs, time));
+        self.next_read = time + REFRESH_INTERVAL;
+        Ok(())
+    }
+
+    /// Last time read or set, advanced by the monotonic clock since.
+    /// Reads the chip when it's been `REFRESH_INTERVAL`, or
+    /// `RETRY_INTERVAL` after a failed read. None while the time is
+    /// invalid or hasn't been read.
+    pub fn now(&mut self, time: Instant) -> Option<DateTime> {
+        if time >= self.next_read {
+            match self.read(time) {
+                Ok(_) | Err(Error::Stopped) => (),
+                Err(e) => warn!("RTC read failed {:?}", e),
+            }
+        }
+        let (secs, at) = self.cached?;
+        let elapsed = time.checked_sub(at).unwrap_or_default();
+        Some(DateTime::from_unix_time(secs + elapsed.as_secs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+
+    const ADDRESS: u8 = 0b110_1000;
+    const STATUS: usize = 0x0F;
+    const OSF: u8 = 0x80;
+    /// Flags that are only cleared by writing
+    const FLAGS: u8 = 0x83;
+    /// 2019-09-29 07:10:15, Sunday
+    const TIME_REGISTERS: [u8; 7] = [0x15, 0x10, 0x07, 0x01, 0x29, 0x09, 0x19];
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    struct BusError;
+
+    #[derive(Debug, Default)]
+    struct Chip {
+        registers: [u8; 0x13],
+        reads: usize,
+        fail: bool,
+    }
+
+    /// DS3231 register file on a mock bus
+    struct MockI2c<'a>(&'a RefCell<Chip>);
+
+    impl<'a> Write for MockI2c<'a> {
+        type Error = BusError;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), BusError> {
+            assert_eq!(address, ADDRESS);
+            let mut chip = self.0.borrow_mut();
+            if chip.fail {
+                return Err(BusError);
+            }
+            let (first, data) = bytes.split_first().unwrap();
+            for (i, b) in data.iter().enumerate() {
+                let reg = usize::from(*first) + i;
+                chip.registers[reg] = if reg == STATUS {
+                    (chip.registers[reg] & b & FLAGS) | (b & !FLAGS)
+                } else {
+                    *b
+                };
+            }
+            Ok(())
+        }
+    }
+
+    impl<'a> WriteRead for MockI2c<'a> {
+        type Error = BusError;
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buf: &mut [u8],
+        ) -> Result<(), BusError> {
+            assert_eq!(address, ADDRESS);
+            let mut chip = self.0.borrow_mut();
+            if chip.fail {
+                return Err(BusError);
+            }
+            chip.reads += 1;
+            let first = usize::from(bytes[0]);
+            buf.copy_from_slice(&chip.registers[first..first + buf.len()]);
+            Ok(())
+        }
+    }
+
+    fn s(secs: u64) -> Instant {
+        Instant::from_secs(secs)
+    }
+
+    fn dt(hour: ds323x::Hours, minute: u8, second: u8) -> DateTime {
+        DateTime::from(ds323x::DateTime {
+            year: 2019,
+            month: 9,
+            day: 29,
+            weekday: 1,
+            hour,
+            minute,
+            second,
+        })
+    }
+
+    #[test]
+    fn stopped_until_set() {
+        // Powered up for the first time
+        let chip = RefCell::new(Chip::default());
+        chip.borrow_mut().registers[STATUS] = OSF;
+        let mut rtc = Rtc::new(MockI2c(&chip));
+        assert_eq!(rtc.now(s(0)), None);
+        assert_eq!(rtc.is_valid(), false);
+        assert_eq!(rtc.read(s(1)), Err(Error::Stopped));
+
+        let time = dt(ds323x::Hours::H24(7), 10, 15);
+        rtc.set(&time, s(10)).unwrap();
+        assert_eq!(rtc.is_valid(), true);
+        assert_eq!(&chip.borrow().registers[..7], &TIME_REGISTERS);
+        assert_eq!(chip.borrow().registers[STATUS] & OSF, 0);
+
+        // Counts on the monotonic clock between reads
+        let reads = chip.borrow().reads;
+        assert_eq!(rtc.now(s(10)), Some(time));
+        assert_eq!(rtc.now(s(71)), Some(dt(ds323x::Hours::H24(7), 11, 16)));
+        assert_eq!(chip.borrow().reads, reads);
+
+        // Chip ran 2 s fast, and is in 12-hour mode
+        chip.borrow_mut().registers[..3].copy_from_slice(&[0x17, 0x20, 0x67]);
+        assert_eq!(rtc.now(s(610)), Some(dt(ds323x::Hours::H24(19), 20, 17)));
+        assert_eq!(rtc.now(s(611)), Some(dt(ds323x::Hours::H24(19), 20, 18)));
+        assert_eq!(chip.borrow().reads, reads + 2);
+
+        // Battery went flat
+        chip.borrow_mut().registers[STATUS] |= OSF;
+        assert_eq!(rtc.now(s(1209)).is_some(), true);
+        assert_eq!(rtc.now(s(1210)), None);
+        assert_eq!(rtc.is_valid(), false);
+        rtc.set(&time, s(1211)).unwrap();
+        assert_eq!(rtc.now(s(1211)), Some(time));
+        assert_eq!(rtc.is_valid(), true);
+    }
+
+    #[test]
+    fn errors() {
+        let chip = RefCell::new(Chip::default());
+        chip.borrow_mut().registers[..7].copy_from_slice(&TIME_REGISTERS);
+        chip.borrow_mut().fail = true;
+        let mut rtc = Rtc::new(MockI2c(&chip));
+        assert_eq!(rtc.now(s(0)), None);
+        assert_eq!(rtc.read(s(0)), Err(Error::Bus(BusError)));
+        let time = dt(ds323x::Hours::H24(7), 10, 15);
+        assert_eq!(rtc.set(&time, s(0)), Err(Error::Bus(BusError)));
+
+        // Tried again soon after a failed read
+        chip.borrow_mut().fail = false;
+        assert_eq!(rtc.now(s(4)), None);
+        assert_eq!(rtc.now(s(5)), Some(time));
+        assert_eq!(rtc.read(s(0)), Ok(time));
+        assert_eq!(rtc.now(s(1)), Some(dt(ds323x::Hours::H24(7), 10, 16)));
+
+        // Keeps counting from the last good read through a failed one
+        chip.borrow_mut().fail = true;
+        let reads = chip.borrow().reads;
+        assert_eq!(rtc.now(s(600)), Some(dt(ds323x::Hours::H24(7), 20, 15)));
+        chip.borrow_mut().fail = false;
+        chip.borrow_mut().registers[..3].copy_from_slice(&[0x20, 0x20, 0x07]);
+        assert_eq!(rtc.now(s(604)), Some(dt(ds323x::Hours::H24(7), 20, 19)));
+        assert_eq!(chip.borrow().reads, reads);
+        assert_eq!(rtc.now(s(605)), Some(dt(ds323x::Hours::H24(7), 20, 20)));
+        assert_eq!(chip.borrow().reads, reads + 2);
+
+        // Doesn't touch the chip
+        chip.borrow_mut().registers[..7].copy_from_slice(&TIME_REGISTERS);
+        let invalid = dt(ds323x::Hours::H24(24), 10, 15);
+        assert_eq!(rtc.set(&invalid, s(1)), Err(Error::InvalidDateTime));
+        assert_eq!(&chip.borrow().registers[..7], &TIME_REGISTERS);
+
+        // Garbage in the registers
+        chip.borrow_mut().registers[5] = 0x13;
+        assert_eq!(rtc.read(s(2)), Err(Error::InvalidDateTime));
+        assert_eq!(rtc.is_valid(), true);
+    }
+}
@@ -0,0 +1,609 @@
+//! Time zones from POSIX TZ strings
+//!
+//! `std offset[dst[offset],start[/time],end[/time]]`, for example
+//! `EST5EDT,M3.2.0,M11.1.0` or `CET-1CEST,M3.5.0,M10.5.0/3`. Offsets are
+//! hours west of UTC, the DST offset defaults to an hour ahead of
+//! standard time and transitions default to 02:00 local time. Names can
+//! be quoted, `<+0530>-5:30`.
+//!
+//! Transition times may run past the day or be negative, as in RFC 8536.
+
+use crate::rtc::date_time::{
+    civil_from_days, days_from_civil, is_leap_year, weekday_from_days, DateTime,
+};
+use heapless::consts::U8;
+use heapless::String;
+
+pub type Name = String<U8>;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+const MAX_OFFSET_HOURS: u16 = 24;
+const MAX_TRANSITION_HOURS: u16 = 167;
+const DEFAULT_TRANSITION_TIME: i32 = 2 * 3600;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Shorter than 3 characters, longer than `Name` holds or not
+    /// alphabetic
+    InvalidName,
+    InvalidOffset,
+    InvalidRule,
+    InvalidTime,
+    /// DST name without start and end rules
+    MissingRule,
+    TrailingCharacters,
+}
+
+/// Day of the year a transition happens on
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Rule {
+    /// `Jn`, 1-365, February 29 is never counted
+    Julian(u16),
+    /// `n`, 0-365, February 29 is counted
+    Day(u16),
+    /// `Mm.w.d`, weekday d (0 is Sunday) of week w (1-5, 5 is the
+    /// last) of month m
+    MonthWeekDay { month: u8, week: u8, weekday: u8 },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Transition {
+    pub rule: Rule,
+    /// Seconds from local midnight, in the time being left
+    pub time: i32,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Dst {
+    pub name: Name,
+    /// Seconds east of UTC
+    pub offset: i32,
+    pub start: Transition,
+    pub end: Transition,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimeZone {
+    pub name: Name,
+    /// Seconds east of UTC, standard time
+    pub offset: i32,
+    pub dst: Option<Dst>,
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        let mut name = Name::new();
+        // Can't fail
+        name.push_str("UTC").unwrap();
+        TimeZone {
+            name,
+            offset: 0,
+            dst: None,
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut p = Parser(s.trim().as_bytes());
+        let name = p.name()?;
+        let offset = -p.time(MAX_OFFSET_HOURS).ok_or(Error::InvalidOffset)?;
+        if p.0.is_empty() {
+            return Ok(TimeZone {
+                name,
+                offset,
+                dst: None,
+            });
+        }
+
+        let dst_name = p.name()?;
+        let dst_offset = match p.peek() {
+            Some(b',') | None => offset + 3600,
+            _ => -p.time(MAX_OFFSET_HOURS).ok_or(Error::InvalidOffset)?,
+        };
+        if !p.eat(b',') {
+            return Err(Error::MissingRule);
+        }
+        let start = p.transition()?;
+        if !p.eat(b',') {
+            return Err(Error::MissingRule);
+        }
+        let end = p.transition()?;
+        if !p.0.is_empty() {
+            return Err(Error::TrailingCharacters);
+        }
+
+        Ok(TimeZone {
+            name,
+            offset,
+            dst: Some(Dst {
+                name: dst_name,
+                offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Whether DST is in effect at the seconds since 1970-01-01 UTC
+    pub fn is_dst(&self, secs: u64) -> bool {
+        let dst = match &self.dst {
+            Some(dst) => dst,
+            None => return false,
+        };
+        // Rules are for the local year
+        let t = secs as i64;
+        let local = (t + i64::from(self.offset)).max(0) as u64;
+        let (year, _, _) = civil_from_days(local / SECONDS_PER_DAY as u64);
+        let start = dst.start.at(year, self.offset);
+        let end = dst.end.at(year, dst.offset);
+        if start <= end {
+            start <= t && t < end
+        } else {
+            // Southern hemisphere, DST over the new year
+            t < end || start <= t
+        }
+    }
+
+    /// Seconds east of UTC at the seconds since 1970-01-01 UTC
+    pub fn offset(&self, secs: u64) -> i32 {
+        match &self.dst {
+            Some(dst) if self.is_dst(secs) => dst.offset,
+            _ => self.offset,
+        }
+    }
+
+    /// Name of the time in effect at the seconds since 1970-01-01 UTC
+    pub fn abbreviation(&self, secs: u64) -> &str {
+        match &self.dst {
+            Some(dst) if self.is_dst(secs) => &dst.name,
+            _ => &self.name,
+        }
+    }
+
+    /// Local date and 24-hour time of a UTC one. None if it's invalid
+    /// or before 1970 once local.
+    pub fn to_local(&self, utc: &DateTime) -> Option<DateTime> {
+        let secs = utc.to_unix_time()?;
+        let local = secs as i64 + i64::from(self.offset(secs));
+        if local < 0 {
+            None
+        } else {
+            Some(DateTime::from_unix_time(local as u64))
+        }
+    }
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        TimeZone::utc()
+    }
+}
+
+impl Transition {
+    /// Seconds since 1970-01-01 UTC it happens at in the year, leaving
+    /// the time at `offset`
+    fn at(&self, year: u64, offset: i32) -> i64 {
+        self.rule.day(year) as i64 * SECONDS_PER_DAY + i64::from(self.time) - i64::from(offset)
+    }
+}
+
+impl Rule {
+    /// Days since 1970-01-01
+    fn day(&self, year: u64) -> u64 {
+        let jan1 = days_from_civil(year, 1, 1);
+        match *self {
+            Rule::Julian(n) => {
+                let n = u64::from(n);
+                let leap_day = if is_leap_year(year) && n >= 60 { 1 } else { 0 };
+                jan1 + n - 1 + leap_day
+            }
+            Rule::Day(n) => jan1 + u64::from(n),
+            Rule::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            } => {
+                let month = u64::from(month);
+                let first = days_from_civil(year, month, 1);
+                let next = if month == 12 {
+                    days_from_civil(year + 1, 1, 1)
+                } else {
+                    days_from_civil(year, month + 1, 1)
+                };
+                let mut day = first
+                    + (u64::from(weekday) + 7 - weekday_from_days(first)) % 7
+                    + (u64::from(week) - 1) * 7;
+                // Week 5 is the last, which may be the 4th
+                while day >= next {
+                    day -= 7;
+                }
+                day
+            }
+        }
+    }
+}
+
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.0.first().cloned()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.0 = &self.0[1..];
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while<F>(&mut self, f: F) -> &'a [u8]
+    where
+        F: Fn(u8) -> bool,
+    {
+        let len = self.0.iter().position(|c| !f(*c)).unwrap_or(self.0.len());
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        taken
+    }
+
+    fn name(&mut self) -> Result<Name, Error> {
+        let name = if self.eat(b'<') {
+            let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == b'+' || c == b'-');
+            if !self.eat(b'>') {
+                return Err(Error::InvalidName);
+            }
+            name
+        } else {
+            self.take_while(|c| c.is_ascii_alphabetic())
+        };
+        if name.len() < 3 {
+            return Err(Error::InvalidName);
+        }
+        let mut out = Name::new();
+        // Can't fail, it's ASCII
+        let name = core::str::from_utf8(name).unwrap();
+        out.push_str(name).map_err(|_| Error::InvalidName)?;
+        Ok(out)
+    }
+
+    fn number(&mut self, max: u16) -> Option<u16> {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        if digits.is_empty() || digits.len() > 3 {
+            return None;
+        }
+        let n = digits.iter().fold(0, |n, c| n * 10 + u16::from(c - b'0'));
+        if n <= max {
+            Some(n)
+        } else {
+            None
+        }
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds
+    fn time(&mut self, max_hours: u16) -> Option<i32> {
+        let negative = if self.eat(b'-') {
+            true
+        } else {
+            self.eat(b'+');
+            false
+        };
+        let mut secs = i32::from(self.number(max_hours)?) * 3600;
+        if self.eat(b':') {
+            secs += i32::from(self.number(59)?) * 60;
+            if self.eat(b':') {
+                secs += i32::from(self.number(59)?);
+            }
+        }
+        Some(if negative { -secs } else { secs })
+    }
+
+    fn transition(&mut self) -> Result<Transition, Error> {
+        let rule = if self.eat(b'J') {
+            let n = self.number(365).filter(|n| *n >= 1);
+            Rule::Julian(n.ok_or(Error::InvalidRule)?)
+        } else if self.eat(b'M') {
+            let month = self.number(12).filter(|m| *m >= 1);
+            let month = month.ok_or(Error::InvalidRule)?;
+            if !self.eat(b'.') {
+                return Err(Error::InvalidRule);
+            }
+            let week = self.number(5).filter(|w| *w >= 1);
+            let week = week.ok_or(Error::InvalidRule)?;
+            if !self.eat(b'.') {
+                return Err(Error::InvalidRule);
+            }
+            let weekday = self.number(6).ok_or(Error::InvalidRule)?;
+            Rule::MonthWeekDay {
+                month: month as u8,
+                week: week as u8,
+                weekday: weekday as u8,
+            }
+        } else {
+            Rule::Day(self.number(365).ok_or(Error::InvalidRule)?)
+        };
+        let time = if self.eat(b'/') {
+            self.time(MAX_TRANSITION_HOURS).ok_or(Error::InvalidTime)?
+        } else {
+            DEFAULT_TRANSITION_TIME
+        };
+        Ok(Transition { rule, time })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EASTERN: &str = "EST5EDT,M3.2.0,M11.1.0";
+    const CENTRAL_EUROPE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+    const SYDNEY: &str = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+    const LORD_HOWE: &str = "<+1030>-10:30<+11>-11,M10.1.0,M4.1.0";
+    const AUCKLAND: &str = "NZST-12NZDT,M9.5.0,M4.1.0/3";
+
+    fn name(s: &str) -> Name {
+        let mut n = Name::new();
+        n.push_str(s).unwrap();
+        n
+    }
+
+    fn utc(hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime::from(ds323x::DateTime {
+            year: 2019,
+            month: 3,
+            day: 10,
+            weekday: 1,
+            hour: ds323x::Hours::H24(hour),
+            minute,
+            second,
+        })
+    }
+
+    fn local(day: u8, hour: u8, minute: u8, second: u8) -> Option<DateTime> {
+        Some(DateTime::from(ds323x::DateTime {
+            year: 2019,
+            month: 3,
+            day,
+            weekday: if day == 10 { 1 } else { 7 },
+            hour: ds323x::Hours::H24(hour),
+            minute,
+            second,
+        }))
+    }
+
+    #[test]
+    fn parse_zones() {
+        assert_eq!(
+            TimeZone::parse(EASTERN),
+            Ok(TimeZone {
+                name: name("EST"),
+                offset: -5 * 3600,
+                dst: Some(Dst {
+                    name: name("EDT"),
+                    offset: -4 * 3600,
+                    start: Transition {
+                        rule: Rule::MonthWeekDay {
+                            month: 3,
+                            week: 2,
+                            weekday: 0
+                        },
+                        time: 2 * 3600,
+                    },
+                    end: Transition {
+                        rule: Rule::MonthWeekDay {
+                            month: 11,
+                            week: 1,
+                            weekday: 0
+                        },
+                        time: 2 * 3600,
+                    },
+                }),
+            })
+        );
+        let tz = TimeZone::parse(LORD_HOWE).unwrap();
+        assert_eq!(tz.name, name("+1030"));
+        assert_eq!(tz.offset, 10 * 3600 + 30 * 60);
+        let dst = tz.dst.unwrap();
+        assert_eq!(dst.name, name("+11"));
+        assert_eq!(dst.offset, 11 * 3600);
+
+        assert_eq!(TimeZone::parse("UTC0"), Ok(TimeZone::utc()));
+        assert_eq!(
+            TimeZone::parse(" <-03>+3 "),
+            Ok(TimeZone {
+                name: name("-03"),
+                offset: -3 * 3600,
+                dst: None,
+            })
+        );
+        assert_eq!(
+            TimeZone::parse("IST-5:30"),
+            Ok(TimeZone {
+                name: name("IST"),
+                offset: 5 * 3600 + 30 * 60,
+                dst: None,
+            })
+        );
+
+        let tz = TimeZone::parse("AAA3:15:30BBB2,J60/-1:30,300/167").unwrap();
+        assert_eq!(tz.offset, -(3 * 3600 + 15 * 60 + 30));
+        assert_eq!(
+            tz.dst,
+            Some(Dst {
+                name: name("BBB"),
+                offset: -2 * 3600,
+                start: Transition {
+                    rule: Rule::Julian(60),
+                    time: -(3600 + 30 * 60),
+                },
+                end: Transition {
+                    rule: Rule::Day(300),
+                    time: 167 * 3600,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let errors = [
+            ("", Error::InvalidName),
+            ("EST+", Error::InvalidOffset),
+            ("E5", Error::InvalidName),
+            ("EST", Error::InvalidOffset),
+            ("EST25", Error::InvalidOffset),
+            ("EST5:60", Error::InvalidOffset),
+            ("<EST5", Error::InvalidName),
+            ("<E_T>5", Error::InvalidName),
+            ("ESTESTEST5", Error::InvalidName),
+            ("EST5E", Error::InvalidName),
+            ("EST5EDT", Error::MissingRule),
+            ("EST5EDT4", Error::MissingRule),
+            ("EST5EDT,M3.2.0", Error::MissingRule),
+            ("EST5EDT,M3.2.0,M11.1.0,", Error::TrailingCharacters),
+            ("EST5EDT,M3.2.0,M11.1.0 x", Error::TrailingCharacters),
+            ("EST5EDT,M0.2.0,M11.1.0", Error::InvalidRule),
+            ("EST5EDT,M13.2.0,M11.1.0", Error::InvalidRule),
+            ("EST5EDT,M3.0.0,M11.1.0", Error::InvalidRule),
+            ("EST5EDT,M3.6.0,M11.1.0", Error::InvalidRule),
+            ("EST5EDT,M3.2.7,M11.1.0", Error::InvalidRule),
+            ("EST5EDT,M3.2,M11.1.0", Error::InvalidRule),
+            ("EST5EDT,J0,J365", Error::InvalidRule),
+            ("EST5EDT,J1,J366", Error::InvalidRule),
+            ("EST5EDT,0,366", Error::InvalidRule),
+            ("EST5EDT,0,0365", Error::InvalidRule),
+            ("EST5EDT,M3.2.0/168,M11.1.0", Error::InvalidTime),
+            ("EST5EDT,M3.2.0/,M11.1.0", Error::InvalidTime),
+        ];
+        for (s, e) in errors.iter() {
+            assert_eq!(TimeZone::parse(s), Err(*e), "{}", s);
+        }
+    }
+
+    #[test]
+    fn transitions() {
+        // From the tz database
+        let transitions = [
+            (EASTERN, 1_552_201_200, -4 * 3600, "EDT"),
+            (EASTERN, 1_572_760_800, -5 * 3600, "EST"),
+            (EASTERN, 1_583_650_800, -4 * 3600, "EDT"),
+            (EASTERN, 1_604_210_400, -5 * 3600, "EST"),
+            (CENTRAL_EUROPE, 1_553_994_000, 2 * 3600, "CEST"),
+            (CENTRAL_EUROPE, 1_572_138_000, 3600, "CET"),
+            (CENTRAL_EUROPE, 1_585_443_600, 2 * 3600, "CEST"),
+            (CENTRAL_EUROPE, 1_603_587_600, 3600, "CET"),
+            (SYDNEY, 1_554_566_400, 10 * 3600, "AEST"),
+            (SYDNEY, 1_570_291_200, 11 * 3600, "AEDT"),
+            (SYDNEY, 1_586_016_000, 10 * 3600, "AEST"),
+            (SYDNEY, 1_601_740_800, 11 * 3600, "AEDT"),
+            (LORD_HOWE, 1_554_562_800, 10 * 3600 + 1800, "+1030"),
+            (LORD_HOWE, 1_570_289_400, 11 * 3600, "+11"),
+            (LORD_HOWE, 1_586_012_400, 10 * 3600 + 1800, "+1030"),
+            (LORD_HOWE, 1_601_739_000, 11 * 3600, "+11"),
+            (AUCKLAND, 1_554_559_200, 12 * 3600, "NZST"),
+            (AUCKLAND, 1_569_679_200, 13 * 3600, "NZDT"),
+            (AUCKLAND, 1_586_008_800, 12 * 3600, "NZST"),
+            (AUCKLAND, 1_601_128_800, 13 * 3600, "NZDT"),
+        ];
+        for (s, t, offset, abbreviation) in transitions.iter() {
+            let tz = TimeZone::parse(s).unwrap();
+            let dst = *offset != tz.offset;
+            assert_eq!(tz.offset(*t), *offset, "{} {}", s, t);
+            assert_eq!(tz.offset(t + 1), *offset, "{} {}", s, t);
+            assert_eq!(tz.is_dst(*t), dst, "{} {}", s, t);
+            assert_eq!(tz.is_dst(t - 1), !dst, "{} {}", s, t);
+            assert_ne!(tz.offset(t - 1), *offset, "{} {}", s, t);
+            assert_eq!(tz.abbreviation(*t), *abbreviation, "{} {}", s, t);
+            assert_ne!(tz.abbreviation(t - 1), *abbreviation, "{} {}", s, t);
+        }
+    }
+
+    #[test]
+    fn transitions_every_year() {
+        let sunday = |year, month, days: core::ops::RangeInclusive<u64>| {
+            days.map(|day| days_from_civil(year, month, day))
+                .find(|days| weekday_from_days(*days) == 0)
+                .unwrap() as i64
+                * SECONDS_PER_DAY
+        };
+        let check = |tz: &TimeZone, t: i64, dst: bool| {
+            assert_eq!(tz.is_dst(t as u64 - 1), !dst, "{}", t);
+            assert_eq!(tz.is_dst(t as u64), dst, "{}", t);
+        };
+        let eastern = TimeZone::parse(EASTERN).unwrap();
+        let central_europe = TimeZone::parse(CENTRAL_EUROPE).unwrap();
+        for year in 1970..2100 {
+            // Second Sunday of March at 02:00 EST, first Sunday of
+            // November at 02:00 EDT
+            check(&eastern, sunday(year, 3, 8..=14) + 7 * 3600, true);
+            check(&eastern, sunday(year, 11, 1..=7) + 6 * 3600, false);
+            // Last Sundays of March and October at 01:00 UTC
+            check(&central_europe, sunday(year, 3, 25..=31) + 3600, true);
+            check(&central_europe, sunday(year, 10, 25..=31) + 3600, false);
+        }
+    }
+
+    #[test]
+    fn day_rules() {
+        // Day 59 is February 29 in leap years, J60 is always March 1
+        let julian = TimeZone::parse("AAA0BBB,J60/0,J300").unwrap();
+        let day = TimeZone::parse("AAA0BBB,59/0,J300").unwrap();
+        let feb_29_2020 = 1_582_934_400;
+        let mar_1_2019 = 1_551_398_400;
+        assert_eq!(julian.is_dst(feb_29_2020), false);
+        assert_eq!(julian.is_dst(feb_29_2020 + 86_399), false);
+        assert_eq!(julian.is_dst(feb_29_2020 + 86_400), true);
+        assert_eq!(day.is_dst(feb_29_2020 - 1), false);
+        assert_eq!(day.is_dst(feb_29_2020), true);
+        assert_eq!(julian.is_dst(mar_1_2019 - 1), false);
+        assert_eq!(julian.is_dst(mar_1_2019), true);
+        assert_eq!(day.is_dst(mar_1_2019 - 1), false);
+        assert_eq!(day.is_dst(mar_1_2019), true);
+
+        // DST all year
+        let tz = TimeZone::parse("EST5EDT4,0/0,J365/25").unwrap();
+        for t in (86_400..4_102_444_800).step_by(86_399 * 3 + 7) {
+            assert_eq!(tz.is_dst(t), true);
+        }
+
+        // Transition times past the end of the day and negative
+        let tz = TimeZone::parse("AAA0BBB,M3.2.0/-2,M11.1.0/26").unwrap();
+        let mar_10_2019 = 1_552_176_000;
+        let nov_3_2019 = 1_572_739_200;
+        assert_eq!(tz.is_dst(mar_10_2019 - 2 * 3600 - 1), false);
+        assert_eq!(tz.is_dst(mar_10_2019 - 2 * 3600), true);
+        assert_eq!(tz.is_dst(nov_3_2019 + 25 * 3600 - 1), true);
+        assert_eq!(tz.is_dst(nov_3_2019 + 25 * 3600), false);
+    }
+
+    #[test]
+    fn to_local() {
+        let tz = TimeZone::parse(EASTERN).unwrap();
+        // Skips 02:00-02:59 EST
+        assert_eq!(tz.to_local(&utc(6, 59, 59)), local(10, 1, 59, 59));
+        assert_eq!(tz.to_local(&utc(7, 0, 0)), local(10, 3, 0, 0));
+        assert_eq!(tz.to_local(&utc(4, 59, 59)), local(9, 23, 59, 59));
+        assert_eq!(tz.to_local(&utc(5, 0, 0)), local(10, 0, 0, 0));
+
+        // Repeats 01:00-01:59
+        let nov_3 = |hour, minute, second| {
+            DateTime::from(ds323x::DateTime {
+                year: 2019,
+                month: 11,
+                day: 3,
+                weekday: 1,
+                hour: ds323x::Hours::H24(hour),
+                minute,
+                second,
+            })
+        };
+        assert_eq!(tz.to_local(&nov_3(5, 59, 59)), Some(nov_3(1, 59, 59)));
+        assert_eq!(tz.to_local(&nov_3(6, 0, 0)), Some(nov_3(1, 0, 0)));
+        assert_eq!(tz.to_local(&nov_3(7, 0, 0)), Some(nov_3(2, 0, 0)));
+
+        assert_eq!(TimeZone::utc().to_local(&utc(7, 0, 0)), Some(utc(7, 0, 0)));
+        assert_eq!(tz.to_local(&DateTime::default()), None);
+        assert_eq!(tz.to_local(&DateTime::from_unix_time(3600)), None);
+    }
+}
@@ -0,0 +1,366 @@
+//! DTMF digits as RFC 4733 telephone-events
+//!
+//! Each digit is sent as a start packet (marker set), continuation
+//! packets every frame with the growing duration, then three
+//! redundant end packets. All packets of a digit share the timestamp
+//! of its start. The local source's timestamp keeps advancing one
+//! frame per packet so audio picks up where the event left off.
+
+use crate::rtp::packet::Header;
+use crate::rtp::sequence::Sender;
+use crate::time::{Duration, Instant};
+use heapless::consts::U8;
+use heapless::spsc::Queue;
+
+/// telephone-event clock rate
+pub const CLOCK_RATE: u32 = 8000;
+
+/// Shortest digit sent, shorter key presses are stretched
+pub const MIN_DURATION: Duration = Duration::from_millis(100);
+
+/// Level of the tone, in -dBm0
+pub const VOLUME: u8 = 10;
+
+/// Redundant end packets
+pub const END_PACKETS: usize = 3;
+
+/// Event payload length
+pub const PAYLOAD_LEN: usize = 4;
+
+pub type MaxQueued = U8;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Not a DTMF digit
+    InvalidDigit(char),
+    /// Digits are queued faster than they're sent
+    QueueFull,
+    /// Remote didn't negotiate telephone-event
+    NotNegotiated,
+}
+
+/// RFC 4733 event code of a DTMF digit
+pub fn event_code(digit: char) -> Option<u8> {
+    match digit {
+        '0'..='9' => Some(digit as u8 - b'0'),
+        '*' => Some(10),
+        '#' => Some(11),
+        'A'..='D' => Some(digit as u8 - b'A' + 12),
+        _ => None,
+    }
+}
+
+/// Telephone-event payload (RFC 4733 section 2.3)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Event {
+    pub code: u8,
+    pub end: bool,
+    pub volume: u8,
+    /// In timestamp units
+    pub duration: u16,
+}
+
+impl Event {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < PAYLOAD_LEN {
+            return None;
+        }
+        Some(Event {
+            code: data[0],
+            end: data[1] & 0x80 != 0,
+            volume: data[1] & 0x3F,
+            duration: u16::from_be_bytes([data[2], data[3]]),
+        })
+    }
+
+    pub fn emit(&self) -> [u8; PAYLOAD_LEN] {
+        let duration = self.duration.to_be_bytes();
+        [
+            self.code,
+            (self.volume & 0x3F) | if self.end { 0x80 } else { 0 },
+            duration[0],
+            duration[1],
+        ]
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum State {
+    Idle,
+    Sending {
+        code: u8,
+        timestamp: u32,
+        duration: u32,
+        target: u32,
+    },
+    Ending {
+        code: u8,
+        timestamp: u32,
+        duration: u32,
+        remaining: usize,
+    },
+}
+
+/// Queues digits and paces out their packets
+pub struct DtmfSender {
+    payload_type: u8,
+    queue: Queue<(u8, Duration), MaxQueued>,
+    state: State,
+    next: Instant,
+}
+
+impl DtmfSender {
+    pub fn new(payload_type: u8) -> Self {
+        DtmfSender {
+            payload_type,
+            queue: Queue::new(),
+            state: State::Idle,
+            next: Instant::from_secs(0),
+        }
+    }
+
+    pub fn payload_type(&self) -> u8 {
+        self.payload_type
+    }
+
+    /// A digit is being sent (audio should be suppressed) or queued
+    pub fn is_active(&self) -> bool {
+        self.state != State::Idle || !self.queue.is_empty()
+    }
+
+    /// Queues a digit that was held for `held`
+    pub fn push(&mut self, digit: char, held: Duration) -> Result<(), Error> {
+        let code = event_code(digit).ok_or(Error::InvalidDigit(digit))?;
+        self.queue
+            .enqueue((code, held))
+            .map_err(|_| Error::QueueFull)
+    }
+
+    /// Stamps the next packet if one is due, one per frame of `sender`
+    pub fn poll(&mut self, time: Instant, sender: &mut Sender) -> Option<(Header, Event)> {
+        if time < self.next {
+            return None;
+        }
+        let frame = sender.samples_per_frame();
+        let (state, marker, event, timestamp) = match self.state {
+            State::Idle => {
+                let (code, held) = self.queue.dequeue()?;
+                let held = if held < MIN_DURATION {
+                    MIN_DURATION
+                } else {
+                    held
+                };
+                let target = (held.as_millis() as u32).saturating_mul(CLOCK_RATE / 1000);
+                let timestamp = sender.timestamp();
+                self.next = time;
+                (
+                    State::Sending {
+                        code,
+                        timestamp,
+                        duration: frame,
+                        target,
+                    },
+                    true,
+                    telephone_event(code, false, frame),
+                    timestamp,
+                )
+            }
+            State::Sending {
+                code,
+                timestamp,
+                duration,
+                target,
+            } => {
+                let duration = duration.saturating_add(frame);
+                if duration >= target {
+                    (
+                        State::Ending {
+                            code,
+                            timestamp,
+                            duration: target,
+                            remaining: END_PACKETS - 1,
+                        },
+                        false,
+                        telephone_event(code, true, target),
+                        timestamp,
+                    )
+                } else {
+                    (
+                        State::Sending {
+                            code,
+                            timestamp,
+                            duration,
+                            target,
+                        },
+                        false,
+                        telephone_event(code, false, duration),
+                        timestamp,
+                    )
+                }
+            }
+            State::Ending {
+                code,
+                timestamp,
+                duration,
+                remaining,
+            } => {
+                let state = if remaining > 1 {
+                    State::Ending {
+                        code,
+                        timestamp,
+                        duration,
+                        remaining: remaining - 1,
+                    }
+                } else {
+                    State::Idle
+                };
+                (
+                    state,
+                    false,
+                    telephone_event(code, true, duration),
+                    timestamp,
+                )
+            }
+        };
+
+        self.state = state;
+        self.next += Duration::from_micros(u64::from(frame) * 1_000_000 / u64::from(CLOCK_RATE));
+        let header = sender.header(self.payload_type, marker, timestamp, PAYLOAD_LEN);
+        // Audio time moves on underneath the event
+        sender.skip(1);
+        Some((header, event))
+    }
+}
+
+fn telephone_event(code: u8, end: bool, duration: u32) -> Event {
+    Event {
+        code,
+        end,
+        volume: VOLUME,
+        // Longer events would need segmenting, key presses are short
+        duration: if duration > u32::from(u16::max_value()) {
+            u16::max_value()
+        } else {
+            duration as u16
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::XorShift32;
+    use heapless::Vec;
+    use typenum::Unsigned;
+
+    fn run(
+        dtmf: &mut DtmfSender,
+        sender: &mut Sender,
+        start: Instant,
+        end: Instant,
+    ) -> Vec<(Instant, Header, Event), heapless::consts::U32> {
+        let mut packets = Vec::new();
+        let mut time = start;
+        while time < end {
+            while let Some((h, e)) = dtmf.poll(time, sender) {
+                packets.push((time, h, e)).unwrap();
+            }
+            time += Duration::from_millis(1);
+        }
+        packets
+    }
+
+    #[test]
+    fn event_codes() {
+        assert_eq!(event_code('0'), Some(0));
+        assert_eq!(event_code('9'), Some(9));
+        assert_eq!(event_code('*'), Some(10));
+        assert_eq!(event_code('#'), Some(11));
+        assert_eq!(event_code('A'), Some(12));
+        assert_eq!(event_code('D'), Some(15));
+        assert_eq!(event_code('x'), None);
+
+        let e = Event {
+            code: 11,
+            end: true,
+            volume: 10,
+            duration: 800,
+        };
+        assert_eq!(e.emit(), [0x0B, 0x8A, 0x03, 0x20]);
+        assert_eq!(Event::parse(&e.emit()), Some(e));
+        assert_eq!(Event::parse(&[0; 3]), None);
+    }
+
+    #[test]
+    fn digit_packets() {
+        let mut sender = Sender::new(&mut XorShift32::new(1), 0, 160);
+        let mut dtmf = DtmfSender::new(101);
+        assert_eq!(dtmf.is_active(), false);
+        let seq = sender.sequence();
+        let ts = sender.timestamp();
+
+        // Held for 120 ms, 960 samples
+        dtmf.push('5', Duration::from_millis(120)).unwrap();
+        assert_eq!(dtmf.is_active(), true);
+        let start = Instant::from_secs(1);
+        let packets = run(
+            &mut dtmf,
+            &mut sender,
+            start,
+            start + Duration::from_secs(1),
+        );
+        assert_eq!(dtmf.is_active(), false);
+
+        // Start, 4 continuations, 3 ends
+        assert_eq!(packets.len(), 8);
+        for (i, (time, h, e)) in packets.iter().enumerate() {
+            assert_eq!(*time, start + Duration::from_millis(20 * i as u64));
+            assert_eq!(h.payload_type, 101);
+            assert_eq!(h.marker, i == 0);
+            assert_eq!(h.sequence, seq.wrapping_add(i as u16));
+            assert_eq!(h.timestamp, ts);
+            assert_eq!(e.code, 5);
+            assert_eq!(e.volume, VOLUME);
+            assert_eq!(e.end, i >= 5);
+            let duration = if i < 5 { 160 * (i as u16 + 1) } else { 960 };
+            assert_eq!(e.duration, duration);
+        }
+        // Audio resumes after the event
+        assert_eq!(sender.timestamp(), ts.wrapping_add(8 * 160));
+    }
+
+    #[test]
+    fn short_press_and_queueing() {
+        let mut sender = Sender::new(&mut XorShift32::new(1), 0, 160);
+        let mut dtmf = DtmfSender::new(101);
+        assert_eq!(dtmf.push('x', MIN_DURATION), Err(Error::InvalidDigit('x')));
+
+        dtmf.push('1', Duration::from_millis(10)).unwrap();
+        dtmf.push('#', Duration::from_millis(10)).unwrap();
+        let start = Instant::from_secs(1);
+        let packets = run(
+            &mut dtmf,
+            &mut sender,
+            start,
+            start + Duration::from_secs(1),
+        );
+
+        // Stretched to 100 ms: start, 3 continuations, 3 ends each
+        assert_eq!(packets.len(), 14);
+        let (first, second) = packets.split_at(7);
+        assert_eq!(first.iter().all(|(_, _, e)| e.code == 1), true);
+        assert_eq!(second.iter().all(|(_, _, e)| e.code == 11), true);
+        assert_eq!(first[6].2.duration, 800);
+        assert_eq!(second[0].1.marker, true);
+        assert_eq!(
+            second[0].1.timestamp,
+            first[0].1.timestamp.wrapping_add(7 * 160)
+        );
+
+        for i in 0..MaxQueued::to_usize() {
+            dtmf.push('0', MIN_DURATION).unwrap();
+            assert_eq!(dtmf.is_active(), true, "{}", i);
+        }
+        assert_eq!(dtmf.push('0', MIN_DURATION), Err(Error::QueueFull));
+    }
+}
@@ -0,0 +1,542 @@
+//! Adaptive jitter buffer for received audio frames
+//!
+//! Frames are held by sequence number and played out one per frame
+//! interval once the buffer holds the target delay. The target follows
+//! the interarrival jitter (RFC 3550 appendix A.8): it grows by
+//! rebuffering when the buffer runs dry or a talkspurt starts, and
+//! shrinks by discarding frames when the buffer runs too deep.
+//!
+//! Frames that never arrived are handed to a `Concealment` in their
+//! place.
+
+use crate::codec::{self, Codec, Concealment};
+use crate::rtp::packet::Header;
+use crate::time::{Duration, Instant};
+use heapless::consts::{U16, U320};
+use heapless::Vec;
+
+/// Fewest frames buffered before playing out
+pub const MIN_DELAY_FRAMES: usize = 2;
+
+/// Most frames buffered before playing out
+pub const MAX_DELAY_FRAMES: usize = 12;
+
+/// Frames above the target that are tolerated before discarding
+pub const DELAY_HYSTERESIS: usize = 2;
+
+pub type MaxFrames = U16;
+
+/// 40 ms of G.711
+pub type MaxFrameSize = U320;
+
+pub type FrameData = Vec<u8, MaxFrameSize>;
+
+/// What happened to a pushed frame
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Push {
+    Queued,
+    /// Its playout time has passed
+    Late,
+    Duplicate,
+    /// No room, or too large for a slot
+    Discarded,
+}
+
+/// What to play for the current frame interval
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Playout<'a> {
+    Frame(&'a [u8]),
+    /// A frame was expected but isn't here, conceal it
+    Missing,
+    /// Nothing received yet, or filling up after a silence
+    Silence,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct Stats {
+    /// Frames played out
+    pub played: u32,
+    /// Arrived after their playout time
+    pub late: u32,
+    /// Never arrived
+    pub lost: u32,
+    /// Dropped to shrink the delay or for lack of room
+    pub discarded: u32,
+    /// Interarrival jitter estimate
+    pub jitter: Duration,
+    /// Target playout delay
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    sequence: u16,
+    marker: bool,
+    data: FrameData,
+}
+
+#[derive(Debug, Clone)]
+pub struct JitterBuffer {
+    clock_rate: u32,
+    samples_per_frame: u32,
+    slots: Vec<Slot, MaxFrames>,
+    /// Frame being played out
+    current: FrameData,
+    /// Sequence number of the next frame to play, once started
+    next: Option<u16>,
+    playing: bool,
+    target: usize,
+    /// Relative transit time of the last arrival, timestamp units
+    transit: Option<u32>,
+    /// Scaled by 16, timestamp units
+    jitter: u32,
+    stats: Stats,
+}
+
+impl JitterBuffer {
+    pub fn new(clock_rate: u32, samples_per_frame: u32) -> Self {
+        let mut jb = JitterBuffer {
+            clock_rate,
+            samples_per_frame,
+            slots: Vec::new(),
+            current: FrameData::new(),
+            next: None,
+            playing: false,
+            target: MIN_DELAY_FRAMES,
+            transit: None,
+            jitter: 0,
+            stats: Stats::default(),
+        };
+        jb.stats.delay = jb.frames_to_duration(jb.target as u32);
+        jb
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Frames buffered
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Target playout delay in frames
+    pub fn target(&self) -> usize {
+        self.target
+    }
+
+    /// Interarrival jitter in timestamp units
+    pub fn jitter(&self) -> u32 {
+        self.jitter >> 4
+    }
+
+    /// Drops the queued frames and plays from the next one pushed once
+    /// the target is buffered again, for a source that restarted its
+    /// sequence. The stats and delay carry on.
+    pub fn reset(&mut self) {
+        self.slots.clear();
+        self.next = None;
+        self.playing = false;
+        // New timestamps aren't comparable with the old
+        self.transit = None;
+    }
+
+    /// Adds a received audio frame
+    pub fn push(&mut self, time: Instant, header: &Header, payload: &[u8]) -> Push {
+        self.update_jitter(time, header.timestamp);
+
+        let seq = header.sequence;
+        if let Some(next) = self.next {
+            if is_before(seq, next) {
+                self.stats.late = self.stats.late.wrapping_add(1);
+                // Was counted lost when its turn came
+                self.stats.lost = self.stats.lost.saturating_sub(1);
+                return Push::Late;
+            }
+        }
+        if self.slots.iter().any(|s| s.sequence == seq) {
+            return Push::Duplicate;
+        }
+
+        let mut data = FrameData::new();
+        if data.extend_from_slice(payload).is_err() {
+            self.stats.discarded = self.stats.discarded.wrapping_add(1);
+            return Push::Discarded;
+        }
+        if self.slots.len() == self.slots.capacity() {
+            let oldest = self.oldest().map(|i| self.slots[i].sequence);
+            match oldest {
+                Some(oldest) if is_before(oldest, seq) => self.discard(oldest),
+                _ => {
+                    self.stats.discarded = self.stats.discarded.wrapping_add(1);
+                    return Push::Discarded;
+                }
+            }
+        }
+        // Can't overflow, made room above
+        self.slots
+            .push(Slot {
+                sequence: seq,
+                marker: header.marker,
+                data,
+            })
+            .unwrap();
+        Push::Queued
+    }
+
+    /// Next thing to play, call once per frame interval
+    pub fn pop(&mut self) -> Playout<'_> {
+        if !self.playing {
+            if self.slots.len() < self.target {
+                return if self.next.is_some() {
+                    // Rebuffering mid-stream
+                    Playout::Missing
+                } else {
+                    Playout::Silence
+                };
+            }
+            self.playing = true;
+            if self.next.is_none() {
+                self.next = self.oldest().map(|i| self.slots[i].sequence);
+            }
+        }
+
+        // Run too deep, catch up
+        while self.slots.len() > self.target + DELAY_HYSTERESIS {
+            if let Some(next) = self.next {
+                if self.slots.iter().any(|s| s.sequence == next) {
+                    self.discard(next);
+                } else if let Some(oldest) = self.oldest().map(|i| self.slots[i].sequence) {
+                    // Skip to what's queued, the frames between never
+                    // arrived
+                    let skipped = oldest.wrapping_sub(next);
+                    self.stats.lost = self.stats.lost.wrapping_add(u32::from(skipped));
+                    self.next = Some(oldest);
+                }
+            }
+        }
+
+        // Can't be none, set when playing started
+        let next = self.next.unwrap();
+        match self.slots.iter().position(|s| s.sequence == next) {
+            Some(index) => {
+                let slot = self.slots.swap_remove(index);
+                if slot.marker && self.slots.is_empty() {
+                    // Start of a talkspurt, a chance to adapt the delay
+                    self.playing = self.target <= 1;
+                }
+                self.current = slot.data;
+                self.next = Some(next.wrapping_add(1));
+                self.stats.played = self.stats.played.wrapping_add(1);
+                Playout::Frame(&self.current)
+            }
+            None => {
+                if self.slots.is_empty() {
+                    // Ran dry, wait for the target delay again
+                    self.playing = false;
+                }
+                self.missed(next);
+                Playout::Missing
+            }
+        }
+    }
+
+    /// Pops the next frame and decodes it, or conceals it when it's
+    /// missing. Returns the samples written to `pcm`.
+    pub fn play_out<C: Codec, P: Concealment>(
+        &mut self,
+        codec: &mut C,
+        concealment: &mut P,
+        pcm: &mut [i16],
+    ) -> Result<usize, codec::Error> {
+        let samples = codec.frame_samples();
+        if pcm.len() < samples {
+            return Err(codec::Error::BufferTooSmall);
+        }
+        let pcm = &mut pcm[..samples];
+        match self.pop() {
+            Playout::Frame(data) => {
+                let len = codec.decode(data, pcm)?;
+                // Pad short frames with silence
+                for s in pcm[len..].iter_mut() {
+                    *s = 0;
+                }
+                concealment.received(pcm);
+            }
+            Playout::Missing => concealment.conceal(pcm),
+            Playout::Silence => {
+                for s in pcm.iter_mut() {
+                    *s = 0;
+                }
+                concealment.received(pcm);
+            }
+        }
+        Ok(samples)
+    }
+
+    fn missed(&mut self, seq: u16) {
+        self.stats.lost = self.stats.lost.wrapping_add(1);
+        self.next = Some(seq.wrapping_add(1));
+    }
+
+    fn discard(&mut self, seq: u16) {
+        if let Some(index) = self.slots.iter().position(|s| s.sequence == seq) {
+            self.slots.swap_remove(index);
+            self.stats.discarded = self.stats.discarded.wrapping_add(1);
+        }
+        if self.next == Some(seq) {
+            self.next = Some(seq.wrapping_add(1));
+        }
+    }
+
+    /// Index of the slot with the earliest sequence number
+    fn oldest(&self) -> Option<usize> {
+        let mut oldest: Option<usize> = None;
+        for (index, slot) in self.slots.iter().enumerate() {
+            match oldest {
+                Some(o) if !is_before(slot.sequence, self.slots[o].sequence) => (),
+                _ => oldest = Some(index),
+            }
+        }
+        oldest
+    }
+
+    fn update_jitter(&mut self, time: Instant, timestamp: u32) {
+        let arrival = (time.as_micros() as u64)
+            .wrapping_mul(u64::from(self.clock_rate))
+            .wrapping_div(1_000_000) as u32;
+        let transit = arrival.wrapping_sub(timestamp);
+        if let Some(prev) = self.transit {
+            let d = (transit.wrapping_sub(prev) as i32).wrapping_abs() as u32;
+            // J += (|D| - J) / 16, with J scaled by 16
+            self.jitter = self
+                .jitter
+                .wrapping_add(d)
+                .wrapping_sub(self.jitter.wrapping_add(8) >> 4);
+        }
+        self.transit = Some(transit);
+
+        // Enough to cover three deviations, plus the frame in flight
+        let jitter_frames =
+            (3 * self.jitter() + self.samples_per_frame - 1) / self.samples_per_frame.max(1);
+        let target = (jitter_frames as usize + 1).max(MIN_DELAY_FRAMES);
+        self.target = target.min(MAX_DELAY_FRAMES);
+        self.stats.jitter = Duration::from_micros(
+            u64::from(self.jitter()) * 1_000_000 / u64::from(self.clock_rate),
+        );
+        self.stats.delay = self.frames_to_duration(self.target as u32);
+    }
+
+    fn frames_to_duration(&self, frames: u32) -> Duration {
+        Duration::from_micros(
+            u64::from(frames * self.samples_per_frame) * 1_000_000 / u64::from(self.clock_rate),
+        )
+    }
+}
+
+/// Sequence number `a` comes before `b`, across wraps
+fn is_before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::g711::{Law, G711};
+    use crate::codec::plc::Silence;
+
+    const FRAME: Duration = Duration::from_millis(20);
+
+    fn header(sequence: u16) -> Header {
+        Header {
+            marker: false,
+            payload_type: 0,
+            sequence,
+            timestamp: u32::from(sequence) * 160,
+            ssrc: 1,
+        }
+    }
+
+    fn at(frame: u16) -> Instant {
+        Instant::from_secs(1) + FRAME * u32::from(frame)
+    }
+
+    fn played(p: Playout) -> Option<u8> {
+        match p {
+            Playout::Frame(data) => Some(data[0]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn buffers_to_target_then_plays_in_order() {
+        let mut jb = JitterBuffer::new(8000, 160);
+        assert_eq!(jb.pop(), Playout::Silence);
+
+        jb.push(at(0), &header(0), &[0]);
+        assert_eq!(jb.pop(), Playout::Silence);
+        // Reordered
+        assert_eq!(jb.push(at(2), &header(2), &[2]), Push::Queued);
+        assert_eq!(jb.push(at(2), &header(1), &[1]), Push::Queued);
+        assert_eq!(jb.push(at(2), &header(1), &[1]), Push::Duplicate);
+
+        assert_eq!(played(jb.pop()), Some(0));
+        assert_eq!(played(jb.pop()), Some(1));
+        assert_eq!(played(jb.pop()), Some(2));
+        assert_eq!(jb.stats().played, 3);
+        // Frame 1 came in 20 ms late
+        assert_eq!(jb.stats().jitter, Duration::from_micros(1125));
+        assert_eq!(jb.target(), MIN_DELAY_FRAMES);
+    }
+
+    #[test]
+    fn lost_and_late() {
+        let mut jb = JitterBuffer::new(8000, 160);
+        for seq in [0, 1, 3, 4].iter() {
+            jb.push(at(*seq), &header(*seq), &[*seq as u8]);
+        }
+        assert_eq!(played(jb.pop()), Some(0));
+        assert_eq!(played(jb.pop()), Some(1));
+        assert_eq!(jb.pop(), Playout::Missing);
+        assert_eq!(jb.stats().lost, 1);
+        assert_eq!(played(jb.pop()), Some(3));
+
+        // Turns up after its turn
+        assert_eq!(jb.push(at(5), &header(2), &[2]), Push::Late);
+        assert_eq!(jb.stats().lost, 0);
+        assert_eq!(jb.stats().late, 1);
+        assert_eq!(played(jb.pop()), Some(4));
+    }
+
+    #[test]
+    fn adapts_to_jitter() {
+        let mut jb = JitterBuffer::new(8000, 160);
+        // Alternating 0 and 60 ms late
+        for seq in 0..100u16 {
+            let delay = if seq % 2 == 0 {
+                Duration::from_millis(0)
+            } else {
+                Duration::from_millis(60)
+            };
+            jb.push(at(seq) + delay, &header(seq), &[0]);
+        }
+        assert!(jb.stats().jitter > Duration::from_millis(40));
+        assert!(jb.target() > MIN_DELAY_FRAMES);
+        assert!(jb.target() <= MAX_DELAY_FRAMES);
+        assert_eq!(jb.stats().delay, FRAME * jb.target() as u32);
+    }
+
+    #[test]
+    fn transit_jump() {
+        let mut jb = JitterBuffer::new(8000, 160);
+        jb.push(at(0), &header(0), &[0]);
+        // Timestamp half the clock space away, |D| is 2^31
+        let jumped = Header {
+            timestamp: 160u32.wrapping_add(0x8000_0000),
+            ..header(1)
+        };
+        assert_eq!(jb.push(at(1), &jumped, &[1]), Push::Queued);
+        assert_eq!(jb.stats().jitter, Duration::from_micros(16_777_216_000));
+        assert_eq!(jb.target(), MAX_DELAY_FRAMES);
+    }
+
+    #[test]
+    fn discards_when_too_deep() {
+        let mut jb = JitterBuffer::new(8000, 160);
+        // A burst of frames all at once
+        for seq in 0..10u16 {
+            jb.push(at(0), &header(seq), &[seq as u8]);
+        }
+        let first = played(jb.pop()).unwrap();
+        assert!(first > 0);
+        assert_eq!(jb.len(), jb.target() + DELAY_HYSTERESIS - 1);
+        assert_eq!(jb.stats().discarded, u32::from(first));
+        assert_eq!(jb.stats().lost, 0);
+    }
+
+    #[test]
+    fn sequence_jumps() {
+        let mut jb = JitterBuffer::new(8000, 160);
+        jb.push(at(0), &header(0), &[0]);
+        jb.push(at(1), &header(1), &[1]);
+        assert_eq!(played(jb.pop()), Some(0));
+
+        // Far ahead, skipped to at once rather than a frame at a time
+        for i in 0..6u16 {
+            let header = Header {
+                sequence: 1000 + i,
+                ..header(2 + i)
+            };
+            assert_eq!(jb.push(at(2 + i), &header, &[10 + i as u8]), Push::Queued);
+        }
+        assert_eq!(played(jb.pop()), Some(12));
+        assert_eq!(jb.stats().lost, 998);
+        assert_eq!(jb.stats().discarded, 3);
+        assert_eq!(played(jb.pop()), Some(13));
+
+        // Restarted behind, played from the new sequence
+        jb.reset();
+        assert_eq!(jb.is_empty(), true);
+        assert_eq!(jb.pop(), Playout::Silence);
+        for i in 0..3u16 {
+            let header = Header {
+                sequence: 500 + i,
+                timestamp: 0x8000_0000 + u32::from(i) * 160,
+                ..header(0)
+            };
+            assert_eq!(jb.push(at(8 + i), &header, &[20 + i as u8]), Push::Queued);
+        }
+        assert_eq!(played(jb.pop()), Some(20));
+        assert_eq!(played(jb.pop()), Some(21));
+        assert_eq!(played(jb.pop()), Some(22));
+        assert_eq!(jb.stats().lost, 998);
+        assert_eq!(jb.stats().late, 0);
+        assert_eq!(jb.stats().jitter, Duration::from_micros(0));
+    }
+
+    #[test]
+    fn full_and_oversized() {
+        let mut jb = JitterBuffer::new(8000, 160);
+        for seq in 0..16u16 {
+            assert_eq!(jb.push(at(0), &header(seq + 1), &[0]), Push::Queued);
+        }
+        // Older than everything buffered
+        assert_eq!(jb.push(at(0), &header(0), &[0]), Push::Discarded);
+        // Newer replaces the oldest
+        assert_eq!(jb.push(at(0), &header(17), &[0]), Push::Queued);
+        assert_eq!(jb.stats().discarded, 2);
+        assert_eq!(jb.push(at(0), &header(18), &[0; 400]), Push::Discarded);
+    }
+
+    #[test]
+    fn underrun_rebuffers_with_concealment() {
+        let mut jb = JitterBuffer::new(8000, 160);
+        let mut codec = G711::new(Law::MuLaw, 20);
+        let mut pcm = [1; 160];
+        assert_eq!(jb.play_out(&mut codec, &mut Silence, &mut pcm), Ok(160));
+        assert_eq!(pcm.iter().all(|s| *s == 0), true);
+
+        jb.push(at(0), &header(0), &[0x80; 160]);
+        jb.push(at(1), &header(1), &[0x80; 160]);
+        jb.play_out(&mut codec, &mut Silence, &mut pcm).unwrap();
+        assert_eq!(pcm[0], 32124);
+        jb.play_out(&mut codec, &mut Silence, &mut pcm).unwrap();
+        // Dry, concealed until the target is buffered again
+        assert_eq!(jb.pop(), Playout::Missing);
+        jb.push(at(3), &header(3), &[3]);
+        assert_eq!(jb.pop(), Playout::Missing);
+        jb.push(at(4), &header(4), &[4]);
+        assert_eq!(played(jb.pop()), Some(3));
+        assert_eq!(jb.stats().lost, 1);
+
+        let mut short = [0; 80];
+        assert_eq!(
+            jb.play_out(&mut codec, &mut Silence, &mut short),
+            Err(codec::Error::BufferTooSmall)
+        );
+    }
+}
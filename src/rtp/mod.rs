@@ -0,0 +1,10 @@
+//! Real-time Transport Protocol (RFC 3550) media path
+
+pub mod dtmf;
+pub mod jitter_buffer;
+pub mod packet;
+pub mod quality;
+pub mod report;
+pub mod rtcp;
+pub mod sequence;
+pub mod session;
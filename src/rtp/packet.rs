@@ -0,0 +1,202 @@
+//! RTP fixed header (RFC 3550 section 5.1)
+//!
+//! Parsing accepts CSRC lists, header extensions and padding but
+//! emitting only produces the 12 byte fixed header, we never mix.
+
+use core::convert::TryInto;
+
+pub const VERSION: u8 = 2;
+
+/// Fixed header length, without CSRCs or extension
+pub const HEADER_LEN: usize = 12;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Shorter than the header says it is
+    Truncated,
+    InvalidVersion,
+    /// Padding count larger than the payload
+    InvalidPadding,
+    /// Emit buffer too small for the header and payload
+    BufferTooSmall,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Header {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl Header {
+    /// Writes the fixed header to the start of `buf`, returns its length
+    pub fn emit(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        // No padding, extension or CSRCs
+        buf[0] = VERSION << 6;
+        buf[1] = (self.payload_type & 0x7F) | if self.marker { 0x80 } else { 0 };
+        buf[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        Ok(HEADER_LEN)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Packet<'a> {
+    pub header: Header,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn new(header: Header, payload: &'a [u8]) -> Self {
+        Packet { header, payload }
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if data[0] >> 6 != VERSION {
+            return Err(Error::InvalidVersion);
+        }
+        let padding = data[0] & 0x20 != 0;
+        let extension = data[0] & 0x10 != 0;
+        let csrc_count = usize::from(data[0] & 0x0F);
+
+        let header = Header {
+            marker: data[1] & 0x80 != 0,
+            payload_type: data[1] & 0x7F,
+            sequence: read_u16(&data[2..4]),
+            timestamp: read_u32(&data[4..8]),
+            ssrc: read_u32(&data[8..12]),
+        };
+
+        let mut start = HEADER_LEN + (4 * csrc_count);
+        if extension {
+            if data.len() < start + 4 {
+                return Err(Error::Truncated);
+            }
+            // Profile specific ID followed by the length in 32-bit words
+            let words = usize::from(read_u16(&data[start + 2..start + 4]));
+            start += 4 + (4 * words);
+        }
+        if data.len() < start {
+            return Err(Error::Truncated);
+        }
+
+        let mut end = data.len();
+        if padding {
+            // Last octet is the padding count, including itself
+            let count = usize::from(data[end - 1]);
+            if count == 0 || count > end - start {
+                return Err(Error::InvalidPadding);
+            }
+            end -= count;
+        }
+
+        Ok(Packet {
+            header,
+            payload: &data[start..end],
+        })
+    }
+
+    /// Writes the header and payload to `buf`, returns the packet length
+    pub fn emit(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = HEADER_LEN + self.payload.len();
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+        self.header.emit(buf)?;
+        buf[HEADER_LEN..len].copy_from_slice(self.payload);
+        Ok(len)
+    }
+}
+
+// Callers have checked the length
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_be_bytes(data[..2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: Header = Header {
+        marker: true,
+        payload_type: 8,
+        sequence: 0x1234,
+        timestamp: 0xDEAD_BEEF,
+        ssrc: 0x0102_0304,
+    };
+
+    #[test]
+    fn emit_parse() {
+        let payload = [0xD5; 160];
+        let mut buf = [0; 256];
+        let len = Packet::new(HEADER, &payload).emit(&mut buf).unwrap();
+        assert_eq!(len, HEADER_LEN + 160);
+        assert_eq!(
+            &buf[..HEADER_LEN],
+            &[0x80, 0x88, 0x12, 0x34, 0xDE, 0xAD, 0xBE, 0xEF, 0x01, 0x02, 0x03, 0x04]
+        );
+
+        let p = Packet::parse(&buf[..len]).unwrap();
+        assert_eq!(p.header, HEADER);
+        assert_eq!(p.payload, &payload[..]);
+
+        assert_eq!(
+            Packet::new(HEADER, &payload).emit(&mut buf[..100]),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn parse_csrc_extension_padding() {
+        let data = [
+            // V=2, P, X, CC=1
+            0xB1, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xA0, 0x00, 0x00, 0x00, 0x05,
+            // CSRC
+            0x00, 0x00, 0x00, 0x06, // Extension, 1 word
+            0xBE, 0xDE, 0x00, 0x01, 0x11, 0x22, 0x33, 0x44, // Payload
+            0xAA, 0xBB, 0xCC, // Padding
+            0x00, 0x00, 0x03,
+        ];
+        let p = Packet::parse(&data).unwrap();
+        assert_eq!(p.header.marker, false);
+        assert_eq!(p.header.payload_type, 0);
+        assert_eq!(p.header.sequence, 1);
+        assert_eq!(p.header.timestamp, 160);
+        assert_eq!(p.header.ssrc, 5);
+        assert_eq!(p.payload, &[0xAA, 0xBB, 0xCC]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut buf = [0; HEADER_LEN];
+        HEADER.emit(&mut buf).unwrap();
+        assert_eq!(Packet::parse(&buf[..11]), Err(Error::Truncated));
+
+        let mut bad = buf;
+        bad[0] = 0x40;
+        assert_eq!(Packet::parse(&bad), Err(Error::InvalidVersion));
+
+        let mut bad = buf;
+        bad[0] |= 0x03;
+        assert_eq!(Packet::parse(&bad), Err(Error::Truncated));
+
+        let mut bad = [0; HEADER_LEN + 2];
+        bad[..HEADER_LEN].copy_from_slice(&buf);
+        bad[0] |= 0x20;
+        bad[HEADER_LEN + 1] = 3;
+        assert_eq!(Packet::parse(&bad), Err(Error::InvalidPadding));
+    }
+}
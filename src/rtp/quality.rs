@@ -0,0 +1,140 @@
+//! Call quality estimate
+//!
+//! The E-model (ITU-T G.107) rates a call from its mouth-to-ear delay
+//! and packet loss, with everything else left at the default values.
+//! The delay impairment uses the Cole-Rosenbluth approximation, and the
+//! codec's loss robustness comes from G.113 appendix I.
+
+use crate::time::Duration;
+
+/// R-factor of a perfect call with default G.107 parameters
+pub const R_MAX: f32 = 93.2;
+
+/// Equipment impairment of G.711
+pub const G711_IE: f32 = 0.0;
+
+/// Packet loss robustness of G.711 with appendix I concealment
+pub const G711_PLC_BPL: f32 = 25.1;
+
+/// Quality of the received media, from RTCP
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct Quality {
+    /// Lost over the last report interval, out of 256
+    pub fraction_lost: u8,
+    /// Lost since the call started
+    pub cumulative_lost: u32,
+    /// Interarrival jitter
+    pub jitter: Duration,
+    /// Round-trip time, once the remote has reported on one of our
+    /// sender reports
+    pub rtt: Option<Duration>,
+    /// E-model transmission rating, 0 to 93
+    pub r_factor: u8,
+    /// Estimated mean opinion score in tenths, 10 to 44
+    pub mos: u8,
+}
+
+impl Quality {
+    /// `delay` is the one-way mouth-to-ear delay
+    pub fn new(
+        fraction_lost: u8,
+        cumulative_lost: u32,
+        jitter: Duration,
+        rtt: Option<Duration>,
+        delay: Duration,
+    ) -> Self {
+        let loss = f32::from(fraction_lost) / 256.0;
+        let r = r_factor(delay, loss, G711_IE, G711_PLC_BPL);
+        Quality {
+            fraction_lost,
+            cumulative_lost,
+            jitter,
+            rtt,
+            // Rounded, both are in range
+            r_factor: (r + 0.5) as u8,
+            mos: (mos(r) * 10.0 + 0.5) as u8,
+        }
+    }
+}
+
+/// Transmission rating of a call with `delay` one-way and `loss` (0 to
+/// 1) of the packets lost at random, with the codec's equipment
+/// impairment `ie` and loss robustness `bpl`
+pub fn r_factor(delay: Duration, loss: f32, ie: f32, bpl: f32) -> f32 {
+    let d = delay.as_micros() as f32 / 1000.0;
+    let mut id = 0.024 * d;
+    if d > 177.3 {
+        id += 0.11 * (d - 177.3);
+    }
+    let ppl = 100.0 * loss.max(0.0).min(1.0);
+    let ie_eff = ie + (95.0 - ie) * ppl / (ppl + bpl);
+    (R_MAX - id - ie_eff).max(0.0).min(100.0)
+}
+
+/// Mean opinion score, 1 to 4.5, of a transmission rating
+pub fn mos(r: f32) -> f32 {
+    if r <= 0.0 {
+        1.0
+    } else if r >= 100.0 {
+        4.5
+    } else {
+        1.0 + 0.035 * r + r * (r - 60.0) * (100.0 - r) * 7.0e-6
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(a: f32, b: f32) -> bool {
+        let d = a - b;
+        d < 0.05 && d > -0.05
+    }
+
+    #[test]
+    fn ratings() {
+        let r = r_factor(Duration::from_millis(0), 0.0, G711_IE, G711_PLC_BPL);
+        assert!(near(r, 93.2), "{}", r);
+        assert!(near(mos(r), 4.41), "{}", mos(r));
+
+        // Delay costs little until about 177 ms
+        let r = r_factor(Duration::from_millis(150), 0.0, G711_IE, G711_PLC_BPL);
+        assert!(near(r, 89.6), "{}", r);
+        let r = r_factor(Duration::from_millis(300), 0.0, G711_IE, G711_PLC_BPL);
+        assert!(near(r, 72.5), "{}", r);
+
+        // 5% loss with concealment, and without
+        let r = r_factor(Duration::from_millis(0), 0.05, G711_IE, G711_PLC_BPL);
+        assert!(near(r, 77.42), "{}", r);
+        let r = r_factor(Duration::from_millis(0), 0.05, G711_IE, 4.3);
+        assert!(r < 50.0, "{}", r);
+
+        assert_eq!(r_factor(Duration::from_secs(2), 1.0, 0.0, 1.0), 0.0);
+        assert_eq!(mos(0.0), 1.0);
+        assert_eq!(mos(100.0), 4.5);
+    }
+
+    #[test]
+    fn quality_rounds() {
+        let q = Quality::new(
+            0,
+            0,
+            Duration::from_millis(2),
+            None,
+            Duration::from_millis(60),
+        );
+        assert_eq!(q.r_factor, 92);
+        assert_eq!(q.mos, 44);
+
+        // A quarter lost
+        let q = Quality::new(
+            64,
+            100,
+            Duration::from_millis(2),
+            None,
+            Duration::from_millis(60),
+        );
+        assert_eq!(q.r_factor, 44);
+        assert_eq!(q.mos, 23);
+    }
+}
@@ -0,0 +1,498 @@
+//! RTCP reporting for one session
+//!
+//! Every report interval the local source sends a compound packet: a
+//! sender report once it has sent media, a receiver report before
+//! that, either with a report block about the remote source, then an
+//! SDES with the CNAME. The interval is randomized between half and one
+//! and a half times `REPORT_INTERVAL` (RFC 3550 section 6.3), the first
+//! report going out after half of it.
+//!
+//! Reports from the remote give the round-trip time (RFC 3550 section
+//! 6.4.1). There's no wallclock, NTP timestamps count from boot, which
+//! is all the round-trip time needs.
+
+use crate::random::{RandomSource, XorShift32};
+use crate::rtp::quality::Quality;
+use crate::rtp::rtcp::{self, Compound, Packet, ReportBlock, ReportBlocks, SenderInfo};
+use crate::rtp::sequence::{Receiver, Sender};
+use crate::time::{Duration, Instant};
+
+/// Nominal time between reports
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Random CNAME length, in hex digits (RFC 7022)
+pub const CNAME_LEN: usize = 16;
+
+/// Largest compound packet sent
+pub const MAX_PACKET_SIZE: usize = 128;
+
+/// Reception state of the remote source at the previous report
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+struct Interval {
+    expected: u32,
+    received: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reporter {
+    rng: XorShift32,
+    cname: [u8; CNAME_LEN],
+    next_report: Option<Instant>,
+    prior: Interval,
+    fraction_lost: u8,
+    /// Middle 32 bits of the remote's last SR timestamp, and its arrival
+    last_sr: Option<(u32, Instant)>,
+    /// What the remote last reported about our stream
+    remote_report: Option<ReportBlock>,
+    rtt: Option<Duration>,
+    reported: bool,
+    bye: bool,
+}
+
+impl Reporter {
+    pub fn new<R: RandomSource>(rng: &mut R) -> Self {
+        let mut cname = [0; CNAME_LEN];
+        for pair in cname.chunks_mut(8) {
+            let mut bits = rng.next_u32();
+            for c in pair.iter_mut() {
+                *c = b"0123456789abcdef"[(bits & 0xF) as usize];
+                bits >>= 4;
+            }
+        }
+        Reporter {
+            rng: XorShift32::new(rng.next_u32()),
+            cname,
+            next_report: None,
+            prior: Interval::default(),
+            fraction_lost: 0,
+            last_sr: None,
+            remote_report: None,
+            rtt: None,
+            reported: false,
+            bye: false,
+        }
+    }
+
+    /// Canonical name of the local source
+    pub fn cname(&self) -> &str {
+        // Can't fail, hex digits
+        core::str::from_utf8(&self.cname).unwrap()
+    }
+
+    /// Round-trip time to the remote, once it has reported on one of
+    /// our sender reports
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// The remote's last report about the local source
+    pub fn remote_report(&self) -> Option<&ReportBlock> {
+        self.remote_report.as_ref()
+    }
+
+    /// Lost from the remote source over the last interval, out of 256
+    pub fn fraction_lost(&self) -> u8 {
+        self.fraction_lost
+    }
+
+    /// The remote sent a BYE
+    pub fn is_bye(&self) -> bool {
+        self.bye
+    }
+
+    /// Whether a report should be sent now
+    pub fn is_due(&mut self, time: Instant) -> bool {
+        match self.next_report {
+            Some(t) => time >= t,
+            None => {
+                self.next_report = Some(time + REPORT_INTERVAL / 2);
+                false
+            }
+        }
+    }
+
+    /// Writes a compound report to `buf` and schedules the next one,
+    /// returns its length. `jitter` is the remote source's interarrival
+    /// jitter, in timestamp units.
+    pub fn report(
+        &mut self,
+        time: Instant,
+        sender: &Sender,
+        receiver: &Receiver,
+        jitter: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, rtcp::Error> {
+        let len = self.emit_report(time, sender, receiver, jitter, buf)?;
+        // 0.5 to 1.5 times the interval, in milliseconds
+        let interval = REPORT_INTERVAL.as_millis() as u32;
+        let ms = interval / 2 + self.rng.next_u32() % (interval + 1);
+        self.next_report = Some(time + Duration::from_millis(u64::from(ms)));
+        Ok(len)
+    }
+
+    /// Writes a compound report ending with a BYE to `buf`, returns its
+    /// length
+    pub fn bye(
+        &mut self,
+        time: Instant,
+        sender: &Sender,
+        receiver: &Receiver,
+        jitter: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, rtcp::Error> {
+        let len = self.emit_report(time, sender, receiver, jitter, buf)?;
+        let bye = Packet::Bye {
+            ssrc: sender.ssrc(),
+        };
+        Ok(len + bye.emit(&mut buf[len..])?)
+    }
+
+    /// Takes in a compound packet from the remote
+    pub fn process(
+        &mut self,
+        time: Instant,
+        local_ssrc: u32,
+        data: &[u8],
+    ) -> Result<(), rtcp::Error> {
+        for packet in Compound::new(data) {
+            match packet? {
+                Packet::SenderReport { info, reports, .. } => {
+                    self.last_sr = Some(((info.ntp_timestamp >> 16) as u32, time));
+                    self.receive_report(time, local_ssrc, &reports);
+                }
+                Packet::ReceiverReport { reports, .. } => {
+                    self.receive_report(time, local_ssrc, &reports);
+                }
+                Packet::Bye { .. } => self.bye = true,
+                Packet::SourceDescription { .. } | Packet::Other(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Quality of the received media as of the last report, `delay` is
+    /// the playout delay on top of the network
+    pub fn quality(
+        &self,
+        receiver: &Receiver,
+        jitter: Duration,
+        delay: Duration,
+    ) -> Option<Quality> {
+        if !self.reported || receiver.ssrc().is_none() {
+            return None;
+        }
+        let network = self.rtt.map_or(Duration::from_secs(0), |rtt| rtt / 2);
+        Some(Quality::new(
+            self.fraction_lost,
+            receiver.lost(),
+            jitter,
+            self.rtt,
+            network + delay,
+        ))
+    }
+
+    fn emit_report(
+        &mut self,
+        time: Instant,
+        sender: &Sender,
+        receiver: &Receiver,
+        jitter: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, rtcp::Error> {
+        let mut block_buf = [0; rtcp::REPORT_BLOCK_LEN];
+        let blocks = match self.report_block(time, receiver, jitter) {
+            Some(block) => {
+                block.emit(&mut block_buf)?;
+                &block_buf[..]
+            }
+            None => &[],
+        };
+        let reports = ReportBlocks::new(blocks);
+        let report = if sender.packets() > 0 {
+            Packet::SenderReport {
+                ssrc: sender.ssrc(),
+                info: SenderInfo {
+                    ntp_timestamp: ntp_timestamp(time),
+                    // Next frame's, close enough at this precision
+                    rtp_timestamp: sender.timestamp(),
+                    packets: sender.packets(),
+                    octets: sender.octets(),
+                },
+                reports,
+            }
+        } else {
+            Packet::ReceiverReport {
+                ssrc: sender.ssrc(),
+                reports,
+            }
+        };
+        let len = report.emit(buf)?;
+        let sdes = Packet::SourceDescription {
+            ssrc: sender.ssrc(),
+            cname: self.cname(),
+        };
+        let len = len + sdes.emit(&mut buf[len..])?;
+        self.reported = true;
+        Ok(len)
+    }
+
+    /// Block about the remote source, updates the interval loss
+    fn report_block(
+        &mut self,
+        time: Instant,
+        receiver: &Receiver,
+        jitter: u32,
+    ) -> Option<ReportBlock> {
+        let ssrc = receiver.ssrc()?;
+        // RFC 3550 appendix A.3
+        let expected = receiver.expected();
+        let received = receiver.stats().received;
+        let expected_interval = expected.wrapping_sub(self.prior.expected);
+        let received_interval = received.wrapping_sub(self.prior.received);
+        self.prior = Interval { expected, received };
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        self.fraction_lost = if expected_interval == 0 || lost_interval == 0 {
+            0
+        } else {
+            ((lost_interval << 8) / expected_interval).min(255) as u8
+        };
+
+        let (last_sr, delay_since_last_sr) = match self.last_sr {
+            Some((lsr, at)) => (lsr, to_fixed(time - at)),
+            None => (0, 0),
+        };
+        Some(ReportBlock {
+            ssrc,
+            fraction_lost: self.fraction_lost,
+            cumulative_lost: receiver.lost(),
+            highest_sequence: receiver.extended_max(),
+            jitter,
+            last_sr,
+            delay_since_last_sr,
+        })
+    }
+
+    fn receive_report(&mut self, time: Instant, local_ssrc: u32, reports: &ReportBlocks) {
+        let block = match reports.find(local_ssrc) {
+            Some(b) => b,
+            None => return,
+        };
+        self.remote_report = Some(block);
+        if block.last_sr == 0 {
+            return;
+        }
+        let now = (ntp_timestamp(time) >> 16) as u32;
+        let rtt = now
+            .wrapping_sub(block.last_sr)
+            .wrapping_sub(block.delay_since_last_sr);
+        // Negative, the remote's clock or delay is off
+        if rtt < 0x8000_0000 {
+            self.rtt = Some(from_fixed(rtt));
+        }
+    }
+}
+
+/// NTP format timestamp of `time`, from boot
+pub fn ntp_timestamp(time: Instant) -> u64 {
+    let fraction = (u64::from(time.subsec_nanos()) << 32) / 1_000_000_000;
+    (time.as_secs() << 32) | fraction
+}
+
+/// Seconds in 16.16 fixed point
+fn to_fixed(d: Duration) -> u32 {
+    ((d.as_secs() << 16) | ((u64::from(d.subsec_nanos()) << 16) / 1_000_000_000)) as u32
+}
+
+fn from_fixed(f: u32) -> Duration {
+    Duration::from_micros((u64::from(f) * 1_000_000) >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtp::packet::Header;
+
+    fn header(sequence: u16) -> Header {
+        Header {
+            marker: false,
+            payload_type: 0,
+            sequence,
+            timestamp: u32::from(sequence) * 160,
+            ssrc: 77,
+        }
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(ntp_timestamp(at(1500)), 0x1_8000_0000);
+        assert_eq!(to_fixed(Duration::from_millis(1500)), 0x1_8000);
+        assert_eq!(from_fixed(0x1_8000), Duration::from_millis(1500));
+        assert_eq!(from_fixed(0x0000_0148), Duration::from_micros(5004));
+    }
+
+    #[test]
+    fn receiver_then_sender_reports() {
+        let mut rng = XorShift32::new(3);
+        let mut reporter = Reporter::new(&mut rng);
+        let mut sender = Sender::new(&mut rng, 0, 160);
+        let mut receiver = Receiver::new();
+        assert_eq!(reporter.cname().len(), CNAME_LEN);
+        assert_ne!(reporter.cname(), Reporter::new(&mut rng).cname());
+
+        // First report after half an interval
+        assert_eq!(reporter.is_due(at(0)), false);
+        assert_eq!(reporter.is_due(at(2499)), false);
+        assert_eq!(reporter.is_due(at(2500)), true);
+
+        // Nothing sent or received yet
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let len = reporter
+            .report(at(2500), &sender, &receiver, 0, &mut buf)
+            .unwrap();
+        let mut packets = Compound::new(&buf[..len]);
+        match packets.next() {
+            Some(Ok(Packet::ReceiverReport { ssrc, reports })) => {
+                assert_eq!(ssrc, sender.ssrc());
+                assert_eq!(reports.is_empty(), true);
+            }
+            p => panic!("Expected an RR {:?}", p),
+        }
+        match packets.next() {
+            Some(Ok(Packet::SourceDescription { cname, .. })) => {
+                assert_eq!(cname, reporter.cname())
+            }
+            p => panic!("Expected an SDES {:?}", p),
+        }
+        assert_eq!(packets.next(), None);
+        for ms in 2501..5000 {
+            assert_eq!(reporter.is_due(at(ms)), false);
+        }
+
+        // 10 received with 2 missing, after a sent frame
+        sender.next_frame(false, 160);
+        for seq in (0..12).filter(|s| *s != 3 && *s != 7) {
+            receiver.update(&header(seq));
+        }
+        let len = reporter
+            .report(at(10_000), &sender, &receiver, 40, &mut buf)
+            .unwrap();
+        match Compound::new(&buf[..len]).next() {
+            Some(Ok(Packet::SenderReport { info, reports, .. })) => {
+                assert_eq!(info.ntp_timestamp, 10 << 32);
+                assert_eq!(info.packets, 1);
+                assert_eq!(info.octets, 160);
+                assert_eq!(
+                    reports.find(77),
+                    Some(ReportBlock {
+                        ssrc: 77,
+                        fraction_lost: 42,
+                        cumulative_lost: 2,
+                        highest_sequence: 11,
+                        jitter: 40,
+                        last_sr: 0,
+                        delay_since_last_sr: 0,
+                    })
+                );
+            }
+            p => panic!("Expected an SR {:?}", p),
+        }
+        assert_eq!(reporter.fraction_lost(), 42);
+
+        // Nothing lost in the next interval
+        for seq in 12..20 {
+            receiver.update(&header(seq));
+        }
+        reporter
+            .report(at(15_000), &sender, &receiver, 40, &mut buf)
+            .unwrap();
+        assert_eq!(reporter.fraction_lost(), 0);
+        let q = reporter
+            .quality(
+                &receiver,
+                Duration::from_millis(5),
+                Duration::from_millis(40),
+            )
+            .unwrap();
+        assert_eq!(q.cumulative_lost, 2);
+        assert_eq!(q.rtt, None);
+        assert_eq!(q.mos, 44);
+
+        let len = reporter
+            .bye(at(16_000), &sender, &receiver, 40, &mut buf)
+            .unwrap();
+        assert_eq!(
+            Compound::new(&buf[..len]).last(),
+            Some(Ok(Packet::Bye {
+                ssrc: sender.ssrc()
+            }))
+        );
+    }
+
+    #[test]
+    fn round_trip_time() {
+        let mut rng = XorShift32::new(5);
+        let mut local = Reporter::new(&mut rng);
+        let mut remote = Reporter::new(&mut rng);
+        let mut local_sender = Sender::new(&mut rng, 0, 160);
+        let mut remote_sender = Sender::new(&mut rng, 0, 160);
+        let mut local_receiver = Receiver::new();
+        let mut remote_receiver = Receiver::new();
+        let mut buf = [0; MAX_PACKET_SIZE];
+
+        let h = local_sender.next_frame(false, 160);
+        remote_receiver.update(&h);
+        let h = remote_sender.next_frame(false, 160);
+        local_receiver.update(&h);
+
+        // Local SR takes 30 ms to get there, the remote holds it for
+        // 1 s then its RR takes 50 ms back
+        let len = local
+            .report(at(2000), &local_sender, &local_receiver, 0, &mut buf)
+            .unwrap();
+        remote
+            .process(at(2030), remote_sender.ssrc(), &buf[..len])
+            .unwrap();
+        assert_eq!(remote.rtt(), None);
+        let len = remote
+            .report(at(3030), &remote_sender, &remote_receiver, 0, &mut buf)
+            .unwrap();
+        local
+            .process(at(3080), local_sender.ssrc(), &buf[..len])
+            .unwrap();
+
+        let rtt = local.rtt().unwrap();
+        assert!(
+            rtt >= Duration::from_millis(79) && rtt <= Duration::from_millis(81),
+            "{:?}",
+            rtt
+        );
+        let block = local.remote_report().unwrap();
+        assert_eq!(block.ssrc, local_sender.ssrc());
+        assert_eq!(block.delay_since_last_sr, 0x1_0000);
+        let q = local
+            .quality(
+                &local_receiver,
+                Duration::from_millis(0),
+                Duration::from_millis(40),
+            )
+            .unwrap();
+        assert_eq!(q.rtt, Some(rtt));
+
+        assert_eq!(local.is_bye(), false);
+        let len = remote
+            .bye(at(4000), &remote_sender, &remote_receiver, 0, &mut buf)
+            .unwrap();
+        local
+            .process(at(4000), local_sender.ssrc(), &buf[..len])
+            .unwrap();
+        assert_eq!(local.is_bye(), true);
+        assert_eq!(
+            local.process(at(4000), local_sender.ssrc(), &buf[..3]),
+            Err(rtcp::Error::Truncated)
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! RTP Control Protocol packets (RFC 3550 section 6)
+//!
+//! Sender and receiver reports, source descriptions and BYE, parsed
+//! from and emitted into compound packets. Only the CNAME of the first
+//! SDES chunk and the first SSRC of a BYE are kept, other packet types
+//! are passed over.
+
+use crate::rtp::packet::VERSION;
+use core::convert::TryInto;
+use core::str;
+
+pub const SENDER_REPORT: u8 = 200;
+pub const RECEIVER_REPORT: u8 = 201;
+pub const SOURCE_DESCRIPTION: u8 = 202;
+pub const BYE: u8 = 203;
+
+/// Common header length
+pub const HEADER_LEN: usize = 4;
+
+pub const SENDER_INFO_LEN: usize = 20;
+
+pub const REPORT_BLOCK_LEN: usize = 24;
+
+/// Most report blocks in one SR or RR
+pub const MAX_REPORT_BLOCKS: usize = 31;
+
+/// SDES item type of the canonical name
+const CNAME: u8 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Shorter than the header says it is
+    Truncated,
+    InvalidVersion,
+    /// CNAME isn't UTF-8
+    InvalidText,
+    /// Emit buffer too small for the packet
+    BufferTooSmall,
+}
+
+/// What a sender has sent, and when
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct SenderInfo {
+    /// NTP timestamp, seconds in the upper 32 bits
+    pub ntp_timestamp: u64,
+    /// RTP timestamp of the same instant
+    pub rtp_timestamp: u32,
+    pub packets: u32,
+    pub octets: u32,
+}
+
+/// Reception statistics of one source
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct ReportBlock {
+    pub ssrc: u32,
+    /// Lost since the previous report, out of 256
+    pub fraction_lost: u8,
+    /// Lost since the start, 24 bits
+    pub cumulative_lost: u32,
+    /// Highest sequence number received, extended with the wrap count
+    pub highest_sequence: u32,
+    /// Interarrival jitter, in timestamp units
+    pub jitter: u32,
+    /// Middle 32 bits of the NTP timestamp of the last SR received
+    pub last_sr: u32,
+    /// Since the last SR was received, in 1/65536 seconds
+    pub delay_since_last_sr: u32,
+}
+
+impl ReportBlock {
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < REPORT_BLOCK_LEN {
+            return Err(Error::Truncated);
+        }
+        Ok(ReportBlock {
+            ssrc: read_u32(&data[0..4]),
+            fraction_lost: data[4],
+            cumulative_lost: read_u32(&data[4..8]) & 0x00FF_FFFF,
+            highest_sequence: read_u32(&data[8..12]),
+            jitter: read_u32(&data[12..16]),
+            last_sr: read_u32(&data[16..20]),
+            delay_since_last_sr: read_u32(&data[20..24]),
+        })
+    }
+
+    pub fn emit(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < REPORT_BLOCK_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        buf[0..4].copy_from_slice(&self.ssrc.to_be_bytes());
+        let lost = self.cumulative_lost.min(0x00FF_FFFF) | (u32::from(self.fraction_lost) << 24);
+        buf[4..8].copy_from_slice(&lost.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.highest_sequence.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.jitter.to_be_bytes());
+        buf[16..20].copy_from_slice(&self.last_sr.to_be_bytes());
+        buf[20..24].copy_from_slice(&self.delay_since_last_sr.to_be_bytes());
+        Ok(REPORT_BLOCK_LEN)
+    }
+}
+
+/// Report blocks of an SR or RR, back to back
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ReportBlocks<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ReportBlocks<'a> {
+    /// Emitted blocks, a trailing partial block is ignored
+    pub fn new(data: &'a [u8]) -> Self {
+        let len = data.len().min(MAX_REPORT_BLOCKS * REPORT_BLOCK_LEN);
+        ReportBlocks {
+            data: &data[..len - (len % REPORT_BLOCK_LEN)],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / REPORT_BLOCK_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ReportBlock> + 'a {
+        // Can't fail, whole blocks only
+        self.data
+            .chunks(REPORT_BLOCK_LEN)
+            .map(|b| ReportBlock::parse(b).unwrap())
+    }
+
+    /// Block about the source `ssrc`
+    pub fn find(&self, ssrc: u32) -> Option<ReportBlock> {
+        self.iter().find(|b| b.ssrc == ssrc)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Packet<'a> {
+    SenderReport {
+        ssrc: u32,
+        info: SenderInfo,
+        reports: ReportBlocks<'a>,
+    },
+    ReceiverReport {
+        ssrc: u32,
+        reports: ReportBlocks<'a>,
+    },
+    /// First chunk only, `cname` is empty when it has none
+    SourceDescription { ssrc: u32, cname: &'a str },
+    /// First source only, the reason isn't kept
+    Bye { ssrc: u32 },
+    /// Any other packet type
+    Other(u8),
+}
+
+impl<'a> Packet<'a> {
+    /// Parses the packet at the start of `data`, returns it and its
+    /// length to find the next one of a compound packet
+    pub fn parse(data: &'a [u8]) -> Result<(Self, usize), Error> {
+        if data.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if data[0] >> 6 != VERSION {
+            return Err(Error::InvalidVersion);
+        }
+        let count = usize::from(data[0] & 0x1F);
+        let packet_type = data[1];
+        let len = 4 * (usize::from(read_u16(&data[2..4])) + 1);
+        if data.len() < len {
+            return Err(Error::Truncated);
+        }
+        // Padding is only allowed on the last packet, and covered by
+        // the length anyway
+        let body = &data[HEADER_LEN..len];
+
+        let packet = match packet_type {
+            SENDER_REPORT => {
+                let blocks = 4 + SENDER_INFO_LEN;
+                if body.len() < blocks + count * REPORT_BLOCK_LEN {
+                    return Err(Error::Truncated);
+                }
+                Packet::SenderReport {
+                    ssrc: read_u32(&body[0..4]),
+                    info: SenderInfo {
+                        ntp_timestamp: read_u64(&body[4..12]),
+                        rtp_timestamp: read_u32(&body[12..16]),
+                        packets: read_u32(&body[16..20]),
+                        octets: read_u32(&body[20..24]),
+                    },
+                    reports: ReportBlocks::new(&body[blocks..blocks + count * REPORT_BLOCK_LEN]),
+                }
+            }
+            RECEIVER_REPORT => {
+                if body.len() < 4 + count * REPORT_BLOCK_LEN {
+                    return Err(Error::Truncated);
+                }
+                Packet::ReceiverReport {
+                    ssrc: read_u32(&body[0..4]),
+                    reports: ReportBlocks::new(&body[4..4 + count * REPORT_BLOCK_LEN]),
+                }
+            }
+            SOURCE_DESCRIPTION if count > 0 => {
+                if body.len() < 4 {
+                    return Err(Error::Truncated);
+                }
+                Packet::SourceDescription {
+                    ssrc: read_u32(&body[0..4]),
+                    cname: parse_cname(&body[4..])?,
+                }
+            }
+            BYE if count > 0 => {
+                if body.len() < 4 {
+                    return Err(Error::Truncated);
+                }
+                Packet::Bye {
+                    ssrc: read_u32(&body[0..4]),
+                }
+            }
+            t => Packet::Other(t),
+        };
+        Ok((packet, len))
+    }
+
+    /// Length once emitted
+    pub fn buffer_len(&self) -> usize {
+        HEADER_LEN
+            + match self {
+                Packet::SenderReport { reports, .. } => {
+                    4 + SENDER_INFO_LEN + reports.len() * REPORT_BLOCK_LEN
+                }
+                Packet::ReceiverReport { reports, .. } => 4 + reports.len() * REPORT_BLOCK_LEN,
+                // Type, length, text and at least one null octet to end
+                // the chunk, padded to 32 bits
+                Packet::SourceDescription { cname, .. } => {
+                    (4 + 2 + cname.len().min(255) + 1 + 3) & !3
+                }
+                Packet::Bye { .. } => 4,
+                Packet::Other(_) => 0,
+            }
+    }
+
+    /// Writes the packet to the start of `buf`, returns its length
+    pub fn emit(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = self.buffer_len();
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+        let buf = &mut buf[..len];
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        let (packet_type, count) = match self {
+            Packet::SenderReport {
+                ssrc,
+                info,
+                reports,
+            } => {
+                buf[4..8].copy_from_slice(&ssrc.to_be_bytes());
+                buf[8..16].copy_from_slice(&info.ntp_timestamp.to_be_bytes());
+                buf[16..20].copy_from_slice(&info.rtp_timestamp.to_be_bytes());
+                buf[20..24].copy_from_slice(&info.packets.to_be_bytes());
+                buf[24..28].copy_from_slice(&info.octets.to_be_bytes());
+                buf[28..].copy_from_slice(reports.data);
+                (SENDER_REPORT, reports.len())
+            }
+            Packet::ReceiverReport { ssrc, reports } => {
+                buf[4..8].copy_from_slice(&ssrc.to_be_bytes());
+                buf[8..].copy_from_slice(reports.data);
+                (RECEIVER_REPORT, reports.len())
+            }
+            Packet::SourceDescription { ssrc, cname } => {
+                let text = &cname.as_bytes()[..cname.len().min(255)];
+                buf[4..8].copy_from_slice(&ssrc.to_be_bytes());
+                buf[8] = CNAME;
+                buf[9] = text.len() as u8;
+                buf[10..10 + text.len()].copy_from_slice(text);
+                (SOURCE_DESCRIPTION, 1)
+            }
+            Packet::Bye { ssrc } => {
+                buf[4..8].copy_from_slice(&ssrc.to_be_bytes());
+                (BYE, 1)
+            }
+            Packet::Other(t) => (*t, 0),
+        };
+        buf[0] = (VERSION << 6) | count as u8;
+        buf[1] = packet_type;
+        buf[2..4].copy_from_slice(&((len / 4 - 1) as u16).to_be_bytes());
+        Ok(len)
+    }
+}
+
+/// Packets of a compound packet, stops at the first bad one
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Compound<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Compound<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Compound { data }
+    }
+}
+
+impl<'a> Iterator for Compound<'a> {
+    type Item = Result<Packet<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        match Packet::parse(self.data) {
+            Ok((packet, len)) => {
+                self.data = &self.data[len..];
+                Some(Ok(packet))
+            }
+            Err(e) => {
+                self.data = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// CNAME item of an SDES chunk, after its SSRC
+fn parse_cname(items: &[u8]) -> Result<&str, Error> {
+    let mut i = 0;
+    while i < items.len() && items[i] != 0 {
+        if i + 2 > items.len() {
+            return Err(Error::Truncated);
+        }
+        let len = usize::from(items[i + 1]);
+        let text = items.get(i + 2..i + 2 + len).ok_or(Error::Truncated)?;
+        if items[i] == CNAME {
+            return str::from_utf8(text).map_err(|_| Error::InvalidText);
+        }
+        i += 2 + len;
+    }
+    Ok("")
+}
+
+// Callers have checked the length
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_be_bytes(data[..2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    u64::from_be_bytes(data[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: ReportBlock = ReportBlock {
+        ssrc: 0x0102_0304,
+        fraction_lost: 64,
+        cumulative_lost: 0x12_3456,
+        highest_sequence: 0x0001_0005,
+        jitter: 80,
+        last_sr: 0xAABB_CCDD,
+        delay_since_last_sr: 0x0001_8000,
+    };
+
+    #[test]
+    fn report_block() {
+        let mut buf = [0; REPORT_BLOCK_LEN];
+        assert_eq!(BLOCK.emit(&mut buf), Ok(REPORT_BLOCK_LEN));
+        assert_eq!(&buf[4..8], &[64, 0x12, 0x34, 0x56]);
+        assert_eq!(ReportBlock::parse(&buf), Ok(BLOCK));
+        assert_eq!(ReportBlock::parse(&buf[..20]), Err(Error::Truncated));
+
+        // Cumulative loss saturates at 24 bits
+        let block = ReportBlock {
+            cumulative_lost: u32::max_value(),
+            ..BLOCK
+        };
+        block.emit(&mut buf).unwrap();
+        assert_eq!(&buf[4..8], &[64, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn compound_emit_parse() {
+        let mut blocks = [0; 2 * REPORT_BLOCK_LEN];
+        BLOCK.emit(&mut blocks[..]).unwrap();
+        let other = ReportBlock { ssrc: 9, ..BLOCK };
+        other.emit(&mut blocks[REPORT_BLOCK_LEN..]).unwrap();
+        let info = SenderInfo {
+            ntp_timestamp: 0x8000_0001_4000_0000,
+            rtp_timestamp: 160,
+            packets: 10,
+            octets: 1600,
+        };
+        let packets = [
+            Packet::SenderReport {
+                ssrc: 5,
+                info,
+                reports: ReportBlocks::new(&blocks),
+            },
+            Packet::ReceiverReport {
+                ssrc: 5,
+                reports: ReportBlocks::new(&[]),
+            },
+            Packet::SourceDescription {
+                ssrc: 5,
+                cname: "phone@10.0.0.2",
+            },
+            Packet::Bye { ssrc: 5 },
+        ];
+
+        let mut buf = [0; 256];
+        let mut len = 0;
+        for p in packets.iter() {
+            len += p.emit(&mut buf[len..]).unwrap();
+        }
+        assert_eq!(len, 76 + 8 + 28 + 8);
+        assert_eq!(&buf[..4], &[0x82, 200, 0, 18]);
+        // CNAME chunk ends with a null octet and padding
+        assert_eq!(&buf[84..88], &[0x81, 202, 0, 6]);
+        assert_eq!(&buf[106..112], &[b'.', b'2', 0, 0, 0, 0]);
+
+        let mut parsed = Compound::new(&buf[..len]);
+        for p in packets.iter() {
+            assert_eq!(parsed.next(), Some(Ok(*p)));
+        }
+        assert_eq!(parsed.next(), None);
+
+        match packets[0] {
+            Packet::SenderReport { reports, .. } => {
+                assert_eq!(reports.len(), 2);
+                assert_eq!(reports.find(9), Some(other));
+                assert_eq!(reports.find(0x0102_0304), Some(BLOCK));
+                assert_eq!(reports.find(7), None);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn parse_other_and_errors() {
+        // APP packet, then an SDES chunk with a NOTE before the CNAME
+        let data = [
+            0x80, 204, 0, 2, 0, 0, 0, 1, b'n', b'a', b'm', b'e', // APP
+            0x81, 202, 0, 4, 0, 0, 0, 7, // SDES SSRC
+            7, 2, b'h', b'i', 1, 3, b'a', b'@', b'b', 0, 0, 0,
+        ];
+        let mut parsed = Compound::new(&data);
+        assert_eq!(parsed.next(), Some(Ok(Packet::Other(204))));
+        assert_eq!(
+            parsed.next(),
+            Some(Ok(Packet::SourceDescription {
+                ssrc: 7,
+                cname: "a@b"
+            }))
+        );
+        assert_eq!(parsed.next(), None);
+
+        assert_eq!(Packet::parse(&data[..3]), Err(Error::Truncated));
+        assert_eq!(Packet::parse(&data[..8]), Err(Error::Truncated));
+        let mut bad = data;
+        bad[0] = 0x40;
+        assert_eq!(Packet::parse(&bad), Err(Error::InvalidVersion));
+        let mut parsed = Compound::new(&bad);
+        assert_eq!(parsed.next(), Some(Err(Error::InvalidVersion)));
+        assert_eq!(parsed.next(), None);
+
+        // RR claiming a report block it doesn't have
+        let rr = [0x81, 201, 0, 1, 0, 0, 0, 1];
+        assert_eq!(Packet::parse(&rr), Err(Error::Truncated));
+
+        let mut buf = [0; 8];
+        assert_eq!(
+            Packet::Bye { ssrc: 1 }.emit(&mut buf[..7]),
+            Err(Error::BufferTooSmall)
+        );
+    }
+}
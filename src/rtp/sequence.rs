@@ -0,0 +1,381 @@
+//! RTP sequence number and timestamp tracking
+//!
+//! `Sender` stamps outgoing frames, `Receiver` follows a remote
+//! source's sequence numbers the way RFC 3550 appendix A.1 does,
+//! classifying each packet as in order, after a gap, reordered or a
+//! duplicate.
+
+use crate::random::RandomSource;
+use crate::rtp::packet::Header;
+
+/// Largest forward jump that's still considered the same sequence
+pub const MAX_DROPOUT: u16 = 3000;
+
+/// Largest backward jump that's still considered a reordered packet
+pub const MAX_MISORDER: u16 = 100;
+
+/// Stamps the frames of the local source
+#[derive(Debug, Clone)]
+pub struct Sender {
+    ssrc: u32,
+    payload_type: u8,
+    samples_per_frame: u32,
+    sequence: u16,
+    timestamp: u32,
+    packets: u32,
+    octets: u32,
+}
+
+impl Sender {
+    /// SSRC, initial sequence number and timestamp are random
+    /// (RFC 3550 section 5.1)
+    pub fn new<R: RandomSource>(rng: &mut R, payload_type: u8, samples_per_frame: u32) -> Self {
+        let ssrc = rng.next_u32();
+        let sequence = rng.next_u32() as u16;
+        let timestamp = rng.next_u32();
+        Sender {
+            ssrc,
+            payload_type,
+            samples_per_frame,
+            sequence,
+            timestamp,
+            packets: 0,
+            octets: 0,
+        }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    pub fn payload_type(&self) -> u8 {
+        self.payload_type
+    }
+
+    /// Codec or packetization changed, keeps the sequence going
+    pub fn set_format(&mut self, payload_type: u8, samples_per_frame: u32) {
+        self.payload_type = payload_type;
+        self.samples_per_frame = samples_per_frame;
+    }
+
+    pub fn samples_per_frame(&self) -> u32 {
+        self.samples_per_frame
+    }
+
+    /// Sequence number of the next packet
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Timestamp of the next frame
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// Packets sent
+    pub fn packets(&self) -> u32 {
+        self.packets
+    }
+
+    /// Payload octets sent
+    pub fn octets(&self) -> u32 {
+        self.octets
+    }
+
+    /// Header for the next frame, advances the sequence number and
+    /// the timestamp by one frame
+    pub fn next_frame(&mut self, marker: bool, payload_len: usize) -> Header {
+        let header = self.header(self.payload_type, marker, self.timestamp, payload_len);
+        self.skip(1);
+        header
+    }
+
+    /// Header with an explicit payload type and timestamp, only the
+    /// sequence number advances. For payloads that aren't one frame of
+    /// audio, i.e. events spanning several frames.
+    pub fn header(
+        &mut self,
+        payload_type: u8,
+        marker: bool,
+        timestamp: u32,
+        payload_len: usize,
+    ) -> Header {
+        let header = Header {
+            marker,
+            payload_type,
+            sequence: self.sequence,
+            timestamp,
+            ssrc: self.ssrc,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.packets = self.packets.wrapping_add(1);
+        self.octets = self.octets.wrapping_add(payload_len as u32);
+        header
+    }
+
+    /// Advances the timestamp over frames that weren't sent (silence)
+    pub fn skip(&mut self, frames: u32) {
+        self.timestamp = self
+            .timestamp
+            .wrapping_add(frames.wrapping_mul(self.samples_per_frame));
+    }
+}
+
+/// How a received packet relates to the ones before it
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Arrival {
+    /// First packet of a new source, or the source restarted its sequence
+    Restart,
+    /// Next in sequence
+    InOrder,
+    /// Ahead of the next expected, skipping this many packets
+    Gap(u16),
+    /// Older than the highest received, fills a gap
+    Reordered,
+    /// Already received
+    Duplicate,
+    /// Too far from the sequence, dropped. Two in a row restart it.
+    Invalid,
+}
+
+impl Arrival {
+    /// Whether the packet should be played out
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Arrival::Duplicate | Arrival::Invalid => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct ReceiverStats {
+    /// Valid packets received, duplicates excluded
+    pub received: u32,
+    pub reordered: u32,
+    pub duplicates: u32,
+    pub invalid: u32,
+    pub restarts: u32,
+}
+
+/// Follows the sequence of the remote source
+#[derive(Debug, Clone, Default)]
+pub struct Receiver {
+    ssrc: Option<u32>,
+    /// Highest sequence number received
+    max_seq: u16,
+    /// Sequence number wraps, shifted into the upper 16 bits
+    cycles: u32,
+    base_seq: u32,
+    /// Sequence number following an invalid packet, for restarts
+    bad_seq: Option<u16>,
+    /// Bit n set when `max_seq - n` was received
+    window: u128,
+    stats: ReceiverStats,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Receiver::default()
+    }
+
+    /// SSRC being followed
+    pub fn ssrc(&self) -> Option<u32> {
+        self.ssrc
+    }
+
+    pub fn stats(&self) -> &ReceiverStats {
+        &self.stats
+    }
+
+    /// Highest sequence number received, extended with the wrap count
+    pub fn extended_max(&self) -> u32 {
+        self.cycles | u32::from(self.max_seq)
+    }
+
+    /// Packets expected since the first one (RFC 3550 appendix A.3)
+    pub fn expected(&self) -> u32 {
+        if self.ssrc.is_none() {
+            return 0;
+        }
+        self.extended_max()
+            .wrapping_sub(self.base_seq)
+            .wrapping_add(1)
+    }
+
+    /// Cumulative packets lost, reordered packets that turned up are
+    /// not lost
+    pub fn lost(&self) -> u32 {
+        self.expected().saturating_sub(self.stats.received)
+    }
+
+    /// Updates the sequence state with a received packet's header
+    pub fn update(&mut self, header: &Header) -> Arrival {
+        let seq = header.sequence;
+        if self.ssrc != Some(header.ssrc) {
+            self.restart(header.ssrc, seq);
+            return Arrival::Restart;
+        }
+
+        let delta = seq.wrapping_sub(self.max_seq);
+        let arrival = if delta == 0 {
+            Arrival::Duplicate
+        } else if delta < MAX_DROPOUT {
+            if seq < self.max_seq {
+                self.cycles = self.cycles.wrapping_add(1 << 16);
+            }
+            self.window = if delta < 128 { self.window << delta } else { 0 };
+            self.window |= 1;
+            self.max_seq = seq;
+            if delta == 1 {
+                Arrival::InOrder
+            } else {
+                Arrival::Gap(delta - 1)
+            }
+        } else if delta <= u16::max_value() - MAX_MISORDER {
+            // Large jump, restart if the next packet follows this one
+            if self.bad_seq == Some(seq) {
+                self.restart(header.ssrc, seq);
+                return Arrival::Restart;
+            }
+            self.bad_seq = Some(seq.wrapping_add(1));
+            self.stats.invalid = self.stats.invalid.wrapping_add(1);
+            return Arrival::Invalid;
+        } else {
+            // Behind the highest, within the window
+            let bit = 1u128 << self.max_seq.wrapping_sub(seq);
+            if self.window & bit != 0 {
+                Arrival::Duplicate
+            } else {
+                self.window |= bit;
+                self.stats.reordered = self.stats.reordered.wrapping_add(1);
+                Arrival::Reordered
+            }
+        };
+
+        self.bad_seq = None;
+        if arrival == Arrival::Duplicate {
+            self.stats.duplicates = self.stats.duplicates.wrapping_add(1);
+        } else {
+            self.stats.received = self.stats.received.wrapping_add(1);
+        }
+        arrival
+    }
+
+    fn restart(&mut self, ssrc: u32, seq: u16) {
+        let stats = self.stats;
+        *self = Receiver {
+            ssrc: Some(ssrc),
+            max_seq: seq,
+            cycles: 0,
+            base_seq: u32::from(seq),
+            bad_seq: None,
+            window: 1,
+            stats: ReceiverStats {
+                received: 1,
+                restarts: stats.restarts.wrapping_add(1),
+                ..ReceiverStats::default()
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::XorShift32;
+
+    fn header(sequence: u16) -> Header {
+        Header {
+            marker: false,
+            payload_type: 0,
+            sequence,
+            timestamp: 0,
+            ssrc: 1234,
+        }
+    }
+
+    #[test]
+    fn sender_advances() {
+        let mut s = Sender::new(&mut XorShift32::new(1), 0, 160);
+        let seq = s.sequence();
+        let ts = s.timestamp();
+
+        let h = s.next_frame(true, 160);
+        assert_eq!(h.marker, true);
+        assert_eq!(h.payload_type, 0);
+        assert_eq!(h.ssrc, s.ssrc());
+        assert_eq!(h.sequence, seq);
+        assert_eq!(h.timestamp, ts);
+
+        let h = s.next_frame(false, 160);
+        assert_eq!(h.sequence, seq.wrapping_add(1));
+        assert_eq!(h.timestamp, ts.wrapping_add(160));
+
+        s.skip(2);
+        let h = s.header(101, true, ts, 4);
+        assert_eq!(h.sequence, seq.wrapping_add(2));
+        assert_eq!(h.timestamp, ts);
+        assert_eq!(h.payload_type, 101);
+        assert_eq!(s.timestamp(), ts.wrapping_add(4 * 160));
+        assert_eq!(s.packets(), 3);
+        assert_eq!(s.octets(), 324);
+    }
+
+    #[test]
+    fn in_order_with_wrap() {
+        let mut r = Receiver::new();
+        assert_eq!(r.update(&header(65534)), Arrival::Restart);
+        assert_eq!(r.update(&header(65535)), Arrival::InOrder);
+        assert_eq!(r.update(&header(0)), Arrival::InOrder);
+        assert_eq!(r.update(&header(1)), Arrival::InOrder);
+        assert_eq!(r.extended_max(), 0x1_0001);
+        assert_eq!(r.expected(), 4);
+        assert_eq!(r.lost(), 0);
+        assert_eq!(r.stats().received, 4);
+    }
+
+    #[test]
+    fn loss_and_reorder() {
+        let mut r = Receiver::new();
+        r.update(&header(10));
+        assert_eq!(r.update(&header(13)), Arrival::Gap(2));
+        assert_eq!(r.lost(), 2);
+        assert_eq!(r.update(&header(11)), Arrival::Reordered);
+        assert_eq!(r.lost(), 1);
+        assert_eq!(r.update(&header(11)), Arrival::Duplicate);
+        assert_eq!(r.update(&header(13)), Arrival::Duplicate);
+        assert_eq!(r.update(&header(14)), Arrival::InOrder);
+        assert_eq!(r.expected(), 5);
+        assert_eq!(r.lost(), 1);
+        assert_eq!(
+            *r.stats(),
+            ReceiverStats {
+                received: 4,
+                reordered: 1,
+                duplicates: 2,
+                invalid: 0,
+                restarts: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn jump_restarts_after_two() {
+        let mut r = Receiver::new();
+        r.update(&header(100));
+        r.update(&header(101));
+        assert_eq!(r.update(&header(20000)), Arrival::Invalid);
+        assert_eq!(r.update(&header(102)), Arrival::InOrder);
+        assert_eq!(r.update(&header(30000)), Arrival::Invalid);
+        assert_eq!(r.update(&header(30001)), Arrival::Restart);
+        assert_eq!(r.update(&header(30002)), Arrival::InOrder);
+        assert_eq!(r.expected(), 2);
+        assert_eq!(r.stats().restarts, 2);
+
+        let mut other = header(7);
+        other.ssrc = 99;
+        assert_eq!(r.update(&other), Arrival::Restart);
+        assert_eq!(r.ssrc(), Some(99));
+    }
+}
@@ -0,0 +1,602 @@
+//! RTP session over a smoltcp UDP socket
+//!
+//! One session per call: frames go to the negotiated remote endpoint,
+//! and only packets from the remote address with the negotiated
+//! payload types are passed up. DTMF digits are sent as
+//! telephone-events when the remote supports them. Received audio
+//! frames are queued in a jitter buffer for playout.
+//!
+//! RTCP goes over a second socket on the next port up, both locally and
+//! remotely, for reports and call quality.
+
+use crate::net::socket_pool::UdpHandle;
+use crate::random::RandomSource;
+use crate::rtp::dtmf::{self, DtmfSender};
+use crate::rtp::jitter_buffer::{JitterBuffer, Push};
+use crate::rtp::packet::{self, Header, Packet};
+use crate::rtp::quality::Quality;
+use crate::rtp::report::{self, Reporter};
+use crate::rtp::rtcp;
+use crate::rtp::sequence::{Arrival, Receiver, Sender};
+use crate::sip::sdp::Negotiated;
+use crate::time::{Duration, Instant};
+use log::debug;
+use smoltcp::socket::{SocketSet, UdpSocket};
+use smoltcp::wire::IpEndpoint;
+
+/// Largest packet sent or received, 20 ms of G.711 is 172 bytes
+pub const MAX_PACKET_SIZE: usize = 512;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    Packet(packet::Error),
+    Dtmf(dtmf::Error),
+    Rtcp(rtcp::Error),
+    Socket(smoltcp::Error),
+}
+
+impl From<packet::Error> for Error {
+    fn from(e: packet::Error) -> Self {
+        Error::Packet(e)
+    }
+}
+
+impl From<dtmf::Error> for Error {
+    fn from(e: dtmf::Error) -> Self {
+        Error::Dtmf(e)
+    }
+}
+
+impl From<rtcp::Error> for Error {
+    fn from(e: rtcp::Error) -> Self {
+        Error::Rtcp(e)
+    }
+}
+
+impl From<smoltcp::Error> for Error {
+    fn from(e: smoltcp::Error) -> Self {
+        Error::Socket(e)
+    }
+}
+
+/// A packet from the remote source
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Received<'a> {
+    pub packet: Packet<'a>,
+    pub arrival: Arrival,
+}
+
+pub struct Session {
+    handle: UdpHandle,
+    rtcp_handle: UdpHandle,
+    local_port: u16,
+    media: Negotiated,
+    sender: Sender,
+    receiver: Receiver,
+    dtmf: Option<DtmfSender>,
+    jitter_buffer: JitterBuffer,
+    reporter: Reporter,
+}
+
+impl Session {
+    /// RTCP uses `rtcp_handle` on `local_port + 1`
+    pub fn new<R: RandomSource>(
+        handle: UdpHandle,
+        rtcp_handle: UdpHandle,
+        local_port: u16,
+        media: Negotiated,
+        rng: &mut R,
+    ) -> Self {
+        Session {
+            handle,
+            rtcp_handle,
+            local_port,
+            sender: Sender::new(rng, media.payload_type, samples_per_frame(&media)),
+            receiver: Receiver::new(),
+            dtmf: media.telephone_event.map(DtmfSender::new),
+            jitter_buffer: jitter_buffer(&media),
+            reporter: Reporter::new(rng),
+            media,
+        }
+    }
+
+    pub fn socket_handle(&self) -> UdpHandle {
+        self.handle
+    }
+
+    pub fn rtcp_socket_handle(&self) -> UdpHandle {
+        self.rtcp_handle
+    }
+
+    pub fn media(&self) -> &Negotiated {
+        &self.media
+    }
+
+    pub fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    pub fn receiver(&self) -> &Receiver {
+        &self.receiver
+    }
+
+    pub fn jitter_buffer(&self) -> &JitterBuffer {
+        &self.jitter_buffer
+    }
+
+    /// Received audio, play out with `JitterBuffer::play_out` once per
+    /// frame interval
+    pub fn jitter_buffer_mut(&mut self) -> &mut JitterBuffer {
+        &mut self.jitter_buffer
+    }
+
+    pub fn reporter(&self) -> &Reporter {
+        &self.reporter
+    }
+
+    /// Quality of the received media, once the first report went out
+    pub fn quality(&self) -> Option<Quality> {
+        let stats = self.jitter_buffer.stats();
+        // Mouth-to-ear on this end, packetization and playout
+        let delay = stats.delay + Duration::from_millis(u64::from(self.media.ptime));
+        self.reporter.quality(&self.receiver, stats.jitter, delay)
+    }
+
+    /// Re-negotiated (re-INVITE), the local source carries on
+    pub fn update(&mut self, media: Negotiated) {
+        self.sender
+            .set_format(media.payload_type, samples_per_frame(&media));
+        if media.telephone_event != self.media.telephone_event {
+            self.dtmf = media.telephone_event.map(DtmfSender::new);
+        }
+        if media.codec != self.media.codec || media.ptime != self.media.ptime {
+            self.jitter_buffer = jitter_buffer(&media);
+        }
+        self.media = media;
+    }
+
+    /// Sends one frame of audio, or just advances the timestamp when
+    /// the remote doesn't want media (on hold). Dropped while a DTMF
+    /// digit is being sent in its place.
+    pub fn send(
+        &mut self,
+        sockets: &mut SocketSet,
+        payload: &[u8],
+        marker: bool,
+    ) -> Result<(), Error> {
+        if self.dtmf.as_ref().map_or(false, |d| d.is_active()) {
+            return Ok(());
+        }
+        if self.media.is_on_hold() {
+            self.sender.skip(1);
+            return Ok(());
+        }
+        let header = self.sender.next_frame(marker, payload.len());
+        self.send_packet(sockets, &Packet::new(header, payload))
+    }
+
+    /// Sends a packet stamped by the caller, see `Sender::header`
+    pub fn send_packet(&mut self, sockets: &mut SocketSet, packet: &Packet) -> Result<(), Error> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let len = packet.emit(&mut buf)?;
+        let mut socket = sockets.get::<UdpSocket>(self.handle.into());
+        bind(&mut socket, self.local_port)?;
+        socket.send_slice(&buf[..len], self.media.remote)?;
+        Ok(())
+    }
+
+    /// Queues a DTMF digit, `held` is how long the key was down
+    pub fn send_dtmf(&mut self, digit: char, held: Duration) -> Result<(), Error> {
+        let dtmf = self.dtmf.as_mut().ok_or(dtmf::Error::NotNegotiated)?;
+        dtmf.push(digit, held)?;
+        Ok(())
+    }
+
+    /// Sends whatever DTMF and RTCP packets are due
+    pub fn poll(&mut self, sockets: &mut SocketSet, time: Instant) -> Result<(), Error> {
+        self.poll_dtmf(sockets, time)?;
+        if self.reporter.is_due(time) {
+            let mut buf = [0; report::MAX_PACKET_SIZE];
+            let len = self.reporter.report(
+                time,
+                &self.sender,
+                &self.receiver,
+                self.jitter_buffer.jitter(),
+                &mut buf,
+            )?;
+            self.send_rtcp(sockets, &buf[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Sends a BYE, before closing
+    pub fn bye(&mut self, sockets: &mut SocketSet, time: Instant) -> Result<(), Error> {
+        let mut buf = [0; report::MAX_PACKET_SIZE];
+        let len = self.reporter.bye(
+            time,
+            &self.sender,
+            &self.receiver,
+            self.jitter_buffer.jitter(),
+            &mut buf,
+        )?;
+        self.send_rtcp(sockets, &buf[..len])
+    }
+
+    fn poll_dtmf(&mut self, sockets: &mut SocketSet, time: Instant) -> Result<(), Error> {
+        loop {
+            let dtmf = match self.dtmf.as_mut() {
+                Some(d) => d,
+                None => return Ok(()),
+            };
+            let (header, event) = match dtmf.poll(time, &mut self.sender) {
+                Some(p) => p,
+                None => return Ok(()),
+            };
+            if self.media.is_on_hold() {
+                continue;
+            }
+            let payload = event.emit();
+            self.send_packet(sockets, &Packet::new(header, &payload))?;
+        }
+    }
+
+    /// Local source, for stamping packets passed to `send_packet`
+    pub fn sender_mut(&mut self) -> &mut Sender {
+        &mut self.sender
+    }
+
+    /// Receives the next packet from the remote into `buf`, skipping
+    /// anything that isn't. Returns `None` once the socket is drained.
+    pub fn recv<'b>(
+        &mut self,
+        sockets: &mut SocketSet,
+        buf: &'b mut [u8],
+    ) -> Result<Option<Received<'b>>, Error> {
+        let mut socket = sockets.get::<UdpSocket>(self.handle.into());
+        bind(&mut socket, self.local_port)?;
+        let len = loop {
+            let (len, from) = match socket.recv_slice(buf) {
+                Ok(r) => r,
+                Err(smoltcp::Error::Exhausted) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if self.accepts(from, &buf[..len]) {
+                break len;
+            }
+        };
+        // Can't fail, checked by accepts()
+        let packet = Packet::parse(&buf[..len]).unwrap();
+        let arrival = self.receiver.update(&packet.header);
+        Ok(Some(Received { packet, arrival }))
+    }
+
+    /// Drains the sockets, queueing audio frames in the jitter buffer
+    /// and taking in RTCP reports
+    pub fn receive(&mut self, sockets: &mut SocketSet, time: Instant) -> Result<(), Error> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        while let Some(r) = self.recv(sockets, &mut buf)? {
+            let header = &r.packet.header;
+            if !r.arrival.is_valid() {
+                continue;
+            }
+            if r.arrival == Arrival::Restart {
+                // Nothing queued lines up with the new sequence
+                self.jitter_buffer.reset();
+            }
+            if header.payload_type != self.media.payload_type {
+                continue;
+            }
+            let push = self.jitter_buffer.push(time, header, r.packet.payload);
+            if push != Push::Queued {
+                debug!("RTP seq {} {:?}", header.sequence, push);
+            }
+        }
+        self.receive_rtcp(sockets, time)
+    }
+
+    /// Stops receiving, anything still queued is dropped
+    pub fn close(&mut self, sockets: &mut SocketSet) {
+        sockets.get::<UdpSocket>(self.handle.into()).close();
+        sockets.get::<UdpSocket>(self.rtcp_handle.into()).close();
+    }
+
+    fn receive_rtcp(&mut self, sockets: &mut SocketSet, time: Instant) -> Result<(), Error> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut socket = sockets.get::<UdpSocket>(self.rtcp_handle.into());
+        bind(&mut socket, self.local_port.wrapping_add(1))?;
+        loop {
+            let (len, from) = match socket.recv_slice(&mut buf) {
+                Ok(r) => r,
+                Err(smoltcp::Error::Exhausted) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            if !self.is_remote(from) {
+                continue;
+            }
+            if let Err(e) = self.reporter.process(time, self.sender.ssrc(), &buf[..len]) {
+                debug!("Bad RTCP packet from {}: {:?}", from, e);
+            }
+        }
+    }
+
+    fn send_rtcp(&mut self, sockets: &mut SocketSet, data: &[u8]) -> Result<(), Error> {
+        // Nowhere to send to while held with a null address
+        if self.media.remote.addr.is_unspecified() {
+            return Ok(());
+        }
+        let remote = IpEndpoint::new(
+            self.media.remote.addr,
+            self.media.remote.port.wrapping_add(1),
+        );
+        let mut socket = sockets.get::<UdpSocket>(self.rtcp_handle.into());
+        bind(&mut socket, self.local_port.wrapping_add(1))?;
+        socket.send_slice(data, remote)?;
+        Ok(())
+    }
+
+    fn is_remote(&self, from: IpEndpoint) -> bool {
+        // Symmetric RTP, but the port may be rewritten by a NAT
+        if !self.media.remote.addr.is_unspecified() && from.addr != self.media.remote.addr {
+            debug!("Ignoring RTP from {}", from);
+            return false;
+        }
+        true
+    }
+
+    fn accepts(&self, from: IpEndpoint, data: &[u8]) -> bool {
+        if !self.is_remote(from) {
+            return false;
+        }
+        match Packet::parse(data) {
+            Ok(p) => self.accepts_payload_type(&p.header),
+            Err(e) => {
+                debug!("Bad RTP packet from {}: {:?}", from, e);
+                false
+            }
+        }
+    }
+
+    fn accepts_payload_type(&self, header: &Header) -> bool {
+        header.payload_type == self.media.payload_type
+            || Some(header.payload_type) == self.media.telephone_event
+    }
+}
+
+fn bind(socket: &mut UdpSocket, port: u16) -> Result<(), Error> {
+    if !socket.is_open() {
+        socket.bind(port)?;
+    }
+    Ok(())
+}
+
+/// Samples in one packetization interval
+pub fn samples_per_frame(media: &Negotiated) -> u32 {
+    media.codec.clock_rate() * media.ptime / 1000
+}
+
+fn jitter_buffer(media: &Negotiated) -> JitterBuffer {
+    JitterBuffer::new(media.codec.clock_rate(), samples_per_frame(media))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::loopback::Loopback;
+    use crate::random::XorShift32;
+    use crate::sip::sdp::{Codec, Direction};
+    use smoltcp::iface::EthernetInterface;
+    use smoltcp::wire::{IpAddress, IpCidr};
+
+    const LOCAL_PORT: u16 = 10000;
+    const REMOTE_PORT: u16 = 20000;
+
+    fn media(port: u16) -> Negotiated {
+        Negotiated {
+            remote: IpEndpoint::new(IpAddress::v4(127, 0, 0, 1), port),
+            codec: Codec::Pcmu,
+            payload_type: 0,
+            telephone_event: Some(101),
+            ptime: 20,
+            direction: Direction::SendRecv,
+        }
+    }
+
+    fn poll(iface: &mut EthernetInterface<Loopback>, sockets: &mut SocketSet) {
+        for _ in 0..4 {
+            let _ = iface.poll(sockets, smoltcp::time::Instant::from_millis(0));
+        }
+    }
+
+    /// Sessions `a` on `LOCAL_PORT` and `b` on `REMOTE_PORT` sending to
+    /// each other over a loopback interface, with `b` bound
+    macro_rules! setup {
+        ($iface:ident, $sockets:ident, $a:ident, $b:ident) => {
+            crate::loopback!($iface, $sockets, [IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8)], 1, {
+                a_handle: [8; 2048],
+                b_handle: [8; 2048],
+                a_rtcp: [4; 512],
+                b_rtcp: [4; 512],
+            });
+            let mut rng = XorShift32::new(1);
+            let mut $a = Session::new(
+                UdpHandle::from_socket(a_handle),
+                UdpHandle::from_socket(a_rtcp),
+                LOCAL_PORT,
+                media(REMOTE_PORT),
+                &mut rng,
+            );
+            let mut $b = Session::new(
+                UdpHandle::from_socket(b_handle),
+                UdpHandle::from_socket(b_rtcp),
+                REMOTE_PORT,
+                media(LOCAL_PORT),
+                &mut rng,
+            );
+            // Bind the receiving side
+            let mut buf = [0; MAX_PACKET_SIZE];
+            assert_eq!($b.recv(&mut $sockets, &mut buf), Ok(None));
+        };
+    }
+
+    #[test]
+    fn loopback_sessions() {
+        setup!(iface, sockets, a, b);
+        assert_ne!(a.sender().ssrc(), b.sender().ssrc());
+        let mut buf = [0; MAX_PACKET_SIZE];
+
+        let frame = [0xFF; 160];
+        let ts = a.sender().timestamp();
+        a.send(&mut sockets, &frame, true).unwrap();
+        a.send(&mut sockets, &frame, false).unwrap();
+        poll(&mut iface, &mut sockets);
+
+        let r = b.recv(&mut sockets, &mut buf).unwrap().unwrap();
+        assert_eq!(r.arrival, Arrival::Restart);
+        assert_eq!(r.packet.header.marker, true);
+        assert_eq!(r.packet.header.timestamp, ts);
+        assert_eq!(r.packet.header.ssrc, a.sender().ssrc());
+        assert_eq!(r.packet.payload, &frame[..]);
+        let r = b.recv(&mut sockets, &mut buf).unwrap().unwrap();
+        assert_eq!(r.arrival, Arrival::InOrder);
+        assert_eq!(r.packet.header.timestamp, ts.wrapping_add(160));
+        assert_eq!(b.recv(&mut sockets, &mut buf), Ok(None));
+
+        // Dropped frame
+        a.sender_mut().next_frame(false, 160);
+        // Unknown payload type is filtered
+        let header = a.sender_mut().next_frame(false, 160);
+        let header = Header {
+            payload_type: 18,
+            ..header
+        };
+        a.send_packet(&mut sockets, &Packet::new(header, &frame))
+            .unwrap();
+        a.send(&mut sockets, &frame, false).unwrap();
+        poll(&mut iface, &mut sockets);
+
+        let r = b.recv(&mut sockets, &mut buf).unwrap().unwrap();
+        assert_eq!(r.arrival, Arrival::Gap(2));
+        assert_eq!(b.recv(&mut sockets, &mut buf), Ok(None));
+        assert_eq!(b.receiver().lost(), 2);
+
+        // On hold, nothing is sent but time moves on
+        let mut held = media(REMOTE_PORT);
+        held.direction = Direction::RecvOnly;
+        a.update(held);
+        let ts = a.sender().timestamp();
+        a.send(&mut sockets, &frame, false).unwrap();
+        assert_eq!(a.sender().timestamp(), ts.wrapping_add(160));
+        poll(&mut iface, &mut sockets);
+        assert_eq!(b.recv(&mut sockets, &mut buf), Ok(None));
+
+        // Off hold, audio goes to the jitter buffer
+        a.update(media(REMOTE_PORT));
+        a.send(&mut sockets, &frame, false).unwrap();
+        a.send(&mut sockets, &frame, false).unwrap();
+        poll(&mut iface, &mut sockets);
+        b.receive(&mut sockets, Instant::from_secs(1)).unwrap();
+        assert_eq!(b.jitter_buffer().len(), 2);
+    }
+
+    #[test]
+    fn rtcp_over_loopback() {
+        setup!(iface, sockets, a, b);
+
+        // Media both ways, the first reports are due after 2.5 s
+        let frame = [0xFF; 160];
+        let mut time = Instant::from_secs(0);
+        for _ in 0..10 {
+            a.poll(&mut sockets, time).unwrap();
+            b.poll(&mut sockets, time).unwrap();
+            a.send(&mut sockets, &frame, false).unwrap();
+            b.send(&mut sockets, &frame, false).unwrap();
+            poll(&mut iface, &mut sockets);
+            a.receive(&mut sockets, time).unwrap();
+            b.receive(&mut sockets, time).unwrap();
+            time += Duration::from_millis(20);
+        }
+        assert_eq!(a.quality(), None);
+
+        // Sender reports 20 ms apart, the second one answers the first
+        let time = Instant::from_millis(2500);
+        a.poll(&mut sockets, time).unwrap();
+        poll(&mut iface, &mut sockets);
+        let time = time + Duration::from_millis(10);
+        b.receive(&mut sockets, time).unwrap();
+        b.poll(&mut sockets, time).unwrap();
+        poll(&mut iface, &mut sockets);
+        let time = time + Duration::from_millis(10);
+        a.receive(&mut sockets, time).unwrap();
+
+        let block = b.reporter().remote_report().unwrap();
+        assert_eq!(block.ssrc, b.sender().ssrc());
+        assert_eq!(block.cumulative_lost, 0);
+        assert_eq!(
+            block.highest_sequence & 0xFFFF,
+            u32::from(b.sender().sequence().wrapping_sub(1))
+        );
+        let rtt = a.reporter().rtt().unwrap();
+        assert!(
+            rtt >= Duration::from_millis(19) && rtt <= Duration::from_millis(21),
+            "{:?}",
+            rtt
+        );
+        let quality = a.quality().unwrap();
+        assert_eq!(quality.rtt, Some(rtt));
+        assert_eq!(quality.fraction_lost, 0);
+        assert!(quality.mos >= 43, "{:?}", quality);
+
+        // Leaving
+        assert_eq!(b.reporter().is_bye(), false);
+        a.bye(&mut sockets, time).unwrap();
+        poll(&mut iface, &mut sockets);
+        b.receive(&mut sockets, time).unwrap();
+        assert_eq!(b.reporter().is_bye(), true);
+    }
+
+    #[test]
+    fn dtmf_over_loopback() {
+        setup!(iface, sockets, a, b);
+        let mut buf = [0; MAX_PACKET_SIZE];
+
+        let frame = [0xFF; 160];
+        a.send_dtmf('7', Duration::from_millis(100)).unwrap();
+        let mut events = heapless::Vec::<_, heapless::consts::U32>::new();
+        let mut time = Instant::from_secs(1);
+        for _ in 0..20 {
+            a.poll(&mut sockets, time).unwrap();
+            // Audio is held back while the digit goes out
+            a.send(&mut sockets, &frame, false).unwrap();
+            poll(&mut iface, &mut sockets);
+            while let Some(r) = b.recv(&mut sockets, &mut buf).unwrap() {
+                events
+                    .push((
+                        r.packet.header.payload_type,
+                        dtmf::Event::parse(r.packet.payload),
+                    ))
+                    .unwrap();
+            }
+            time += Duration::from_millis(20);
+        }
+
+        // Start, 3 continuations, 3 ends, then audio resumes alongside
+        // the last end packet
+        assert_eq!(events.len(), 21);
+        for (pt, event) in events[..7].iter() {
+            assert_eq!(*pt, 101);
+            assert_eq!(event.unwrap().code, 7);
+        }
+        assert_eq!(events[6].1.unwrap().end, true);
+        assert_eq!(events[6].1.unwrap().duration, 800);
+        assert_eq!(events[7].0, 0);
+        assert_eq!(b.receiver().lost(), 0);
+
+        let mut none = media(REMOTE_PORT);
+        none.telephone_event = None;
+        a.update(none);
+        assert_eq!(
+            a.send_dtmf('7', Duration::from_millis(100)),
+            Err(Error::Dtmf(dtmf::Error::NotNegotiated))
+        );
+    }
+}
@@ -0,0 +1,479 @@
+//! SIP message builder
+//!
+//! Serializes requests and responses into fixed capacity buffers,
+//! Content-Length is computed from the body when the message is finished.
+
+use crate::random::RandomSource;
+use crate::sip::message::{Message, Method, BRANCH_MAGIC_COOKIE, SIP_VERSION};
+use core::fmt::{self, Write};
+use heapless::consts::{U2048, U64};
+use heapless::{ArrayLength, String, Vec};
+
+/// Sized to match `net::eth::SOCKET_BUFFER_SIZE`
+pub type MessageBuffer = Vec<u8, U2048>;
+
+/// Storage for generated branch, tag and Call-ID values
+pub type Token = String<U64>;
+
+pub const MAX_FORWARDS: u32 = 70;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Message doesn't fit in the buffer
+    BufferFull,
+    /// Request used to build a response is missing mandatory headers
+    InvalidRequest,
+}
+
+/// Byte sink the builder writes into
+pub trait Buffer {
+    fn extend(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<N> Buffer for Vec<u8, N>
+where
+    N: ArrayLength<u8>,
+{
+    fn extend(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(data).map_err(|_| Error::BufferFull)
+    }
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+}
+
+/// Adapts a plain `&mut [u8]` to `Buffer`
+#[derive(Debug)]
+pub struct SliceBuffer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceBuffer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        SliceBuffer { buf, len: 0 }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<'a> Buffer for SliceBuffer<'a> {
+    fn extend(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            return Err(Error::BufferFull);
+        }
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// New Via branch, includes the RFC 3261 magic cookie
+pub fn new_branch<R: RandomSource>(rng: &mut R) -> Token {
+    let mut t = Token::new();
+    // Can't overflow the token
+    write!(
+        t,
+        "{}{:08x}{:08x}",
+        BRANCH_MAGIC_COOKIE,
+        rng.next_u32(),
+        rng.next_u32()
+    )
+    .unwrap();
+    t
+}
+
+/// New From/To tag
+pub fn new_tag<R: RandomSource>(rng: &mut R) -> Token {
+    let mut t = Token::new();
+    write!(t, "{:08x}", rng.next_u32()).unwrap();
+    t
+}
+
+/// New Call-ID, `host` is appended when given and fits
+pub fn new_call_id<R: RandomSource>(rng: &mut R, host: Option<&str>) -> Token {
+    let mut t = Token::new();
+    write!(
+        t,
+        "{:08x}{:08x}{:08x}",
+        rng.next_u32(),
+        rng.next_u32(),
+        rng.next_u32()
+    )
+    .unwrap();
+    if let Some(host) = host {
+        if t.len() + 1 + host.len() <= t.capacity() {
+            write!(t, "@{}", host).unwrap();
+        }
+    }
+    t
+}
+
+pub struct Builder<'b, B: Buffer> {
+    buf: &'b mut B,
+}
+
+impl<'b, B: Buffer> Builder<'b, B> {
+    /// Starts a request with its request line
+    pub fn request<U: fmt::Display>(buf: &'b mut B, method: Method, uri: U) -> Result<Self, Error> {
+        let mut b = Builder { buf };
+        b.write_fmt(format_args!("{} {} {}\r\n", method, uri, SIP_VERSION))?;
+        Ok(b)
+    }
+
+    /// Starts a response with its status line
+    pub fn response(buf: &'b mut B, code: u16, reason: &str) -> Result<Self, Error> {
+        let mut b = Builder { buf };
+        b.write_fmt(format_args!("{} {:03} {}\r\n", SIP_VERSION, code, reason))?;
+        Ok(b)
+    }
+
+    /// Starts a response to `request`, copying its Via, From, To, Call-ID
+    /// and CSeq headers. `to_tag` is added to the To header if it
+    /// doesn't already have one.
+    pub fn response_to(
+        buf: &'b mut B,
+        request: &Message,
+        code: u16,
+        reason: &str,
+        to_tag: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut b = Self::response(buf, code, reason)?;
+        for h in request.headers.iter().filter(|h| h.is("Via")) {
+            b.header("Via", h.value)?;
+        }
+        let from = request.header("From").ok_or(Error::InvalidRequest)?;
+        b.header("From", from)?;
+        let to = request.to().map_err(|_| Error::InvalidRequest)?;
+        let to_value = request.header("To").ok_or(Error::InvalidRequest)?;
+        match to_tag {
+            Some(tag) if to.tag().is_none() => {
+                b.header("To", format_args!("{};tag={}", to_value, tag))?
+            }
+            _ => b.header("To", to_value)?,
+        };
+        let call_id = request.call_id().map_err(|_| Error::InvalidRequest)?;
+        b.call_id(call_id)?;
+        let cseq = request.header("CSeq").ok_or(Error::InvalidRequest)?;
+        b.header("CSeq", cseq)?;
+        Ok(b)
+    }
+
+    pub fn header<V: fmt::Display>(&mut self, name: &str, value: V) -> Result<&mut Self, Error> {
+        self.write_fmt(format_args!("{}: {}\r\n", name, value))?;
+        Ok(self)
+    }
+
+    pub fn via(
+        &mut self,
+        transport: &str,
+        host: &str,
+        port: u16,
+        branch: &str,
+    ) -> Result<&mut Self, Error> {
+        self.header(
+            "Via",
+            format_args!(
+                "{}/{} {}:{};branch={};rport",
+                SIP_VERSION, transport, host, port, branch
+            ),
+        )
+    }
+
+    pub fn max_forwards(&mut self, hops: u32) -> Result<&mut Self, Error> {
+        self.header("Max-Forwards", hops)
+    }
+
+    pub fn from<U: fmt::Display>(
+        &mut self,
+        display_name: Option<&str>,
+        uri: U,
+        tag: Option<&str>,
+    ) -> Result<&mut Self, Error> {
+        self.name_addr("From", display_name, uri, tag)
+    }
+
+    pub fn to<U: fmt::Display>(
+        &mut self,
+        display_name: Option<&str>,
+        uri: U,
+        tag: Option<&str>,
+    ) -> Result<&mut Self, Error> {
+        self.name_addr("To", display_name, uri, tag)
+    }
+
+    pub fn contact<U: fmt::Display>(&mut self, uri: U) -> Result<&mut Self, Error> {
+        self.header("Contact", format_args!("<{}>", uri))
+    }
+
+    pub fn call_id(&mut self, call_id: &str) -> Result<&mut Self, Error> {
+        self.header("Call-ID", call_id)
+    }
+
+    pub fn cseq(&mut self, seq: u32, method: Method) -> Result<&mut Self, Error> {
+        self.header("CSeq", format_args!("{} {}", seq, method))
+    }
+
+    pub fn expires(&mut self, seconds: u32) -> Result<&mut Self, Error> {
+        self.header("Expires", seconds)
+    }
+
+    pub fn content_type(&mut self, content_type: &str) -> Result<&mut Self, Error> {
+        self.header("Content-Type", content_type)
+    }
+
+    /// Writes Content-Length, the end of the header section and the body,
+    /// returning the total message length
+    pub fn finish(self, body: &[u8]) -> Result<usize, Error> {
+        let mut b = self;
+        b.write_fmt(format_args!("Content-Length: {}\r\n\r\n", body.len()))?;
+        b.buf.extend(body)?;
+        Ok(b.buf.len())
+    }
+
+    fn name_addr<U: fmt::Display>(
+        &mut self,
+        name: &str,
+        display_name: Option<&str>,
+        uri: U,
+        tag: Option<&str>,
+    ) -> Result<&mut Self, Error> {
+        self.write_fmt(format_args!("{}: ", name))?;
+        if let Some(display_name) = display_name {
+            self.write_fmt(format_args!("\"{}\" ", display_name))?;
+        }
+        self.write_fmt(format_args!("<{}>", uri))?;
+        if let Some(tag) = tag {
+            self.write_fmt(format_args!(";tag={}", tag))?;
+        }
+        self.buf.extend(b"\r\n")?;
+        Ok(self)
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments) -> Result<(), Error> {
+        let mut w = Writer {
+            buf: self.buf,
+            error: None,
+        };
+        match fmt::write(&mut w, args) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(w.error.unwrap_or(Error::BufferFull)),
+        }
+    }
+}
+
+struct Writer<'a, B: Buffer> {
+    buf: &'a mut B,
+    error: Option<Error>,
+}
+
+impl<'a, B: Buffer> fmt::Write for Writer<'a, B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buf.extend(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::XorShift32;
+    use crate::sip::message::Uri;
+    use core::str;
+    use log::debug;
+
+    const SDP: &[u8] = b"v=0\r\n\
+o=alice 2890844526 2890844526 IN IP4 pc33.atlanta.com\r\n\
+s=-\r\n\
+c=IN IP4 pc33.atlanta.com\r\n\
+t=0 0\r\n\
+m=audio 49172 RTP/AVP 0\r\n\
+a=rtpmap:0 PCMU/8000\r\n";
+
+    #[test]
+    fn identifiers() {
+        let mut rng = XorShift32::new(1);
+        let branch = new_branch(&mut rng);
+        assert!(branch.starts_with(BRANCH_MAGIC_COOKIE));
+        assert_eq!(branch.len(), BRANCH_MAGIC_COOKIE.len() + 16);
+        assert_ne!(new_branch(&mut rng), branch);
+
+        let tag = new_tag(&mut rng);
+        assert_eq!(tag.len(), 8);
+
+        let call_id = new_call_id(&mut rng, Some("192.168.1.39"));
+        assert!(call_id.ends_with("@192.168.1.39"));
+        let call_id = new_call_id(&mut rng, None);
+        assert_eq!(call_id.len(), 24);
+    }
+
+    #[test]
+    fn build_invite() {
+        let mut rng = XorShift32::new(1);
+        let branch = new_branch(&mut rng);
+        let tag = new_tag(&mut rng);
+        let call_id = new_call_id(&mut rng, Some("pc33.atlanta.com"));
+        let uri = Uri::parse("sip:bob@biloxi.com").unwrap();
+
+        let mut buf = MessageBuffer::new();
+        let mut b = Builder::request(&mut buf, Method::Invite, &uri).unwrap();
+        b.via("UDP", "pc33.atlanta.com", 5060, &branch)
+            .unwrap()
+            .max_forwards(MAX_FORWARDS)
+            .unwrap()
+            .to(Some("Bob"), &uri, None)
+            .unwrap()
+            .from(Some("Alice"), "sip:alice@atlanta.com", Some(&tag))
+            .unwrap()
+            .call_id(&call_id)
+            .unwrap()
+            .cseq(314159, Method::Invite)
+            .unwrap()
+            .contact("sip:alice@pc33.atlanta.com")
+            .unwrap()
+            .content_type("application/sdp")
+            .unwrap();
+        let len = b.finish(SDP).unwrap();
+        assert_eq!(len, buf.len());
+        debug!("{}", str::from_utf8(&buf).unwrap());
+
+        let msg = Message::parse(&buf).unwrap();
+        assert_eq!(msg.method(), Some(Method::Invite));
+        assert_eq!(msg.request_line().unwrap().uri, uri);
+        let via = msg.via().unwrap();
+        assert_eq!(via.branch(), Some(branch.as_str()));
+        assert_eq!(via.port, Some(5060));
+        assert_eq!(via.rport(), Some(None));
+        assert_eq!(msg.max_forwards(), Ok(Some(70)));
+        assert_eq!(msg.to().unwrap().display_name, Some("Bob"));
+        assert_eq!(msg.from().unwrap().tag(), Some(tag.as_str()));
+        assert_eq!(msg.call_id(), Ok(call_id.as_str()));
+        assert_eq!(msg.cseq().unwrap().seq, 314159);
+        assert_eq!(msg.content_length(), Ok(Some(SDP.len())));
+        assert_eq!(msg.body, SDP);
+    }
+
+    #[test]
+    fn build_other_requests() {
+        let mut rng = XorShift32::new(2);
+        for method in [Method::Register, Method::Ack, Method::Bye, Method::Cancel].iter() {
+            let mut buf = MessageBuffer::new();
+            let mut b = Builder::request(&mut buf, *method, "sip:registrar.biloxi.com").unwrap();
+            b.via("UDP", "192.168.1.39", 5060, &new_branch(&mut rng))
+                .unwrap()
+                .max_forwards(MAX_FORWARDS)
+                .unwrap()
+                .to(None, "sip:bob@biloxi.com", None)
+                .unwrap()
+                .from(None, "sip:bob@biloxi.com", Some(&new_tag(&mut rng)))
+                .unwrap()
+                .call_id(&new_call_id(&mut rng, None))
+                .unwrap()
+                .cseq(1, *method)
+                .unwrap();
+            b.finish(&[]).unwrap();
+
+            let msg = Message::parse(&buf).unwrap();
+            assert_eq!(msg.method(), Some(*method));
+            assert_eq!(msg.cseq().unwrap().method, *method);
+            assert_eq!(msg.content_length(), Ok(Some(0)));
+        }
+    }
+
+    #[test]
+    fn build_response_to_request() {
+        let mut buf = MessageBuffer::new();
+        let mut b = Builder::request(&mut buf, Method::Invite, "sip:bob@biloxi.com").unwrap();
+        b.via("UDP", "pc33.atlanta.com", 5060, "z9hG4bK776asdhds")
+            .unwrap()
+            .header("Via", "SIP/2.0/UDP proxy.atlanta.com;branch=z9hG4bK1")
+            .unwrap()
+            .to(Some("Bob"), "sip:bob@biloxi.com", None)
+            .unwrap()
+            .from(Some("Alice"), "sip:alice@atlanta.com", Some("1928301774"))
+            .unwrap()
+            .call_id("a84b4c76e66710")
+            .unwrap()
+            .cseq(314159, Method::Invite)
+            .unwrap();
+        b.finish(&[]).unwrap();
+        let request = Message::parse(&buf).unwrap();
+
+        let mut resp_buf = MessageBuffer::new();
+        let mut b =
+            Builder::response_to(&mut resp_buf, &request, 180, "Ringing", Some("a6c85cf")).unwrap();
+        b.contact("sip:bob@192.0.2.4").unwrap();
+        b.finish(&[]).unwrap();
+        debug!("{}", str::from_utf8(&resp_buf).unwrap());
+
+        let resp = Message::parse(&resp_buf).unwrap();
+        assert_eq!(resp.status_code(), Some(180));
+        assert_eq!(resp.status_line().unwrap().reason, "Ringing");
+        assert_eq!(resp.vias().count(), 2);
+        assert_eq!(resp.via().unwrap().branch(), Some("z9hG4bK776asdhds"));
+        assert_eq!(resp.to().unwrap().tag(), Some("a6c85cf"));
+        assert_eq!(resp.from().unwrap().tag(), Some("1928301774"));
+        assert_eq!(resp.call_id(), Ok("a84b4c76e66710"));
+        assert_eq!(resp.cseq(), request.cseq());
+
+        // Existing To tag is kept
+        let mut buf = MessageBuffer::new();
+        let b = Builder::response_to(&mut buf, &resp, 200, "OK", Some("other")).unwrap();
+        b.finish(&[]).unwrap();
+        let resp = Message::parse(&buf).unwrap();
+        assert_eq!(resp.to().unwrap().tag(), Some("a6c85cf"));
+    }
+
+    #[test]
+    fn slice_buffer() {
+        let mut storage = [0_u8; 512];
+        let mut buf = SliceBuffer::new(&mut storage);
+        let mut b = Builder::response(&mut buf, 200, "OK").unwrap();
+        b.via("UDP", "pc33.atlanta.com", 5060, "z9hG4bK776asdhds")
+            .unwrap()
+            .to(None, "sip:bob@biloxi.com", Some("a6c85cf"))
+            .unwrap()
+            .from(None, "sip:alice@atlanta.com", Some("1928301774"))
+            .unwrap()
+            .call_id("a84b4c76e66710")
+            .unwrap()
+            .cseq(1, Method::Bye)
+            .unwrap();
+        let len = b.finish(&[]).unwrap();
+        assert_eq!(buf.len(), len);
+        let msg = Message::parse(buf.as_slice()).unwrap();
+        assert_eq!(msg.status_code(), Some(200));
+    }
+
+    #[test]
+    fn buffer_full() {
+        let mut storage = [0_u8; 64];
+        let mut buf = SliceBuffer::new(&mut storage);
+        let mut b = Builder::request(&mut buf, Method::Invite, "sip:bob@biloxi.com").unwrap();
+        assert_eq!(
+            b.via("UDP", "pc33.atlanta.com", 5060, "z9hG4bK776asdhds")
+                .err(),
+            Some(Error::BufferFull)
+        );
+
+        let mut buf: Vec<u8, heapless::consts::U32> = Vec::new();
+        let b = Builder::response(&mut buf, 200, "OK").unwrap();
+        assert_eq!(b.finish(SDP), Err(Error::BufferFull));
+    }
+}
@@ -0,0 +1,2045 @@
+//! SIP dialogs (RFC 3261 section 12) and call control
+//!
+//! `Dialog` holds the state of one INVITE dialog and builds the
+//! in-dialog requests. `Call` drives a single call over a
+//! `transaction::Table`, turning SIP messages into `PhoneEvent`s for
+//! the phone state machine and carrying out its `PhoneCommand`s.
+//!
+//! Every request goes to the configured registrar, which acts as the
+//! outbound proxy. The session is negotiated with SDP offer/answer,
+//! the offer is always in the INVITE.
+
+use crate::phone_number::PhoneNumber;
+use crate::phone_state::{PhoneCommand, PhoneEvent};
+use crate::random::RandomSource;
+use crate::sip::builder::{self, new_branch, new_call_id, new_tag, Buffer, Builder, MessageBuffer};
+use crate::sip::digest::{Authorization, CNonce, Challenge, Credentials};
+use crate::sip::message::{self, Message, Method, NameAddr, Uri};
+use crate::sip::registration::Config;
+use crate::sip::sdp::{self, Description, Direction, LocalMedia, Negotiated, SessionDescription};
+use crate::sip::transaction::{self, CallId, Table, TransactionId};
+use crate::time::Instant;
+use core::fmt::{self, Write};
+use heapless::consts::{U128, U16, U20, U4, U64};
+use heapless::{ArrayLength, String, Vec};
+use log::{debug, warn};
+use smoltcp::wire::IpEndpoint;
+
+pub type Tag = String<U64>;
+pub type UriString = String<U128>;
+pub type MaxRoutes = U4;
+/// Route header values, including the angle brackets
+pub type RouteSet = Vec<UriString, MaxRoutes>;
+
+/// Methods a `Call` handles, for Allow headers
+pub const ALLOW: &str = "INVITE, ACK, BYE, CANCEL, OPTIONS";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    Parse(message::Error),
+    Build(builder::Error),
+    Transaction(transaction::Error),
+    Sdp(sdp::Error),
+    /// From or To tag needed to identify the dialog is missing
+    MissingTag,
+    /// Dialog forming message has no Contact
+    MissingContact,
+    /// A URI, tag or the route set doesn't fit in its storage
+    TooLarge,
+    /// The command isn't valid in the current call state
+    InvalidState(CallState),
+}
+
+impl From<message::Error> for Error {
+    fn from(e: message::Error) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<builder::Error> for Error {
+    fn from(e: builder::Error) -> Self {
+        Error::Build(e)
+    }
+}
+
+impl From<sdp::Error> for Error {
+    fn from(e: sdp::Error) -> Self {
+        Error::Sdp(e)
+    }
+}
+
+impl From<transaction::Error> for Error {
+    fn from(e: transaction::Error) -> Self {
+        Error::Transaction(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum DialogState {
+    Early,
+    Confirmed,
+    Terminated,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Dialog {
+    state: DialogState,
+    call_id: CallId,
+    local_tag: Tag,
+    remote_tag: Tag,
+    local_uri: UriString,
+    remote_uri: UriString,
+    remote_target: UriString,
+    route_set: RouteSet,
+    local_seq: u32,
+    remote_seq: Option<u32>,
+}
+
+impl Dialog {
+    /// Dialog for a received INVITE that we respond to with `local_tag`
+    /// (RFC 3261 12.1.1)
+    pub fn uas(invite: &Message, local_tag: &str) -> Result<Self, Error> {
+        let from = invite.from()?;
+        let to = invite.to()?;
+        let contact = invite.contact().map_err(|_| Error::MissingContact)?;
+
+        let mut route_set = RouteSet::new();
+        for route in invite.header_values("Record-Route") {
+            route_set.push(copy(route)?).map_err(|_| Error::TooLarge)?;
+        }
+
+        Ok(Dialog {
+            state: DialogState::Early,
+            call_id: copy(invite.call_id()?)?,
+            local_tag: copy(local_tag)?,
+            remote_tag: copy(from.tag().ok_or(Error::MissingTag)?)?,
+            local_uri: uri_string(&to.uri)?,
+            remote_uri: uri_string(&from.uri)?,
+            remote_target: uri_string(&contact.uri)?,
+            route_set,
+            local_seq: 0,
+            remote_seq: Some(invite.cseq()?.seq),
+        })
+    }
+
+    /// Dialog for an INVITE we sent, from a provisional or 2xx
+    /// response with a To tag (RFC 3261 12.1.2)
+    pub fn uac(invite: &Message, response: &Message) -> Result<Self, Error> {
+        let from = invite.from()?;
+        let to = invite.to()?;
+        let code = response
+            .status_code()
+            .ok_or(Error::Parse(message::Error::InvalidStartLine))?;
+
+        // Provisional responses may omit the Contact
+        let remote_target = match response.contact() {
+            Ok(contact) => uri_string(&contact.uri)?,
+            Err(_) if code < 200 => match invite.request_line() {
+                Some(r) => uri_string(&r.uri)?,
+                None => return Err(Error::MissingContact),
+            },
+            Err(_) => return Err(Error::MissingContact),
+        };
+
+        // Reverse order of the Record-Route values
+        let mut routes: Vec<&str, MaxRoutes> = Vec::new();
+        for route in response.header_values("Record-Route") {
+            routes.push(route).map_err(|_| Error::TooLarge)?;
+        }
+        let mut route_set = RouteSet::new();
+        for route in routes.iter().rev() {
+            route_set.push(copy(route)?).map_err(|_| Error::TooLarge)?;
+        }
+
+        Ok(Dialog {
+            state: if code < 200 {
+                DialogState::Early
+            } else {
+                DialogState::Confirmed
+            },
+            call_id: copy(invite.call_id()?)?,
+            local_tag: copy(from.tag().ok_or(Error::MissingTag)?)?,
+            remote_tag: copy(response.to()?.tag().ok_or(Error::MissingTag)?)?,
+            local_uri: uri_string(&from.uri)?,
+            remote_uri: uri_string(&to.uri)?,
+            remote_target,
+            route_set,
+            local_seq: invite.cseq()?.seq,
+            remote_seq: None,
+        })
+    }
+
+    pub fn state(&self) -> DialogState {
+        self.state
+    }
+
+    pub fn call_id(&self) -> &str {
+        self.call_id.as_str()
+    }
+
+    pub fn local_tag(&self) -> &str {
+        self.local_tag.as_str()
+    }
+
+    pub fn remote_tag(&self) -> &str {
+        self.remote_tag.as_str()
+    }
+
+    pub fn local_uri(&self) -> &str {
+        self.local_uri.as_str()
+    }
+
+    pub fn remote_uri(&self) -> &str {
+        self.remote_uri.as_str()
+    }
+
+    pub fn remote_target(&self) -> &str {
+        self.remote_target.as_str()
+    }
+
+    pub fn route_set(&self) -> &[UriString] {
+        &self.route_set
+    }
+
+    pub fn local_seq(&self) -> u32 {
+        self.local_seq
+    }
+
+    pub fn remote_seq(&self) -> Option<u32> {
+        self.remote_seq
+    }
+
+    /// The remote party's number, from the From (incoming) or To (outgoing) URI
+    pub fn remote_number(&self) -> PhoneNumber {
+        match Uri::parse(self.remote_uri.as_str()) {
+            Ok(uri) => phone_number(&uri),
+            Err(_) => PhoneNumber::INVALID,
+        }
+    }
+
+    /// Request sent by the remote party in this dialog
+    pub fn matches_request(&self, msg: &Message) -> bool {
+        self.matches(msg, self.remote_tag.as_str(), self.local_tag.as_str())
+    }
+
+    /// Response to a request we sent in this dialog
+    pub fn matches_response(&self, msg: &Message) -> bool {
+        self.matches(msg, self.local_tag.as_str(), self.remote_tag.as_str())
+    }
+
+    fn matches(&self, msg: &Message, from_tag: &str, to_tag: &str) -> bool {
+        let from = msg.from().ok().and_then(|f| f.tag()) == Some(from_tag);
+        let to = msg.to().ok().and_then(|t| t.tag()) == Some(to_tag);
+        self.state != DialogState::Terminated
+            && from
+            && to
+            && msg.call_id() == Ok(self.call_id.as_str())
+    }
+
+    /// Checks and records the CSeq of an in-dialog request, a re-INVITE
+    /// also refreshes the remote target. Returns false if the request
+    /// is out of order and should be rejected with a 500.
+    pub fn on_request(&mut self, msg: &Message) -> Result<bool, Error> {
+        let cseq = msg.cseq()?;
+        match msg.method() {
+            Some(Method::Ack) | Some(Method::Cancel) => return Ok(true),
+            _ => (),
+        }
+        if let Some(seq) = self.remote_seq {
+            if cseq.seq <= seq {
+                return Ok(false);
+            }
+        }
+        self.remote_seq = Some(cseq.seq);
+        if msg.method() == Some(Method::Invite) {
+            if let Ok(contact) = msg.contact() {
+                self.remote_target = uri_string(&contact.uri)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// 2xx to the INVITE received or ACK for our 2xx
+    pub fn confirm(&mut self, response: Option<&Message>) -> Result<(), Error> {
+        if let Some(response) = response {
+            if let Ok(contact) = response.contact() {
+                self.remote_target = uri_string(&contact.uri)?;
+            }
+        }
+        self.state = DialogState::Confirmed;
+        Ok(())
+    }
+
+    pub fn terminate(&mut self) {
+        self.state = DialogState::Terminated;
+    }
+
+    /// Starts an in-dialog request (RFC 3261 12.2.1.1), the caller adds
+    /// any other headers and finishes it. ACK and CANCEL reuse the
+    /// current CSeq number, other methods increment it.
+    pub fn request<'b, B: Buffer>(
+        &mut self,
+        buf: &'b mut B,
+        method: Method,
+        via_host: &str,
+        via_port: u16,
+        branch: &str,
+    ) -> Result<Builder<'b, B>, Error> {
+        match method {
+            Method::Ack | Method::Cancel => (),
+            _ => self.local_seq = self.local_seq.wrapping_add(1),
+        }
+
+        // A first route without ;lr is a strict router, it becomes the
+        // Request-URI and the remote target goes to the end of the routes
+        let strict = match self.route_set.first() {
+            Some(route) => match NameAddr::parse(route.as_str()) {
+                Ok(r) => !r.uri.params.contains("lr"),
+                Err(_) => false,
+            },
+            None => false,
+        };
+
+        let mut b = if strict {
+            let first = NameAddr::parse(self.route_set[0].as_str())?;
+            Builder::request(buf, method, first.uri)?
+        } else {
+            Builder::request(buf, method, self.remote_target.as_str())?
+        };
+        b.via("UDP", via_host, via_port, branch)?
+            .max_forwards(builder::MAX_FORWARDS)?;
+        let routes = if strict {
+            &self.route_set[1..]
+        } else {
+            &self.route_set[..]
+        };
+        for route in routes.iter() {
+            b.header("Route", route.as_str())?;
+        }
+        if strict {
+            b.header("Route", format_args!("<{}>", self.remote_target))?;
+        }
+        b.from(None, self.local_uri.as_str(), Some(self.local_tag.as_str()))?
+            .to(
+                None,
+                self.remote_uri.as_str(),
+                Some(self.remote_tag.as_str()),
+            )?
+            .call_id(self.call_id.as_str())?
+            .cseq(self.local_seq, method)?;
+        Ok(b)
+    }
+}
+
+/// Number from the user part of a SIP URI, `PhoneNumber::INVALID` if it
+/// doesn't hold a domestic number. Visual separators and a leading
+/// +1/1 country code are ignored.
+pub fn phone_number(uri: &Uri) -> PhoneNumber {
+    let user = match uri.user {
+        Some(u) => u,
+        None => return PhoneNumber::INVALID,
+    };
+    let mut digits: String<U16> = String::new();
+    for c in user.chars() {
+        match c {
+            '0'..='9' => {
+                if digits.push(c).is_err() {
+                    return PhoneNumber::INVALID;
+                }
+            }
+            '+' | '-' | '.' | '(' | ')' => (),
+            _ => return PhoneNumber::INVALID,
+        }
+    }
+    let digits = match digits.len() {
+        10 => digits.as_str(),
+        11 if digits.starts_with('1') => &digits[1..],
+        _ => return PhoneNumber::INVALID,
+    };
+    PhoneNumber::from_utf8(digits).unwrap_or(PhoneNumber::INVALID)
+}
+
+fn copy<N: ArrayLength<u8>>(s: &str) -> Result<String<N>, Error> {
+    let mut out = String::new();
+    out.push_str(s).map_err(|_| Error::TooLarge)?;
+    Ok(out)
+}
+
+fn uri_string(uri: &Uri) -> Result<UriString, Error> {
+    let mut out = UriString::new();
+    write!(out, "{}", uri).map_err(|_| Error::TooLarge)?;
+    Ok(out)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum CallState {
+    Idle,
+    /// INVITE sent, waiting for the remote party to answer
+    Outgoing,
+    /// INVITE received, ringing
+    Incoming,
+    /// Our 2xx was sent, waiting for the ACK
+    Answered,
+    Established,
+}
+
+/// A single call, at most one at a time
+pub struct Call<'a, R: RandomSource> {
+    config: Config<'a>,
+    rng: R,
+    state: CallState,
+    dialog: Option<Dialog>,
+    /// Transaction of the INVITE that set up the call
+    invite_id: Option<TransactionId>,
+    /// The INVITE sent or received, kept for responses, CANCEL and auth retries
+    invite: MessageBuffer,
+    /// ACK for the 2xx to our INVITE, resent for each 2xx retransmission
+    ack: MessageBuffer,
+    send_ack: bool,
+    /// Outgoing call abandoned before a provisional response arrived
+    cancel_pending: bool,
+    /// Outgoing call got a provisional response
+    provisional: bool,
+    /// Hung up before our 2xx was ACKed, the BYE waits for the ACK
+    bye_pending: bool,
+    challenge: Option<(Challenge, bool)>,
+    /// Current INVITE carried credentials
+    authorized: bool,
+    nc: u32,
+    /// Call-ID, From tag and CSeq of our INVITEs
+    call_id: CallId,
+    tag: Tag,
+    cseq: u32,
+    remote: PhoneNumber,
+    local_media: LocalMedia<'a>,
+    /// Negotiated session, used once the call is established
+    media: Option<Negotiated>,
+    /// SDP o= line session id and version
+    session_id: u64,
+    session_version: u64,
+}
+
+impl<'a, R: RandomSource> Call<'a, R> {
+    pub fn new(config: Config<'a>, local_media: LocalMedia<'a>, rng: R) -> Self {
+        Call {
+            config,
+            rng,
+            state: CallState::Idle,
+            dialog: None,
+            invite_id: None,
+            invite: MessageBuffer::new(),
+            ack: MessageBuffer::new(),
+            send_ack: false,
+            cancel_pending: false,
+            provisional: false,
+            bye_pending: false,
+            challenge: None,
+            authorized: false,
+            nc: 0,
+            call_id: CallId::new(),
+            tag: Tag::new(),
+            cseq: 0,
+            remote: PhoneNumber::INVALID,
+            local_media,
+            media: None,
+            session_id: 0,
+            session_version: 0,
+        }
+    }
+
+    pub fn state(&self) -> CallState {
+        self.state
+    }
+
+    pub fn dialog(&self) -> Option<&Dialog> {
+        self.dialog.as_ref()
+    }
+
+    pub fn remote(&self) -> PhoneNumber {
+        self.remote
+    }
+
+    /// Where and how to send/receive RTP for the answered call
+    pub fn media(&self) -> Option<&Negotiated> {
+        match self.state {
+            CallState::Answered if !self.bye_pending => self.media.as_ref(),
+            CallState::Established => self.media.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Address changed (i.e. new DHCP lease), used for Via, Contact and SDP
+    pub fn set_local_addr(&mut self, addr: smoltcp::wire::Ipv4Address) {
+        self.config.local_addr = addr;
+        self.local_media.addr = addr;
+    }
+
+    /// Registrar (the outbound proxy) moved, requests go there from now on
+    pub fn set_registrar(&mut self, registrar: IpEndpoint) {
+        self.config.registrar = registrar;
+    }
+
+    /// Carries out a command from the phone state machine, returns
+    /// `CallFailed` when an outgoing call couldn't be placed
+    pub fn handle_command(
+        &mut self,
+        table: &mut Table,
+        cmd: PhoneCommand,
+        time: Instant,
+    ) -> Result<Option<PhoneEvent>, Error> {
+        match (cmd, self.state) {
+            (PhoneCommand::PlaceCall(number), CallState::Idle) => {
+                self.remote = number;
+                self.challenge = None;
+                self.cancel_pending = false;
+                self.provisional = false;
+                let host = self.local_host();
+                self.call_id = copy(new_call_id(&mut self.rng, Some(host.as_str())).as_str())?;
+                self.tag = copy(new_tag(&mut self.rng).as_str())?;
+                self.new_session();
+                if let Err(e) = self.send_invite(table, time) {
+                    return Ok(self.call_failed(e));
+                }
+                self.state = CallState::Outgoing;
+            }
+            (PhoneCommand::CancelCall, CallState::Outgoing) => {
+                if self.cancel_pending {
+                    return Ok(None);
+                }
+                self.cancel_pending = true;
+                // CANCEL can only follow a provisional response, otherwise
+                // it's sent when one arrives
+                if self.provisional {
+                    self.send_cancel(table, time)?;
+                }
+            }
+            (PhoneCommand::AnswerCall, CallState::Incoming) => {
+                self.respond_invite(table, 200, "OK", time)?;
+                self.state = CallState::Answered;
+            }
+            (PhoneCommand::DeclineCall, CallState::Incoming) => {
+                self.respond_invite(table, 486, "Busy Here", time)?;
+                self.reset();
+            }
+            (PhoneCommand::HangUp, CallState::Answered) => {
+                // No BYE before the ACK (RFC 3261 15)
+                self.bye_pending = true;
+            }
+            (PhoneCommand::HangUp, CallState::Established) => {
+                self.send_bye(table, time)?;
+                self.reset();
+            }
+            (PhoneCommand::StartRinger, _) | (PhoneCommand::StopRinger, _) => (),
+            (PhoneCommand::PlayTone(_), _) | (PhoneCommand::StopTone, _) => (),
+            // Sent on the media path, not signalled
+            (PhoneCommand::SendDtmf(..), _) => (),
+            // Already handled, i.e. a second INVITE was rejected on arrival
+            (PhoneCommand::DeclineCall, _) => (),
+            (_, state) => return Err(Error::InvalidState(state)),
+        }
+        Ok(None)
+    }
+
+    /// New request on server transaction `id`, `data` is the raw
+    /// message which is kept for an INVITE
+    pub fn handle_request(
+        &mut self,
+        table: &mut Table,
+        id: TransactionId,
+        data: &[u8],
+        time: Instant,
+    ) -> Result<Option<PhoneEvent>, Error> {
+        let msg = &Message::parse(data)?;
+        let method = msg.method().unwrap_or(Method::Unknown);
+        let in_dialog = match &self.dialog {
+            Some(d) => d.matches_request(msg),
+            None => false,
+        };
+
+        match method {
+            Method::Invite if in_dialog => {
+                let ok = match &mut self.dialog {
+                    Some(d) => d.on_request(msg)?,
+                    None => false,
+                };
+                if !ok {
+                    respond(table, id, msg, 500, "Server Internal Error", None, time)?;
+                    return Ok(None);
+                }
+                if msg.body.is_empty() {
+                    // Offer in the 2xx, the answer in the ACK isn't needed
+                    // since nothing changes on our side
+                    self.session_version += 1;
+                    let direction = self.media.map(|m| m.direction).unwrap_or_default();
+                    let body = Description::offer(
+                        &self.local_media,
+                        self.session_id,
+                        self.session_version,
+                        direction,
+                    )
+                    .to_body()?;
+                    self.respond_with_body(table, id, msg, None, &body, time)?;
+                    return Ok(None);
+                }
+                match offered_media(msg, &self.local_media) {
+                    Ok(media) => {
+                        self.session_version += 1;
+                        let body = self.answer(msg, &media)?;
+                        self.respond_with_body(table, id, msg, None, &body, time)?;
+                        debug!("Session modified {:?}", media);
+                        self.media = Some(media);
+                    }
+                    Err(e) => {
+                        warn!("Unacceptable session offer {:?}", e);
+                        respond(table, id, msg, 488, "Not Acceptable Here", None, time)?;
+                    }
+                }
+                Ok(None)
+            }
+            Method::Invite => {
+                if msg.to()?.tag().is_some() {
+                    // Mid-dialog request for a dialog we don't know
+                    respond(
+                        table,
+                        id,
+                        msg,
+                        481,
+                        "Call/Transaction Does Not Exist",
+                        None,
+                        time,
+                    )?;
+                    return Ok(None);
+                }
+                if self.state != CallState::Idle {
+                    // No call waiting support
+                    respond(table, id, msg, 486, "Busy Here", None, time)?;
+                    return Ok(None);
+                }
+
+                if data.len() > self.invite.capacity() {
+                    respond(table, id, msg, 413, "Request Entity Too Large", None, time)?;
+                    return Ok(None);
+                }
+                // TODO - offerless INVITEs, the offer goes in our 2xx and
+                // the answer comes in the ACK
+                let media = match offered_media(msg, &self.local_media) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        warn!("Unacceptable session offer {:?}", e);
+                        respond(table, id, msg, 488, "Not Acceptable Here", None, time)?;
+                        return Ok(None);
+                    }
+                };
+
+                let tag = new_tag(&mut self.rng);
+                let dialog = match Dialog::uas(msg, tag.as_str()) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        warn!("Can't create dialog {:?}", e);
+                        respond(table, id, msg, 400, "Bad Request", None, time)?;
+                        return Ok(None);
+                    }
+                };
+                self.invite.clear();
+                // Can't overflow, checked above
+                self.invite.extend_from_slice(data).unwrap();
+                self.remote = dialog.remote_number();
+                self.dialog = Some(dialog);
+                self.invite_id = Some(id);
+                self.media = Some(media);
+                self.new_session();
+                self.state = CallState::Incoming;
+                self.respond_invite(table, 180, "Ringing", time)?;
+                debug!("Incoming call from {}", self.remote);
+                Ok(Some(PhoneEvent::IncomingCall(self.remote)))
+            }
+            Method::Cancel => {
+                let cancels_invite = match (self.state, self.invite_id.and_then(|i| table.get(i))) {
+                    (CallState::Incoming, Some(t)) => {
+                        msg.via().ok().and_then(|v| v.branch()) == Some(t.branch())
+                    }
+                    _ => false,
+                };
+                if cancels_invite {
+                    respond(table, id, msg, 200, "OK", None, time)?;
+                    self.respond_invite(table, 487, "Request Terminated", time)?;
+                    self.reset();
+                    Ok(Some(PhoneEvent::RemoteHangup))
+                } else {
+                    respond(
+                        table,
+                        id,
+                        msg,
+                        481,
+                        "Call/Transaction Does Not Exist",
+                        None,
+                        time,
+                    )?;
+                    Ok(None)
+                }
+            }
+            Method::Bye if in_dialog => {
+                respond(table, id, msg, 200, "OK", None, time)?;
+                let hung_up = self.bye_pending;
+                self.reset();
+                if hung_up {
+                    Ok(None)
+                } else {
+                    Ok(Some(PhoneEvent::RemoteHangup))
+                }
+            }
+            Method::Bye => {
+                respond(
+                    table,
+                    id,
+                    msg,
+                    481,
+                    "Call/Transaction Does Not Exist",
+                    None,
+                    time,
+                )?;
+                Ok(None)
+            }
+            Method::Options => {
+                respond(table, id, msg, 200, "OK", Some(("Allow", ALLOW)), time)?;
+                Ok(None)
+            }
+            _ => {
+                respond(
+                    table,
+                    id,
+                    msg,
+                    405,
+                    "Method Not Allowed",
+                    Some(("Allow", ALLOW)),
+                    time,
+                )?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// ACK for the 2xx we sent on transaction `id`, establishes the call
+    pub fn handle_ack(
+        &mut self,
+        table: &mut Table,
+        id: TransactionId,
+        time: Instant,
+    ) -> Result<(), Error> {
+        if self.invite_id != Some(id) || self.state != CallState::Answered {
+            return Ok(());
+        }
+        if let Some(d) = &mut self.dialog {
+            d.confirm(None)?;
+        }
+        if self.bye_pending {
+            self.send_bye(table, time)?;
+            self.reset();
+        } else {
+            self.state = CallState::Established;
+        }
+        Ok(())
+    }
+
+    /// Response on client transaction `id`
+    pub fn handle_response(
+        &mut self,
+        table: &mut Table,
+        id: TransactionId,
+        msg: &Message,
+        time: Instant,
+    ) -> Result<Option<PhoneEvent>, Error> {
+        if self.invite_id != Some(id) || self.state != CallState::Outgoing {
+            // 2xx retransmission after we answered, or a BYE/CANCEL response
+            if self.invite_id == Some(id) && self.dialog.is_some() {
+                if let Some(200..=299) = msg.status_code() {
+                    self.send_ack = true;
+                }
+            }
+            return Ok(None);
+        }
+
+        let code = msg.status_code().unwrap_or(0);
+        match code {
+            100 => Ok(None),
+            101..=199 => {
+                self.provisional = true;
+                if self.dialog.is_none() && msg.to()?.tag().is_some() {
+                    self.dialog = Some(self.uac_dialog(msg)?);
+                }
+                if self.cancel_pending {
+                    self.send_cancel(table, time)?;
+                    Ok(None)
+                } else {
+                    Ok(Some(PhoneEvent::RemoteRinging))
+                }
+            }
+            200..=299 => {
+                let mut dialog = self.uac_dialog(msg)?;
+                if let Some(early) = &self.dialog {
+                    // Keep the early dialog's CSeq, same tags means same dialog
+                    if early.remote_tag() == dialog.remote_tag() {
+                        dialog.local_seq = early.local_seq;
+                    }
+                }
+                dialog.confirm(Some(msg))?;
+                self.dialog = Some(dialog);
+                self.build_ack()?;
+                self.send_ack = true;
+
+                if self.cancel_pending {
+                    // Answered before the CANCEL got there
+                    self.send_bye(table, time)?;
+                    self.reset();
+                    return Ok(None);
+                }
+                match answered_media(msg, &self.local_media) {
+                    Ok(media) => {
+                        self.media = Some(media);
+                        self.state = CallState::Established;
+                        Ok(Some(PhoneEvent::RemoteAnswered))
+                    }
+                    Err(e) => {
+                        // The call has to be ACKed then ended (RFC 3261 13.2.2.4)
+                        warn!("Unacceptable session answer {:?}", e);
+                        self.send_bye(table, time)?;
+                        self.reset();
+                        Ok(Some(PhoneEvent::RemoteHangup))
+                    }
+                }
+            }
+            401 | 407 if !self.cancel_pending => {
+                let proxy = code == 407;
+                let name = if proxy {
+                    "Proxy-Authenticate"
+                } else {
+                    "WWW-Authenticate"
+                };
+                match msg.header(name).map(Challenge::parse) {
+                    Some(Ok(challenge)) if !self.authorized || challenge.stale => {
+                        self.challenge = Some((challenge, proxy));
+                        self.nc = 0;
+                        match self.send_invite(table, time) {
+                            Ok(()) => Ok(None),
+                            Err(e) => Ok(self.call_failed(e)),
+                        }
+                    }
+                    _ => {
+                        warn!("Call authentication failed");
+                        self.reset();
+                        Ok(Some(PhoneEvent::RemoteHangup))
+                    }
+                }
+            }
+            _ => {
+                debug!("Call failed with {}", code);
+                let cancelled = self.cancel_pending;
+                self.reset();
+                if cancelled {
+                    Ok(None)
+                } else {
+                    Ok(Some(PhoneEvent::RemoteHangup))
+                }
+            }
+        }
+    }
+
+    /// Transaction `id` timed out
+    pub fn handle_timeout(
+        &mut self,
+        table: &mut Table,
+        id: TransactionId,
+        time: Instant,
+    ) -> Result<Option<PhoneEvent>, Error> {
+        if self.invite_id != Some(id) {
+            return Ok(None);
+        }
+        match self.state {
+            CallState::Outgoing => {
+                let cancelled = self.cancel_pending;
+                self.reset();
+                if cancelled {
+                    Ok(None)
+                } else {
+                    Ok(Some(PhoneEvent::RemoteHangup))
+                }
+            }
+            CallState::Answered => {
+                // Our 2xx was never ACKed (RFC 3261 13.3.1.4)
+                warn!("No ACK for the call");
+                let hung_up = self.bye_pending;
+                self.send_bye(table, time)?;
+                self.reset();
+                if hung_up {
+                    Ok(None)
+                } else {
+                    Ok(Some(PhoneEvent::RemoteHangup))
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Sends any pending ACK for a 2xx, those aren't part of a transaction
+    pub fn poll_transmit<F>(&mut self, transmit: &mut F)
+    where
+        F: FnMut(&[u8], IpEndpoint) -> bool,
+    {
+        if self.send_ack && transmit(&self.ack, self.config.registrar) {
+            self.send_ack = false;
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(d) = &mut self.dialog {
+            d.terminate();
+        }
+        self.state = CallState::Idle;
+        self.cancel_pending = false;
+        self.provisional = false;
+        self.bye_pending = false;
+        self.authorized = false;
+        self.media = None;
+    }
+
+    /// New SDP session for a call
+    fn new_session(&mut self) {
+        self.session_id = u64::from(self.rng.next_u32());
+        self.session_version = 1;
+    }
+
+    /// SDP answer to the offer in `request`
+    fn answer(&self, request: &Message, media: &Negotiated) -> Result<sdp::Body, Error> {
+        let offer = core::str::from_utf8(request.body).map_err(|_| sdp::Error::InvalidLine)?;
+        let offer = SessionDescription::parse(offer)?;
+        let answer = Description::answer(
+            &offer,
+            &self.local_media,
+            media,
+            self.session_id,
+            self.session_version,
+        );
+        Ok(answer.to_body()?)
+    }
+
+    /// INVITE couldn't be sent, the call ends before it started
+    fn call_failed(&mut self, e: Error) -> Option<PhoneEvent> {
+        warn!("Call not placed {:?}", e);
+        self.reset();
+        Some(PhoneEvent::CallFailed)
+    }
+
+    fn local_host(&self) -> String<U20> {
+        let mut host = String::new();
+        // Can't overflow, longest IPv4 address is 15 chars
+        write!(host, "{}", self.config.local_addr).unwrap();
+        host
+    }
+
+    fn contact(&self) -> ContactUri<'a> {
+        ContactUri {
+            username: self.config.username,
+            addr: self.config.local_addr,
+            port: self.config.local_port,
+        }
+    }
+
+    /// Sends an INVITE with the next CSeq, with credentials when
+    /// there's a challenge to answer
+    fn send_invite(&mut self, table: &mut Table, time: Instant) -> Result<(), Error> {
+        let c = self.config;
+        let host = self.local_host();
+        let branch = new_branch(&mut self.rng);
+        self.cseq = self.cseq.wrapping_add(1);
+        let number = Digits(self.remote);
+
+        let mut uri = UriString::new();
+        write!(uri, "sip:{}@{}", number, c.domain).map_err(|_| Error::TooLarge)?;
+        let mut cnonce = CNonce::new();
+        // Can't overflow the cnonce
+        write!(
+            cnonce,
+            "{:08x}{:08x}",
+            self.rng.next_u32(),
+            self.rng.next_u32()
+        )
+        .unwrap();
+
+        let mut invite = MessageBuffer::new();
+        let mut b = Builder::request(&mut invite, Method::Invite, uri.as_str())?;
+        b.via("UDP", host.as_str(), c.local_port, branch.as_str())?
+            .max_forwards(builder::MAX_FORWARDS)?
+            .from(
+                None,
+                format_args!("sip:{}@{}", c.username, c.domain),
+                Some(self.tag.as_str()),
+            )?
+            .to(None, uri.as_str(), None)?
+            .call_id(self.call_id.as_str())?
+            .cseq(self.cseq, Method::Invite)?
+            .contact(self.contact())?
+            .header("Allow", ALLOW)?
+            .content_type(sdp::CONTENT_TYPE)?;
+        self.authorized = false;
+        if let Some((challenge, proxy)) = &self.challenge {
+            self.nc = self.nc.wrapping_add(1);
+            let credentials = Credentials {
+                username: c.username,
+                password: c.password,
+            };
+            let auth = Authorization {
+                challenge,
+                credentials: &credentials,
+                method: Method::Invite.as_str(),
+                uri: uri.as_str(),
+                nc: self.nc,
+                cnonce: cnonce.as_str(),
+            };
+            let name = if *proxy {
+                "Proxy-Authorization"
+            } else {
+                "Authorization"
+            };
+            b.header(name, auth)?;
+            self.authorized = true;
+        }
+        let offer = Description::offer(
+            &self.local_media,
+            self.session_id,
+            self.session_version,
+            Direction::SendRecv,
+        )
+        .to_body()?;
+        b.finish(offer.as_bytes())?;
+
+        let id = table.send_request(&invite, c.registrar, time)?;
+        self.invite = invite;
+        self.invite_id = Some(id);
+        self.dialog = None;
+        Ok(())
+    }
+
+    fn uac_dialog(&self, response: &Message) -> Result<Dialog, Error> {
+        let invite = Message::parse(&self.invite)?;
+        Dialog::uac(&invite, response)
+    }
+
+    /// CANCEL for our INVITE (RFC 3261 9.1)
+    fn send_cancel(&mut self, table: &mut Table, time: Instant) -> Result<(), Error> {
+        let mut cancel = MessageBuffer::new();
+        {
+            let invite = Message::parse(&self.invite)?;
+            let uri = invite
+                .request_line()
+                .ok_or(Error::Parse(message::Error::InvalidStartLine))?
+                .uri;
+            let via = invite
+                .header_values("Via")
+                .next()
+                .ok_or(message::Error::MissingHeader)?;
+            let mut b = Builder::request(&mut cancel, Method::Cancel, uri)?;
+            b.header("Via", via)?.max_forwards(builder::MAX_FORWARDS)?;
+            for route in invite.headers.iter().filter(|h| h.is("Route")) {
+                b.header("Route", route.value)?;
+            }
+            b.header(
+                "From",
+                invite.header("From").ok_or(message::Error::MissingHeader)?,
+            )?
+            .header(
+                "To",
+                invite.header("To").ok_or(message::Error::MissingHeader)?,
+            )?
+            .call_id(invite.call_id()?)?
+            .cseq(invite.cseq()?.seq, Method::Cancel)?;
+            b.finish(&[])?;
+        }
+        table.send_request(&cancel, self.config.registrar, time)?;
+        Ok(())
+    }
+
+    fn send_bye(&mut self, table: &mut Table, time: Instant) -> Result<(), Error> {
+        let host = self.local_host();
+        let branch = new_branch(&mut self.rng);
+        let port = self.config.local_port;
+        let mut bye = MessageBuffer::new();
+        match &mut self.dialog {
+            Some(d) => {
+                d.request(&mut bye, Method::Bye, host.as_str(), port, branch.as_str())?
+                    .finish(&[])?;
+                d.terminate();
+            }
+            None => return Err(Error::InvalidState(self.state)),
+        }
+        table.send_request(&bye, self.config.registrar, time)?;
+        Ok(())
+    }
+
+    /// ACK for the 2xx to our INVITE (RFC 3261 13.2.2.4)
+    fn build_ack(&mut self) -> Result<(), Error> {
+        let host = self.local_host();
+        let branch = new_branch(&mut self.rng);
+        let port = self.config.local_port;
+        let mut ack = MessageBuffer::new();
+        match &mut self.dialog {
+            Some(d) => {
+                d.request(&mut ack, Method::Ack, host.as_str(), port, branch.as_str())?
+                    .finish(&[])?;
+            }
+            None => return Err(Error::InvalidState(self.state)),
+        }
+        self.ack = ack;
+        Ok(())
+    }
+
+    /// Responds on the INVITE server transaction with our To tag
+    fn respond_invite(
+        &mut self,
+        table: &mut Table,
+        code: u16,
+        reason: &str,
+        time: Instant,
+    ) -> Result<(), Error> {
+        let id = self.invite_id.ok_or(Error::InvalidState(self.state))?;
+        let tag = match &self.dialog {
+            Some(d) => copy::<U64>(d.local_tag())?,
+            None => return Err(Error::InvalidState(self.state)),
+        };
+        let invite = Message::parse(&self.invite)?;
+        match (code, &self.media) {
+            (200..=299, Some(media)) => {
+                let body = self.answer(&invite, media)?;
+                self.respond_with_body(table, id, &invite, Some(tag.as_str()), &body, time)
+            }
+            _ => {
+                let mut response = MessageBuffer::new();
+                Builder::response_to(&mut response, &invite, code, reason, Some(tag.as_str()))?
+                    .finish(&[])?;
+                table.respond(id, &response, time)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// 200 to an INVITE with our Contact and an SDP body
+    fn respond_with_body(
+        &self,
+        table: &mut Table,
+        id: TransactionId,
+        request: &Message,
+        to_tag: Option<&str>,
+        body: &sdp::Body,
+        time: Instant,
+    ) -> Result<(), Error> {
+        let mut response = MessageBuffer::new();
+        let mut b = Builder::response_to(&mut response, request, 200, "OK", to_tag)?;
+        b.contact(self.contact())?
+            .header("Allow", ALLOW)?
+            .content_type(sdp::CONTENT_TYPE)?;
+        b.finish(body.as_bytes())?;
+        table.respond(id, &response, time)?;
+        Ok(())
+    }
+}
+
+/// Session from the SDP offer in an INVITE
+fn offered_media(request: &Message, local: &LocalMedia) -> Result<Negotiated, sdp::Error> {
+    let offer = core::str::from_utf8(request.body).map_err(|_| sdp::Error::InvalidLine)?;
+    Negotiated::from_offer(&SessionDescription::parse(offer)?, local)
+}
+
+/// Session from the SDP answer in a 2xx to our INVITE
+fn answered_media(response: &Message, local: &LocalMedia) -> Result<Negotiated, sdp::Error> {
+    let answer = core::str::from_utf8(response.body).map_err(|_| sdp::Error::InvalidLine)?;
+    Negotiated::from_answer(&SessionDescription::parse(answer)?, local)
+}
+
+/// Stateless-style response on server transaction `id`
+fn respond(
+    table: &mut Table,
+    id: TransactionId,
+    request: &Message,
+    code: u16,
+    reason: &str,
+    header: Option<(&str, &str)>,
+    time: Instant,
+) -> Result<(), Error> {
+    let mut response = MessageBuffer::new();
+    let mut b = Builder::response_to(&mut response, request, code, reason, None)?;
+    if let Some((name, value)) = header {
+        b.header(name, value)?;
+    }
+    b.finish(&[])?;
+    table.respond(id, &response, time)?;
+    Ok(())
+}
+
+/// Contact URI for our binding
+struct ContactUri<'a> {
+    username: &'a str,
+    addr: smoltcp::wire::Ipv4Address,
+    port: u16,
+}
+
+impl<'a> fmt::Display for ContactUri<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sip:{}@{}:{}", self.username, self.addr, self.port)
+    }
+}
+
+/// Number as dialable digits
+struct Digits(PhoneNumber);
+
+impl fmt::Display for Digits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:03}{:03}{:04}",
+            self.0.area_code(),
+            self.0.exchange(),
+            self.0.line_number()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::XorShift32;
+    use crate::sip::transaction::Received;
+    use crate::time::Duration;
+    use heapless::consts::U8;
+    use smoltcp::wire::{IpAddress, Ipv4Address};
+
+    const INVITE: &[u8] = b"INVITE sip:1001@192.168.1.39:5060 SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.2:5060;branch=z9hG4bKpx1\r\n\
+Record-Route: <sip:192.168.1.2;lr>\r\n\
+Max-Forwards: 70\r\n\
+From: \"Bob\" <sip:555-123-4567@home.lan>;tag=caller1\r\n\
+To: <sip:1001@home.lan>\r\n\
+Call-ID: call1@192.168.1.2\r\n\
+CSeq: 10 INVITE\r\n\
+Contact: <sip:bob@192.168.1.50:5062>\r\n\
+Content-Type: application/sdp\r\n\
+Content-Length: 161\r\n\r\n\
+v=0\r\n\
+o=bob 1 1 IN IP4 192.168.1.50\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.50\r\n\
+t=0 0\r\n\
+m=audio 20000 RTP/AVP 18 0 101\r\n\
+a=rtpmap:18 G729/8000\r\n\
+a=rtpmap:101 telephone-event/8000\r\n";
+
+    /// Answer to our offer
+    const ANSWER: &str = "v=0\r\n\
+o=bob 1 1 IN IP4 192.168.1.50\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.50\r\n\
+t=0 0\r\n\
+m=audio 20000 RTP/AVP 8 101\r\n\
+a=rtpmap:101 telephone-event/8000\r\n";
+
+    const LOCAL_CODECS: [sdp::Codec; 3] = [
+        sdp::Codec::Pcmu,
+        sdp::Codec::Pcma,
+        sdp::Codec::TelephoneEvent,
+    ];
+
+    type Sent = Vec<MessageBuffer, U8>;
+
+    fn proxy() -> IpEndpoint {
+        IpEndpoint::new(IpAddress::v4(192, 168, 1, 2), 5060)
+    }
+
+    fn config() -> Config<'static> {
+        Config {
+            registrar: proxy(),
+            domain: "home.lan",
+            username: "1001",
+            password: "secret",
+            local_addr: Ipv4Address::new(192, 168, 1, 39),
+            local_port: 5060,
+            expires: 120,
+        }
+    }
+
+    fn bob() -> PhoneNumber {
+        PhoneNumber::new(555, 123, 4567)
+    }
+
+    fn local_media() -> LocalMedia<'static> {
+        LocalMedia {
+            addr: Ipv4Address::new(192, 168, 1, 39),
+            port: 10000,
+            codecs: &LOCAL_CODECS,
+            ptime: 20,
+        }
+    }
+
+    fn call() -> Call<'static, XorShift32> {
+        Call::new(config(), local_media(), XorShift32::new(1234))
+    }
+
+    /// Hands a message from the proxy to the transaction layer and the call
+    fn deliver(
+        table: &mut Table,
+        call: &mut Call<XorShift32>,
+        data: &[u8],
+        time: Instant,
+    ) -> Option<PhoneEvent> {
+        let msg = Message::parse(data).unwrap();
+        match table.receive(&msg, proxy(), time).unwrap() {
+            Received::Request(id) => call.handle_request(table, id, data, time).unwrap(),
+            Received::Response(id) => call.handle_response(table, id, &msg, time).unwrap(),
+            Received::Ack(id) => {
+                call.handle_ack(table, id, time).unwrap();
+                None
+            }
+            Received::Absorbed | Received::Unmatched => None,
+        }
+    }
+
+    /// Everything the call and its transactions send
+    fn transmit(table: &mut Table, call: &mut Call<XorShift32>, time: Instant) -> Sent {
+        let mut sent = Sent::new();
+        let mut f = |data: &[u8], remote: IpEndpoint| {
+            assert_eq!(remote, proxy());
+            let mut buf = MessageBuffer::new();
+            buf.extend_from_slice(data).unwrap();
+            sent.push(buf).unwrap();
+            true
+        };
+        call.poll_transmit(&mut f);
+        table.poll(time, &mut f);
+        sent
+    }
+
+    fn response(request: &[u8], code: u16, to_tag: &str, extra: &[(&str, &str)]) -> MessageBuffer {
+        let msg = Message::parse(request).unwrap();
+        let mut buf = MessageBuffer::new();
+        let mut b = Builder::response_to(&mut buf, &msg, code, "Reason", Some(to_tag)).unwrap();
+        for (name, value) in extra.iter() {
+            b.header(name, value).unwrap();
+        }
+        b.finish(&[]).unwrap();
+        buf
+    }
+
+    /// 200 with a Contact and SDP answer
+    fn answered(request: &[u8], answer: &str) -> MessageBuffer {
+        let msg = Message::parse(request).unwrap();
+        let mut buf = MessageBuffer::new();
+        let mut b = Builder::response_to(&mut buf, &msg, 200, "OK", Some("them")).unwrap();
+        b.contact("sip:bob@192.168.1.50:5062")
+            .unwrap()
+            .content_type(sdp::CONTENT_TYPE)
+            .unwrap();
+        b.finish(answer.as_bytes()).unwrap();
+        buf
+    }
+
+    /// ACK for our 2xx to `INVITE`
+    fn ack(tag: &str) -> MessageBuffer {
+        let mut ack = MessageBuffer::new();
+        let mut b = Builder::request(&mut ack, Method::Ack, "sip:1001@192.168.1.39:5060").unwrap();
+        b.via("UDP", "192.168.1.2", 5060, "z9hG4bKpx3")
+            .unwrap()
+            .from(None, "sip:555-123-4567@home.lan", Some("caller1"))
+            .unwrap()
+            .to(None, "sip:1001@home.lan", Some(tag))
+            .unwrap()
+            .call_id("call1@192.168.1.2")
+            .unwrap()
+            .cseq(10, Method::Ack)
+            .unwrap();
+        b.finish(&[]).unwrap();
+        ack
+    }
+
+    #[test]
+    fn uas_dialog() {
+        let invite = Message::parse(INVITE).unwrap();
+        let d = Dialog::uas(&invite, "local1").unwrap();
+        assert_eq!(d.state(), DialogState::Early);
+        assert_eq!(d.call_id(), "call1@192.168.1.2");
+        assert_eq!(d.local_tag(), "local1");
+        assert_eq!(d.remote_tag(), "caller1");
+        assert_eq!(d.local_uri(), "sip:1001@home.lan");
+        assert_eq!(d.remote_uri(), "sip:555-123-4567@home.lan");
+        assert_eq!(d.remote_target(), "sip:bob@192.168.1.50:5062");
+        assert_eq!(d.route_set().len(), 1);
+        assert_eq!(d.route_set()[0].as_str(), "<sip:192.168.1.2;lr>");
+        assert_eq!(d.remote_seq(), Some(10));
+        assert_eq!(d.remote_number(), bob());
+        assert_eq!(d.matches_request(&invite), false);
+    }
+
+    #[test]
+    fn uac_dialog_reverses_route_set() {
+        let invite = b"INVITE sip:5551234567@home.lan SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.39:5060;branch=z9hG4bKa\r\n\
+From: <sip:1001@home.lan>;tag=me\r\n\
+To: <sip:5551234567@home.lan>\r\n\
+Call-ID: out1\r\n\
+CSeq: 3 INVITE\r\n\
+Contact: <sip:1001@192.168.1.39:5060>\r\n\
+Content-Length: 0\r\n\r\n";
+        let ok = response(
+            invite,
+            200,
+            "them",
+            &[
+                ("Record-Route", "<sip:p1.home.lan;lr>, <sip:p2.home.lan;lr>"),
+                ("Contact", "<sip:bob@192.168.1.50:5062>"),
+            ],
+        );
+        let invite = Message::parse(invite).unwrap();
+        let mut d = Dialog::uac(&invite, &Message::parse(&ok).unwrap()).unwrap();
+        assert_eq!(d.state(), DialogState::Confirmed);
+        assert_eq!(d.local_tag(), "me");
+        assert_eq!(d.remote_tag(), "them");
+        assert_eq!(d.local_seq(), 3);
+        assert_eq!(d.remote_target(), "sip:bob@192.168.1.50:5062");
+        assert_eq!(d.route_set()[0].as_str(), "<sip:p2.home.lan;lr>");
+        assert_eq!(d.route_set()[1].as_str(), "<sip:p1.home.lan;lr>");
+        assert_eq!(d.remote_number(), bob());
+
+        let mut buf = MessageBuffer::new();
+        d.request(&mut buf, Method::Bye, "192.168.1.39", 5060, "z9hG4bKb")
+            .unwrap()
+            .finish(&[])
+            .unwrap();
+        let bye = Message::parse(&buf).unwrap();
+        assert_eq!(
+            bye.request_line().unwrap().uri,
+            Uri::parse("sip:bob@192.168.1.50:5062").unwrap()
+        );
+        let routes: Vec<&str, U4> = bye.header_values("Route").collect();
+        assert_eq!(
+            &routes[..],
+            &["<sip:p2.home.lan;lr>", "<sip:p1.home.lan;lr>"]
+        );
+        assert_eq!(bye.from().unwrap().tag(), Some("me"));
+        assert_eq!(bye.to().unwrap().tag(), Some("them"));
+        assert_eq!(bye.cseq().unwrap().seq, 4);
+        assert_eq!(bye.cseq().unwrap().method, Method::Bye);
+        // Its response copies the From/To tags
+        assert_eq!(d.matches_response(&bye), true);
+        assert_eq!(d.matches_request(&bye), false);
+    }
+
+    #[test]
+    fn strict_router() {
+        let invite = Message::parse(INVITE).unwrap();
+        let mut d = Dialog::uas(&invite, "local1").unwrap();
+        d.route_set[0] = copy("<sip:192.168.1.2>").unwrap();
+        let mut buf = MessageBuffer::new();
+        d.request(&mut buf, Method::Bye, "192.168.1.39", 5060, "z9hG4bKb")
+            .unwrap()
+            .finish(&[])
+            .unwrap();
+        let bye = Message::parse(&buf).unwrap();
+        assert_eq!(
+            bye.request_line().unwrap().uri,
+            Uri::parse("sip:192.168.1.2").unwrap()
+        );
+        let routes: Vec<&str, U4> = bye.header_values("Route").collect();
+        assert_eq!(&routes[..], &["<sip:bob@192.168.1.50:5062>"]);
+        assert_eq!(bye.cseq().unwrap().seq, 1);
+    }
+
+    #[test]
+    fn in_dialog_request_order() {
+        let invite = Message::parse(INVITE).unwrap();
+        let mut d = Dialog::uas(&invite, "local1").unwrap();
+        let bye = b"BYE sip:1001@192.168.1.39:5060 SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.2:5060;branch=z9hG4bKpx2\r\n\
+From: <sip:555-123-4567@home.lan>;tag=caller1\r\n\
+To: <sip:1001@home.lan>;tag=local1\r\n\
+Call-ID: call1@192.168.1.2\r\n\
+CSeq: 9 BYE\r\n\
+Content-Length: 0\r\n\r\n";
+        let bye = Message::parse(bye).unwrap();
+        assert_eq!(d.matches_request(&bye), true);
+        assert_eq!(d.on_request(&bye), Ok(false));
+        assert_eq!(d.remote_seq(), Some(10));
+    }
+
+    #[test]
+    fn phone_numbers() {
+        let number = |s| phone_number(&Uri::parse(s).unwrap());
+        assert_eq!(number("sip:5551234567@home.lan"), bob());
+        assert_eq!(number("sip:+1-555-123-4567@home.lan"), bob());
+        assert_eq!(number("sip:15551234567@home.lan;user=phone"), bob());
+        assert_eq!(number("sip:1001@home.lan"), PhoneNumber::INVALID);
+        assert_eq!(number("sip:bob@home.lan"), PhoneNumber::INVALID);
+        assert_eq!(number("sip:home.lan"), PhoneNumber::INVALID);
+    }
+
+    #[test]
+    fn incoming_call_answered_then_remote_hangup() {
+        let mut table = Table::new();
+        let mut call = call();
+        let mut time = Instant::from_secs(1);
+
+        let event = deliver(&mut table, &mut call, INVITE, time);
+        assert_eq!(event, Some(PhoneEvent::IncomingCall(bob())));
+        assert_eq!(call.state(), CallState::Incoming);
+        assert_eq!(call.remote(), bob());
+
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 1);
+        let ringing = Message::parse(&sent[0]).unwrap();
+        assert_eq!(ringing.status_code(), Some(180));
+        let tag = ringing.to().unwrap().tag().unwrap();
+        assert_eq!(tag, call.dialog().unwrap().local_tag());
+
+        // Retransmitted INVITE is absorbed
+        assert_eq!(deliver(&mut table, &mut call, INVITE, time), None);
+
+        time += Duration::from_secs(2);
+        call.handle_command(&mut table, PhoneCommand::AnswerCall, time)
+            .unwrap();
+        assert_eq!(call.state(), CallState::Answered);
+        let sent = transmit(&mut table, &mut call, time);
+        let ok = Message::parse(&sent[0]).unwrap();
+        assert_eq!(ok.status_code(), Some(200));
+        assert_eq!(ok.to().unwrap().tag(), Some(tag));
+        assert_eq!(
+            ok.contact().unwrap().uri,
+            Uri::parse("sip:1001@192.168.1.39:5060").unwrap()
+        );
+        assert_eq!(ok.content_type(), Some(sdp::CONTENT_TYPE));
+        let answer = core::str::from_utf8(ok.body).unwrap();
+        let answer = SessionDescription::parse(answer).unwrap();
+        let (_, audio) = answer.audio().unwrap();
+        assert_eq!(audio.port, 10000);
+        assert_eq!(&audio.formats[..], &[0, 101]);
+
+        let media = call.media().unwrap();
+        assert_eq!(media.codec, sdp::Codec::Pcmu);
+        assert_eq!(media.telephone_event, Some(101));
+        assert_eq!(
+            media.remote,
+            IpEndpoint::new(IpAddress::v4(192, 168, 1, 50), 20000)
+        );
+
+        assert_eq!(deliver(&mut table, &mut call, &ack(tag), time), None);
+        assert_eq!(call.dialog().unwrap().state(), DialogState::Confirmed);
+        assert_eq!(call.state(), CallState::Established);
+
+        let mut bye = MessageBuffer::new();
+        let mut b = Builder::request(&mut bye, Method::Bye, "sip:1001@192.168.1.39:5060").unwrap();
+        b.via("UDP", "192.168.1.2", 5060, "z9hG4bKpx4")
+            .unwrap()
+            .from(None, "sip:555-123-4567@home.lan", Some("caller1"))
+            .unwrap()
+            .to(None, "sip:1001@home.lan", Some(tag))
+            .unwrap()
+            .call_id("call1@192.168.1.2")
+            .unwrap()
+            .cseq(11, Method::Bye)
+            .unwrap();
+        b.finish(&[]).unwrap();
+        time += Duration::from_secs(10);
+        let event = deliver(&mut table, &mut call, &bye, time);
+        assert_eq!(event, Some(PhoneEvent::RemoteHangup));
+        assert_eq!(call.state(), CallState::Idle);
+        let sent = transmit(&mut table, &mut call, time);
+        let ok = Message::parse(&sent[0]).unwrap();
+        assert_eq!(ok.status_code(), Some(200));
+        assert_eq!(ok.cseq().unwrap().method, Method::Bye);
+
+        // The terminated dialog no longer matches
+        let mut bye = MessageBuffer::new();
+        let mut b = Builder::request(&mut bye, Method::Bye, "sip:1001@192.168.1.39:5060").unwrap();
+        b.via("UDP", "192.168.1.2", 5060, "z9hG4bKpx5")
+            .unwrap()
+            .from(None, "sip:555-123-4567@home.lan", Some("caller1"))
+            .unwrap()
+            .to(None, "sip:1001@home.lan", Some(tag))
+            .unwrap()
+            .call_id("call1@192.168.1.2")
+            .unwrap()
+            .cseq(12, Method::Bye)
+            .unwrap();
+        b.finish(&[]).unwrap();
+        assert_eq!(deliver(&mut table, &mut call, &bye, time), None);
+        let sent = transmit(&mut table, &mut call, time);
+        let response = Message::parse(&sent[0]).unwrap();
+        assert_eq!(response.status_code(), Some(481));
+    }
+
+    #[test]
+    fn hang_up_before_ack() {
+        let mut table = Table::new();
+        let mut call = call();
+        let mut time = Instant::from_secs(1);
+        deliver(&mut table, &mut call, INVITE, time);
+        transmit(&mut table, &mut call, time);
+        call.handle_command(&mut table, PhoneCommand::AnswerCall, time)
+            .unwrap();
+        let sent = transmit(&mut table, &mut call, time);
+        let ok = Message::parse(&sent[0]).unwrap();
+        let tag = ok.to().unwrap().tag().unwrap();
+
+        // No BYE until the ACK
+        call.handle_command(&mut table, PhoneCommand::HangUp, time)
+            .unwrap();
+        assert_eq!(call.state(), CallState::Answered);
+        assert_eq!(call.media(), None);
+        time += Duration::from_millis(100);
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 0);
+
+        assert_eq!(deliver(&mut table, &mut call, &ack(tag), time), None);
+        assert_eq!(call.state(), CallState::Idle);
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 1);
+        let bye = Message::parse(&sent[0]).unwrap();
+        assert_eq!(bye.method(), Some(Method::Bye));
+    }
+
+    #[test]
+    fn outgoing_call_not_placed() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        // Fill the transaction table
+        for i in 0..8 {
+            let mut branch: String<U20> = String::new();
+            write!(branch, "z9hG4bK{}", i).unwrap();
+            let mut options = MessageBuffer::new();
+            let mut b =
+                Builder::request(&mut options, Method::Options, "sip:1001@192.168.1.39").unwrap();
+            b.via("UDP", "192.168.1.2", 5060, branch.as_str())
+                .unwrap()
+                .from(None, "sip:proxy@home.lan", Some("p"))
+                .unwrap()
+                .to(None, "sip:1001@home.lan", None)
+                .unwrap()
+                .call_id("opt1")
+                .unwrap()
+                .cseq(1, Method::Options)
+                .unwrap();
+            b.finish(&[]).unwrap();
+            deliver(&mut table, &mut call, &options, time);
+        }
+
+        let event = call
+            .handle_command(&mut table, PhoneCommand::PlaceCall(bob()), time)
+            .unwrap();
+        assert_eq!(event, Some(PhoneEvent::CallFailed));
+        assert_eq!(call.state(), CallState::Idle);
+    }
+
+    #[test]
+    fn incoming_call_cancelled() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        deliver(&mut table, &mut call, INVITE, time);
+        transmit(&mut table, &mut call, time);
+
+        let cancel = b"CANCEL sip:1001@192.168.1.39:5060 SIP/2.0\r\n\
+Via: SIP/2.0/UDP 192.168.1.2:5060;branch=z9hG4bKpx1\r\n\
+From: \"Bob\" <sip:555-123-4567@home.lan>;tag=caller1\r\n\
+To: <sip:1001@home.lan>\r\n\
+Call-ID: call1@192.168.1.2\r\n\
+CSeq: 10 CANCEL\r\n\
+Content-Length: 0\r\n\r\n";
+        let event = deliver(&mut table, &mut call, cancel, time);
+        assert_eq!(event, Some(PhoneEvent::RemoteHangup));
+        assert_eq!(call.state(), CallState::Idle);
+
+        let sent = transmit(&mut table, &mut call, time);
+        let codes: Vec<(u16, Method), U4> = sent
+            .iter()
+            .map(|s| {
+                let m = Message::parse(s).unwrap();
+                (m.status_code().unwrap(), m.cseq().unwrap().method)
+            })
+            .collect();
+        assert_eq!(codes.len(), 2);
+        assert_eq!(codes.contains(&(200, Method::Cancel)), true);
+        assert_eq!(codes.contains(&(487, Method::Invite)), true);
+
+        // Phone state machine's StopRinger after the hangup is harmless
+        call.handle_command(&mut table, PhoneCommand::StopRinger, time)
+            .unwrap();
+    }
+
+    #[test]
+    fn busy_and_declined() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        call.handle_command(&mut table, PhoneCommand::PlaceCall(bob()), time)
+            .unwrap();
+        transmit(&mut table, &mut call, time);
+
+        // Already on a call
+        assert_eq!(deliver(&mut table, &mut call, INVITE, time), None);
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(Message::parse(&sent[0]).unwrap().status_code(), Some(486));
+
+        let mut table = Table::new();
+        let mut call = self::call();
+        deliver(&mut table, &mut call, INVITE, time);
+        transmit(&mut table, &mut call, time);
+        call.handle_command(&mut table, PhoneCommand::DeclineCall, time)
+            .unwrap();
+        assert_eq!(call.state(), CallState::Idle);
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(Message::parse(&sent[0]).unwrap().status_code(), Some(486));
+    }
+
+    #[test]
+    fn outgoing_call_answered_then_hangup() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        call.handle_command(&mut table, PhoneCommand::PlaceCall(bob()), time)
+            .unwrap();
+        assert_eq!(call.state(), CallState::Outgoing);
+
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 1);
+        let invite = sent[0].clone();
+        let msg = Message::parse(&invite).unwrap();
+        assert_eq!(msg.method(), Some(Method::Invite));
+        assert_eq!(
+            msg.request_line().unwrap().uri,
+            Uri::parse("sip:5551234567@home.lan").unwrap()
+        );
+        assert_eq!(msg.cseq().unwrap().seq, 1);
+        assert_eq!(msg.from().unwrap().tag().is_some(), true);
+        assert_eq!(msg.to().unwrap().tag(), None);
+        let offer = SessionDescription::parse(core::str::from_utf8(msg.body).unwrap()).unwrap();
+        let (_, audio) = offer.audio().unwrap();
+        assert_eq!(audio.port, 10000);
+        assert_eq!(&audio.formats[..], &[0, 8, 101]);
+
+        let trying = response(&invite, 100, "", &[]);
+        let trying = Message::parse(&trying).unwrap();
+        let mut trying_buf = MessageBuffer::new();
+        Builder::response_to(&mut trying_buf, &trying, 100, "Trying", None)
+            .unwrap()
+            .finish(&[])
+            .unwrap();
+        assert_eq!(deliver(&mut table, &mut call, &trying_buf, time), None);
+
+        let ringing = response(&invite, 180, "them", &[]);
+        let event = deliver(&mut table, &mut call, &ringing, time);
+        assert_eq!(event, Some(PhoneEvent::RemoteRinging));
+        assert_eq!(call.dialog().unwrap().state(), DialogState::Early);
+
+        assert_eq!(call.media(), None);
+        let ok = answered(&invite, ANSWER);
+        let event = deliver(&mut table, &mut call, &ok, time);
+        assert_eq!(event, Some(PhoneEvent::RemoteAnswered));
+        assert_eq!(call.state(), CallState::Established);
+        let media = call.media().unwrap();
+        assert_eq!(media.codec, sdp::Codec::Pcma);
+        assert_eq!(media.payload_type, 8);
+        assert_eq!(
+            media.remote,
+            IpEndpoint::new(IpAddress::v4(192, 168, 1, 50), 20000)
+        );
+
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 1);
+        let ack = Message::parse(&sent[0]).unwrap();
+        assert_eq!(ack.method(), Some(Method::Ack));
+        assert_eq!(ack.cseq().unwrap().seq, 1);
+        assert_eq!(ack.to().unwrap().tag(), Some("them"));
+        assert_eq!(
+            ack.request_line().unwrap().uri,
+            Uri::parse("sip:bob@192.168.1.50:5062").unwrap()
+        );
+
+        // 2xx retransmission gets the same ACK
+        deliver(&mut table, &mut call, &ok, time);
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            Message::parse(&sent[0]).unwrap().method(),
+            Some(Method::Ack)
+        );
+
+        call.handle_command(&mut table, PhoneCommand::HangUp, time)
+            .unwrap();
+        assert_eq!(call.state(), CallState::Idle);
+        let sent = transmit(&mut table, &mut call, time);
+        let bye = Message::parse(&sent[0]).unwrap();
+        assert_eq!(bye.method(), Some(Method::Bye));
+        assert_eq!(bye.cseq().unwrap().seq, 2);
+        assert_eq!(bye.call_id(), msg.call_id());
+    }
+
+    #[test]
+    fn outgoing_call_cancelled_after_provisional() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        call.handle_command(&mut table, PhoneCommand::PlaceCall(bob()), time)
+            .unwrap();
+        let invite = transmit(&mut table, &mut call, time)[0].clone();
+
+        // Nothing to CANCEL until there's a provisional response
+        call.handle_command(&mut table, PhoneCommand::CancelCall, time)
+            .unwrap();
+        assert_eq!(transmit(&mut table, &mut call, time).len(), 0);
+
+        let ringing = response(&invite, 180, "them", &[]);
+        assert_eq!(deliver(&mut table, &mut call, &ringing, time), None);
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 1);
+        let cancel = Message::parse(&sent[0]).unwrap();
+        let msg = Message::parse(&invite).unwrap();
+        assert_eq!(cancel.method(), Some(Method::Cancel));
+        assert_eq!(cancel.cseq().unwrap().seq, msg.cseq().unwrap().seq);
+        assert_eq!(cancel.via().unwrap().branch(), msg.via().unwrap().branch());
+        assert_eq!(cancel.to().unwrap().tag(), None);
+
+        let terminated = response(&invite, 487, "them", &[]);
+        assert_eq!(deliver(&mut table, &mut call, &terminated, time), None);
+        assert_eq!(call.state(), CallState::Idle);
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(
+            Message::parse(&sent[0]).unwrap().method(),
+            Some(Method::Ack)
+        );
+    }
+
+    #[test]
+    fn outgoing_call_rejected() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        call.handle_command(&mut table, PhoneCommand::PlaceCall(bob()), time)
+            .unwrap();
+        let invite = transmit(&mut table, &mut call, time)[0].clone();
+        let busy = response(&invite, 486, "them", &[]);
+        let event = deliver(&mut table, &mut call, &busy, time);
+        assert_eq!(event, Some(PhoneEvent::RemoteHangup));
+        assert_eq!(call.state(), CallState::Idle);
+    }
+
+    #[test]
+    fn outgoing_call_with_proxy_challenge() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        call.handle_command(&mut table, PhoneCommand::PlaceCall(bob()), time)
+            .unwrap();
+        let first = transmit(&mut table, &mut call, time)[0].clone();
+        let challenge = response(
+            &first,
+            407,
+            "px",
+            &[(
+                "Proxy-Authenticate",
+                "Digest realm=\"home.lan\", nonce=\"abc\", qop=\"auth\"",
+            )],
+        );
+        assert_eq!(deliver(&mut table, &mut call, &challenge, time), None);
+
+        let sent = transmit(&mut table, &mut call, time);
+        // ACK for the 407, then the new INVITE
+        assert_eq!(sent.len(), 2);
+        let first = Message::parse(&first).unwrap();
+        let ack = Message::parse(&sent[0]).unwrap();
+        assert_eq!(ack.method(), Some(Method::Ack));
+        let second = Message::parse(&sent[1]).unwrap();
+        assert_eq!(second.method(), Some(Method::Invite));
+        assert_eq!(second.call_id(), first.call_id());
+        assert_eq!(second.from().unwrap().tag(), first.from().unwrap().tag());
+        assert_eq!(second.cseq().unwrap().seq, 2);
+        assert_ne!(
+            second.via().unwrap().branch(),
+            first.via().unwrap().branch()
+        );
+        assert_eq!(second.header("Proxy-Authorization").is_some(), true);
+
+        // Rejected credentials end the call
+        let challenge = response(
+            &sent[1],
+            407,
+            "px",
+            &[(
+                "Proxy-Authenticate",
+                "Digest realm=\"home.lan\", nonce=\"abc\", qop=\"auth\"",
+            )],
+        );
+        let event = deliver(&mut table, &mut call, &challenge, time);
+        assert_eq!(event, Some(PhoneEvent::RemoteHangup));
+        assert_eq!(call.state(), CallState::Idle);
+    }
+
+    #[test]
+    fn outgoing_call_timeout() {
+        let mut table = Table::new();
+        let mut call = call();
+        let mut time = Instant::from_secs(1);
+        call.handle_command(&mut table, PhoneCommand::PlaceCall(bob()), time)
+            .unwrap();
+        let mut event = None;
+        while time < Instant::from_secs(40) && event.is_none() {
+            let mut f = |_: &[u8], _| true;
+            for id in table.poll(time, &mut f).iter() {
+                event = call.handle_timeout(&mut table, *id, time).unwrap();
+            }
+            time += Duration::from_millis(100);
+        }
+        assert_eq!(event, Some(PhoneEvent::RemoteHangup));
+        assert_eq!(call.state(), CallState::Idle);
+        assert!(time > Instant::from_secs(32));
+    }
+
+    #[test]
+    fn options_and_unsupported_methods() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        let request = |method: Method, branch: &str| {
+            let mut buf = MessageBuffer::new();
+            let mut b = Builder::request(&mut buf, method, "sip:1001@192.168.1.39").unwrap();
+            b.via("UDP", "192.168.1.2", 5060, branch)
+                .unwrap()
+                .from(None, "sip:proxy@home.lan", Some("p"))
+                .unwrap()
+                .to(None, "sip:1001@home.lan", None)
+                .unwrap()
+                .call_id("opt1")
+                .unwrap()
+                .cseq(1, method)
+                .unwrap();
+            b.finish(&[]).unwrap();
+            buf
+        };
+
+        deliver(
+            &mut table,
+            &mut call,
+            &request(Method::Options, "z9hG4bK1"),
+            time,
+        );
+        deliver(
+            &mut table,
+            &mut call,
+            &request(Method::Message, "z9hG4bK2"),
+            time,
+        );
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 2);
+        for (s, code) in sent.iter().zip([200, 405].iter()) {
+            let msg = Message::parse(s).unwrap();
+            assert_eq!(msg.status_code(), Some(*code));
+            assert_eq!(msg.header("Allow"), Some(ALLOW));
+        }
+        assert_eq!(call.state(), CallState::Idle);
+    }
+
+    #[test]
+    fn incoming_call_without_common_codec() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        let pcma = [sdp::Codec::Pcma];
+        call.local_media.codecs = &pcma;
+
+        assert_eq!(deliver(&mut table, &mut call, INVITE, time), None);
+        assert_eq!(call.state(), CallState::Idle);
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(Message::parse(&sent[0]).unwrap().status_code(), Some(488));
+    }
+
+    #[test]
+    fn reinvite_puts_call_on_hold() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        deliver(&mut table, &mut call, INVITE, time);
+        call.handle_command(&mut table, PhoneCommand::AnswerCall, time)
+            .unwrap();
+        let sent = transmit(&mut table, &mut call, time);
+        let ok = Message::parse(&sent[0]).unwrap();
+        let tag = ok.to().unwrap().tag().unwrap();
+        let first = SessionDescription::parse(core::str::from_utf8(ok.body).unwrap()).unwrap();
+        deliver(&mut table, &mut call, &ack(tag), time);
+
+        let hold = "v=0\r\n\
+o=bob 1 2 IN IP4 192.168.1.50\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.50\r\n\
+t=0 0\r\n\
+m=audio 20000 RTP/AVP 0\r\n\
+a=sendonly\r\n";
+        let mut reinvite = MessageBuffer::new();
+        let mut b =
+            Builder::request(&mut reinvite, Method::Invite, "sip:1001@192.168.1.39:5060").unwrap();
+        b.via("UDP", "192.168.1.2", 5060, "z9hG4bKpx9")
+            .unwrap()
+            .from(None, "sip:555-123-4567@home.lan", Some("caller1"))
+            .unwrap()
+            .to(None, "sip:1001@home.lan", Some(tag))
+            .unwrap()
+            .call_id("call1@192.168.1.2")
+            .unwrap()
+            .cseq(11, Method::Invite)
+            .unwrap()
+            .contact("sip:bob@192.168.1.50:5062")
+            .unwrap()
+            .content_type(sdp::CONTENT_TYPE)
+            .unwrap();
+        b.finish(hold.as_bytes()).unwrap();
+
+        assert_eq!(deliver(&mut table, &mut call, &reinvite, time), None);
+        assert_eq!(call.state(), CallState::Established);
+        assert_eq!(call.media().unwrap().is_on_hold(), true);
+        assert_eq!(call.media().unwrap().direction, Direction::RecvOnly);
+
+        let sent = transmit(&mut table, &mut call, time);
+        let ok = Message::parse(&sent[0]).unwrap();
+        assert_eq!(ok.status_code(), Some(200));
+        assert_eq!(ok.cseq().unwrap().seq, 11);
+        let answer = SessionDescription::parse(core::str::from_utf8(ok.body).unwrap()).unwrap();
+        assert_eq!(answer.origin.session_id, first.origin.session_id);
+        assert_eq!(
+            answer.origin.session_version,
+            first.origin.session_version + 1
+        );
+        assert_eq!(answer.media[0].direction, Direction::RecvOnly);
+    }
+
+    #[test]
+    fn outgoing_call_with_unacceptable_answer() {
+        let mut table = Table::new();
+        let mut call = call();
+        let time = Instant::from_secs(1);
+        call.handle_command(&mut table, PhoneCommand::PlaceCall(bob()), time)
+            .unwrap();
+        let invite = transmit(&mut table, &mut call, time)[0].clone();
+
+        let g729 = "v=0\r\n\
+o=bob 1 1 IN IP4 192.168.1.50\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.50\r\n\
+t=0 0\r\n\
+m=audio 20000 RTP/AVP 18\r\n";
+        let ok = answered(&invite, g729);
+        let event = deliver(&mut table, &mut call, &ok, time);
+        assert_eq!(event, Some(PhoneEvent::RemoteHangup));
+        assert_eq!(call.state(), CallState::Idle);
+
+        let sent = transmit(&mut table, &mut call, time);
+        assert_eq!(sent.len(), 2);
+        let ack = Message::parse(&sent[0]).unwrap();
+        assert_eq!(ack.method(), Some(Method::Ack));
+        let bye = Message::parse(&sent[1]).unwrap();
+        assert_eq!(bye.method(), Some(Method::Bye));
+        assert_eq!(bye.to().unwrap().tag(), Some("them"));
+    }
+}
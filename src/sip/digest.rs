@@ -0,0 +1,568 @@
+//! HTTP digest authentication (RFC 2617, RFC 7616 MD5) for SIP
+//!
+//! Includes a small MD5 (RFC 1321) implementation.
+
+use crate::sip::message::ValueList;
+use core::fmt::{self, Write};
+use heapless::consts::{U128, U32, U64};
+use heapless::String;
+
+pub type Realm = String<U64>;
+pub type Nonce = String<U128>;
+pub type Opaque = String<U128>;
+pub type CNonce = String<U32>;
+/// Lower case hex MD5 digest
+pub type HexDigest = String<U32>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Scheme other than Digest
+    UnsupportedScheme,
+    /// Algorithm other than MD5/MD5-sess
+    UnsupportedAlgorithm,
+    /// qop offered but doesn't include "auth"
+    UnsupportedQop,
+    MissingRealm,
+    MissingNonce,
+    /// A parameter doesn't fit in its storage
+    TooLong,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Algorithm {
+    Md5,
+    Md5Sess,
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Algorithm::Md5 => f.write_str("MD5"),
+            Algorithm::Md5Sess => f.write_str("MD5-sess"),
+        }
+    }
+}
+
+/// A parsed WWW-Authenticate or Proxy-Authenticate challenge
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Challenge {
+    pub realm: Realm,
+    pub nonce: Nonce,
+    pub opaque: Option<Opaque>,
+    pub algorithm: Algorithm,
+    /// Server offered qop=auth
+    pub qop_auth: bool,
+    /// Nonce was stale, the credentials themselves were fine
+    pub stale: bool,
+}
+
+impl Challenge {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        let params = match s.find(|c: char| c == ' ' || c == '\t') {
+            Some(i) if s[..i].eq_ignore_ascii_case("Digest") => &s[i + 1..],
+            _ => return Err(Error::UnsupportedScheme),
+        };
+
+        let mut realm = None;
+        let mut nonce = None;
+        let mut opaque = None;
+        let mut algorithm = Algorithm::Md5;
+        let mut qop = None;
+        let mut stale = false;
+
+        for param in ValueList::new(params) {
+            let (name, value) = match param.find('=') {
+                Some(i) => (param[..i].trim(), unquote(param[i + 1..].trim())),
+                None => continue,
+            };
+            if name.eq_ignore_ascii_case("realm") {
+                realm = Some(value);
+            } else if name.eq_ignore_ascii_case("nonce") {
+                nonce = Some(value);
+            } else if name.eq_ignore_ascii_case("opaque") {
+                opaque = Some(value);
+            } else if name.eq_ignore_ascii_case("algorithm") {
+                algorithm = if value.eq_ignore_ascii_case("MD5") {
+                    Algorithm::Md5
+                } else if value.eq_ignore_ascii_case("MD5-sess") {
+                    Algorithm::Md5Sess
+                } else {
+                    return Err(Error::UnsupportedAlgorithm);
+                };
+            } else if name.eq_ignore_ascii_case("qop") {
+                qop = Some(value);
+            } else if name.eq_ignore_ascii_case("stale") {
+                stale = value.eq_ignore_ascii_case("true");
+            }
+        }
+
+        let qop_auth = match qop {
+            Some(qop) => {
+                if qop
+                    .split(',')
+                    .any(|q| q.trim().eq_ignore_ascii_case("auth"))
+                {
+                    true
+                } else {
+                    return Err(Error::UnsupportedQop);
+                }
+            }
+            None => false,
+        };
+
+        Ok(Challenge {
+            realm: copy_str(realm.ok_or(Error::MissingRealm)?)?,
+            nonce: copy_str(nonce.ok_or(Error::MissingNonce)?)?,
+            opaque: match opaque {
+                Some(o) => Some(copy_str(o)?),
+                None => None,
+            },
+            algorithm,
+            qop_auth,
+            stale,
+        })
+    }
+}
+
+fn unquote(s: &str) -> &str {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    }
+}
+
+fn copy_str<N>(s: &str) -> Result<String<N>, Error>
+where
+    N: heapless::ArrayLength<u8>,
+{
+    let mut out = String::new();
+    out.push_str(s).map_err(|_| Error::TooLong)?;
+    Ok(out)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Credentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+/// Computes the request-digest for `method` and `uri`.
+/// `nc` and `cnonce` are only used when the challenge has qop=auth or
+/// the algorithm is MD5-sess.
+pub fn response(
+    challenge: &Challenge,
+    credentials: &Credentials,
+    method: &str,
+    uri: &str,
+    nc: u32,
+    cnonce: &str,
+) -> HexDigest {
+    let mut md5 = Md5::new();
+    write!(
+        md5,
+        "{}:{}:{}",
+        credentials.username, challenge.realm, credentials.password
+    )
+    .unwrap();
+    let mut ha1 = md5.finish_hex();
+    if challenge.algorithm == Algorithm::Md5Sess {
+        let mut md5 = Md5::new();
+        write!(md5, "{}:{}:{}", ha1, challenge.nonce, cnonce).unwrap();
+        ha1 = md5.finish_hex();
+    }
+
+    let mut md5 = Md5::new();
+    write!(md5, "{}:{}", method, uri).unwrap();
+    let ha2 = md5.finish_hex();
+
+    let mut md5 = Md5::new();
+    if challenge.qop_auth {
+        write!(
+            md5,
+            "{}:{}:{:08x}:{}:auth:{}",
+            ha1, challenge.nonce, nc, cnonce, ha2
+        )
+        .unwrap();
+    } else {
+        write!(md5, "{}:{}:{}", ha1, challenge.nonce, ha2).unwrap();
+    }
+    md5.finish_hex()
+}
+
+/// Authorization or Proxy-Authorization header value
+pub struct Authorization<'a> {
+    pub challenge: &'a Challenge,
+    pub credentials: &'a Credentials<'a>,
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub nc: u32,
+    pub cnonce: &'a str,
+}
+
+impl<'a> fmt::Display for Authorization<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = self.challenge;
+        let resp = response(
+            c,
+            self.credentials,
+            self.method,
+            self.uri,
+            self.nc,
+            self.cnonce,
+        );
+        write!(
+            f,
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}",
+            self.credentials.username, c.realm, c.nonce, self.uri, resp, c.algorithm
+        )?;
+        if c.qop_auth || c.algorithm == Algorithm::Md5Sess {
+            write!(f, ", cnonce=\"{}\"", self.cnonce)?;
+        }
+        if c.qop_auth {
+            write!(f, ", qop=auth, nc={:08x}", self.nc)?;
+        }
+        if let Some(opaque) = &c.opaque {
+            write!(f, ", opaque=\"{}\"", opaque)?;
+        }
+        Ok(())
+    }
+}
+
+/// MD5 message digest (RFC 1321)
+#[derive(Clone)]
+pub struct Md5 {
+    state: [u32; 4],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+const S: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15,
+    21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+const K: [u32; 64] = [
+    0xd76a_a478,
+    0xe8c7_b756,
+    0x2420_70db,
+    0xc1bd_ceee,
+    0xf57c_0faf,
+    0x4787_c62a,
+    0xa830_4613,
+    0xfd46_9501,
+    0x6980_98d8,
+    0x8b44_f7af,
+    0xffff_5bb1,
+    0x895c_d7be,
+    0x6b90_1122,
+    0xfd98_7193,
+    0xa679_438e,
+    0x49b4_0821,
+    0xf61e_2562,
+    0xc040_b340,
+    0x265e_5a51,
+    0xe9b6_c7aa,
+    0xd62f_105d,
+    0x0244_1453,
+    0xd8a1_e681,
+    0xe7d3_fbc8,
+    0x21e1_cde6,
+    0xc337_07d6,
+    0xf4d5_0d87,
+    0x455a_14ed,
+    0xa9e3_e905,
+    0xfcef_a3f8,
+    0x676f_02d9,
+    0x8d2a_4c8a,
+    0xfffa_3942,
+    0x8771_f681,
+    0x6d9d_6122,
+    0xfde5_380c,
+    0xa4be_ea44,
+    0x4bde_cfa9,
+    0xf6bb_4b60,
+    0xbebf_bc70,
+    0x289b_7ec6,
+    0xeaa1_27fa,
+    0xd4ef_3085,
+    0x0488_1d05,
+    0xd9d4_d039,
+    0xe6db_99e5,
+    0x1fa2_7cf8,
+    0xc4ac_5665,
+    0xf429_2244,
+    0x432a_ff97,
+    0xab94_23a7,
+    0xfc93_a039,
+    0x655b_59c3,
+    0x8f0c_cc92,
+    0xffef_f47d,
+    0x8584_5dd1,
+    0x6fa8_7e4f,
+    0xfe2c_e6e0,
+    0xa301_4314,
+    0x4e08_11a1,
+    0xf753_7e82,
+    0xbd3a_f235,
+    0x2ad7_d2bb,
+    0xeb86_d391,
+];
+
+impl Md5 {
+    pub fn new() -> Self {
+        Md5 {
+            state: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.total_len = self.total_len.wrapping_add(data.len() as u64);
+        for b in data {
+            self.block[self.block_len] = *b;
+            self.block_len += 1;
+            if self.block_len == 64 {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 16] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        let mut len_bytes = [0; 8];
+        for (i, b) in len_bytes.iter_mut().enumerate() {
+            *b = (bit_len >> (8 * i)) as u8;
+        }
+        self.update(&len_bytes);
+
+        let mut out = [0; 16];
+        for (i, word) in self.state.iter().enumerate() {
+            for j in 0..4 {
+                out[i * 4 + j] = (word >> (8 * j)) as u8;
+            }
+        }
+        out
+    }
+
+    pub fn finish_hex(self) -> HexDigest {
+        let mut hex = HexDigest::new();
+        for b in self.finish().iter() {
+            write!(hex, "{:02x}", b).unwrap();
+        }
+        hex
+    }
+
+    fn compress(&mut self) {
+        let mut m = [0_u32; 16];
+        for (i, word) in m.iter_mut().enumerate() {
+            *word = u32::from(self.block[i * 4])
+                | u32::from(self.block[i * 4 + 1]) << 8
+                | u32::from(self.block[i * 4 + 2]) << 16
+                | u32::from(self.block[i * 4 + 3]) << 24;
+        }
+
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(K[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(S[i]));
+        }
+
+        self.state[0] = self.state[0].wrapping_add(a);
+        self.state[1] = self.state[1].wrapping_add(b);
+        self.state[2] = self.state[2].wrapping_add(c);
+        self.state[3] = self.state[3].wrapping_add(d);
+    }
+}
+
+impl Default for Md5 {
+    fn default() -> Self {
+        Md5::new()
+    }
+}
+
+impl fmt::Write for Md5 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.update(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn md5_hex(data: &[u8]) -> HexDigest {
+        let mut md5 = Md5::new();
+        md5.update(data);
+        md5.finish_hex()
+    }
+
+    #[test]
+    fn md5_test_suite() {
+        // RFC 1321 appendix A.5
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5_hex(b"a"), "0cc175b9c0f1b6a831c399e269772661");
+        assert_eq!(md5_hex(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            md5_hex(b"message digest"),
+            "f96b697d7cb7938d525a2f31aaf161d0"
+        );
+        assert_eq!(
+            md5_hex(b"abcdefghijklmnopqrstuvwxyz"),
+            "c3fcd3d76192e4007dfb496cca67e13b"
+        );
+        assert_eq!(
+            md5_hex(b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789"),
+            "d174ab98d277d9f5a5611c2c9f419d9f"
+        );
+        assert_eq!(
+            md5_hex(
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"
+            ),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+
+    #[test]
+    fn md5_incremental() {
+        let mut md5 = Md5::new();
+        for b in b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"
+            .chunks(7)
+        {
+            md5.update(b);
+        }
+        assert_eq!(md5.finish_hex(), "57edf4a22be3c955ac49da2e2107b67a");
+    }
+
+    #[test]
+    fn parse_challenge() {
+        let c = Challenge::parse(
+            "Digest realm=\"atlanta.example.com\", qop=\"auth,auth-int\", \
+             nonce=\"ea9c8e88df84f1cec4341ae6cbe5a359\", opaque=\"\", stale=FALSE, algorithm=MD5",
+        )
+        .unwrap();
+        assert_eq!(c.realm, "atlanta.example.com");
+        assert_eq!(c.nonce, "ea9c8e88df84f1cec4341ae6cbe5a359");
+        assert_eq!(c.opaque, Some(Opaque::from("")));
+        assert_eq!(c.algorithm, Algorithm::Md5);
+        assert_eq!(c.qop_auth, true);
+        assert_eq!(c.stale, false);
+
+        let c = Challenge::parse("digest nonce=\"abc\",realm=\"r\",stale=true").unwrap();
+        assert_eq!(c.realm, "r");
+        assert_eq!(c.qop_auth, false);
+        assert_eq!(c.opaque, None);
+        assert_eq!(c.stale, true);
+
+        assert_eq!(
+            Challenge::parse("Basic realm=\"r\""),
+            Err(Error::UnsupportedScheme)
+        );
+        assert_eq!(
+            Challenge::parse("Digest realm=\"r\", nonce=\"n\", algorithm=SHA-256"),
+            Err(Error::UnsupportedAlgorithm)
+        );
+        assert_eq!(
+            Challenge::parse("Digest realm=\"r\", nonce=\"n\", qop=\"auth-int\""),
+            Err(Error::UnsupportedQop)
+        );
+        assert_eq!(
+            Challenge::parse("Digest nonce=\"n\""),
+            Err(Error::MissingRealm)
+        );
+        assert_eq!(
+            Challenge::parse("Digest realm=\"r\""),
+            Err(Error::MissingNonce)
+        );
+    }
+
+    #[test]
+    fn rfc2617_response() {
+        // RFC 2617 section 3.5
+        let c = Challenge::parse(
+            "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        )
+        .unwrap();
+        let creds = Credentials {
+            username: "Mufasa",
+            password: "Circle Of Life",
+        };
+        let resp = response(&c, &creds, "GET", "/dir/index.html", 1, "0a4f113b");
+        assert_eq!(resp, "6629fae49393a05397450978507c4ef1");
+    }
+
+    #[test]
+    fn rfc2069_response() {
+        // No qop, SIP style
+        let c = Challenge::parse(
+            "Digest realm=\"biloxi.com\", nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\"",
+        )
+        .unwrap();
+        let creds = Credentials {
+            username: "bob",
+            password: "zanzibar",
+        };
+        let resp = response(&c, &creds, "REGISTER", "sip:biloxi.com", 1, "");
+
+        // HA1 = MD5("bob:biloxi.com:zanzibar"), HA2 = MD5("REGISTER:sip:biloxi.com")
+        let ha1 = md5_hex(b"bob:biloxi.com:zanzibar");
+        let ha2 = md5_hex(b"REGISTER:sip:biloxi.com");
+        let mut md5 = Md5::new();
+        write!(md5, "{}:dcd98b7102dd2f0e8b11d0f600bfb0c093:{}", ha1, ha2).unwrap();
+        assert_eq!(resp, md5.finish_hex());
+    }
+
+    #[test]
+    fn authorization_header() {
+        let c = Challenge::parse(
+            "Digest realm=\"testrealm@host.com\", qop=\"auth\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        )
+        .unwrap();
+        let creds = Credentials {
+            username: "Mufasa",
+            password: "Circle Of Life",
+        };
+        let auth = Authorization {
+            challenge: &c,
+            credentials: &creds,
+            method: "GET",
+            uri: "/dir/index.html",
+            nc: 1,
+            cnonce: "0a4f113b",
+        };
+        let mut s: String<heapless::consts::U512> = String::new();
+        write!(s, "{}", auth).unwrap();
+        assert_eq!(
+            s.as_str(),
+            "Digest username=\"Mufasa\", realm=\"testrealm@host.com\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", uri=\"/dir/index.html\", \
+             response=\"6629fae49393a05397450978507c4ef1\", algorithm=MD5, \
+             cnonce=\"0a4f113b\", qop=auth, nc=00000001, \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""
+        );
+    }
+}
@@ -0,0 +1,1177 @@
+//! SIP message parser
+//!
+//! Zero allocation parser for RFC 3261 requests and responses.
+//! Everything borrows from the UDP payload, header values are only
+//! parsed into their typed form when asked for.
+
+use core::fmt;
+use core::str;
+use heapless::consts::U32;
+use heapless::Vec;
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case, take_while1, take_while_m_n};
+use nom::character::complete::{digit1, space0, space1};
+use nom::combinator::{map, opt, rest};
+use nom::error::ErrorKind;
+use nom::{do_parse, named, tag, tag_no_case, take_while1, IResult};
+
+pub const SIP_VERSION: &str = "SIP/2.0";
+
+/// Max number of header lines in a message
+pub type MaxHeaders = U32;
+
+pub type Headers<'a> = Vec<Header<'a>, MaxHeaders>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Nothing but whitespace (keep-alive)
+    Empty,
+    /// No end of the header section
+    Incomplete,
+    /// Header section isn't UTF-8
+    InvalidUtf8,
+    InvalidStartLine,
+    InvalidVersion,
+    InvalidStatusCode,
+    InvalidHeader,
+    TooManyHeaders,
+    MissingHeader,
+    InvalidContentLength,
+    /// Fewer body bytes than the Content-Length claims
+    Truncated,
+    InvalidUri,
+    InvalidVia,
+    InvalidNameAddr,
+    InvalidCSeq,
+    InvalidNumber,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Method {
+    Invite,
+    Ack,
+    Bye,
+    Cancel,
+    Register,
+    Options,
+    Info,
+    Update,
+    Prack,
+    Subscribe,
+    Notify,
+    Refer,
+    Message,
+    /// Extension method we don't know about
+    Unknown,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Invite => "INVITE",
+            Method::Ack => "ACK",
+            Method::Bye => "BYE",
+            Method::Cancel => "CANCEL",
+            Method::Register => "REGISTER",
+            Method::Options => "OPTIONS",
+            Method::Info => "INFO",
+            Method::Update => "UPDATE",
+            Method::Prack => "PRACK",
+            Method::Subscribe => "SUBSCRIBE",
+            Method::Notify => "NOTIFY",
+            Method::Refer => "REFER",
+            Method::Message => "MESSAGE",
+            Method::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Method {
+    /// Methods are case-sensitive
+    fn from(s: &'a str) -> Self {
+        match s {
+            "INVITE" => Method::Invite,
+            "ACK" => Method::Ack,
+            "BYE" => Method::Bye,
+            "CANCEL" => Method::Cancel,
+            "REGISTER" => Method::Register,
+            "OPTIONS" => Method::Options,
+            "INFO" => Method::Info,
+            "UPDATE" => Method::Update,
+            "PRACK" => Method::Prack,
+            "SUBSCRIBE" => Method::Subscribe,
+            "NOTIFY" => Method::Notify,
+            "REFER" => Method::Refer,
+            "MESSAGE" => Method::Message,
+            _ => Method::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RequestLine<'a> {
+    pub method: Method,
+    /// Raw method token, useful for `Method::Unknown`
+    pub method_str: &'a str,
+    pub uri: Uri<'a>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct StatusLine<'a> {
+    pub code: u16,
+    pub reason: &'a str,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum StartLine<'a> {
+    Request(RequestLine<'a>),
+    Response(StatusLine<'a>),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    /// Value with leading/trailing whitespace removed, folded lines
+    /// are kept as-is
+    pub value: &'a str,
+}
+
+impl<'a> Header<'a> {
+    /// Case-insensitive name comparison which also accepts the
+    /// compact form of `name`
+    pub fn is(&self, name: &str) -> bool {
+        if self.name.eq_ignore_ascii_case(name) {
+            return true;
+        }
+        match compact_form(name) {
+            Some(c) => self.name.eq_ignore_ascii_case(c),
+            None => false,
+        }
+    }
+}
+
+fn compact_form(name: &str) -> Option<&'static str> {
+    const FORMS: [(&str, &str); 12] = [
+        ("Via", "v"),
+        ("From", "f"),
+        ("To", "t"),
+        ("Call-ID", "i"),
+        ("Contact", "m"),
+        ("Content-Length", "l"),
+        ("Content-Type", "c"),
+        ("Content-Encoding", "e"),
+        ("Supported", "k"),
+        ("Subject", "s"),
+        ("Refer-To", "r"),
+        ("Event", "o"),
+    ];
+    FORMS
+        .iter()
+        .find(|(long, _)| long.eq_ignore_ascii_case(name))
+        .map(|(_, short)| *short)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Scheme {
+    Sip,
+    Sips,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scheme::Sip => f.write_str("sip"),
+            Scheme::Sips => f.write_str("sips"),
+        }
+    }
+}
+
+/// Semicolon separated `name[=value]` parameters
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct Params<'a>(pub &'a str);
+
+impl<'a> Params<'a> {
+    /// `Some("")` for a parameter without a value
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.0
+            .split(';')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.find('=') {
+                Some(i) => (p[..i].trim(), p[i + 1..].trim()),
+                None => (p, ""),
+            })
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.0
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Uri<'a> {
+    pub scheme: Scheme,
+    pub user: Option<&'a str>,
+    pub password: Option<&'a str>,
+    /// Hostname, IPv4 address or bracketed IPv6 reference
+    pub host: &'a str,
+    pub port: Option<u16>,
+    pub params: Params<'a>,
+    pub headers: Option<&'a str>,
+}
+
+impl<'a> Uri<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, Error> {
+        let s = s.trim();
+        let (s, scheme) = scheme(s).map_err(|_| Error::InvalidUri)?;
+
+        let (s, headers) = match s.find('?') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+
+        let (userinfo, s) = match s.find('@') {
+            Some(i) => (Some(&s[..i]), &s[i + 1..]),
+            None => (None, s),
+        };
+        let (user, password) = match userinfo {
+            Some(u) => match u.find(':') {
+                Some(i) => (Some(&u[..i]), Some(&u[i + 1..])),
+                None => (Some(u), None),
+            },
+            None => (None, None),
+        };
+        if user == Some("") {
+            return Err(Error::InvalidUri);
+        }
+
+        let (s, (host, port)) = host_port(s).map_err(|_| Error::InvalidUri)?;
+        let params = if s.is_empty() {
+            Params::default()
+        } else if s.starts_with(';') {
+            Params(&s[1..])
+        } else {
+            return Err(Error::InvalidUri);
+        };
+
+        Ok(Uri {
+            scheme,
+            user,
+            password,
+            host,
+            port,
+            params,
+            headers,
+        })
+    }
+}
+
+impl<'a> fmt::Display for Uri<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.scheme)?;
+        if let Some(user) = self.user {
+            f.write_str(user)?;
+            if let Some(pass) = self.password {
+                write!(f, ":{}", pass)?;
+            }
+            f.write_str("@")?;
+        }
+        f.write_str(self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        if !self.params.0.is_empty() {
+            write!(f, ";{}", self.params.0)?;
+        }
+        if let Some(headers) = self.headers {
+            write!(f, "?{}", headers)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_host_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '.'
+}
+
+fn is_ipv6_char(c: char) -> bool {
+    c.is_ascii_hexdigit() || c == ':' || c == '.'
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-.!%*_+`'~".contains(c)
+}
+
+fn scheme(i: &str) -> IResult<&str, Scheme> {
+    alt((
+        map(tag_no_case("sips:"), |_| Scheme::Sips),
+        map(tag_no_case("sip:"), |_| Scheme::Sip),
+    ))(i)
+}
+
+fn ipv6_reference(i: &str) -> IResult<&str, &str> {
+    let start = i;
+    let (i, _) = tag("[")(i)?;
+    let (i, addr) = take_while1(is_ipv6_char)(i)?;
+    let (i, _) = tag("]")(i)?;
+    Ok((i, &start[..addr.len() + 2]))
+}
+
+fn port(i: &str) -> IResult<&str, u16> {
+    let (i, _) = tag(":")(i)?;
+    let (i, digits) = take_while_m_n(1, 5, |c: char| c.is_ascii_digit())(i)?;
+    let port =
+        u16::from_str_radix(digits, 10).map_err(|_| nom::Err::Error((digits, ErrorKind::Digit)))?;
+    Ok((i, port))
+}
+
+fn host_port(i: &str) -> IResult<&str, (&str, Option<u16>)> {
+    let (i, host) = alt((ipv6_reference, take_while1(is_host_char)))(i)?;
+    let (i, port) = opt(port)(i)?;
+    Ok((i, (host, port)))
+}
+
+fn token(i: &str) -> IResult<&str, &str> {
+    take_while1(is_token_char)(i)
+}
+
+fn status_code(i: &str) -> IResult<&str, u16> {
+    let (i, digits) = take_while_m_n(3, 3, |c: char| c.is_ascii_digit())(i)?;
+    let code =
+        u16::from_str_radix(digits, 10).map_err(|_| nom::Err::Error((digits, ErrorKind::Digit)))?;
+    Ok((i, code))
+}
+
+named!(
+    request_line<&str, (&str, &str, &str)>,
+    do_parse!(
+        method: token
+            >> space1
+            >> uri: take_while1!(|c: char| !c.is_ascii_whitespace())
+            >> space1
+            >> version: rest
+            >> ((method, uri, version))
+    )
+);
+
+named!(
+    status_line<&str, (u16, &str)>,
+    do_parse!(
+        tag_no_case!(SIP_VERSION)
+            >> tag!(" ")
+            >> code: status_code
+            >> tag!(" ")
+            >> reason: rest
+            >> ((code, reason))
+    )
+);
+
+impl<'a> StartLine<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, Error> {
+        let is_response = s
+            .get(..SIP_VERSION.len())
+            .map(|v| v.eq_ignore_ascii_case(SIP_VERSION))
+            .unwrap_or(false);
+        if is_response {
+            let (_, (code, reason)) = status_line(s).map_err(|_| Error::InvalidStatusCode)?;
+            if code < 100 || code > 699 {
+                return Err(Error::InvalidStatusCode);
+            }
+            return Ok(StartLine::Response(StatusLine { code, reason }));
+        }
+
+        let (_, (method_str, uri, version)) =
+            request_line(s).map_err(|_| Error::InvalidStartLine)?;
+        if !version.eq_ignore_ascii_case(SIP_VERSION) {
+            return Err(Error::InvalidVersion);
+        }
+        Ok(StartLine::Request(RequestLine {
+            method: Method::from(method_str),
+            method_str,
+            uri: Uri::parse(uri)?,
+        }))
+    }
+}
+
+/// A single Via header value
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Via<'a> {
+    pub transport: &'a str,
+    pub host: &'a str,
+    pub port: Option<u16>,
+    pub params: Params<'a>,
+}
+
+/// RFC 3261 magic cookie for branch parameters
+pub const BRANCH_MAGIC_COOKIE: &str = "z9hG4bK";
+
+named!(
+    via_sent_protocol<&str, &str>,
+    do_parse!(
+        tag_no_case!("SIP")
+            >> space0
+            >> tag!("/")
+            >> space0
+            >> tag!("2.0")
+            >> space0
+            >> tag!("/")
+            >> space0
+            >> transport: token
+            >> space1
+            >> (transport)
+    )
+);
+
+impl<'a> Via<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, Error> {
+        let (i, transport) = via_sent_protocol(s.trim()).map_err(|_| Error::InvalidVia)?;
+        let (i, (host, port)) = host_port(i).map_err(|_| Error::InvalidVia)?;
+        let i = i.trim_start();
+        let params = if i.is_empty() {
+            Params::default()
+        } else if i.starts_with(';') {
+            Params(&i[1..])
+        } else {
+            return Err(Error::InvalidVia);
+        };
+        Ok(Via {
+            transport,
+            host,
+            port,
+            params,
+        })
+    }
+
+    pub fn branch(&self) -> Option<&'a str> {
+        self.params.get("branch")
+    }
+
+    pub fn received(&self) -> Option<&'a str> {
+        self.params.get("received")
+    }
+
+    /// `Some(None)` when the rport parameter is present without a value
+    pub fn rport(&self) -> Option<Option<u16>> {
+        self.params
+            .get("rport")
+            .map(|p| u16::from_str_radix(p, 10).ok())
+    }
+}
+
+/// From, To, Contact, Route style header value
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct NameAddr<'a> {
+    pub display_name: Option<&'a str>,
+    pub uri: Uri<'a>,
+    pub params: Params<'a>,
+}
+
+impl<'a> NameAddr<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, Error> {
+        let s = s.trim();
+        let (display_name, s) = if s.starts_with('"') {
+            let end = closing_quote(s).ok_or(Error::InvalidNameAddr)?;
+            (Some(&s[1..end]), s[end + 1..].trim_start())
+        } else {
+            match s.find('<') {
+                Some(i) => {
+                    let name = s[..i].trim();
+                    (if name.is_empty() { None } else { Some(name) }, &s[i..])
+                }
+                None => (None, s),
+            }
+        };
+
+        let (uri, params) = if s.starts_with('<') {
+            let end = s.find('>').ok_or(Error::InvalidNameAddr)?;
+            (&s[1..end], s[end + 1..].trim_start())
+        } else if display_name.is_some() {
+            return Err(Error::InvalidNameAddr);
+        } else {
+            // addr-spec form, parameters belong to the header
+            match s.find(';') {
+                Some(i) => (&s[..i], &s[i..]),
+                None => (s, ""),
+            }
+        };
+
+        let params = if params.is_empty() {
+            Params::default()
+        } else if params.starts_with(';') {
+            Params(&params[1..])
+        } else {
+            return Err(Error::InvalidNameAddr);
+        };
+
+        Ok(NameAddr {
+            display_name,
+            uri: Uri::parse(uri)?,
+            params,
+        })
+    }
+
+    pub fn tag(&self) -> Option<&'a str> {
+        self.params.get("tag")
+    }
+}
+
+/// Index of the quote closing the quoted-string at the start of `s`
+fn closing_quote(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(i),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct CSeq {
+    pub seq: u32,
+    pub method: Method,
+}
+
+named!(
+    cseq<&str, (&str, &str)>,
+    do_parse!(seq: digit1 >> space1 >> method: token >> ((seq, method)))
+);
+
+impl CSeq {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match cseq(s.trim()) {
+            Ok(("", (seq, method))) => Ok(CSeq {
+                seq: u32::from_str_radix(seq, 10).map_err(|_| Error::InvalidCSeq)?,
+                method: Method::from(method),
+            }),
+            _ => Err(Error::InvalidCSeq),
+        }
+    }
+}
+
+/// Splits a comma separated header value, ignoring commas inside of
+/// quoted-strings and angle brackets
+#[derive(Debug, Clone)]
+pub struct ValueList<'a> {
+    rest: &'a str,
+}
+
+impl<'a> ValueList<'a> {
+    pub fn new(s: &'a str) -> Self {
+        ValueList { rest: s }
+    }
+}
+
+impl<'a> Iterator for ValueList<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+            let mut quoted = false;
+            let mut escaped = false;
+            let mut angle = false;
+            let mut end = self.rest.len();
+            for (i, c) in self.rest.char_indices() {
+                match c {
+                    '\\' if quoted && !escaped => {
+                        escaped = true;
+                        continue;
+                    }
+                    '"' if !escaped => quoted = !quoted,
+                    '<' if !quoted => angle = true,
+                    '>' if !quoted => angle = false,
+                    ',' if !quoted && !angle => {
+                        end = i;
+                        break;
+                    }
+                    _ => (),
+                }
+                escaped = false;
+            }
+            let value = self.rest[..end].trim();
+            self.rest = if end < self.rest.len() {
+                &self.rest[end + 1..]
+            } else {
+                ""
+            };
+            if !value.is_empty() {
+                return Some(value);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message<'a> {
+    pub start_line: StartLine<'a>,
+    pub headers: Headers<'a>,
+    pub body: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    /// Parses and validates a complete message, mandatory headers
+    /// (Via, From, To, Call-ID, CSeq) must be present and well formed
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        // Leading CRLFs are allowed and ignored
+        let start = data
+            .iter()
+            .position(|b| *b != b'\r' && *b != b'\n')
+            .ok_or(Error::Empty)?;
+        let data = &data[start..];
+
+        let head_len = find(data, b"\r\n\r\n").ok_or(Error::Incomplete)?;
+        let head = str::from_utf8(&data[..head_len]).map_err(|_| Error::InvalidUtf8)?;
+        let remaining = &data[head_len + 4..];
+
+        let (start_line, header_section) = match head.find("\r\n") {
+            Some(i) => (&head[..i], &head[i + 2..]),
+            None => (head, ""),
+        };
+
+        let mut msg = Message {
+            start_line: StartLine::parse(start_line)?,
+            headers: parse_headers(header_section)?,
+            body: &[],
+        };
+
+        msg.body = match msg.content_length()? {
+            Some(len) if len > remaining.len() => return Err(Error::Truncated),
+            Some(len) => &remaining[..len],
+            None => remaining,
+        };
+
+        msg.via()?;
+        msg.from()?;
+        msg.to()?;
+        msg.call_id()?;
+        msg.cseq()?;
+
+        Ok(msg)
+    }
+
+    pub fn is_request(&self) -> bool {
+        match self.start_line {
+            StartLine::Request(_) => true,
+            StartLine::Response(_) => false,
+        }
+    }
+
+    pub fn request_line(&self) -> Option<&RequestLine<'a>> {
+        match &self.start_line {
+            StartLine::Request(r) => Some(r),
+            StartLine::Response(_) => None,
+        }
+    }
+
+    pub fn status_line(&self) -> Option<&StatusLine<'a>> {
+        match &self.start_line {
+            StartLine::Request(_) => None,
+            StartLine::Response(s) => Some(s),
+        }
+    }
+
+    pub fn method(&self) -> Option<Method> {
+        self.request_line().map(|r| r.method)
+    }
+
+    pub fn status_code(&self) -> Option<u16> {
+        self.status_line().map(|s| s.code)
+    }
+
+    /// Value of the first header named `name`
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers.iter().find(|h| h.is(name)).map(|h| h.value)
+    }
+
+    /// Every comma separated value of every header named `name`, in order
+    pub fn header_values<'s>(&'s self, name: &'s str) -> impl Iterator<Item = &'a str> + 's {
+        self.headers
+            .iter()
+            .filter(move |h| h.is(name))
+            .flat_map(|h| ValueList::new(h.value))
+    }
+
+    /// Top-most Via
+    pub fn via(&self) -> Result<Via<'a>, Error> {
+        self.header_values("Via")
+            .next()
+            .ok_or(Error::MissingHeader)
+            .and_then(Via::parse)
+    }
+
+    pub fn vias<'s>(&'s self) -> impl Iterator<Item = Result<Via<'a>, Error>> + 's {
+        self.header_values("Via").map(Via::parse)
+    }
+
+    pub fn from(&self) -> Result<NameAddr<'a>, Error> {
+        self.name_addr("From")
+    }
+
+    pub fn to(&self) -> Result<NameAddr<'a>, Error> {
+        self.name_addr("To")
+    }
+
+    pub fn contact(&self) -> Result<NameAddr<'a>, Error> {
+        self.name_addr("Contact")
+    }
+
+    pub fn call_id(&self) -> Result<&'a str, Error> {
+        match self.header("Call-ID") {
+            Some(id) if !id.is_empty() => Ok(id),
+            Some(_) => Err(Error::InvalidHeader),
+            None => Err(Error::MissingHeader),
+        }
+    }
+
+    pub fn cseq(&self) -> Result<CSeq, Error> {
+        self.header("CSeq")
+            .ok_or(Error::MissingHeader)
+            .and_then(CSeq::parse)
+    }
+
+    pub fn content_length(&self) -> Result<Option<usize>, Error> {
+        match self.header("Content-Length") {
+            Some(l) => parse_number(l)
+                .map(|l| Some(l as usize))
+                .map_err(|_| Error::InvalidContentLength),
+            None => Ok(None),
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&'a str> {
+        self.header("Content-Type")
+    }
+
+    pub fn max_forwards(&self) -> Result<Option<u32>, Error> {
+        self.header("Max-Forwards").map(parse_number).transpose()
+    }
+
+    pub fn expires(&self) -> Result<Option<u32>, Error> {
+        self.header("Expires").map(parse_number).transpose()
+    }
+
+    fn name_addr(&self, name: &str) -> Result<NameAddr<'a>, Error> {
+        self.header_values(name)
+            .next()
+            .ok_or(Error::MissingHeader)
+            .and_then(NameAddr::parse)
+    }
+}
+
+fn parse_number(s: &str) -> Result<u32, Error> {
+    let s = s.trim();
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::InvalidNumber);
+    }
+    u32::from_str_radix(s, 10).map_err(|_| Error::InvalidNumber)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn parse_headers(s: &str) -> Result<Headers<'_>, Error> {
+    let mut headers = Headers::new();
+    if s.is_empty() {
+        return Ok(headers);
+    }
+
+    // Byte offsets of the current header's name and value within `s`
+    let mut current: Option<(usize, usize, usize)> = None;
+    let mut offset = 0;
+    for line in s.split("\r\n") {
+        let line_start = offset;
+        offset += line.len() + 2;
+
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Folded continuation of the previous header value
+            match current {
+                Some(_) => continue,
+                None => return Err(Error::InvalidHeader),
+            }
+        }
+
+        if let Some((name_start, name_end, value_start)) = current.take() {
+            push_header(
+                &mut headers,
+                s,
+                name_start,
+                name_end,
+                value_start,
+                line_start,
+            )?;
+        }
+
+        let colon = line.find(':').ok_or(Error::InvalidHeader)?;
+        let name = line[..colon].trim_end_matches(|c: char| c == ' ' || c == '\t');
+        if name.is_empty() || !name.chars().all(is_token_char) {
+            return Err(Error::InvalidHeader);
+        }
+        current = Some((line_start, line_start + name.len(), line_start + colon + 1));
+    }
+
+    if let Some((name_start, name_end, value_start)) = current {
+        push_header(
+            &mut headers,
+            s,
+            name_start,
+            name_end,
+            value_start,
+            s.len() + 2,
+        )?;
+    }
+
+    Ok(headers)
+}
+
+fn push_header<'a>(
+    headers: &mut Headers<'a>,
+    s: &'a str,
+    name_start: usize,
+    name_end: usize,
+    value_start: usize,
+    next_line_start: usize,
+) -> Result<(), Error> {
+    // Exclude the CRLF ending the value
+    let value_end = next_line_start - 2;
+    headers
+        .push(Header {
+            name: &s[name_start..name_end],
+            value: s[value_start..value_end].trim(),
+        })
+        .map_err(|_| Error::TooManyHeaders)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::debug;
+
+    // RFC 3261 section 24.2, F1
+    const INVITE: &[u8] = b"INVITE sip:bob@biloxi.com SIP/2.0\r\n\
+Via: SIP/2.0/UDP pc33.atlanta.com;branch=z9hG4bKnashds8\r\n\
+Max-Forwards: 70\r\n\
+To: Bob <sip:bob@biloxi.com>\r\n\
+From: Alice <sip:alice@atlanta.com>;tag=1928301774\r\n\
+Call-ID: a84b4c76e66710\r\n\
+CSeq: 314159 INVITE\r\n\
+Contact: <sip:alice@pc33.atlanta.com>\r\n\
+Content-Type: application/sdp\r\n\
+Content-Length: 142\r\n\
+\r\n\
+v=0\r\n\
+o=alice 2890844526 2890844526 IN IP4 pc33.atlanta.com\r\n\
+s=-\r\n\
+c=IN IP4 pc33.atlanta.com\r\n\
+t=0 0\r\n\
+m=audio 49172 RTP/AVP 0\r\n\
+a=rtpmap:0 PCMU/8000\r\n";
+
+    // RFC 3261 section 24.2, F6
+    const OK: &[u8] = b"SIP/2.0 200 OK\r\n\
+Via: SIP/2.0/UDP server10.biloxi.com\r\n \
+;branch=z9hG4bK4b43c2ff8.1;received=192.0.2.3\r\n\
+Via: SIP/2.0/UDP bigbox3.site3.atlanta.com\r\n \
+;branch=z9hG4bK77ef4c2312983.1;received=192.0.2.2\r\n\
+Via: SIP/2.0/UDP pc33.atlanta.com\r\n \
+;branch=z9hG4bK776asdhds ;received=192.0.2.1\r\n\
+To: Bob <sip:bob@biloxi.com>;tag=a6c85cf\r\n\
+From: Alice <sip:alice@atlanta.com>;tag=1928301774\r\n\
+Call-ID: a84b4c76e66710@pc33.atlanta.com\r\n\
+CSeq: 314159 INVITE\r\n\
+Contact: <sip:bob@192.0.2.4>\r\n\
+Content-Type: application/sdp\r\n\
+Content-Length: 0\r\n\
+\r\n";
+
+    // RFC 3261 section 24.1, F1 (compact form, no Content-Length)
+    const REGISTER: &[u8] = b"REGISTER sips:ss2.biloxi.example.com SIP/2.0\r\n\
+v: SIP/2.0/TLS client.biloxi.example.com:5061;branch=z9hG4bKnashds7\r\n\
+Max-Forwards: 70\r\n\
+f: Bob <sips:bob@biloxi.example.com>;tag=a73kszlfl\r\n\
+t: Bob <sips:bob@biloxi.example.com>\r\n\
+i: 1j9FpLxk3uxtm8tn@biloxi.example.com\r\n\
+CSeq: 1 REGISTER\r\n\
+m: <sips:bob@client.biloxi.example.com>\r\n\
+\r\n";
+
+    #[test]
+    fn parse_invite() {
+        let msg = Message::parse(INVITE).unwrap();
+        debug!("{:?}", msg);
+        assert_eq!(msg.is_request(), true);
+        assert_eq!(msg.method(), Some(Method::Invite));
+        let rl = msg.request_line().unwrap();
+        assert_eq!(rl.uri.user, Some("bob"));
+        assert_eq!(rl.uri.host, "biloxi.com");
+        assert_eq!(rl.uri.port, None);
+
+        let via = msg.via().unwrap();
+        assert_eq!(via.transport, "UDP");
+        assert_eq!(via.host, "pc33.atlanta.com");
+        assert_eq!(via.branch(), Some("z9hG4bKnashds8"));
+
+        let to = msg.to().unwrap();
+        assert_eq!(to.display_name, Some("Bob"));
+        assert_eq!(to.uri.user, Some("bob"));
+        assert_eq!(to.tag(), None);
+
+        let from = msg.from().unwrap();
+        assert_eq!(from.display_name, Some("Alice"));
+        assert_eq!(from.uri.host, "atlanta.com");
+        assert_eq!(from.tag(), Some("1928301774"));
+
+        assert_eq!(msg.call_id(), Ok("a84b4c76e66710"));
+        assert_eq!(
+            msg.cseq(),
+            Ok(CSeq {
+                seq: 314159,
+                method: Method::Invite
+            })
+        );
+        assert_eq!(msg.max_forwards(), Ok(Some(70)));
+        assert_eq!(msg.contact().unwrap().uri.host, "pc33.atlanta.com");
+        assert_eq!(msg.content_type(), Some("application/sdp"));
+        assert_eq!(msg.content_length(), Ok(Some(142)));
+        assert_eq!(msg.body.len(), 142);
+        assert!(msg.body.starts_with(b"v=0\r\n"));
+    }
+
+    #[test]
+    fn parse_response_with_folding() {
+        let msg = Message::parse(OK).unwrap();
+        assert_eq!(msg.is_request(), false);
+        assert_eq!(msg.status_code(), Some(200));
+        assert_eq!(msg.status_line().unwrap().reason, "OK");
+
+        let mut vias = msg.vias();
+        let via = vias.next().unwrap().unwrap();
+        assert_eq!(via.host, "server10.biloxi.com");
+        assert_eq!(via.branch(), Some("z9hG4bK4b43c2ff8.1"));
+        assert_eq!(via.received(), Some("192.0.2.3"));
+        assert!(vias.next().unwrap().is_ok());
+        let via = vias.next().unwrap().unwrap();
+        assert_eq!(via.branch(), Some("z9hG4bK776asdhds"));
+        assert_eq!(via.received(), Some("192.0.2.1"));
+        assert!(vias.next().is_none());
+
+        assert_eq!(msg.to().unwrap().tag(), Some("a6c85cf"));
+        assert_eq!(msg.contact().unwrap().uri.host, "192.0.2.4");
+        assert_eq!(msg.body.len(), 0);
+    }
+
+    #[test]
+    fn parse_compact_form() {
+        let msg = Message::parse(REGISTER).unwrap();
+        assert_eq!(msg.method(), Some(Method::Register));
+        assert_eq!(msg.request_line().unwrap().uri.scheme, Scheme::Sips);
+        let via = msg.via().unwrap();
+        assert_eq!(via.transport, "TLS");
+        assert_eq!(via.port, Some(5061));
+        assert_eq!(msg.from().unwrap().tag(), Some("a73kszlfl"));
+        assert_eq!(msg.call_id(), Ok("1j9FpLxk3uxtm8tn@biloxi.example.com"));
+        assert_eq!(msg.content_length(), Ok(None));
+        assert_eq!(msg.body.len(), 0);
+    }
+
+    #[test]
+    fn comma_separated_values() {
+        let data = b"SIP/2.0 100 Trying\r\n\
+Via: SIP/2.0/UDP a.example.com;branch=z9hG4bK1, SIP/2.0/UDP b.example.com:5070;branch=z9hG4bK2;rport\r\n\
+To: \"Bob, Jr.\" <sip:bob@biloxi.com>\r\n\
+From: sip:alice@atlanta.com;tag=88\r\n\
+Call-ID: 1\r\n\
+CSeq: 1 INVITE\r\n\
+\r\n";
+        let msg = Message::parse(&data[..]).unwrap();
+        let vias: Vec<Via, U32> = msg.vias().map(|v| v.unwrap()).collect();
+        assert_eq!(vias.len(), 2);
+        assert_eq!(vias[0].host, "a.example.com");
+        assert_eq!(vias[1].port, Some(5070));
+        assert_eq!(vias[1].rport(), Some(None));
+        assert_eq!(msg.to().unwrap().display_name, Some("Bob, Jr."));
+        let from = msg.from().unwrap();
+        assert_eq!(from.display_name, None);
+        assert_eq!(from.uri.params, Params(""));
+        assert_eq!(from.tag(), Some("88"));
+    }
+
+    #[test]
+    fn uris() {
+        let uri = Uri::parse("sip:alice:secretword@atlanta.com;transport=tcp").unwrap();
+        assert_eq!(uri.user, Some("alice"));
+        assert_eq!(uri.password, Some("secretword"));
+        assert_eq!(uri.params.get("transport"), Some("tcp"));
+
+        let uri = Uri::parse("sip:+1-212-555-1212:1234@gateway.com;user=phone").unwrap();
+        assert_eq!(uri.user, Some("+1-212-555-1212"));
+        assert_eq!(uri.params.get("user"), Some("phone"));
+
+        let uri = Uri::parse("sip:atlanta.com;method=REGISTER?to=alice%40atlanta.com").unwrap();
+        assert_eq!(uri.user, None);
+        assert_eq!(uri.params.get("method"), Some("REGISTER"));
+        assert_eq!(uri.headers, Some("to=alice%40atlanta.com"));
+
+        let uri = Uri::parse("sip:[2001:db8::10]:5070").unwrap();
+        assert_eq!(uri.host, "[2001:db8::10]");
+        assert_eq!(uri.port, Some(5070));
+
+        let uri = Uri::parse("sip:alice@192.0.2.4:5060;lr").unwrap();
+        assert_eq!(uri.host, "192.0.2.4");
+        assert_eq!(uri.params.contains("lr"), true);
+
+        assert_eq!(Uri::parse("http://example.com"), Err(Error::InvalidUri));
+        assert_eq!(Uri::parse("sip:@example.com"), Err(Error::InvalidUri));
+        assert_eq!(Uri::parse("sip:a@b.com:99999"), Err(Error::InvalidUri));
+        assert_eq!(Uri::parse("sip:a@"), Err(Error::InvalidUri));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Message::parse(b""), Err(Error::Empty));
+        assert_eq!(Message::parse(b"\r\n\r\n"), Err(Error::Empty));
+        assert_eq!(
+            Message::parse(b"INVITE sip:bob@biloxi.com SIP/2.0\r\n"),
+            Err(Error::Incomplete)
+        );
+        assert_eq!(
+            Message::parse(b"INVITE sip:bob@biloxi.com\r\n\r\n"),
+            Err(Error::InvalidStartLine)
+        );
+        assert_eq!(
+            Message::parse(b"INVITE sip:bob@biloxi.com SIP/3.0\r\n\r\n"),
+            Err(Error::InvalidVersion)
+        );
+        assert_eq!(
+            Message::parse(b"SIP/2.0 2000 OK\r\n\r\n"),
+            Err(Error::InvalidStatusCode)
+        );
+        assert_eq!(
+            Message::parse(b"SIP/2.0 099 Huh\r\n\r\n"),
+            Err(Error::InvalidStatusCode)
+        );
+        assert_eq!(
+            Message::parse(b"SIP/2.0 200 OK\r\nNo colon\r\n\r\n"),
+            Err(Error::InvalidHeader)
+        );
+        assert_eq!(
+            Message::parse(b"SIP/2.0 200 OK\r\n folded\r\n\r\n"),
+            Err(Error::InvalidHeader)
+        );
+        assert_eq!(
+            Message::parse(b"SIP/2.0 200 OK\r\n\xff: x\r\n\r\n"),
+            Err(Error::InvalidUtf8)
+        );
+        assert_eq!(
+            Message::parse(b"SIP/2.0 200 OK\r\nContent-Length: 10\r\n\r\nabc"),
+            Err(Error::Truncated)
+        );
+        assert_eq!(
+            Message::parse(b"SIP/2.0 200 OK\r\nContent-Length: -1\r\n\r\n"),
+            Err(Error::InvalidContentLength)
+        );
+        assert_eq!(
+            Message::parse(b"SIP/2.0 200 OK\r\nContent-Length: 0\r\n\r\n"),
+            Err(Error::MissingHeader)
+        );
+
+        let mut data: Vec<u8, heapless::consts::U1024> = Vec::new();
+        data.extend_from_slice(b"SIP/2.0 200 OK\r\n").unwrap();
+        for _ in 0..33 {
+            data.extend_from_slice(b"X: y\r\n").unwrap();
+        }
+        data.extend_from_slice(b"\r\n").unwrap();
+        assert_eq!(Message::parse(&data), Err(Error::TooManyHeaders));
+    }
+
+    #[test]
+    fn invalid_mandatory_headers() {
+        let data = b"SIP/2.0 200 OK\r\n\
+Via: SIP/2.0/UDP pc33.atlanta.com;branch=z9hG4bK776asdhds\r\n\
+To: Bob <sip:bob@biloxi.com>;tag=a6c85cf\r\n\
+From: Alice <sip:alice@atlanta.com;tag=1928301774\r\n\
+Call-ID: a84b4c76e66710@pc33.atlanta.com\r\n\
+CSeq: 314159 INVITE\r\n\
+\r\n";
+        assert_eq!(Message::parse(&data[..]), Err(Error::InvalidNameAddr));
+
+        let data = b"SIP/2.0 200 OK\r\n\
+Via: SIP/2.0/UDP pc33.atlanta.com;branch=z9hG4bK776asdhds\r\n\
+To: Bob <sip:bob@biloxi.com>;tag=a6c85cf\r\n\
+From: Alice <sip:alice@atlanta.com>;tag=1928301774\r\n\
+Call-ID: a84b4c76e66710@pc33.atlanta.com\r\n\
+CSeq: INVITE 314159\r\n\
+\r\n";
+        assert_eq!(Message::parse(&data[..]), Err(Error::InvalidCSeq));
+
+        let data = b"SIP/2.0 200 OK\r\n\
+Via: SIP/2.0 UDP pc33.atlanta.com\r\n\
+To: Bob <sip:bob@biloxi.com>;tag=a6c85cf\r\n\
+From: Alice <sip:alice@atlanta.com>;tag=1928301774\r\n\
+Call-ID: a84b4c76e66710@pc33.atlanta.com\r\n\
+CSeq: 314159 INVITE\r\n\
+\r\n";
+        assert_eq!(Message::parse(&data[..]), Err(Error::InvalidVia));
+    }
+
+    #[test]
+    fn fuzz_robust() {
+        // Deterministic xorshift mutations of the sample messages,
+        // parsing must never panic
+        let mut state: u32 = 0x1234_5678;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        for sample in [INVITE, OK, REGISTER].iter() {
+            for _ in 0..2000 {
+                let mut data: Vec<u8, heapless::consts::U1024> = Vec::new();
+                data.extend_from_slice(sample).unwrap();
+                let mutations = 1 + (next() % 8);
+                for _ in 0..mutations {
+                    let idx = next() as usize % data.len();
+                    match next() % 3 {
+                        0 => data[idx] = next() as u8,
+                        1 => data.resize(idx, 0).unwrap(),
+                        _ => data[idx] = b"\r\n:;<>\", @"[next() as usize % 10],
+                    }
+                    if data.is_empty() {
+                        break;
+                    }
+                }
+                let res = Message::parse(&data);
+                if let Ok(msg) = res {
+                    let _ = msg.contact();
+                    let _ = msg.expires();
+                    for v in msg.vias() {
+                        let _ = v.map(|v| v.rport());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! Session Initiation Protocol (RFC 3261) user agent support
+
+pub mod builder;
+pub mod dialog;
+pub mod digest;
+pub mod message;
+pub mod registration;
+pub mod sdp;
+pub mod transaction;
+pub mod user_agent;
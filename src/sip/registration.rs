@@ -0,0 +1,868 @@
+//! SIP registration client (RFC 3261 section 10)
+//!
+//! Keeps a binding for the configured address-of-record alive with the
+//! registrar over a UDP socket, answering digest challenges and
+//! refreshing the binding before it expires.
+
+use crate::net::socket_pool::UdpHandle;
+use crate::random::RandomSource;
+use crate::sip::builder::{self, new_branch, new_call_id, new_tag, Builder, MessageBuffer, Token};
+use crate::sip::digest::{Authorization, CNonce, Challenge, Credentials};
+use crate::sip::message::{Message, Method, NameAddr};
+use crate::sip::transaction::{self, State, Transaction, TIMER_F};
+use crate::time::{Duration, Instant};
+use core::fmt::{self, Write};
+use heapless::consts::{U128, U20};
+use heapless::String;
+use log::{debug, warn};
+use smoltcp::socket::{SocketSet, UdpSocket};
+use smoltcp::wire::{IpEndpoint, Ipv4Address};
+
+pub const DEFAULT_PORT: u16 = 5060;
+pub const DEFAULT_EXPIRES: u32 = 3600;
+
+/// Wait before trying again after a failed registration
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+type RequestUri = String<U128>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    Socket(smoltcp::Error),
+}
+
+impl From<smoltcp::Error> for Error {
+    fn from(e: smoltcp::Error) -> Self {
+        Error::Socket(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Failure {
+    /// No final response from the registrar
+    Timeout,
+    /// Registrar rejected our credentials
+    Authentication,
+    /// Any other final error response
+    Rejected(u16),
+    /// The REGISTER doesn't fit in a message buffer
+    Config,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Status {
+    Unregistered,
+    Registering,
+    Registered,
+    Failed(Failure),
+}
+
+impl Status {
+    pub fn is_registered(&self) -> bool {
+        *self == Status::Registered
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::Unregistered
+    }
+}
+
+/// Short form that fits on a display row
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s: String<U20> = String::new();
+        match self {
+            Status::Unregistered => s.push_str("Not Registered"),
+            Status::Registering => s.push_str("Registering"),
+            Status::Registered => s.push_str("Registered"),
+            Status::Failed(Failure::Timeout) => s.push_str("No Registrar"),
+            Status::Failed(Failure::Authentication) => s.push_str("Bad Credentials"),
+            Status::Failed(Failure::Config) => s.push_str("Bad SIP Config"),
+            Status::Failed(Failure::Rejected(code)) => {
+                write!(s, "Reg Failed ({})", code).map_err(|_| ())
+            }
+        }
+        .map_err(|_| fmt::Error)?;
+        f.pad(s.as_str())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Config<'a> {
+    /// Registrar (or outbound proxy) address
+    pub registrar: IpEndpoint,
+    /// SIP domain of the address-of-record, also the Request-URI host
+    pub domain: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+    /// Our address, used in Via and Contact
+    pub local_addr: Ipv4Address,
+    pub local_port: u16,
+    /// Requested binding lifetime in seconds
+    pub expires: u32,
+}
+
+pub struct Registration<'a, R: RandomSource> {
+    config: Config<'a>,
+    handle: UdpHandle,
+    rng: R,
+    status: Status,
+    call_id: Token,
+    tag: Token,
+    cseq: u32,
+    expires: u32,
+    /// Challenge and whether it came from a proxy (407)
+    challenge: Option<(Challenge, bool)>,
+    nc: u32,
+    /// Current REGISTER transaction
+    transaction: Option<Transaction>,
+    /// Current REGISTER carried credentials for the stored challenge
+    authorized: bool,
+    /// When to send the next REGISTER, if not in a transaction
+    next_register: Option<Instant>,
+}
+
+impl<'a, R: RandomSource> Registration<'a, R> {
+    /// `handle` is a UDP socket in the interface's socket set,
+    /// it gets bound to `config.local_port`.
+    pub fn new(config: Config<'a>, handle: UdpHandle, mut rng: R) -> Self {
+        let call_id = new_call_id(&mut rng, None);
+        let tag = new_tag(&mut rng);
+        Registration {
+            config,
+            handle,
+            rng,
+            status: Status::Unregistered,
+            call_id,
+            tag,
+            cseq: 0,
+            expires: config.expires,
+            challenge: None,
+            nc: 0,
+            transaction: None,
+            authorized: false,
+            next_register: None,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn socket_handle(&self) -> UdpHandle {
+        self.handle
+    }
+
+    /// Registers on the next poll
+    pub fn start(&mut self, time: Instant) {
+        if !self.is_pending() {
+            self.next_register = Some(time);
+        }
+    }
+
+    /// Address changed (i.e. new DHCP lease), re-register with the new Contact
+    pub fn set_local_addr(&mut self, addr: Ipv4Address, time: Instant) {
+        if addr != self.config.local_addr {
+            self.config.local_addr = addr;
+            self.transaction = None;
+            self.start(time);
+        }
+    }
+
+    /// Registrar resolved to a different address (i.e. new DNS answer),
+    /// re-register there
+    pub fn set_registrar(&mut self, registrar: IpEndpoint, time: Instant) {
+        if registrar != self.config.registrar {
+            self.config.registrar = registrar;
+            self.transaction = None;
+            self.start(time);
+        }
+    }
+
+    /// Receives responses, runs timers and sends any outstanding request.
+    /// Returns true if a request was queued on the socket.
+    pub fn poll(&mut self, sockets: &mut SocketSet, time: Instant) -> Result<bool, Error> {
+        let mut socket = sockets.get::<UdpSocket>(self.handle.into());
+        if !socket.is_open() {
+            socket.bind(self.config.local_port)?;
+        }
+
+        while let Ok((data, from)) = socket.recv() {
+            match Message::parse(data) {
+                Ok(msg) => {
+                    if !self.handle_response(&msg, time) {
+                        debug!("Ignoring SIP message from {}", from);
+                    }
+                }
+                Err(e) => warn!("Bad SIP message from {}: {:?}", from, e),
+            }
+        }
+
+        let mut sent = false;
+        self.poll_transmit(time, &mut |data, remote| {
+            sent = socket.send_slice(data, remote).is_ok();
+            sent
+        });
+
+        Ok(sent)
+    }
+
+    /// Runs the timers and (re)transmits the REGISTER through `transmit`,
+    /// for when the socket is shared and `handle_response` is fed directly
+    pub fn poll_transmit<F>(&mut self, time: Instant, transmit: &mut F)
+    where
+        F: FnMut(&[u8], IpEndpoint) -> bool,
+    {
+        match self.next_register {
+            Some(at) if !self.is_pending() && time >= at => {
+                self.next_register = None;
+                self.send_register(time);
+            }
+            _ => (),
+        }
+
+        let timed_out = match &mut self.transaction {
+            Some(t) => t.poll(time, transmit),
+            None => false,
+        };
+        if timed_out {
+            warn!("Registration timed out");
+            self.failed(Failure::Timeout, time);
+        }
+    }
+
+    /// Processes a response to our REGISTER, returns false if it
+    /// doesn't belong to the current transaction
+    pub fn handle_response(&mut self, msg: &Message, time: Instant) -> bool {
+        let code = match msg.status_code() {
+            Some(c) => c,
+            None => return false,
+        };
+        let up = match &mut self.transaction {
+            Some(t) if t.matches_response(msg) && msg.call_id() == Ok(self.call_id.as_str()) => {
+                t.on_response(msg, time).unwrap_or(false)
+            }
+            _ => return false,
+        };
+        // Retransmission or provisional, the transaction deals with those
+        if !up || code < 200 {
+            return true;
+        }
+
+        match code {
+            200..=299 => self.registered(msg, time),
+            401 | 407 => {
+                let proxy = code == 407;
+                let name = if proxy {
+                    "Proxy-Authenticate"
+                } else {
+                    "WWW-Authenticate"
+                };
+                match msg.header(name).map(Challenge::parse) {
+                    Some(Ok(challenge)) => {
+                        if self.authorized && !challenge.stale {
+                            warn!("Registration credentials rejected");
+                            self.failed(Failure::Authentication, time);
+                        } else {
+                            self.challenge = Some((challenge, proxy));
+                            self.nc = 0;
+                            self.send_register(time);
+                        }
+                    }
+                    _ => {
+                        warn!("Unusable {} challenge", code);
+                        self.failed(Failure::Rejected(code), time);
+                    }
+                }
+            }
+            423 => match msg.header("Min-Expires").map(str::parse::<u32>) {
+                Some(Ok(min)) if min > self.expires => {
+                    self.expires = min;
+                    self.send_register(time);
+                }
+                _ => self.failed(Failure::Rejected(code), time),
+            },
+            _ => {
+                warn!("Registration failed with {}", code);
+                self.failed(Failure::Rejected(code), time);
+            }
+        }
+
+        true
+    }
+
+    /// REGISTER transaction waiting for a final response
+    fn is_pending(&self) -> bool {
+        match &self.transaction {
+            Some(t) => match t.state() {
+                State::Trying | State::Proceeding => true,
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    fn registered(&mut self, msg: &Message, time: Instant) {
+        // Prefer the expires param of our own Contact binding
+        let username = self.config.username;
+        let port = self.config.local_port;
+        let contact_expires = msg
+            .header_values("Contact")
+            .filter_map(|v| NameAddr::parse(v).ok())
+            .filter(|c| c.uri.user == Some(username) && c.uri.port == Some(port))
+            .filter_map(|c| c.params.get("expires").and_then(|e| e.parse::<u32>().ok()))
+            .next();
+        let expires = match contact_expires {
+            Some(e) => e,
+            None => msg.expires().ok().and_then(|e| e).unwrap_or(self.expires),
+        };
+
+        if expires == 0 {
+            warn!("Registrar granted no binding");
+            self.status = Status::Unregistered;
+            self.next_register = Some(time + RETRY_INTERVAL);
+        } else {
+            debug!("Registered for {} seconds", expires);
+            self.status = Status::Registered;
+            self.next_register = Some(time + refresh_delay(expires));
+        }
+    }
+
+    fn failed(&mut self, failure: Failure, time: Instant) {
+        self.status = Status::Failed(failure);
+        self.next_register = Some(time + RETRY_INTERVAL);
+    }
+
+    fn send_register(&mut self, time: Instant) {
+        if self.status != Status::Registered {
+            self.status = Status::Registering;
+        }
+        self.cseq = self.cseq.wrapping_add(1);
+
+        let mut request = MessageBuffer::new();
+        let transaction = self
+            .build_register(&mut request)
+            .map_err(transaction::Error::from)
+            .and_then(|authorized| {
+                self.authorized = authorized;
+                Transaction::client(&request, self.config.registrar, time)
+            });
+        match transaction {
+            Ok(t) => self.transaction = Some(t),
+            Err(e) => {
+                warn!("Failed to start REGISTER: {:?}", e);
+                self.transaction = None;
+                self.failed(Failure::Config, time);
+            }
+        }
+    }
+
+    /// Builds the REGISTER into `request`, returns true if it has credentials
+    fn build_register(&mut self, request: &mut MessageBuffer) -> Result<bool, builder::Error> {
+        let c = self.config;
+        let mut uri = RequestUri::new();
+        write!(uri, "sip:{}", c.domain).map_err(|_| builder::Error::BufferFull)?;
+
+        let mut cnonce = CNonce::new();
+        // Can't overflow the cnonce
+        write!(
+            cnonce,
+            "{:08x}{:08x}",
+            self.rng.next_u32(),
+            self.rng.next_u32()
+        )
+        .unwrap();
+
+        let branch = new_branch(&mut self.rng);
+        let mut b = Builder::request(request, Method::Register, uri.as_str())?;
+        let mut local_host: String<U20> = String::new();
+        write!(local_host, "{}", c.local_addr).map_err(|_| builder::Error::BufferFull)?;
+        b.via("UDP", local_host.as_str(), c.local_port, branch.as_str())?
+            .max_forwards(builder::MAX_FORWARDS)?
+            .from(
+                None,
+                format_args!("sip:{}@{}", c.username, c.domain),
+                Some(self.tag.as_str()),
+            )?
+            .to(None, format_args!("sip:{}@{}", c.username, c.domain), None)?
+            .call_id(self.call_id.as_str())?
+            .cseq(self.cseq, Method::Register)?
+            .contact(format_args!(
+                "sip:{}@{}:{}",
+                c.username, c.local_addr, c.local_port
+            ))?
+            .expires(self.expires)?;
+
+        let authorized = if let Some((challenge, proxy)) = &self.challenge {
+            self.nc = self.nc.wrapping_add(1);
+            let credentials = Credentials {
+                username: c.username,
+                password: c.password,
+            };
+            let auth = Authorization {
+                challenge,
+                credentials: &credentials,
+                method: Method::Register.as_str(),
+                uri: uri.as_str(),
+                nc: self.nc,
+                cnonce: cnonce.as_str(),
+            };
+            let name = if *proxy {
+                "Proxy-Authorization"
+            } else {
+                "Authorization"
+            };
+            b.header(name, auth)?;
+            true
+        } else {
+            false
+        };
+
+        b.finish(&[])?;
+        Ok(authorized)
+    }
+}
+
+/// How long after a successful registration to refresh it, leaving
+/// enough time for a full transaction timeout before the binding expires
+pub fn refresh_delay(expires: u32) -> Duration {
+    let expires = Duration::from_secs(u64::from(expires));
+    if expires > TIMER_F * 2 {
+        expires - TIMER_F
+    } else {
+        expires / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::loopback::Loopback;
+    use crate::random::XorShift32;
+    use crate::sip::digest;
+    use crate::sip::message::ValueList;
+    use core::cell::Cell;
+    use log::debug;
+    use smoltcp::iface::EthernetInterface;
+    use smoltcp::socket::SocketHandle;
+    use smoltcp::wire::{IpAddress, IpCidr};
+
+    const REGISTRAR_PORT: u16 = 5060;
+    const LOCAL_PORT: u16 = 5070;
+    const STEP: Duration = Duration::from_millis(10);
+
+    fn config() -> Config<'static> {
+        Config {
+            registrar: IpEndpoint::new(IpAddress::v4(127, 0, 0, 1), REGISTRAR_PORT),
+            domain: "home.lan",
+            username: "1001",
+            password: "secret",
+            local_addr: Ipv4Address::new(127, 0, 0, 1),
+            local_port: LOCAL_PORT,
+            expires: 120,
+        }
+    }
+
+    fn auth_param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+        let params = value.trim().trim_start_matches("Digest").trim_start();
+        ValueList::new(params).find_map(|p| {
+            let i = p.find('=')?;
+            if p[..i].trim() == name {
+                Some(p[i + 1..].trim().trim_matches('"'))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Scripted registrar, returns the response to send for a REGISTER
+    type Script<'s> = &'s mut dyn FnMut(&Message) -> Option<MessageBuffer>;
+
+    /// Runs the interface, registration and registrar for `duration`
+    fn run(
+        iface: &mut EthernetInterface<Loopback>,
+        sockets: &mut SocketSet,
+        reg: &mut Registration<XorShift32>,
+        registrar: SocketHandle,
+        time: &mut Instant,
+        duration: Duration,
+        script: Script,
+    ) {
+        let end = *time + duration;
+        while *time < end {
+            let t = smoltcp::time::Instant::from_millis(time.as_millis() as i64);
+            let _ = iface.poll(sockets, t);
+
+            reg.poll(sockets, *time).unwrap();
+
+            let mut socket = sockets.get::<UdpSocket>(registrar);
+            if !socket.is_open() {
+                socket.bind(REGISTRAR_PORT).unwrap();
+            }
+            let mut response = None;
+            while let Ok((data, from)) = socket.recv() {
+                let msg = Message::parse(data).unwrap();
+                assert_eq!(msg.method(), Some(Method::Register));
+                debug!("Registrar got REGISTER from {}", from);
+                response = script(&msg).map(|r| (r, from));
+            }
+            if let Some((r, to)) = response {
+                socket.send_slice(&r, to).unwrap();
+            }
+
+            *time += STEP;
+        }
+    }
+
+    fn respond(
+        msg: &Message,
+        code: u16,
+        reason: &str,
+        extra: Option<(&str, &str)>,
+    ) -> MessageBuffer {
+        let mut buf = MessageBuffer::new();
+        let mut b = Builder::response_to(&mut buf, msg, code, reason, Some("reg")).unwrap();
+        if let Some((name, value)) = extra {
+            b.header(name, value).unwrap();
+        }
+        b.finish(&[]).unwrap();
+        buf
+    }
+
+    fn challenge(msg: &Message) -> MessageBuffer {
+        respond(
+            msg,
+            401,
+            "Unauthorized",
+            Some((
+                "WWW-Authenticate",
+                "Digest realm=\"home.lan\", nonce=\"5f1e3a\", qop=\"auth\", algorithm=MD5",
+            )),
+        )
+    }
+
+    /// Checks the Authorization header against the expected digest
+    fn verify_authorization(msg: &Message, nc: &str) {
+        let auth = msg.header("Authorization").expect("Missing Authorization");
+        assert_eq!(auth_param(auth, "username"), Some("1001"));
+        assert_eq!(auth_param(auth, "realm"), Some("home.lan"));
+        assert_eq!(auth_param(auth, "uri"), Some("sip:home.lan"));
+        assert_eq!(auth_param(auth, "nc"), Some(nc));
+
+        let challenge = Challenge::parse(
+            "Digest realm=\"home.lan\", nonce=\"5f1e3a\", qop=\"auth\", algorithm=MD5",
+        )
+        .unwrap();
+        let credentials = Credentials {
+            username: "1001",
+            password: "secret",
+        };
+        let expected = digest::response(
+            &challenge,
+            &credentials,
+            "REGISTER",
+            "sip:home.lan",
+            u32::from_str_radix(nc, 16).unwrap(),
+            auth_param(auth, "cnonce").unwrap(),
+        );
+        assert_eq!(auth_param(auth, "response"), Some(expected.as_str()));
+    }
+
+    macro_rules! setup {
+        ($iface:ident, $sockets:ident, $reg:ident, $registrar:ident) => {
+            crate::loopback!($iface, $sockets, [IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8)], 1, {
+                ua_handle: [4; 4096],
+                $registrar: [4; 4096],
+            });
+            let mut $reg =
+                Registration::new(config(), UdpHandle::from_socket(ua_handle), XorShift32::new(1));
+        };
+    }
+
+    #[test]
+    fn status_fits_row() {
+        let statuses = [
+            Status::Unregistered,
+            Status::Registering,
+            Status::Registered,
+            Status::Failed(Failure::Timeout),
+            Status::Failed(Failure::Authentication),
+            Status::Failed(Failure::Rejected(403)),
+            Status::Failed(Failure::Config),
+        ];
+        for s in statuses.iter() {
+            let mut row: String<U20> = String::new();
+            write!(row, "{: ^20}", s).unwrap();
+            assert_eq!(row.len(), 20);
+        }
+        assert_eq!(Status::default().is_registered(), false);
+        assert_eq!(Status::Registered.is_registered(), true);
+    }
+
+    #[test]
+    fn refresh_before_expiry() {
+        assert_eq!(refresh_delay(3600), Duration::from_secs(3600 - 32));
+        assert_eq!(refresh_delay(120), Duration::from_secs(88));
+        assert_eq!(refresh_delay(60), Duration::from_secs(30));
+        assert_eq!(refresh_delay(1), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn register_with_digest_challenge_and_refresh() {
+        setup!(iface, sockets, reg, registrar);
+        let mut time = Instant::from_millis(0);
+        let requests = Cell::new(0);
+        let mut script = |msg: &Message| {
+            requests.set(requests.get() + 1);
+            assert_eq!(msg.cseq().unwrap().seq, requests.get());
+            assert_eq!(msg.expires(), Ok(Some(120)));
+            let contact = msg.contact().unwrap();
+            assert_eq!(contact.uri.user, Some("1001"));
+            assert_eq!(contact.uri.host, "127.0.0.1");
+            assert_eq!(contact.uri.port, Some(LOCAL_PORT));
+            match requests.get() {
+                1 => {
+                    assert!(msg.header("Authorization").is_none());
+                    Some(challenge(msg))
+                }
+                2 => {
+                    verify_authorization(msg, "00000001");
+                    Some(respond(
+                        msg,
+                        200,
+                        "OK",
+                        Some(("Contact", "<sip:1001@127.0.0.1:5070>;expires=120")),
+                    ))
+                }
+                3 => {
+                    // Refresh reuses the challenge
+                    verify_authorization(msg, "00000002");
+                    Some(respond(msg, 200, "OK", Some(("Expires", "60"))))
+                }
+                _ => None,
+            }
+        };
+
+        reg.start(time);
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(2),
+            &mut script,
+        );
+        assert_eq!(reg.status(), Status::Registered);
+
+        // Refreshed 88 seconds after being granted 120
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(85),
+            &mut script,
+        );
+        assert_eq!(requests.get(), 2);
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(2),
+            &mut script,
+        );
+        assert_eq!(requests.get(), 3);
+        assert_eq!(reg.status(), Status::Registered);
+    }
+
+    #[test]
+    fn stale_nonce_retries() {
+        setup!(iface, sockets, reg, registrar);
+        let mut time = Instant::from_millis(0);
+        let requests = Cell::new(0);
+        let mut script = |msg: &Message| {
+            requests.set(requests.get() + 1);
+            match requests.get() {
+                1 => Some(challenge(msg)),
+                2 => Some(respond(
+                    msg,
+                    401,
+                    "Unauthorized",
+                    Some((
+                        "WWW-Authenticate",
+                        "Digest realm=\"home.lan\", nonce=\"5f1e3a\", qop=\"auth\", stale=true",
+                    )),
+                )),
+                3 => {
+                    verify_authorization(msg, "00000001");
+                    Some(respond(msg, 200, "OK", None))
+                }
+                _ => None,
+            }
+        };
+
+        reg.start(time);
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(2),
+            &mut script,
+        );
+        assert_eq!(requests.get(), 3);
+        assert_eq!(reg.status(), Status::Registered);
+    }
+
+    #[test]
+    fn bad_credentials() {
+        setup!(iface, sockets, reg, registrar);
+        let mut time = Instant::from_millis(0);
+        let requests = Cell::new(0);
+        let mut script = |msg: &Message| {
+            requests.set(requests.get() + 1);
+            Some(challenge(msg))
+        };
+
+        reg.start(time);
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(2),
+            &mut script,
+        );
+        assert_eq!(requests.get(), 2);
+        assert_eq!(reg.status(), Status::Failed(Failure::Authentication));
+
+        // Tries again later
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            RETRY_INTERVAL,
+            &mut script,
+        );
+        assert_eq!(requests.get(), 3);
+    }
+
+    #[test]
+    fn interval_too_brief() {
+        setup!(iface, sockets, reg, registrar);
+        let mut time = Instant::from_millis(0);
+        let requests = Cell::new(0);
+        let mut script = |msg: &Message| {
+            requests.set(requests.get() + 1);
+            match requests.get() {
+                1 => Some(respond(
+                    msg,
+                    423,
+                    "Interval Too Brief",
+                    Some(("Min-Expires", "600")),
+                )),
+                _ => {
+                    assert_eq!(msg.expires(), Ok(Some(600)));
+                    Some(respond(msg, 200, "OK", None))
+                }
+            }
+        };
+
+        reg.start(time);
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(2),
+            &mut script,
+        );
+        assert_eq!(requests.get(), 2);
+        assert_eq!(reg.status(), Status::Registered);
+    }
+
+    #[test]
+    fn retransmits_then_times_out() {
+        setup!(iface, sockets, reg, registrar);
+        let mut time = Instant::from_millis(0);
+        let requests = Cell::new(0);
+        let mut script = |_msg: &Message| {
+            requests.set(requests.get() + 1);
+            None
+        };
+
+        reg.start(time);
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(5),
+            &mut script,
+        );
+        assert_eq!(reg.status(), Status::Registering);
+        // 0, 0.5, 1.5, 3.5 seconds
+        assert_eq!(requests.get(), 4);
+
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(28),
+            &mut script,
+        );
+        assert_eq!(reg.status(), Status::Failed(Failure::Timeout));
+        // Then every T2
+        assert_eq!(requests.get(), 11);
+    }
+
+    #[test]
+    fn ignores_unrelated_responses() {
+        setup!(iface, sockets, reg, registrar);
+        let mut time = Instant::from_millis(0);
+        let requests = Cell::new(0);
+        let mut script = |msg: &Message| {
+            requests.set(requests.get() + 1);
+            let mut buf = MessageBuffer::new();
+            let mut b = Builder::response(&mut buf, 200, "OK").unwrap();
+            b.via("UDP", "127.0.0.1", LOCAL_PORT, "z9hG4bKother")
+                .unwrap()
+                .header("From", msg.header("From").unwrap())
+                .unwrap()
+                .header("To", msg.header("To").unwrap())
+                .unwrap()
+                .call_id(msg.call_id().unwrap())
+                .unwrap()
+                .cseq(msg.cseq().unwrap().seq, Method::Register)
+                .unwrap();
+            b.finish(&[]).unwrap();
+            Some(buf)
+        };
+
+        reg.start(time);
+        run(
+            &mut iface,
+            &mut sockets,
+            &mut reg,
+            registrar,
+            &mut time,
+            Duration::from_secs(1),
+            &mut script,
+        );
+        assert_eq!(reg.status(), Status::Registering);
+    }
+}
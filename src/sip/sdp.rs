@@ -0,0 +1,926 @@
+//! Session Description Protocol (RFC 4566) and the offer/answer
+//! model (RFC 3264)
+//!
+//! Only what an audio phone needs: a single IPv4 RTP/AVP audio stream
+//! with G.711 (PCMU/PCMA) and RFC 4733 telephone-event.
+
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use heapless::consts::{U16, U4, U512, U8};
+use heapless::{String, Vec};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+pub const CONTENT_TYPE: &str = "application/sdp";
+
+/// Payload type offered for telephone-event
+pub const DTMF_PAYLOAD_TYPE: u8 = 101;
+
+/// Packetization time used when the remote doesn't say otherwise
+pub const DEFAULT_PTIME: u32 = 20;
+
+pub type MaxMedia = U4;
+pub type MaxFormats = U16;
+pub type MaxFmtps = U8;
+pub type Formats = Vec<u8, MaxFormats>;
+/// Rendered session description, a message body
+pub type Body = String<U512>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    /// Line isn't `<type>=<value>`
+    InvalidLine,
+    InvalidVersion,
+    InvalidOrigin,
+    InvalidConnection,
+    InvalidMedia,
+    InvalidAttribute,
+    /// Only IN IP4 addresses are supported
+    UnsupportedAddress,
+    TooManyMedia,
+    TooManyFormats,
+    /// Neither the session nor the media has a connection address
+    MissingConnection,
+    /// No audio stream to negotiate
+    NoAudio,
+    NoCommonCodec,
+    BufferFull,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Default for Direction {
+    fn default() -> Self {
+        Direction::SendRecv
+    }
+}
+
+impl Direction {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+
+    /// The direction seen from the other side
+    pub fn reverse(&self) -> Self {
+        match self {
+            Direction::SendOnly => Direction::RecvOnly,
+            Direction::RecvOnly => Direction::SendOnly,
+            d => *d,
+        }
+    }
+
+    pub fn sends(&self) -> bool {
+        *self == Direction::SendRecv || *self == Direction::SendOnly
+    }
+
+    pub fn receives(&self) -> bool {
+        *self == Direction::SendRecv || *self == Direction::RecvOnly
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Codec {
+    /// G.711 mu-law
+    Pcmu,
+    /// G.711 A-law
+    Pcma,
+    /// RFC 4733 DTMF events
+    TelephoneEvent,
+}
+
+impl Codec {
+    pub fn encoding_name(&self) -> &'static str {
+        match self {
+            Codec::Pcmu => "PCMU",
+            Codec::Pcma => "PCMA",
+            Codec::TelephoneEvent => "telephone-event",
+        }
+    }
+
+    pub fn clock_rate(&self) -> u32 {
+        8000
+    }
+
+    /// RFC 3551 static payload type
+    pub fn static_payload_type(&self) -> Option<u8> {
+        match self {
+            Codec::Pcmu => Some(0),
+            Codec::Pcma => Some(8),
+            Codec::TelephoneEvent => None,
+        }
+    }
+
+    /// Payload type used in our offers
+    pub fn payload_type(&self) -> u8 {
+        self.static_payload_type().unwrap_or(DTMF_PAYLOAD_TYPE)
+    }
+
+    pub fn is_audio(&self) -> bool {
+        *self != Codec::TelephoneEvent
+    }
+
+    fn from_rtpmap(map: &RtpMap) -> Option<Self> {
+        [Codec::Pcmu, Codec::Pcma, Codec::TelephoneEvent]
+            .iter()
+            .find(|c| {
+                map.encoding.eq_ignore_ascii_case(c.encoding_name())
+                    && map.clock_rate == c.clock_rate()
+                    && map.channels.unwrap_or(1) == 1
+            })
+            .cloned()
+    }
+
+    fn from_static(payload_type: u8) -> Option<Self> {
+        match payload_type {
+            0 => Some(Codec::Pcmu),
+            8 => Some(Codec::Pcma),
+            _ => None,
+        }
+    }
+}
+
+/// `o=` line
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Origin<'a> {
+    pub username: &'a str,
+    pub session_id: u64,
+    pub session_version: u64,
+    /// Unicast address, may be a hostname
+    pub address: &'a str,
+}
+
+/// `a=rtpmap:<payload type> <encoding name>/<clock rate>[/<channels>]`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RtpMap<'a> {
+    pub payload_type: u8,
+    pub encoding: &'a str,
+    pub clock_rate: u32,
+    pub channels: Option<u8>,
+}
+
+/// `a=fmtp:<payload type> <parameters>`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Fmtp<'a> {
+    pub payload_type: u8,
+    pub params: &'a str,
+}
+
+/// `m=` section, connection, ptime and direction are resolved against
+/// the session level values
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Media<'a> {
+    pub media: &'a str,
+    /// Zero for a rejected or disabled stream
+    pub port: u16,
+    pub proto: &'a str,
+    /// Payload types in order of preference, RTP profiles only
+    pub formats: Formats,
+    pub connection: Ipv4Address,
+    pub rtpmaps: Vec<RtpMap<'a>, MaxFormats>,
+    pub fmtps: Vec<Fmtp<'a>, MaxFmtps>,
+    pub ptime: Option<u32>,
+    pub direction: Direction,
+}
+
+impl<'a> Media<'a> {
+    pub fn is_rtp_audio(&self) -> bool {
+        self.media == "audio" && self.proto == "RTP/AVP"
+    }
+
+    pub fn rtpmap(&self, payload_type: u8) -> Option<&RtpMap<'a>> {
+        self.rtpmaps.iter().find(|m| m.payload_type == payload_type)
+    }
+
+    pub fn fmtp(&self, payload_type: u8) -> Option<&'a str> {
+        self.fmtps
+            .iter()
+            .find(|f| f.payload_type == payload_type)
+            .map(|f| f.params)
+    }
+
+    /// Codec of a payload type, from its rtpmap or the static assignment
+    pub fn codec(&self, payload_type: u8) -> Option<Codec> {
+        match self.rtpmap(payload_type) {
+            Some(map) => Codec::from_rtpmap(map),
+            None => Codec::from_static(payload_type),
+        }
+    }
+
+    /// Payload type for `codec` in this media
+    pub fn payload_type(&self, codec: Codec) -> Option<u8> {
+        self.formats
+            .iter()
+            .cloned()
+            .find(|pt| self.codec(*pt) == Some(codec))
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SessionDescription<'a> {
+    pub origin: Origin<'a>,
+    pub session_name: &'a str,
+    pub media: Vec<Media<'a>, MaxMedia>,
+}
+
+impl<'a> SessionDescription<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, Error> {
+        let mut origin = None;
+        let mut session_name = "";
+        let mut session_connection = None;
+        let mut session_ptime = None;
+        let mut session_direction = None;
+        let mut version = false;
+        let mut media: Vec<(Media<'a>, bool, bool), MaxMedia> = Vec::new();
+
+        for line in s.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let bytes = line.as_bytes();
+            if bytes.len() < 2 || bytes[1] != b'=' {
+                return Err(Error::InvalidLine);
+            }
+            let value = &line[2..];
+
+            match (bytes[0], media.last_mut()) {
+                (b'v', _) => {
+                    if value != "0" {
+                        return Err(Error::InvalidVersion);
+                    }
+                    version = true;
+                }
+                (b'o', None) => origin = Some(parse_origin(value)?),
+                (b's', None) => session_name = value,
+                (b'c', None) => session_connection = Some(parse_connection(value)?),
+                (b'c', Some((m, connection, _))) => {
+                    m.connection = parse_connection(value)?;
+                    *connection = true;
+                }
+                (b'm', _) => media
+                    .push((parse_media(value)?, false, false))
+                    .map_err(|_| Error::TooManyMedia)?,
+                (b'a', None) => match parse_attribute(value)? {
+                    Attribute::Direction(d) => session_direction = Some(d),
+                    Attribute::Ptime(p) => session_ptime = Some(p),
+                    _ => (),
+                },
+                (b'a', Some((m, _, direction))) => match parse_attribute(value)? {
+                    Attribute::RtpMap(map) => {
+                        m.rtpmaps.push(map).map_err(|_| Error::TooManyFormats)?
+                    }
+                    Attribute::Fmtp(fmtp) => {
+                        m.fmtps.push(fmtp).map_err(|_| Error::TooManyFormats)?
+                    }
+                    Attribute::Ptime(p) => m.ptime = Some(p),
+                    Attribute::Direction(d) => {
+                        m.direction = d;
+                        *direction = true;
+                    }
+                    Attribute::Other => (),
+                },
+                // Bandwidth, timing, etc aren't used
+                _ => (),
+            }
+        }
+
+        if !version {
+            return Err(Error::InvalidVersion);
+        }
+        let mut sdp = SessionDescription {
+            origin: origin.ok_or(Error::InvalidOrigin)?,
+            session_name,
+            media: Vec::new(),
+        };
+        for (mut m, connection, direction) in media.into_iter() {
+            if !connection {
+                m.connection = session_connection.ok_or(Error::MissingConnection)?;
+            }
+            if !direction {
+                m.direction = session_direction.unwrap_or_default();
+            }
+            if m.ptime.is_none() {
+                m.ptime = session_ptime;
+            }
+            // Can't overflow, same capacity
+            sdp.media.push(m).unwrap();
+        }
+        Ok(sdp)
+    }
+
+    /// First audio stream that isn't disabled, and its index
+    pub fn audio(&self) -> Option<(usize, &Media<'a>)> {
+        self.media
+            .iter()
+            .enumerate()
+            .find(|(_, m)| m.is_rtp_audio() && m.port != 0)
+    }
+}
+
+enum Attribute<'a> {
+    RtpMap(RtpMap<'a>),
+    Fmtp(Fmtp<'a>),
+    Ptime(u32),
+    Direction(Direction),
+    Other,
+}
+
+fn parse_origin(s: &str) -> Result<Origin<'_>, Error> {
+    let mut fields = s.split_whitespace();
+    let mut next = || fields.next().ok_or(Error::InvalidOrigin);
+    let username = next()?;
+    let session_id = u64::from_str(next()?).map_err(|_| Error::InvalidOrigin)?;
+    let session_version = u64::from_str(next()?).map_err(|_| Error::InvalidOrigin)?;
+    let _net_type = next()?;
+    let _addr_type = next()?;
+    let address = next()?;
+    Ok(Origin {
+        username,
+        session_id,
+        session_version,
+        address,
+    })
+}
+
+fn parse_connection(s: &str) -> Result<Ipv4Address, Error> {
+    let mut fields = s.split_whitespace();
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some("IN"), Some("IP4"), Some(addr)) => {
+            // Multicast addresses carry a /ttl
+            let addr = addr.split('/').next().unwrap_or(addr);
+            Ipv4Address::from_str(addr).map_err(|_| Error::InvalidConnection)
+        }
+        (Some("IN"), Some(_), Some(_)) => Err(Error::UnsupportedAddress),
+        _ => Err(Error::InvalidConnection),
+    }
+}
+
+fn parse_media(s: &str) -> Result<Media<'_>, Error> {
+    let mut fields = s.split_whitespace();
+    let media = fields.next().ok_or(Error::InvalidMedia)?;
+    let port = fields.next().ok_or(Error::InvalidMedia)?;
+    // Ignore the optional /<number of ports>
+    let port = port.split('/').next().unwrap_or(port);
+    let port = u16::from_str(port).map_err(|_| Error::InvalidMedia)?;
+    let proto = fields.next().ok_or(Error::InvalidMedia)?;
+
+    let mut formats = Formats::new();
+    if proto.starts_with("RTP/") {
+        for f in fields {
+            let pt = u8::from_str(f).map_err(|_| Error::InvalidMedia)?;
+            formats.push(pt).map_err(|_| Error::TooManyFormats)?;
+        }
+    }
+
+    Ok(Media {
+        media,
+        port,
+        proto,
+        formats,
+        connection: Ipv4Address::UNSPECIFIED,
+        rtpmaps: Vec::new(),
+        fmtps: Vec::new(),
+        ptime: None,
+        direction: Direction::default(),
+    })
+}
+
+fn parse_attribute(s: &str) -> Result<Attribute<'_>, Error> {
+    let (name, value) = match s.find(':') {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    match (name, value) {
+        ("rtpmap", Some(v)) => {
+            let (pt, v) = payload_type(v)?;
+            let mut parts = v.split('/');
+            let encoding = parts.next().ok_or(Error::InvalidAttribute)?.trim();
+            let clock_rate = parts
+                .next()
+                .and_then(|r| u32::from_str(r.trim()).ok())
+                .ok_or(Error::InvalidAttribute)?;
+            let channels = match parts.next() {
+                Some(c) => Some(u8::from_str(c.trim()).map_err(|_| Error::InvalidAttribute)?),
+                None => None,
+            };
+            Ok(Attribute::RtpMap(RtpMap {
+                payload_type: pt,
+                encoding,
+                clock_rate,
+                channels,
+            }))
+        }
+        ("fmtp", Some(v)) => {
+            let (pt, params) = payload_type(v)?;
+            Ok(Attribute::Fmtp(Fmtp {
+                payload_type: pt,
+                params,
+            }))
+        }
+        ("ptime", Some(v)) => u32::from_str(v.trim())
+            .map(Attribute::Ptime)
+            .map_err(|_| Error::InvalidAttribute),
+        (name, None) => Ok(Direction::parse(name)
+            .map(Attribute::Direction)
+            .unwrap_or(Attribute::Other)),
+        _ => Ok(Attribute::Other),
+    }
+}
+
+/// Splits `<payload type> <rest>`
+fn payload_type(s: &str) -> Result<(u8, &str), Error> {
+    let s = s.trim();
+    let (pt, rest) = match s.find(' ') {
+        Some(i) => (&s[..i], s[i + 1..].trim()),
+        None => (s, ""),
+    };
+    let pt = u8::from_str(pt).map_err(|_| Error::InvalidAttribute)?;
+    Ok((pt, rest))
+}
+
+/// Our side of the media session
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct LocalMedia<'a> {
+    /// RTP address and port, RTCP uses the next port
+    pub addr: Ipv4Address,
+    pub port: u16,
+    /// Codecs in order of preference, telephone-event is offered when included
+    pub codecs: &'a [Codec],
+    pub ptime: u32,
+}
+
+impl<'a> LocalMedia<'a> {
+    fn supports(&self, codec: Codec) -> bool {
+        self.codecs.contains(&codec)
+    }
+}
+
+/// Result of an offer/answer exchange
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Negotiated {
+    /// Where to send RTP, unspecified address when on hold (RFC 2543 style)
+    pub remote: IpEndpoint,
+    pub codec: Codec,
+    pub payload_type: u8,
+    /// Payload type for RFC 4733 events, if both sides support them
+    pub telephone_event: Option<u8>,
+    /// Packetization time to send with
+    pub ptime: u32,
+    /// Our direction
+    pub direction: Direction,
+}
+
+impl Negotiated {
+    /// Answerer side: picks the first offered codec we support, keeping the
+    /// offer's payload type numbers (RFC 3264 section 6.1)
+    pub fn from_offer(offer: &SessionDescription, local: &LocalMedia) -> Result<Self, Error> {
+        let (_, media) = offer.audio().ok_or(Error::NoAudio)?;
+        Self::select(media, local, media.direction.reverse())
+    }
+
+    /// Offerer side: takes the codec from the answer to our offer
+    pub fn from_answer(answer: &SessionDescription, local: &LocalMedia) -> Result<Self, Error> {
+        let (_, media) = answer.audio().ok_or(Error::NoAudio)?;
+        Self::select(media, local, media.direction.reverse())
+    }
+
+    fn select(media: &Media, local: &LocalMedia, direction: Direction) -> Result<Self, Error> {
+        let (payload_type, codec) = media
+            .formats
+            .iter()
+            .filter_map(|pt| media.codec(*pt).map(|c| (*pt, c)))
+            .find(|(_, c)| c.is_audio() && local.supports(*c))
+            .ok_or(Error::NoCommonCodec)?;
+        let telephone_event = if local.supports(Codec::TelephoneEvent) {
+            media.payload_type(Codec::TelephoneEvent)
+        } else {
+            None
+        };
+        Ok(Negotiated {
+            remote: IpEndpoint::new(IpAddress::Ipv4(media.connection), media.port),
+            codec,
+            payload_type,
+            telephone_event,
+            ptime: media.ptime.unwrap_or(DEFAULT_PTIME),
+            direction,
+        })
+    }
+
+    /// The remote put us on hold, there's nothing to send
+    pub fn is_on_hold(&self) -> bool {
+        !self.direction.sends() || self.remote.addr == IpAddress::Ipv4(Ipv4Address::UNSPECIFIED)
+    }
+}
+
+/// One `m=` line of a description we send
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct MediaLine<'a> {
+    media: &'a str,
+    port: u16,
+    proto: &'a str,
+    formats: Vec<(u8, Codec), MaxFormats>,
+    /// Formats of a rejected stream, copied from the offer
+    rejected: Option<u8>,
+    ptime: u32,
+    direction: Direction,
+}
+
+/// Session description we send, an offer or an answer
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Description<'a> {
+    session_id: u64,
+    session_version: u64,
+    addr: Ipv4Address,
+    media: Vec<MediaLine<'a>, MaxMedia>,
+}
+
+impl<'a> Description<'a> {
+    /// Offer with every local codec, `session_version` must increase
+    /// with each new description in the session
+    pub fn offer(
+        local: &LocalMedia,
+        session_id: u64,
+        session_version: u64,
+        direction: Direction,
+    ) -> Self {
+        let mut formats: Vec<(u8, Codec), MaxFormats> = Vec::new();
+        for c in local.codecs.iter().take(formats.capacity()) {
+            formats.push((c.payload_type(), *c)).unwrap();
+        }
+        let mut media = Vec::new();
+        media
+            .push(MediaLine {
+                media: "audio",
+                port: local.port,
+                proto: "RTP/AVP",
+                formats,
+                rejected: None,
+                ptime: local.ptime,
+                direction,
+            })
+            .unwrap();
+        Description {
+            session_id,
+            session_version,
+            addr: local.addr,
+            media,
+        }
+    }
+
+    /// Answer to `offer` that accepts the `negotiated` audio stream and
+    /// rejects every other stream
+    pub fn answer(
+        offer: &SessionDescription<'a>,
+        local: &LocalMedia,
+        negotiated: &Negotiated,
+        session_id: u64,
+        session_version: u64,
+    ) -> Self {
+        let accepted = offer.audio().map(|(i, _)| i);
+        let mut media = Vec::new();
+        for (i, m) in offer.media.iter().enumerate() {
+            let line = if Some(i) == accepted {
+                let mut formats = Vec::new();
+                formats
+                    .push((negotiated.payload_type, negotiated.codec))
+                    .unwrap();
+                if let Some(pt) = negotiated.telephone_event {
+                    formats.push((pt, Codec::TelephoneEvent)).unwrap();
+                }
+                MediaLine {
+                    media: m.media,
+                    port: local.port,
+                    proto: m.proto,
+                    formats,
+                    rejected: None,
+                    ptime: local.ptime,
+                    direction: negotiated.direction,
+                }
+            } else {
+                MediaLine {
+                    media: m.media,
+                    port: 0,
+                    proto: m.proto,
+                    formats: Vec::new(),
+                    rejected: Some(m.formats.first().cloned().unwrap_or(0)),
+                    ptime: local.ptime,
+                    direction: Direction::Inactive,
+                }
+            };
+            // Can't overflow, same capacity
+            media.push(line).unwrap();
+        }
+        Description {
+            session_id,
+            session_version,
+            addr: local.addr,
+            media,
+        }
+    }
+
+    /// Renders into a message body
+    pub fn to_body(&self) -> Result<Body, Error> {
+        let mut body = Body::new();
+        write!(body, "{}", self).map_err(|_| Error::BufferFull)?;
+        Ok(body)
+    }
+}
+
+impl<'a> fmt::Display for Description<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v=0\r\n")?;
+        write!(
+            f,
+            "o=- {} {} IN IP4 {}\r\n",
+            self.session_id, self.session_version, self.addr
+        )?;
+        write!(f, "s=-\r\n")?;
+        write!(f, "c=IN IP4 {}\r\n", self.addr)?;
+        write!(f, "t=0 0\r\n")?;
+        for m in self.media.iter() {
+            write!(f, "m={} {} {}", m.media, m.port, m.proto)?;
+            if let Some(pt) = m.rejected {
+                write!(f, " {}\r\n", pt)?;
+                continue;
+            }
+            for (pt, _) in m.formats.iter() {
+                write!(f, " {}", pt)?;
+            }
+            write!(f, "\r\n")?;
+            for (pt, codec) in m.formats.iter() {
+                write!(
+                    f,
+                    "a=rtpmap:{} {}/{}\r\n",
+                    pt,
+                    codec.encoding_name(),
+                    codec.clock_rate()
+                )?;
+                if *codec == Codec::TelephoneEvent {
+                    write!(f, "a=fmtp:{} 0-16\r\n", pt)?;
+                }
+            }
+            write!(f, "a=ptime:{}\r\n", m.ptime)?;
+            write!(f, "a={}\r\n", m.direction)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+o=alice 2890844526 2890844526 IN IP4 host.atlanta.example.com\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.50\r\n\
+t=0 0\r\n\
+m=audio 49170 RTP/AVP 18 8 0 101\r\n\
+a=rtpmap:18 G729/8000\r\n\
+a=rtpmap:8 PCMA/8000\r\n\
+a=rtpmap:101 telephone-event/8000\r\n\
+a=fmtp:101 0-15\r\n\
+a=ptime:30\r\n\
+m=video 51372 RTP/AVP 31\r\n\
+c=IN IP4 192.168.1.51\r\n\
+a=recvonly\r\n";
+
+    const LOCAL_CODECS: [Codec; 3] = [Codec::Pcmu, Codec::Pcma, Codec::TelephoneEvent];
+
+    fn local() -> LocalMedia<'static> {
+        LocalMedia {
+            addr: Ipv4Address::new(192, 168, 1, 39),
+            port: 10000,
+            codecs: &LOCAL_CODECS,
+            ptime: 20,
+        }
+    }
+
+    #[test]
+    fn parse_offer() {
+        let sdp = SessionDescription::parse(OFFER).unwrap();
+        assert_eq!(sdp.origin.username, "alice");
+        assert_eq!(sdp.origin.session_id, 2890844526);
+        assert_eq!(sdp.origin.address, "host.atlanta.example.com");
+        assert_eq!(sdp.session_name, "-");
+        assert_eq!(sdp.media.len(), 2);
+
+        let audio = &sdp.media[0];
+        assert_eq!(audio.is_rtp_audio(), true);
+        assert_eq!(audio.port, 49170);
+        assert_eq!(&audio.formats[..], &[18, 8, 0, 101]);
+        assert_eq!(audio.connection, Ipv4Address::new(192, 168, 1, 50));
+        assert_eq!(audio.codec(18), None);
+        assert_eq!(audio.codec(8), Some(Codec::Pcma));
+        // Static payload type without an rtpmap
+        assert_eq!(audio.codec(0), Some(Codec::Pcmu));
+        assert_eq!(audio.codec(101), Some(Codec::TelephoneEvent));
+        assert_eq!(audio.fmtp(101), Some("0-15"));
+        assert_eq!(audio.ptime, Some(30));
+        assert_eq!(audio.direction, Direction::SendRecv);
+
+        let video = &sdp.media[1];
+        assert_eq!(video.is_rtp_audio(), false);
+        assert_eq!(video.connection, Ipv4Address::new(192, 168, 1, 51));
+        assert_eq!(video.direction, Direction::RecvOnly);
+        assert_eq!(sdp.audio().map(|(i, _)| i), Some(0));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            SessionDescription::parse("o=- 1 1 IN IP4 a\r\n"),
+            Err(Error::InvalidVersion)
+        );
+        assert_eq!(
+            SessionDescription::parse("v=0\r\ns=-\r\n"),
+            Err(Error::InvalidOrigin)
+        );
+        assert_eq!(
+            SessionDescription::parse("v=0\r\no=- 1 1 IN IP4 a\r\nm=audio 4000 RTP/AVP 0\r\n"),
+            Err(Error::MissingConnection)
+        );
+        assert_eq!(
+            SessionDescription::parse("v=0\r\no=- 1 1 IN IP6 ::1\r\nc=IN IP6 ::1\r\n"),
+            Err(Error::UnsupportedAddress)
+        );
+        assert_eq!(
+            SessionDescription::parse("v=0\r\nbogus\r\n"),
+            Err(Error::InvalidLine)
+        );
+        assert_eq!(
+            SessionDescription::parse(
+                "v=0\r\no=- 1 1 IN IP4 a\r\nc=IN IP4 1.2.3.4\r\nm=audio x RTP/AVP 0\r\n"
+            ),
+            Err(Error::InvalidMedia)
+        );
+    }
+
+    #[test]
+    fn answer_offer() {
+        let offer = SessionDescription::parse(OFFER).unwrap();
+        let n = Negotiated::from_offer(&offer, &local()).unwrap();
+        // Offerer's preference, first one we support
+        assert_eq!(n.codec, Codec::Pcma);
+        assert_eq!(n.payload_type, 8);
+        assert_eq!(n.telephone_event, Some(101));
+        assert_eq!(n.ptime, 30);
+        assert_eq!(n.direction, Direction::SendRecv);
+        assert_eq!(
+            n.remote,
+            IpEndpoint::new(IpAddress::v4(192, 168, 1, 50), 49170)
+        );
+        assert_eq!(n.is_on_hold(), false);
+
+        let body = Description::answer(&offer, &local(), &n, 7, 1)
+            .to_body()
+            .unwrap();
+        assert_eq!(
+            body.as_str(),
+            "v=0\r\n\
+o=- 7 1 IN IP4 192.168.1.39\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.39\r\n\
+t=0 0\r\n\
+m=audio 10000 RTP/AVP 8 101\r\n\
+a=rtpmap:8 PCMA/8000\r\n\
+a=rtpmap:101 telephone-event/8000\r\n\
+a=fmtp:101 0-16\r\n\
+a=ptime:20\r\n\
+a=sendrecv\r\n\
+m=video 0 RTP/AVP 31\r\n"
+        );
+
+        // Answer parses back to the same selection
+        let answer = SessionDescription::parse(body.as_str()).unwrap();
+        assert_eq!(answer.media.len(), offer.media.len());
+        let n = Negotiated::from_answer(&answer, &local()).unwrap();
+        assert_eq!(n.codec, Codec::Pcma);
+        assert_eq!(n.telephone_event, Some(101));
+        assert_eq!(
+            n.remote,
+            IpEndpoint::new(IpAddress::v4(192, 168, 1, 39), 10000)
+        );
+    }
+
+    #[test]
+    fn offer_round_trip() {
+        let body = Description::offer(&local(), 42, 1, Direction::SendRecv)
+            .to_body()
+            .unwrap();
+        let offer = SessionDescription::parse(body.as_str()).unwrap();
+        assert_eq!(offer.origin.session_id, 42);
+        let (_, audio) = offer.audio().unwrap();
+        assert_eq!(&audio.formats[..], &[0, 8, DTMF_PAYLOAD_TYPE]);
+        assert_eq!(audio.fmtp(DTMF_PAYLOAD_TYPE), Some("0-16"));
+        assert_eq!(audio.ptime, Some(20));
+        assert_eq!(audio.port, 10000);
+
+        // Remote answers with our second choice and a dynamic event type
+        let answer = "v=0\r\n\
+o=bob 1 1 IN IP4 192.168.1.50\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.50\r\n\
+t=0 0\r\n\
+m=audio 20000 RTP/AVP 8 96\r\n\
+a=rtpmap:96 TELEPHONE-EVENT/8000\r\n";
+        let answer = SessionDescription::parse(answer).unwrap();
+        let n = Negotiated::from_answer(&answer, &local()).unwrap();
+        assert_eq!(n.codec, Codec::Pcma);
+        assert_eq!(n.telephone_event, Some(96));
+        assert_eq!(n.ptime, DEFAULT_PTIME);
+    }
+
+    #[test]
+    fn no_common_codec() {
+        let offer = "v=0\r\n\
+o=- 1 1 IN IP4 192.168.1.50\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.50\r\n\
+t=0 0\r\n\
+m=audio 49170 RTP/AVP 18 101\r\n\
+a=rtpmap:18 G729/8000\r\n\
+a=rtpmap:101 telephone-event/8000\r\n";
+        let offer = SessionDescription::parse(offer).unwrap();
+        assert_eq!(
+            Negotiated::from_offer(&offer, &local()),
+            Err(Error::NoCommonCodec)
+        );
+
+        // Only PCMU configured, no events
+        let pcmu = [Codec::Pcmu];
+        let local = LocalMedia {
+            codecs: &pcmu,
+            ..local()
+        };
+        let offer = SessionDescription::parse(OFFER).unwrap();
+        let n = Negotiated::from_offer(&offer, &local).unwrap();
+        assert_eq!(n.codec, Codec::Pcmu);
+        assert_eq!(n.payload_type, 0);
+        assert_eq!(n.telephone_event, None);
+
+        let video_only = "v=0\r\n\
+o=- 1 1 IN IP4 192.168.1.50\r\n\
+s=-\r\n\
+c=IN IP4 192.168.1.50\r\n\
+t=0 0\r\n\
+m=video 51372 RTP/AVP 31\r\n";
+        let offer = SessionDescription::parse(video_only).unwrap();
+        assert_eq!(Negotiated::from_offer(&offer, &local), Err(Error::NoAudio));
+    }
+
+    #[test]
+    fn hold() {
+        for (attr, direction) in [
+            ("a=sendonly", Direction::RecvOnly),
+            ("a=inactive", Direction::Inactive),
+        ]
+        .iter()
+        {
+            let mut offer: String<U512> = String::new();
+            write!(
+                offer,
+                "v=0\r\no=- 1 2 IN IP4 h\r\ns=-\r\nc=IN IP4 192.168.1.50\r\nt=0 0\r\n{}\r\nm=audio 4000 RTP/AVP 0\r\n",
+                attr
+            )
+            .unwrap();
+            let offer = SessionDescription::parse(offer.as_str()).unwrap();
+            let n = Negotiated::from_offer(&offer, &local()).unwrap();
+            assert_eq!(n.direction, *direction);
+            assert_eq!(n.is_on_hold(), true);
+        }
+
+        // RFC 2543 style
+        let offer = "v=0\r\no=- 1 2 IN IP4 h\r\ns=-\r\nc=IN IP4 0.0.0.0\r\nt=0 0\r\nm=audio 4000 RTP/AVP 0\r\n";
+        let offer = SessionDescription::parse(offer).unwrap();
+        let n = Negotiated::from_offer(&offer, &local()).unwrap();
+        assert_eq!(n.direction, Direction::SendRecv);
+        assert_eq!(n.is_on_hold(), true);
+    }
+}
@@ -0,0 +1,1144 @@
+//! SIP transactions (RFC 3261 section 17) over UDP
+//!
+//! INVITE/non-INVITE client and server transactions, matched by the
+//! top Via branch, with the retransmission and timeout timers driven
+//! by `time::Instant`. Messages are handed in as bytes and copied
+//! into a fixed buffer per transaction for retransmission.
+//!
+//! INVITE 2xx handling follows RFC 6026: the client transaction stays
+//! in Accepted to pass retransmitted 2xx responses up, and the server
+//! transaction retransmits its 2xx until the ACK arrives.
+
+use crate::sip::builder::{self, Builder, MessageBuffer, MAX_FORWARDS};
+use crate::sip::message::{self, Message, Method};
+use crate::time::{Duration, Instant};
+use core::cmp;
+use heapless::consts::{U128, U64, U8};
+use heapless::{String, Vec};
+use smoltcp::wire::IpEndpoint;
+
+/// RTT estimate
+pub const T1: Duration = Duration::from_millis(500);
+/// Maximum retransmit interval for non-INVITE requests and INVITE responses
+pub const T2: Duration = Duration::from_secs(4);
+/// Maximum duration a message will remain in the network
+pub const T4: Duration = Duration::from_secs(5);
+
+/// INVITE client transaction timeout
+pub const TIMER_B: Duration = Duration::from_secs(32);
+/// Wait time for response retransmits in the INVITE client transaction
+pub const TIMER_D: Duration = Duration::from_secs(32);
+/// Non-INVITE client transaction timeout
+pub const TIMER_F: Duration = Duration::from_secs(32);
+/// Wait time for ACK receipt in the INVITE server transaction
+pub const TIMER_H: Duration = Duration::from_secs(32);
+/// Wait time for ACK retransmits
+pub const TIMER_I: Duration = T4;
+/// Wait time for non-INVITE request retransmits
+pub const TIMER_J: Duration = Duration::from_secs(32);
+/// Wait time for non-INVITE response retransmits
+pub const TIMER_K: Duration = T4;
+/// Wait time for the ACK of a 2xx (RFC 6026)
+pub const TIMER_L: Duration = Duration::from_secs(32);
+/// Wait time for 2xx retransmits (RFC 6026)
+pub const TIMER_M: Duration = Duration::from_secs(32);
+
+pub type MaxTransactions = U8;
+
+pub type Branch = String<U64>;
+pub type CallId = String<U128>;
+
+/// Transactions that timed out during a `Table::poll`
+pub type Timeouts = Vec<TransactionId, MaxTransactions>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Error {
+    Parse(message::Error),
+    Build(builder::Error),
+    /// Expected a request (that isn't an ACK)
+    NotARequest,
+    NotAResponse,
+    /// Top Via has no branch parameter
+    MissingBranch,
+    /// Message or identifier doesn't fit in its storage
+    TooLarge,
+    TableFull,
+    UnknownTransaction,
+    /// Operation isn't valid for the transaction's kind or state
+    InvalidState(State),
+}
+
+impl From<message::Error> for Error {
+    fn from(e: message::Error) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<builder::Error> for Error {
+    fn from(e: builder::Error) -> Self {
+        Error::Build(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Kind {
+    InviteClient,
+    NonInviteClient,
+    InviteServer,
+    NonInviteServer,
+}
+
+impl Kind {
+    pub fn is_client(&self) -> bool {
+        match self {
+            Kind::InviteClient | Kind::NonInviteClient => true,
+            Kind::InviteServer | Kind::NonInviteServer => false,
+        }
+    }
+
+    pub fn is_invite(&self) -> bool {
+        match self {
+            Kind::InviteClient | Kind::InviteServer => true,
+            Kind::NonInviteClient | Kind::NonInviteServer => false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum State {
+    /// INVITE client, waiting for a response
+    Calling,
+    /// Non-INVITE, no response sent/received yet
+    Trying,
+    Proceeding,
+    /// INVITE 2xx sent/received (RFC 6026)
+    Accepted,
+    Completed,
+    /// INVITE server, ACK received
+    Confirmed,
+    Terminated,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TransactionId(u32);
+
+/// What `Table::receive` did with a message
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Received {
+    /// New request, a server transaction was created for the TU to respond on
+    Request(TransactionId),
+    /// Response for a client transaction, the TU should act on it
+    Response(TransactionId),
+    /// ACK for a 2xx sent by an INVITE server transaction
+    Ack(TransactionId),
+    /// Retransmission, handled by the transaction
+    Absorbed,
+    /// Belongs to no transaction, i.e. a stray response or an ACK
+    /// the TU has to deal with
+    Unmatched,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    kind: Kind,
+    state: State,
+    branch: Branch,
+    /// INVITE for an ACK
+    method: Method,
+    call_id: CallId,
+    cseq: u32,
+    /// Where requests (client) or responses (server) are sent
+    remote: IpEndpoint,
+    /// Request (client) or last response (server) to (re)transmit
+    buffer: MessageBuffer,
+    transmit: bool,
+    status_code: Option<u16>,
+    interval: Duration,
+    retransmit_at: Option<Instant>,
+    deadline: Option<Instant>,
+}
+
+impl Transaction {
+    /// New client transaction for `request`, it's sent on the next poll
+    pub fn client(request: &[u8], remote: IpEndpoint, time: Instant) -> Result<Self, Error> {
+        let msg = Message::parse(request)?;
+        let (kind, state, timeout) = match msg.method() {
+            None | Some(Method::Ack) => return Err(Error::NotARequest),
+            Some(Method::Invite) => (Kind::InviteClient, State::Calling, TIMER_B),
+            Some(_) => (Kind::NonInviteClient, State::Trying, TIMER_F),
+        };
+        let mut t = Self::new(kind, state, &msg, remote)?;
+        t.buffer
+            .extend_from_slice(request)
+            .map_err(|_| Error::TooLarge)?;
+        t.transmit = true;
+        t.interval = T1;
+        t.retransmit_at = Some(time + T1);
+        t.deadline = Some(time + timeout);
+        Ok(t)
+    }
+
+    /// New server transaction for `request` received from `source`.
+    /// Responses are sent back to `source` (RFC 3581 symmetric response routing).
+    pub fn server(request: &Message, source: IpEndpoint) -> Result<Self, Error> {
+        match request.method() {
+            None | Some(Method::Ack) => Err(Error::NotARequest),
+            Some(Method::Invite) => {
+                Self::new(Kind::InviteServer, State::Proceeding, request, source)
+            }
+            Some(_) => Self::new(Kind::NonInviteServer, State::Trying, request, source),
+        }
+    }
+
+    fn new(kind: Kind, state: State, msg: &Message, remote: IpEndpoint) -> Result<Self, Error> {
+        let via = msg.via()?;
+        let mut branch = Branch::new();
+        branch
+            .push_str(via.branch().ok_or(Error::MissingBranch)?)
+            .map_err(|_| Error::TooLarge)?;
+        let mut call_id = CallId::new();
+        call_id
+            .push_str(msg.call_id()?)
+            .map_err(|_| Error::TooLarge)?;
+        let cseq = msg.cseq()?;
+        Ok(Transaction {
+            kind,
+            state,
+            branch,
+            method: cseq.method,
+            call_id,
+            cseq: cseq.seq,
+            remote,
+            buffer: MessageBuffer::new(),
+            transmit: false,
+            status_code: None,
+            interval: T1,
+            retransmit_at: None,
+            deadline: None,
+        })
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn branch(&self) -> &str {
+        self.branch.as_str()
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn call_id(&self) -> &str {
+        self.call_id.as_str()
+    }
+
+    pub fn cseq(&self) -> u32 {
+        self.cseq
+    }
+
+    pub fn remote(&self) -> IpEndpoint {
+        self.remote
+    }
+
+    /// Last final or provisional status code sent (server) or received (client)
+    pub fn status_code(&self) -> Option<u16> {
+        self.status_code
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.state == State::Terminated
+    }
+
+    /// Response belongs to this client transaction (RFC 3261 17.1.3)
+    pub fn matches_response(&self, msg: &Message) -> bool {
+        if !self.kind.is_client() {
+            return false;
+        }
+        let branch = match msg.via() {
+            Ok(via) => via.branch() == Some(self.branch.as_str()),
+            Err(_) => false,
+        };
+        let method = match msg.cseq() {
+            Ok(cseq) => cseq.method == self.method,
+            Err(_) => false,
+        };
+        branch && method
+    }
+
+    /// Request belongs to this server transaction (RFC 3261 17.2.3),
+    /// ACKs match the INVITE transaction they acknowledge
+    pub fn matches_request(&self, msg: &Message) -> bool {
+        if self.kind.is_client() {
+            return false;
+        }
+        let branch = match msg.via() {
+            Ok(via) => via.branch() == Some(self.branch.as_str()),
+            Err(_) => false,
+        };
+        let method = match msg.method() {
+            Some(Method::Ack) => self.method == Method::Invite,
+            Some(m) => m == self.method,
+            None => false,
+        };
+        branch && method
+    }
+
+    /// ACK for a 2xx sent by this INVITE server transaction, it has
+    /// its own branch so is matched on the Call-ID and CSeq
+    pub fn matches_ack(&self, msg: &Message) -> bool {
+        if self.kind != Kind::InviteServer || msg.method() != Some(Method::Ack) {
+            return false;
+        }
+        let cseq = match msg.cseq() {
+            Ok(cseq) => cseq.seq == self.cseq,
+            Err(_) => false,
+        };
+        cseq && msg.call_id() == Ok(self.call_id.as_str())
+    }
+
+    /// Handles a matching response, returns true if it should be passed to the TU
+    pub fn on_response(&mut self, msg: &Message, time: Instant) -> Result<bool, Error> {
+        let code = msg.status_code().ok_or(Error::NotAResponse)?;
+        let up = match (self.kind, self.state) {
+            (Kind::InviteClient, State::Calling) | (Kind::InviteClient, State::Proceeding) => {
+                self.status_code = Some(code);
+                self.retransmit_at = None;
+                if code < 200 {
+                    // Timer B only runs in Calling, the TU cancels from here on
+                    self.state = State::Proceeding;
+                    self.deadline = None;
+                } else if code < 300 {
+                    self.state = State::Accepted;
+                    self.deadline = Some(time + TIMER_M);
+                } else {
+                    self.state = State::Completed;
+                    self.deadline = Some(time + TIMER_D);
+                    self.build_ack(msg)?;
+                    self.transmit = true;
+                }
+                true
+            }
+            // Retransmitted 2xx, the TU re-sends its ACK
+            (Kind::InviteClient, State::Accepted) => (200..300).contains(&code),
+            (Kind::InviteClient, State::Completed) => {
+                if code >= 300 {
+                    self.transmit = true;
+                }
+                false
+            }
+            (Kind::NonInviteClient, State::Trying) | (Kind::NonInviteClient, State::Proceeding) => {
+                self.status_code = Some(code);
+                if code < 200 {
+                    self.state = State::Proceeding;
+                } else {
+                    self.state = State::Completed;
+                    self.retransmit_at = None;
+                    self.deadline = Some(time + TIMER_K);
+                }
+                true
+            }
+            (Kind::InviteClient, _) | (Kind::NonInviteClient, _) => false,
+            (_, state) => return Err(Error::InvalidState(state)),
+        };
+        Ok(up)
+    }
+
+    /// Handles a matching request retransmission or ACK, returns true
+    /// for the ACK of a 2xx which the TU should know about
+    pub fn on_request(&mut self, msg: &Message, time: Instant) -> Result<bool, Error> {
+        let ack = match msg.method() {
+            Some(Method::Ack) => true,
+            Some(_) => false,
+            None => return Err(Error::NotARequest),
+        };
+        match (self.kind, self.state, ack) {
+            (Kind::InviteServer, State::Completed, true)
+            | (Kind::InviteServer, State::Accepted, true) => {
+                let up = self.state == State::Accepted;
+                self.state = State::Confirmed;
+                self.retransmit_at = None;
+                self.deadline = Some(time + TIMER_I);
+                Ok(up)
+            }
+            (Kind::InviteServer, State::Proceeding, false)
+            | (Kind::InviteServer, State::Completed, false)
+            | (Kind::NonInviteServer, State::Proceeding, false)
+            | (Kind::NonInviteServer, State::Completed, false) => {
+                // Resend the last response
+                self.transmit = !self.buffer.is_empty();
+                Ok(false)
+            }
+            (Kind::InviteServer, _, _) | (Kind::NonInviteServer, _, _) => Ok(false),
+            (_, state, _) => Err(Error::InvalidState(state)),
+        }
+    }
+
+    /// Sends `response` on this server transaction
+    pub fn respond(&mut self, response: &[u8], time: Instant) -> Result<(), Error> {
+        let msg = Message::parse(response)?;
+        let code = msg.status_code().ok_or(Error::NotAResponse)?;
+        match (self.kind, self.state) {
+            (Kind::InviteServer, State::Proceeding)
+            | (Kind::NonInviteServer, State::Trying)
+            | (Kind::NonInviteServer, State::Proceeding) => (),
+            (_, state) => return Err(Error::InvalidState(state)),
+        }
+
+        self.buffer.clear();
+        self.buffer
+            .extend_from_slice(response)
+            .map_err(|_| Error::TooLarge)?;
+        self.transmit = true;
+        self.status_code = Some(code);
+
+        if code < 200 {
+            if self.kind == Kind::NonInviteServer {
+                self.state = State::Proceeding;
+            }
+        } else if self.kind == Kind::NonInviteServer {
+            self.state = State::Completed;
+            self.deadline = Some(time + TIMER_J);
+        } else {
+            // Timer G for non-2xx, 2xx retransmits until the ACK (RFC 3261 13.3.1.4)
+            self.state = if code < 300 {
+                State::Accepted
+            } else {
+                State::Completed
+            };
+            self.interval = T1;
+            self.retransmit_at = Some(time + T1);
+            self.deadline = Some(time + if code < 300 { TIMER_L } else { TIMER_H });
+        }
+
+        Ok(())
+    }
+
+    /// Runs the timers and (re)transmits through `transmit`, which returns
+    /// false if the message couldn't be sent and should be tried again.
+    /// Returns true if the transaction timed out, the TU should be told.
+    pub fn poll<F>(&mut self, time: Instant, transmit: &mut F) -> bool
+    where
+        F: FnMut(&[u8], IpEndpoint) -> bool,
+    {
+        if self.state == State::Terminated {
+            return false;
+        }
+
+        match self.deadline {
+            Some(deadline) if time >= deadline => {
+                let timed_out = match (self.kind, self.state) {
+                    // Timer B, F
+                    (Kind::InviteClient, State::Calling)
+                    | (Kind::NonInviteClient, State::Trying)
+                    | (Kind::NonInviteClient, State::Proceeding) => true,
+                    // Timer H, L without an ACK
+                    (Kind::InviteServer, State::Completed)
+                    | (Kind::InviteServer, State::Accepted) => true,
+                    _ => false,
+                };
+                self.state = State::Terminated;
+                self.transmit = false;
+                return timed_out;
+            }
+            _ => (),
+        }
+
+        match self.retransmit_at {
+            Some(at) if time >= at => {
+                // Timer A doubles without bound, E and G are capped at T2
+                // and E is reset to T2 once proceeding
+                self.interval = match (self.kind, self.state) {
+                    (Kind::InviteClient, _) => self.interval * 2,
+                    (Kind::NonInviteClient, State::Proceeding) => T2,
+                    _ => cmp::min(self.interval * 2, T2),
+                };
+                self.retransmit_at = Some(time + self.interval);
+                self.transmit = true;
+            }
+            _ => (),
+        }
+
+        if self.transmit && !self.buffer.is_empty() && transmit(&self.buffer, self.remote) {
+            self.transmit = false;
+        }
+
+        false
+    }
+
+    /// Replaces the INVITE in the buffer with the ACK for a non-2xx
+    /// final response (RFC 3261 17.1.1.3)
+    fn build_ack(&mut self, response: &Message) -> Result<(), Error> {
+        let mut ack = MessageBuffer::new();
+        {
+            let invite = Message::parse(&self.buffer)?;
+            let uri = invite.request_line().ok_or(Error::NotARequest)?.uri;
+            let via = invite
+                .header_values("Via")
+                .next()
+                .ok_or(message::Error::MissingHeader)?;
+            let from = invite.header("From").ok_or(message::Error::MissingHeader)?;
+            let to = response.header("To").ok_or(message::Error::MissingHeader)?;
+
+            let mut b = Builder::request(&mut ack, Method::Ack, uri)?;
+            b.header("Via", via)?.max_forwards(MAX_FORWARDS)?;
+            for route in invite.headers.iter().filter(|h| h.is("Route")) {
+                b.header("Route", route.value)?;
+            }
+            b.header("From", from)?
+                .header("To", to)?
+                .call_id(self.call_id.as_str())?
+                .cseq(self.cseq, Method::Ack)?;
+            b.finish(&[])?;
+        }
+        self.buffer = ack;
+        Ok(())
+    }
+}
+
+/// Bounded set of transactions
+#[derive(Debug, Clone)]
+pub struct Table {
+    transactions: Vec<(TransactionId, Transaction), MaxTransactions>,
+    next_id: u32,
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Table::new()
+    }
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Table {
+            transactions: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn get(&self, id: TransactionId) -> Option<&Transaction> {
+        self.transactions
+            .iter()
+            .find(|(i, _)| *i == id)
+            .map(|(_, t)| t)
+    }
+
+    /// Starts a client transaction for `request`
+    pub fn send_request(
+        &mut self,
+        request: &[u8],
+        remote: IpEndpoint,
+        time: Instant,
+    ) -> Result<TransactionId, Error> {
+        let t = Transaction::client(request, remote, time)?;
+        self.insert(t)
+    }
+
+    /// Sends `response` on server transaction `id`
+    pub fn respond(
+        &mut self,
+        id: TransactionId,
+        response: &[u8],
+        time: Instant,
+    ) -> Result<(), Error> {
+        self.get_mut(id)
+            .ok_or(Error::UnknownTransaction)?
+            .respond(response, time)
+    }
+
+    /// Drops a transaction, i.e. the TU gave up on it
+    pub fn remove(&mut self, id: TransactionId) -> Option<Transaction> {
+        let idx = self.transactions.iter().position(|(i, _)| *i == id)?;
+        Some(self.transactions.swap_remove(idx).1)
+    }
+
+    /// Dispatches a received message to its transaction, creating a
+    /// server transaction for new requests
+    pub fn receive(
+        &mut self,
+        msg: &Message,
+        source: IpEndpoint,
+        time: Instant,
+    ) -> Result<Received, Error> {
+        if msg.is_request() {
+            if let Some((id, t)) = self
+                .transactions
+                .iter_mut()
+                .find(|(_, t)| t.matches_request(msg))
+            {
+                return if t.on_request(msg, time)? {
+                    Ok(Received::Ack(*id))
+                } else {
+                    Ok(Received::Absorbed)
+                };
+            }
+            if msg.method() == Some(Method::Ack) {
+                return match self
+                    .transactions
+                    .iter_mut()
+                    .find(|(_, t)| t.matches_ack(msg))
+                {
+                    Some((id, t)) => {
+                        if t.on_request(msg, time)? {
+                            Ok(Received::Ack(*id))
+                        } else {
+                            Ok(Received::Absorbed)
+                        }
+                    }
+                    None => Ok(Received::Unmatched),
+                };
+            }
+            let t = Transaction::server(msg, source)?;
+            self.insert(t).map(Received::Request)
+        } else {
+            match self
+                .transactions
+                .iter_mut()
+                .find(|(_, t)| t.matches_response(msg))
+            {
+                Some((id, t)) => {
+                    if t.on_response(msg, time)? {
+                        Ok(Received::Response(*id))
+                    } else {
+                        Ok(Received::Absorbed)
+                    }
+                }
+                None => Ok(Received::Unmatched),
+            }
+        }
+    }
+
+    /// Runs every transaction's timers and transmissions, removing the
+    /// terminated ones. Returns the transactions that timed out.
+    pub fn poll<F>(&mut self, time: Instant, mut transmit: F) -> Timeouts
+    where
+        F: FnMut(&[u8], IpEndpoint) -> bool,
+    {
+        let mut timeouts = Timeouts::new();
+        for (id, t) in self.transactions.iter_mut() {
+            if t.poll(time, &mut transmit) {
+                // Can't overflow, same capacity as the table
+                timeouts.push(*id).ok();
+            }
+        }
+        let mut idx = 0;
+        while idx < self.transactions.len() {
+            if self.transactions[idx].1.is_terminated() {
+                self.transactions.swap_remove(idx);
+            } else {
+                idx += 1;
+            }
+        }
+        timeouts
+    }
+
+    fn get_mut(&mut self, id: TransactionId) -> Option<&mut Transaction> {
+        self.transactions
+            .iter_mut()
+            .find(|(i, _)| *i == id)
+            .map(|(_, t)| t)
+    }
+
+    fn insert(&mut self, t: Transaction) -> Result<TransactionId, Error> {
+        let id = TransactionId(self.next_id);
+        self.transactions
+            .push((id, t))
+            .map_err(|_| Error::TableFull)?;
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::debug;
+    use smoltcp::wire::IpAddress;
+
+    const BRANCH: &str = "z9hG4bK776asdhds";
+
+    fn ms(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn remote() -> IpEndpoint {
+        IpEndpoint::new(IpAddress::v4(192, 168, 1, 2), 5060)
+    }
+
+    fn request(method: Method, branch: &str, seq: u32) -> MessageBuffer {
+        let mut buf = MessageBuffer::new();
+        let mut b = Builder::request(&mut buf, method, "sip:bob@biloxi.com").unwrap();
+        b.via("UDP", "pc33.atlanta.com", 5060, branch)
+            .unwrap()
+            .max_forwards(MAX_FORWARDS)
+            .unwrap()
+            .header("Route", "<sip:proxy.atlanta.com;lr>")
+            .unwrap()
+            .from(Some("Alice"), "sip:alice@atlanta.com", Some("1928301774"))
+            .unwrap()
+            .to(Some("Bob"), "sip:bob@biloxi.com", None)
+            .unwrap()
+            .call_id("a84b4c76e66710@pc33.atlanta.com")
+            .unwrap()
+            .cseq(seq, method)
+            .unwrap();
+        b.finish(&[]).unwrap();
+        buf
+    }
+
+    fn response(req: &[u8], code: u16) -> MessageBuffer {
+        let req = Message::parse(req).unwrap();
+        let mut buf = MessageBuffer::new();
+        Builder::response_to(&mut buf, &req, code, "Reason", Some("a6c85cf"))
+            .unwrap()
+            .finish(&[])
+            .unwrap();
+        buf
+    }
+
+    /// Records what a poll transmits
+    #[derive(Default)]
+    struct Sent {
+        count: usize,
+        last: MessageBuffer,
+    }
+
+    impl Sent {
+        fn poll(&mut self, table: &mut Table, time: Instant) -> Timeouts {
+            table.poll(time, |data, to| {
+                assert_eq!(to, remote());
+                debug!("Sent {} bytes at {:?}", data.len(), time);
+                self.count += 1;
+                self.last.clear();
+                self.last.extend_from_slice(data).unwrap();
+                true
+            })
+        }
+
+        /// Polls every 10 ms up to `end`
+        fn run(&mut self, table: &mut Table, time: &mut Instant, end: Instant) -> Timeouts {
+            let mut timeouts = Timeouts::new();
+            while *time < end {
+                for id in self.poll(table, *time).iter() {
+                    timeouts.push(*id).unwrap();
+                }
+                *time += Duration::from_millis(10);
+            }
+            timeouts
+        }
+    }
+
+    fn receive(table: &mut Table, data: &[u8], time: Instant) -> Received {
+        let msg = Message::parse(data).unwrap();
+        table.receive(&msg, remote(), time).unwrap()
+    }
+
+    #[test]
+    fn non_invite_client_retransmits_then_times_out() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let req = request(Method::Options, BRANCH, 1);
+        let id = table.send_request(&req, remote(), time).unwrap();
+        assert_eq!(table.get(id).unwrap().kind(), Kind::NonInviteClient);
+        assert_eq!(table.get(id).unwrap().state(), State::Trying);
+
+        // Timer E: 0, 0.5, 1.5, 3.5, 7.5, then every T2
+        let timeouts = sent.run(&mut table, &mut time, ms(8000));
+        assert_eq!(timeouts.len(), 0);
+        assert_eq!(sent.count, 5);
+        assert_eq!(&sent.last[..], &req[..]);
+
+        // Timer F
+        let timeouts = sent.run(&mut table, &mut time, ms(32000));
+        assert_eq!(sent.count, 11);
+        assert_eq!(timeouts.len(), 0);
+        let timeouts = sent.run(&mut table, &mut time, ms(32020));
+        assert_eq!(&timeouts[..], &[id]);
+        assert_eq!(sent.count, 11);
+        assert_eq!(table.is_empty(), true);
+    }
+
+    #[test]
+    fn non_invite_client_provisional_and_final() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let req = request(Method::Register, BRANCH, 1);
+        let id = table.send_request(&req, remote(), time).unwrap();
+        sent.run(&mut table, &mut time, ms(100));
+        assert_eq!(sent.count, 1);
+
+        let trying = response(&req, 100);
+        assert_eq!(receive(&mut table, &trying, time), Received::Response(id));
+        assert_eq!(table.get(id).unwrap().state(), State::Proceeding);
+
+        // Retransmits continue at T2 while proceeding
+        sent.run(&mut table, &mut time, ms(10_000));
+        assert_eq!(sent.count, 4);
+
+        let ok = response(&req, 200);
+        assert_eq!(receive(&mut table, &ok, time), Received::Response(id));
+        assert_eq!(table.get(id).unwrap().state(), State::Completed);
+        assert_eq!(table.get(id).unwrap().status_code(), Some(200));
+        // Retransmitted final response is absorbed
+        assert_eq!(receive(&mut table, &ok, time), Received::Absorbed);
+
+        // Timer K
+        let end = time + TIMER_K + Duration::from_millis(20);
+        let timeouts = sent.run(&mut table, &mut time, end);
+        assert_eq!(timeouts.len(), 0);
+        assert_eq!(sent.count, 4);
+        assert_eq!(table.is_empty(), true);
+    }
+
+    #[test]
+    fn invite_client_timer_a_doubles() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let req = request(Method::Invite, BRANCH, 1);
+        let id = table.send_request(&req, remote(), time).unwrap();
+        assert_eq!(table.get(id).unwrap().state(), State::Calling);
+
+        // 0, 0.5, 1.5, 3.5, 7.5, 15.5, 31.5
+        let timeouts = sent.run(&mut table, &mut time, ms(32020));
+        assert_eq!(sent.count, 7);
+        assert_eq!(&timeouts[..], &[id]);
+        assert_eq!(table.is_empty(), true);
+    }
+
+    #[test]
+    fn invite_client_provisional_stops_timers() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let req = request(Method::Invite, BRANCH, 1);
+        let id = table.send_request(&req, remote(), time).unwrap();
+        sent.run(&mut table, &mut time, ms(100));
+
+        let ringing = response(&req, 180);
+        assert_eq!(receive(&mut table, &ringing, time), Received::Response(id));
+        assert_eq!(table.get(id).unwrap().state(), State::Proceeding);
+
+        // No retransmits or Timer B, the TU cancels when it wants to
+        let timeouts = sent.run(&mut table, &mut time, ms(60_000));
+        assert_eq!(timeouts.len(), 0);
+        assert_eq!(sent.count, 1);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn invite_client_acks_non_2xx() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let req = request(Method::Invite, BRANCH, 7);
+        let id = table.send_request(&req, remote(), time).unwrap();
+        sent.run(&mut table, &mut time, ms(100));
+
+        let busy = response(&req, 486);
+        assert_eq!(receive(&mut table, &busy, time), Received::Response(id));
+        assert_eq!(table.get(id).unwrap().state(), State::Completed);
+        sent.run(&mut table, &mut time, ms(200));
+        assert_eq!(sent.count, 2);
+
+        let last = sent.last.clone();
+        let ack = Message::parse(&last).unwrap();
+        assert_eq!(ack.method(), Some(Method::Ack));
+        let uri = ack.request_line().unwrap().uri;
+        assert_eq!((uri.user, uri.host), (Some("bob"), "biloxi.com"));
+        assert_eq!(ack.via().unwrap().branch(), Some(BRANCH));
+        assert_eq!(ack.header("Route"), Some("<sip:proxy.atlanta.com;lr>"));
+        assert_eq!(ack.from().unwrap().tag(), Some("1928301774"));
+        assert_eq!(ack.to().unwrap().tag(), Some("a6c85cf"));
+        assert_eq!(ack.call_id(), Ok("a84b4c76e66710@pc33.atlanta.com"));
+        let cseq = ack.cseq().unwrap();
+        assert_eq!((cseq.seq, cseq.method), (7, Method::Ack));
+
+        // Retransmitted response gets the ACK again, but isn't passed up
+        assert_eq!(receive(&mut table, &busy, time), Received::Absorbed);
+        sent.run(&mut table, &mut time, ms(300));
+        assert_eq!(sent.count, 3);
+
+        // Timer D
+        let timeouts = sent.run(&mut table, &mut time, ms(32_400));
+        assert_eq!(timeouts.len(), 0);
+        assert_eq!(sent.count, 3);
+        assert_eq!(table.is_empty(), true);
+    }
+
+    #[test]
+    fn invite_client_accepted_passes_2xx() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let req = request(Method::Invite, BRANCH, 1);
+        let id = table.send_request(&req, remote(), time).unwrap();
+        sent.run(&mut table, &mut time, ms(100));
+
+        let ok = response(&req, 200);
+        assert_eq!(receive(&mut table, &ok, time), Received::Response(id));
+        assert_eq!(table.get(id).unwrap().state(), State::Accepted);
+        assert_eq!(receive(&mut table, &ok, time), Received::Response(id));
+
+        // ACK for a 2xx is the TU's job
+        let timeouts = sent.run(&mut table, &mut time, ms(32_200));
+        assert_eq!(timeouts.len(), 0);
+        assert_eq!(sent.count, 1);
+        assert_eq!(table.is_empty(), true);
+        assert_eq!(receive(&mut table, &ok, time), Received::Unmatched);
+    }
+
+    #[test]
+    fn invite_server_non_2xx() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let invite = request(Method::Invite, BRANCH, 1);
+        let id = match receive(&mut table, &invite, time) {
+            Received::Request(id) => id,
+            r => panic!("Expected a new request {:?}", r),
+        };
+        assert_eq!(table.get(id).unwrap().kind(), Kind::InviteServer);
+        assert_eq!(table.get(id).unwrap().state(), State::Proceeding);
+
+        // Nothing to resend yet
+        assert_eq!(receive(&mut table, &invite, time), Received::Absorbed);
+        sent.run(&mut table, &mut time, ms(100));
+        assert_eq!(sent.count, 0);
+
+        table.respond(id, &response(&invite, 180), time).unwrap();
+        sent.run(&mut table, &mut time, ms(200));
+        assert_eq!(sent.count, 1);
+        assert_eq!(receive(&mut table, &invite, time), Received::Absorbed);
+        sent.run(&mut table, &mut time, ms(300));
+        assert_eq!(sent.count, 2);
+        assert_eq!(Message::parse(&sent.last).unwrap().status_code(), Some(180));
+
+        // Timer G: 0, 0.5, 1.5
+        table.respond(id, &response(&invite, 486), time).unwrap();
+        assert_eq!(table.get(id).unwrap().state(), State::Completed);
+        let end = time + Duration::from_millis(2000);
+        sent.run(&mut table, &mut time, end);
+        assert_eq!(sent.count, 5);
+        assert_eq!(Message::parse(&sent.last).unwrap().status_code(), Some(486));
+
+        // Can't respond twice
+        assert_eq!(
+            table.respond(id, &response(&invite, 200), time),
+            Err(Error::InvalidState(State::Completed))
+        );
+
+        let ack = request(Method::Ack, BRANCH, 1);
+        assert_eq!(receive(&mut table, &ack, time), Received::Absorbed);
+        assert_eq!(table.get(id).unwrap().state(), State::Confirmed);
+
+        // Timer I
+        let end = time + TIMER_I + Duration::from_millis(20);
+        let timeouts = sent.run(&mut table, &mut time, end);
+        assert_eq!(timeouts.len(), 0);
+        assert_eq!(sent.count, 5);
+        assert_eq!(table.is_empty(), true);
+    }
+
+    #[test]
+    fn invite_server_non_2xx_without_ack() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let invite = request(Method::Invite, BRANCH, 1);
+        let id = match receive(&mut table, &invite, time) {
+            Received::Request(id) => id,
+            r => panic!("Expected a new request {:?}", r),
+        };
+        table.respond(id, &response(&invite, 603), time).unwrap();
+
+        // Timer H
+        let timeouts = sent.run(&mut table, &mut time, ms(32_020));
+        assert_eq!(&timeouts[..], &[id]);
+        assert_eq!(table.is_empty(), true);
+    }
+
+    #[test]
+    fn invite_server_2xx_until_ack() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let invite = request(Method::Invite, BRANCH, 1);
+        let id = match receive(&mut table, &invite, time) {
+            Received::Request(id) => id,
+            r => panic!("Expected a new request {:?}", r),
+        };
+        table.respond(id, &response(&invite, 200), time).unwrap();
+        assert_eq!(table.get(id).unwrap().state(), State::Accepted);
+
+        // 0, 0.5, 1.5, 3.5, 7.5
+        sent.run(&mut table, &mut time, ms(8000));
+        assert_eq!(sent.count, 5);
+
+        // Retransmitted INVITE is absorbed
+        assert_eq!(receive(&mut table, &invite, time), Received::Absorbed);
+
+        // The ACK for a 2xx has its own branch
+        let ack = request(Method::Ack, "z9hG4bKnashds8", 1);
+        assert_eq!(receive(&mut table, &ack, time), Received::Ack(id));
+        assert_eq!(table.get(id).unwrap().state(), State::Confirmed);
+        let timeouts = sent.run(&mut table, &mut time, ms(40_000));
+        assert_eq!(timeouts.len(), 0);
+        assert_eq!(sent.count, 5);
+        assert_eq!(table.is_empty(), true);
+
+        // Late ACK retransmissions go to the TU
+        assert_eq!(receive(&mut table, &ack, time), Received::Unmatched);
+    }
+
+    #[test]
+    fn invite_server_2xx_without_ack() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let invite = request(Method::Invite, BRANCH, 1);
+        let id = match receive(&mut table, &invite, time) {
+            Received::Request(id) => id,
+            r => panic!("Expected a new request {:?}", r),
+        };
+        table.respond(id, &response(&invite, 200), time).unwrap();
+
+        // Timer L
+        let timeouts = sent.run(&mut table, &mut time, ms(32_020));
+        assert_eq!(&timeouts[..], &[id]);
+        assert_eq!(sent.count, 11);
+    }
+
+    #[test]
+    fn non_invite_server() {
+        let mut table = Table::new();
+        let mut sent = Sent::default();
+        let mut time = ms(0);
+        let bye = request(Method::Bye, BRANCH, 2);
+        let id = match receive(&mut table, &bye, time) {
+            Received::Request(id) => id,
+            r => panic!("Expected a new request {:?}", r),
+        };
+        assert_eq!(table.get(id).unwrap().kind(), Kind::NonInviteServer);
+        assert_eq!(table.get(id).unwrap().state(), State::Trying);
+
+        table.respond(id, &response(&bye, 200), time).unwrap();
+        assert_eq!(table.get(id).unwrap().state(), State::Completed);
+        sent.run(&mut table, &mut time, ms(100));
+        assert_eq!(sent.count, 1);
+
+        // Responses aren't retransmitted on their own, only for requests
+        sent.run(&mut table, &mut time, ms(10_000));
+        assert_eq!(sent.count, 1);
+        assert_eq!(receive(&mut table, &bye, time), Received::Absorbed);
+        sent.run(&mut table, &mut time, ms(10_100));
+        assert_eq!(sent.count, 2);
+
+        // Timer J
+        let timeouts = sent.run(&mut table, &mut time, ms(32_100));
+        assert_eq!(timeouts.len(), 0);
+        assert_eq!(table.is_empty(), true);
+    }
+
+    #[test]
+    fn cancel_is_its_own_transaction() {
+        let mut table = Table::new();
+        let time = ms(0);
+        let invite = request(Method::Invite, BRANCH, 1);
+        let cancel = request(Method::Cancel, BRANCH, 1);
+        let invite_id = match receive(&mut table, &invite, time) {
+            Received::Request(id) => id,
+            r => panic!("Expected a new request {:?}", r),
+        };
+        let cancel_id = match receive(&mut table, &cancel, time) {
+            Received::Request(id) => id,
+            r => panic!("Expected a new request {:?}", r),
+        };
+        assert_ne!(invite_id, cancel_id);
+        assert_eq!(table.get(cancel_id).unwrap().method(), Method::Cancel);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn unmatched_messages() {
+        let mut table = Table::new();
+        let time = ms(0);
+        let req = request(Method::Options, BRANCH, 1);
+        table.send_request(&req, remote(), time).unwrap();
+
+        // Different branch, different method
+        let other = request(Method::Options, "z9hG4bKother", 1);
+        assert_eq!(
+            receive(&mut table, &response(&other, 200), time),
+            Received::Unmatched
+        );
+        let other = request(Method::Info, BRANCH, 1);
+        assert_eq!(
+            receive(&mut table, &response(&other, 200), time),
+            Received::Unmatched
+        );
+        let ack = request(Method::Ack, "z9hG4bKack", 1);
+        assert_eq!(receive(&mut table, &ack, time), Received::Unmatched);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn invalid_use() {
+        let mut table = Table::new();
+        let time = ms(0);
+        let ack = request(Method::Ack, BRANCH, 1);
+        assert_eq!(
+            table.send_request(&ack, remote(), time),
+            Err(Error::NotARequest)
+        );
+
+        let req = request(Method::Options, BRANCH, 1);
+        let id = table.send_request(&req, remote(), time).unwrap();
+        assert_eq!(
+            table.respond(id, &response(&req, 200), time),
+            Err(Error::InvalidState(State::Trying))
+        );
+        assert_eq!(table.remove(id).is_some(), true);
+        assert_eq!(
+            table.respond(id, &response(&req, 200), time),
+            Err(Error::UnknownTransaction)
+        );
+    }
+
+    #[test]
+    fn bounded_table() {
+        let mut table = Table::new();
+        let time = ms(0);
+        let mut branch = Branch::new();
+        for i in 0..9 {
+            branch.clear();
+            core::fmt::Write::write_fmt(&mut branch, format_args!("{}{}", BRANCH, i)).unwrap();
+            let req = request(Method::Options, branch.as_str(), i);
+            let res = table.send_request(&req, remote(), time);
+            if i < 8 {
+                assert_eq!(res.is_ok(), true);
+            } else {
+                assert_eq!(res, Err(Error::TableFull));
+            }
+        }
+        let invite = request(Method::Invite, BRANCH, 1);
+        let msg = Message::parse(&invite).unwrap();
+        assert_eq!(table.receive(&msg, remote(), time), Err(Error::TableFull));
+    }
+}
@@ -0,0 +1,369 @@
+//! SIP user agent
+//!
+//! Multiplexes the single SIP socket between the registration, the
+//! transaction layer and the call. The REGISTER Contact points at this
+//! socket, so it's where requests for our calls arrive.
+
+use crate::net::socket_pool::UdpHandle;
+use crate::phone_state::{PhoneCommand, PhoneEvent};
+use crate::random::{RandomSource, XorShift32};
+use crate::sip::dialog::{self, Call, CallState};
+use crate::sip::message::Message;
+use crate::sip::registration::{Config, Registration, Status};
+use crate::sip::sdp::{LocalMedia, Negotiated};
+use crate::sip::transaction::{Received, Table};
+use crate::time::Instant;
+use heapless::consts::U4;
+use heapless::Vec;
+use log::{debug, warn};
+use smoltcp::socket::{SocketSet, UdpSocket};
+use smoltcp::wire::{IpEndpoint, Ipv4Address};
+
+pub type PhoneEvents = Vec<PhoneEvent, U4>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    Socket(smoltcp::Error),
+}
+
+impl From<smoltcp::Error> for Error {
+    fn from(e: smoltcp::Error) -> Self {
+        Error::Socket(e)
+    }
+}
+
+pub struct UserAgent<'a> {
+    handle: UdpHandle,
+    local_port: u16,
+    registration: Registration<'a, XorShift32>,
+    transactions: Table,
+    call: Call<'a, XorShift32>,
+    status: Status,
+}
+
+impl<'a> UserAgent<'a> {
+    pub fn new<R: RandomSource>(
+        config: Config<'a>,
+        media: LocalMedia<'a>,
+        handle: UdpHandle,
+        rng: &mut R,
+    ) -> Self {
+        let registration = Registration::new(config, handle, XorShift32::new(rng.next_u32()));
+        UserAgent {
+            handle,
+            local_port: config.local_port,
+            status: registration.status(),
+            registration,
+            transactions: Table::new(),
+            call: Call::new(config, media, XorShift32::new(rng.next_u32())),
+        }
+    }
+
+    pub fn socket_handle(&self) -> UdpHandle {
+        self.handle
+    }
+
+    pub fn registration(&self) -> Status {
+        self.registration.status()
+    }
+
+    pub fn call(&self) -> CallState {
+        self.call.state()
+    }
+
+    /// Negotiated RTP session of the established call
+    pub fn media(&self) -> Option<&Negotiated> {
+        self.call.media()
+    }
+
+    /// Starts registering
+    pub fn start(&mut self, time: Instant) {
+        self.registration.start(time);
+    }
+
+    /// Address changed (i.e. new DHCP lease), re-registers
+    pub fn set_local_addr(&mut self, addr: Ipv4Address, time: Instant) {
+        self.registration.set_local_addr(addr, time);
+        self.call.set_local_addr(addr);
+    }
+
+    /// Registrar resolved to a different address, re-registers
+    pub fn set_registrar(&mut self, registrar: IpEndpoint, time: Instant) {
+        self.registration.set_registrar(registrar, time);
+        self.call.set_registrar(registrar);
+    }
+
+    /// Carries out a command from the phone state machine, the
+    /// resulting requests/responses go out on the next `poll`. Returns
+    /// an event for the phone state machine if the command failed it.
+    pub fn handle_command(
+        &mut self,
+        cmd: PhoneCommand,
+        time: Instant,
+    ) -> Result<Option<PhoneEvent>, dialog::Error> {
+        self.call.handle_command(&mut self.transactions, cmd, time)
+    }
+
+    /// Receives and dispatches SIP messages, runs the timers and
+    /// transmits. Returns the events for the phone state machine.
+    pub fn poll(&mut self, sockets: &mut SocketSet, time: Instant) -> Result<PhoneEvents, Error> {
+        let mut socket = sockets.get::<UdpSocket>(self.handle.into());
+        if !socket.is_open() {
+            socket.bind(self.local_port)?;
+        }
+
+        let mut events = PhoneEvents::new();
+        let status = self.registration.status();
+        if status != self.status {
+            self.status = status;
+            // Can't overflow, events is empty
+            events.push(PhoneEvent::Registration(status)).unwrap();
+        }
+
+        // Leave anything else queued once there's no room for its event
+        while events.len() < events.capacity() {
+            let (data, from) = match socket.recv() {
+                Ok(r) => r,
+                Err(_) => break,
+            };
+            let msg = match Message::parse(data) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Bad SIP message from {}: {:?}", from, e);
+                    continue;
+                }
+            };
+            if self.registration.handle_response(&msg, time) {
+                continue;
+            }
+
+            let result = match self.transactions.receive(&msg, from, time) {
+                Ok(Received::Request(id)) => {
+                    self.call
+                        .handle_request(&mut self.transactions, id, data, time)
+                }
+                Ok(Received::Response(id)) => {
+                    self.call
+                        .handle_response(&mut self.transactions, id, &msg, time)
+                }
+                Ok(Received::Ack(id)) => self
+                    .call
+                    .handle_ack(&mut self.transactions, id, time)
+                    .map(|_| None),
+                Ok(Received::Absorbed) => Ok(None),
+                Ok(Received::Unmatched) => {
+                    debug!("Ignoring SIP message from {}", from);
+                    Ok(None)
+                }
+                Err(e) => {
+                    warn!("SIP transaction error {:?}", e);
+                    Ok(None)
+                }
+            };
+            match result {
+                // Can't overflow, checked by the loop
+                Ok(Some(event)) => events.push(event).unwrap(),
+                Ok(None) => (),
+                Err(e) => warn!("SIP call error {:?}", e),
+            }
+        }
+
+        let mut transmit =
+            |data: &[u8], remote: IpEndpoint| socket.send_slice(data, remote).is_ok();
+        self.registration.poll_transmit(time, &mut transmit);
+        self.call.poll_transmit(&mut transmit);
+        let timeouts = self.transactions.poll(time, &mut transmit);
+
+        for id in timeouts.iter() {
+            match self.call.handle_timeout(&mut self.transactions, *id, time) {
+                Ok(Some(event)) => {
+                    if let Err(event) = events.push(event) {
+                        warn!("Dropped SIP event {:?}", event);
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => warn!("SIP call error {:?}", e),
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::loopback::Loopback;
+    use crate::phone_number::PhoneNumber;
+    use crate::sip::builder::{Builder, MessageBuffer};
+    use crate::sip::message::Method;
+    use crate::sip::sdp::{self, Codec};
+    use crate::time::Duration;
+    use smoltcp::iface::EthernetInterface;
+    use smoltcp::socket::SocketHandle;
+    use smoltcp::wire::{IpAddress, IpCidr};
+
+    const PROXY_PORT: u16 = 5060;
+    const LOCAL_PORT: u16 = 5070;
+    const STEP: Duration = Duration::from_millis(10);
+
+    const INVITE: &[u8] = b"INVITE sip:1001@127.0.0.1:5070 SIP/2.0\r\n\
+Via: SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKpx1\r\n\
+From: <sip:5551234567@home.lan>;tag=caller1\r\n\
+To: <sip:1001@home.lan>\r\n\
+Call-ID: call1@127.0.0.1\r\n\
+CSeq: 1 INVITE\r\n\
+Contact: <sip:5551234567@127.0.0.1:5060>\r\n\
+Content-Type: application/sdp\r\n\
+Content-Length: 132\r\n\r\n\
+v=0\r\n\
+o=caller 1 1 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+c=IN IP4 127.0.0.1\r\n\
+t=0 0\r\n\
+m=audio 20000 RTP/AVP 0 101\r\n\
+a=rtpmap:101 telephone-event/8000\r\n";
+
+    fn media() -> LocalMedia<'static> {
+        LocalMedia {
+            addr: Ipv4Address::new(127, 0, 0, 1),
+            port: 10000,
+            codecs: &[Codec::Pcmu, Codec::TelephoneEvent],
+            ptime: sdp::DEFAULT_PTIME,
+        }
+    }
+
+    fn config() -> Config<'static> {
+        Config {
+            registrar: IpEndpoint::new(IpAddress::v4(127, 0, 0, 1), PROXY_PORT),
+            domain: "home.lan",
+            username: "1001",
+            password: "secret",
+            local_addr: Ipv4Address::new(127, 0, 0, 1),
+            local_port: LOCAL_PORT,
+            expires: 120,
+        }
+    }
+
+    /// Runs the interface, user agent and a proxy that accepts any REGISTER,
+    /// returns the user agent's events and what else the proxy received
+    fn run(
+        iface: &mut EthernetInterface<Loopback>,
+        sockets: &mut SocketSet,
+        ua: &mut UserAgent,
+        proxy: SocketHandle,
+        time: &mut Instant,
+        duration: Duration,
+    ) -> (Vec<PhoneEvent, U4>, Vec<MessageBuffer, U4>) {
+        let mut events = Vec::new();
+        let mut received = Vec::new();
+        let end = *time + duration;
+        while *time < end {
+            let t = smoltcp::time::Instant::from_millis(time.as_millis() as i64);
+            let _ = iface.poll(sockets, t);
+
+            for e in ua.poll(sockets, *time).unwrap() {
+                events.push(e).unwrap();
+            }
+
+            let mut socket = sockets.get::<UdpSocket>(proxy);
+            if !socket.is_open() {
+                socket.bind(PROXY_PORT).unwrap();
+            }
+            let mut response = None;
+            while let Ok((data, from)) = socket.recv() {
+                let msg = Message::parse(data).unwrap();
+                if msg.method() == Some(Method::Register) {
+                    let mut buf = MessageBuffer::new();
+                    let mut b =
+                        Builder::response_to(&mut buf, &msg, 200, "OK", Some("reg")).unwrap();
+                    b.expires(120).unwrap();
+                    b.finish(&[]).unwrap();
+                    response = Some((buf, from));
+                } else {
+                    let mut buf = MessageBuffer::new();
+                    buf.extend_from_slice(data).unwrap();
+                    received.push(buf).unwrap();
+                }
+            }
+            if let Some((r, to)) = response {
+                socket.send_slice(&r, to).unwrap();
+            }
+
+            *time += STEP;
+        }
+        (events, received)
+    }
+
+    #[test]
+    fn registration_and_incoming_call_share_socket() {
+        crate::loopback!(iface, sockets, [IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8)], 1, {
+            ua_handle: [4; 4096],
+            proxy: [4; 4096],
+        });
+        let mut ua = UserAgent::new(
+            config(),
+            media(),
+            UdpHandle::from_socket(ua_handle),
+            &mut XorShift32::new(1),
+        );
+
+        let mut time = Instant::from_secs(0);
+        ua.start(time);
+        let (events, received) = run(
+            &mut iface,
+            &mut sockets,
+            &mut ua,
+            proxy,
+            &mut time,
+            Duration::from_secs(1),
+        );
+        assert_eq!(
+            &events[..],
+            &[
+                PhoneEvent::Registration(Status::Registering),
+                PhoneEvent::Registration(Status::Registered)
+            ]
+        );
+        assert_eq!(received.len(), 0);
+
+        sockets
+            .get::<UdpSocket>(proxy)
+            .send_slice(
+                INVITE,
+                IpEndpoint::new(IpAddress::v4(127, 0, 0, 1), LOCAL_PORT),
+            )
+            .unwrap();
+        let (events, received) = run(
+            &mut iface,
+            &mut sockets,
+            &mut ua,
+            proxy,
+            &mut time,
+            Duration::from_secs(1),
+        );
+        assert_eq!(
+            &events[..],
+            &[PhoneEvent::IncomingCall(PhoneNumber::new(555, 123, 4567))]
+        );
+        assert_eq!(ua.call(), CallState::Incoming);
+        assert_eq!(received.len(), 1);
+        let ringing = Message::parse(&received[0]).unwrap();
+        assert_eq!(ringing.status_code(), Some(180));
+
+        ua.handle_command(PhoneCommand::AnswerCall, time).unwrap();
+        let (events, received) = run(
+            &mut iface,
+            &mut sockets,
+            &mut ua,
+            proxy,
+            &mut time,
+            Duration::from_millis(100),
+        );
+        assert_eq!(events.len(), 0);
+        let ok = Message::parse(&received[0]).unwrap();
+        assert_eq!(ok.status_code(), Some(200));
+        // Established once the proxy ACKs
+        assert_eq!(ua.call(), CallState::Answered);
+    }
+}
@@ -0,0 +1,421 @@
+//! In-band DTMF detection
+//!
+//! Eight Goertzel filters, one per DTMF frequency, run over blocks of
+//! 102 samples (12.75 ms). A block holds a digit when its strongest
+//! row and column tones are loud enough, within the twist limits, well
+//! clear of the other rows and columns, and carry nearly all of the
+//! block's energy. A digit needs two blocks in a row to start and three
+//! without it to end, which rejects tones shorter than about 25 ms and
+//! bridges short dropouts.
+//!
+//! Digits are reported when they end, like keypad presses.
+
+use crate::keypad::{KeypadEvent, LONGPRESS_DURATION};
+use crate::time::Duration;
+
+/// Samples per detection block
+pub const BLOCK_LEN: usize = 102;
+
+/// Duration of one block
+pub const BLOCK_DURATION: Duration = Duration::from_micros(12_750);
+
+/// Blocks with a digit before it starts
+const START_BLOCKS: usize = 2;
+/// Blocks without a digit before it ends
+const END_BLOCKS: usize = 3;
+
+/// Quietest tone accepted, about -30 dBm0
+const MIN_AMPLITUDE: f32 = 700.0;
+/// Row tone may be louder than the column tone by 8 dB
+const NORMAL_TWIST: f32 = 6.31;
+/// Column tone may be louder than the row tone by 4 dB
+const REVERSE_TWIST: f32 = 2.51;
+/// Other rows and columns must be 8 dB down
+const RELATIVE_PEAK: f32 = 6.31;
+/// The two tones must be 6 dB over everything else in the block
+const MIN_SNR: f32 = 3.98;
+
+const ROW_FREQUENCIES: [u16; 4] = [697, 770, 852, 941];
+const COLUMN_FREQUENCIES: [u16; 4] = [1209, 1336, 1477, 1633];
+
+/// 2 cos(2 pi f / 8000) of the row and column frequencies
+const ROW_COEFFICIENTS: [f32; 4] = [1.707_737_8, 1.645_281, 1.568_687, 1.478_204_6];
+const COLUMN_COEFFICIENTS: [f32; 4] = [1.164_104, 0.996_370_2, 0.798_618_4, 0.568_532_7];
+
+const DIGITS: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+/// Row and column frequencies of a digit, in Hz
+pub fn frequencies(digit: char) -> Option<(u16, u16)> {
+    for (r, row) in DIGITS.iter().enumerate() {
+        for (c, d) in row.iter().enumerate() {
+            if *d == digit {
+                return Some((ROW_FREQUENCIES[r], COLUMN_FREQUENCIES[c]));
+            }
+        }
+    }
+    None
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+struct Goertzel {
+    s1: f32,
+    s2: f32,
+}
+
+impl Goertzel {
+    fn update(&mut self, coefficient: f32, sample: f32) {
+        let s0 = sample + coefficient * self.s1 - self.s2;
+        self.s2 = self.s1;
+        self.s1 = s0;
+    }
+
+    /// Power at the filter's frequency, scaled to compare with the
+    /// block energy
+    fn power(&self, coefficient: f32) -> f32 {
+        let p = self.s1 * self.s1 + self.s2 * self.s2 - coefficient * self.s1 * self.s2;
+        p * 2.0 / BLOCK_LEN as f32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DtmfDetector {
+    rows: [Goertzel; 4],
+    columns: [Goertzel; 4],
+    energy: f32,
+    samples: usize,
+    /// Digit seen in the last `hits` blocks, not yet started
+    candidate: Option<char>,
+    hits: usize,
+    /// Digit sounding, for `held` blocks
+    digit: Option<char>,
+    held: usize,
+    misses: usize,
+    last_held: Duration,
+}
+
+impl Default for DtmfDetector {
+    fn default() -> Self {
+        DtmfDetector {
+            rows: [Goertzel::default(); 4],
+            columns: [Goertzel::default(); 4],
+            energy: 0.0,
+            samples: 0,
+            candidate: None,
+            hits: 0,
+            digit: None,
+            held: 0,
+            misses: 0,
+            last_held: Duration::from_secs(0),
+        }
+    }
+}
+
+impl DtmfDetector {
+    pub fn new() -> Self {
+        DtmfDetector::default()
+    }
+
+    /// Digit sounding now, reported once it ends
+    pub fn digit(&self) -> Option<char> {
+        self.digit
+    }
+
+    /// How long the digit of the last event sounded
+    pub fn held(&self) -> Duration {
+        self.last_held
+    }
+
+    /// Runs 8 kHz audio through the detector, calling `on_digit` for
+    /// each digit that ends
+    pub fn process<F: FnMut(KeypadEvent)>(&mut self, pcm: &[i16], mut on_digit: F) {
+        for s in pcm.iter() {
+            let x = f32::from(*s);
+            for (g, c) in self.rows.iter_mut().zip(ROW_COEFFICIENTS.iter()) {
+                g.update(*c, x);
+            }
+            for (g, c) in self.columns.iter_mut().zip(COLUMN_COEFFICIENTS.iter()) {
+                g.update(*c, x);
+            }
+            self.energy += x * x;
+            self.samples += 1;
+
+            if self.samples == BLOCK_LEN {
+                let digit = self.classify();
+                self.rows = [Goertzel::default(); 4];
+                self.columns = [Goertzel::default(); 4];
+                self.energy = 0.0;
+                self.samples = 0;
+                if let Some(event) = self.update(digit) {
+                    on_digit(event);
+                }
+            }
+        }
+    }
+
+    /// Digit in the block just finished, if any
+    fn classify(&self) -> Option<char> {
+        let mut rows = [0.0; 4];
+        for (p, (g, c)) in rows
+            .iter_mut()
+            .zip(self.rows.iter().zip(ROW_COEFFICIENTS.iter()))
+        {
+            *p = g.power(*c);
+        }
+        let mut columns = [0.0; 4];
+        for (p, (g, c)) in columns
+            .iter_mut()
+            .zip(self.columns.iter().zip(COLUMN_COEFFICIENTS.iter()))
+        {
+            *p = g.power(*c);
+        }
+        let r = strongest(&rows)?;
+        let c = strongest(&columns)?;
+        let row = rows[r];
+        let column = columns[c];
+
+        let min_power = MIN_AMPLITUDE * MIN_AMPLITUDE * BLOCK_LEN as f32 / 2.0;
+        if row < min_power || column < min_power {
+            return None;
+        }
+        if row > column * NORMAL_TWIST || column > row * REVERSE_TWIST {
+            return None;
+        }
+        let tones = row + column;
+        if tones < MIN_SNR * (self.energy - tones) {
+            return None;
+        }
+        Some(DIGITS[r][c])
+    }
+
+    fn update(&mut self, digit: Option<char>) -> Option<KeypadEvent> {
+        if let Some(current) = self.digit {
+            if digit == Some(current) {
+                self.held += 1 + self.misses;
+                self.misses = 0;
+                return None;
+            }
+            self.misses += 1;
+            if self.misses < END_BLOCKS {
+                return None;
+            }
+            self.digit = None;
+            self.last_held = BLOCK_DURATION * self.held as u32;
+            self.candidate = digit;
+            self.hits = if digit.is_some() { 1 } else { 0 };
+            return Some(if self.last_held >= LONGPRESS_DURATION {
+                KeypadEvent::LongPress(current)
+            } else {
+                KeypadEvent::KeyPress(current)
+            });
+        }
+
+        if digit.is_some() && digit == self.candidate {
+            self.hits += 1;
+        } else {
+            self.candidate = digit;
+            self.hits = if digit.is_some() { 1 } else { 0 };
+        }
+        if self.hits >= START_BLOCKS {
+            self.digit = self.candidate.take();
+            self.held = self.hits;
+            self.hits = 0;
+            self.misses = 0;
+        }
+        None
+    }
+}
+
+/// Index of the strongest power, when it's well clear of the others
+fn strongest(powers: &[f32; 4]) -> Option<usize> {
+    let mut best = 0;
+    for (i, p) in powers.iter().enumerate() {
+        if *p > powers[best] {
+            best = i;
+        }
+    }
+    for (i, p) in powers.iter().enumerate() {
+        if i != best && *p * RELATIVE_PEAK > powers[best] {
+            return None;
+        }
+    }
+    Some(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{RandomSource, XorShift32};
+    use crate::tones::{amplitude, sine};
+    use heapless::consts::U32;
+    use heapless::Vec;
+
+    const FRAME: usize = 160;
+
+    /// Signal made of tones and noise, generated a frame at a time
+    #[derive(Debug)]
+    struct Signal {
+        /// (frequency, dBm0)
+        tones: [(f32, i8); 2],
+        phases: [u32; 2],
+        noise: i32,
+        rng: XorShift32,
+    }
+
+    impl Signal {
+        fn new(tones: [(f32, i8); 2], noise: i32) -> Self {
+            Signal {
+                tones,
+                phases: [0; 2],
+                noise,
+                rng: XorShift32::new(7),
+            }
+        }
+
+        fn digit(digit: char, level: i8) -> Self {
+            let (r, c) = frequencies(digit).unwrap();
+            Signal::new([(f32::from(r), level), (f32::from(c), level)], 0)
+        }
+
+        fn fill(&mut self, pcm: &mut [i16]) {
+            for s in pcm.iter_mut() {
+                let mut v = 0;
+                for ((f, level), phase) in self.tones.iter().zip(self.phases.iter_mut()) {
+                    if *f > 0.0 {
+                        v += (sine(*phase) * amplitude(*level)) >> 15;
+                        let step = f64::from(*f) * 4_294_967_296.0 / 8000.0;
+                        *phase = phase.wrapping_add(step as u32);
+                    }
+                }
+                if self.noise > 0 {
+                    let r = (self.rng.next_u32() % (2 * self.noise as u32 + 1)) as i32;
+                    v += r - self.noise;
+                }
+                *s = v as i16;
+            }
+        }
+    }
+
+    /// Plays each (signal, milliseconds) in turn, then a silence to end
+    /// the last digit
+    fn detect(parts: &mut [(Option<Signal>, usize)]) -> Vec<(KeypadEvent, Duration), U32> {
+        let mut detector = DtmfDetector::new();
+        let mut events = Vec::new();
+        let mut pcm = [0; FRAME];
+        let mut end = (None, 200);
+        for (signal, ms) in parts.iter_mut().chain(core::iter::once(&mut end)) {
+            for _ in 0..*ms / 20 {
+                match signal {
+                    Some(s) => s.fill(&mut pcm),
+                    None => pcm = [0; FRAME],
+                }
+                let mut ended = None;
+                detector.process(&pcm, |e| ended = Some(e));
+                if let Some(e) = ended {
+                    events.push((e, detector.held())).unwrap();
+                }
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn every_digit() {
+        let mut parts: Vec<(Option<Signal>, usize), U32> = Vec::new();
+        for d in "123A456B789C*0#D".chars() {
+            parts.push((Some(Signal::digit(d, -10)), 100)).unwrap();
+            parts.push((None, 100)).unwrap();
+        }
+        let events = detect(&mut parts);
+        let digits: Vec<char, U32> = events.iter().map(|(e, _)| e.as_char()).collect();
+        assert_eq!(
+            &digits[..],
+            &"123A456B789C*0#D".chars().collect::<Vec<char, U32>>()[..]
+        );
+        for (e, held) in events.iter() {
+            assert_eq!(*e, KeypadEvent::KeyPress(e.as_char()));
+            assert!(
+                *held >= Duration::from_millis(80) && *held <= Duration::from_millis(110),
+                "{:?}",
+                held
+            );
+        }
+        assert_eq!(frequencies('x'), None);
+    }
+
+    #[test]
+    fn durations() {
+        // Too short
+        let events = detect(&mut [(Some(Signal::digit('5', -10)), 20)]);
+        assert_eq!(events.len(), 0);
+        let events = detect(&mut [(Some(Signal::digit('5', -10)), 40)]);
+        assert_eq!(events.len(), 1);
+
+        let events = detect(&mut [(Some(Signal::digit('#', -10)), 1200)]);
+        assert_eq!(&events[..1], &[(KeypadEvent::LongPress('#'), events[0].1)]);
+        assert!(events[0].1 >= Duration::from_millis(1180));
+
+        // A short dropout doesn't split the digit
+        let events = detect(&mut [
+            (Some(Signal::digit('7', -10)), 100),
+            (None, 20),
+            (Some(Signal::digit('7', -10)), 100),
+        ]);
+        assert_eq!(events.len(), 1);
+        let events = detect(&mut [
+            (Some(Signal::digit('7', -10)), 100),
+            (None, 60),
+            (Some(Signal::digit('7', -10)), 100),
+        ]);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn levels_and_twist() {
+        let tone = |row: i8, column: i8| Some(Signal::new([(697.0, row), (1209.0, column)], 0));
+        assert_eq!(detect(&mut [(tone(-25, -25), 100)]).len(), 1);
+        assert_eq!(detect(&mut [(tone(-36, -36), 100)]).len(), 0);
+        // Row louder
+        assert_eq!(detect(&mut [(tone(-10, -16), 100)]).len(), 1);
+        assert_eq!(detect(&mut [(tone(-10, -20), 100)]).len(), 0);
+        // Column louder
+        assert_eq!(detect(&mut [(tone(-13, -10), 100)]).len(), 1);
+        assert_eq!(detect(&mut [(tone(-16, -10), 100)]).len(), 0);
+        // Single tone
+        assert_eq!(detect(&mut [(tone(-10, -90), 100)]).len(), 0);
+    }
+
+    #[test]
+    fn frequency_tolerance() {
+        for deviation in [0.985, 1.015].iter() {
+            let signal = Signal::new([(852.0 * deviation, -10), (1477.0 * deviation, -10)], 0);
+            let events = detect(&mut [(Some(signal), 100)]);
+            assert_eq!(&events[..], &[(KeypadEvent::KeyPress('9'), events[0].1)]);
+        }
+    }
+
+    #[test]
+    fn noise_and_other_tones() {
+        // 20 dB SNR, about 1200 rms noise
+        let signal = Signal::new([(941.0, -10), (1336.0, -10)], 2000);
+        let events = detect(&mut [(Some(signal), 100)]);
+        assert_eq!(&events[..], &[(KeypadEvent::KeyPress('0'), events[0].1)]);
+
+        // Noise as loud as the tones
+        let signal = Signal::new([(941.0, -10), (1336.0, -10)], 9000);
+        assert_eq!(detect(&mut [(Some(signal), 100)]).len(), 0);
+
+        // Dial tone and ringback aren't digits
+        let signal = Signal::new([(350.0, -10), (440.0, -10)], 0);
+        assert_eq!(detect(&mut [(Some(signal), 500)]).len(), 0);
+        let signal = Signal::new([(440.0, -10), (480.0, -10)], 0);
+        assert_eq!(detect(&mut [(Some(signal), 500)]).len(), 0);
+        // Nor a pair two apart in the same group
+        let signal = Signal::new([(697.0, -10), (852.0, -10)], 0);
+        assert_eq!(detect(&mut [(Some(signal), 500)]).len(), 0);
+    }
+}
@@ -0,0 +1,371 @@
+//! Call-progress tones
+//!
+//! Dial tone, ringback, busy, reorder and call-waiting beeps are one
+//! or two summed sine waves, switched on and off by a cadence. The
+//! generator mixes the current tone into 8 kHz linear PCM frames,
+//! locating each sample in the cadence from the `Instant` of the frame.
+//!
+//! The DTMF detector listens for keypad digits sent in-band.
+
+pub mod dtmf;
+pub mod region;
+
+use crate::time::{Duration, Instant};
+pub use crate::tones::dtmf::DtmfDetector;
+pub use crate::tones::region::{Region, ToneSpec};
+
+pub const SAMPLE_RATE: u32 = 8000;
+
+/// Peak of a 0 dBm0 sine, 3.17 dB below G.711 mu-law full scale
+const DBM0_PEAK: i32 = 22656;
+
+/// 10^(-n/20) for n in 0..20 dB, Q15
+const ATTENUATION: [i32; 20] = [
+    32768, 29205, 26029, 23198, 20675, 18427, 16423, 14637, 13045, 11627, 10362, 9235, 8231, 7336,
+    6538, 5827, 5193, 4629, 4125, 3677,
+];
+
+/// First quarter of a 1024 point sine wave, Q15
+const QUARTER_SINE: [i16; 257] = [
+    0, 201, 402, 603, 804, 1005, 1206, 1407, 1608, 1809, 2009, 2210, 2410, 2611, 2811, 3012, 3212,
+    3412, 3612, 3811, 4011, 4210, 4410, 4609, 4808, 5007, 5205, 5404, 5602, 5800, 5998, 6195, 6393,
+    6590, 6786, 6983, 7179, 7375, 7571, 7767, 7962, 8157, 8351, 8545, 8739, 8933, 9126, 9319, 9512,
+    9704, 9896, 10087, 10278, 10469, 10659, 10849, 11039, 11228, 11417, 11605, 11793, 11980, 12167,
+    12353, 12539, 12725, 12910, 13094, 13279, 13462, 13645, 13828, 14010, 14191, 14372, 14553,
+    14732, 14912, 15090, 15269, 15446, 15623, 15800, 15976, 16151, 16325, 16499, 16673, 16846,
+    17018, 17189, 17360, 17530, 17700, 17869, 18037, 18204, 18371, 18537, 18703, 18868, 19032,
+    19195, 19357, 19519, 19680, 19841, 20000, 20159, 20317, 20475, 20631, 20787, 20942, 21096,
+    21250, 21403, 21554, 21705, 21856, 22005, 22154, 22301, 22448, 22594, 22739, 22884, 23027,
+    23170, 23311, 23452, 23592, 23731, 23870, 24007, 24143, 24279, 24413, 24547, 24680, 24811,
+    24942, 25072, 25201, 25329, 25456, 25582, 25708, 25832, 25955, 26077, 26198, 26319, 26438,
+    26556, 26674, 26790, 26905, 27019, 27133, 27245, 27356, 27466, 27575, 27683, 27790, 27896,
+    28001, 28105, 28208, 28310, 28411, 28510, 28609, 28706, 28803, 28898, 28992, 29085, 29177,
+    29268, 29358, 29447, 29534, 29621, 29706, 29791, 29874, 29956, 30037, 30117, 30195, 30273,
+    30349, 30424, 30498, 30571, 30643, 30714, 30783, 30852, 30919, 30985, 31050, 31113, 31176,
+    31237, 31297, 31356, 31414, 31470, 31526, 31580, 31633, 31685, 31736, 31785, 31833, 31880,
+    31926, 31971, 32014, 32057, 32098, 32137, 32176, 32213, 32250, 32285, 32318, 32351, 32382,
+    32412, 32441, 32469, 32495, 32521, 32545, 32567, 32589, 32609, 32628, 32646, 32663, 32678,
+    32692, 32705, 32717, 32728, 32737, 32745, 32752, 32757, 32761, 32765, 32766, 32767,
+];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Tone {
+    Dial,
+    Ringback,
+    Busy,
+    /// Reorder, or congestion
+    Reorder,
+    CallWaiting,
+}
+
+impl Tone {
+    pub fn enumerate() -> &'static [Tone] {
+        &[
+            Tone::Dial,
+            Tone::Ringback,
+            Tone::Busy,
+            Tone::Reorder,
+            Tone::CallWaiting,
+        ]
+    }
+}
+
+/// Sounds for `on`, then silent for `off`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Step {
+    pub on: Duration,
+    pub off: Duration,
+}
+
+impl Step {
+    pub const fn from_millis(on_ms: u64, off_ms: u64) -> Self {
+        Step {
+            on: Duration::from_millis(on_ms),
+            off: Duration::from_millis(off_ms),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Cadence {
+    /// No steps is a steady tone
+    pub steps: &'static [Step],
+    /// Start over after the last step, otherwise the tone ends
+    pub repeat: bool,
+}
+
+impl Cadence {
+    pub const CONTINUOUS: Cadence = Cadence {
+        steps: &[],
+        repeat: true,
+    };
+
+    /// Whether the tone sounds `elapsed` after it started, `None` once
+    /// the cadence has ended
+    pub fn is_on(&self, elapsed: Duration) -> Option<bool> {
+        let period = self
+            .steps
+            .iter()
+            .fold(0, |p, s| p + s.on.as_micros() + s.off.as_micros());
+        if period == 0 {
+            return Some(true);
+        }
+        let mut t = elapsed.as_micros();
+        if self.repeat {
+            t %= period;
+        } else if t >= period {
+            return None;
+        }
+        for step in self.steps.iter() {
+            let on = step.on.as_micros();
+            let off = step.off.as_micros();
+            if t < on {
+                return Some(true);
+            } else if t < on + off {
+                return Some(false);
+            }
+            t -= on + off;
+        }
+        // Can't get here, t is within the period
+        Some(false)
+    }
+}
+
+/// Plays one tone at a time into PCM frames
+#[derive(Debug, Clone)]
+pub struct ToneGenerator {
+    region: Region,
+    tone: Option<Tone>,
+    started: Instant,
+    phases: [u32; 2],
+}
+
+impl ToneGenerator {
+    pub fn new(region: Region) -> Self {
+        ToneGenerator {
+            region,
+            tone: None,
+            started: Instant::from_millis(0),
+            phases: [0; 2],
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Takes effect from the next frame
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    /// Tone being played, if it hasn't ended
+    pub fn tone(&self) -> Option<Tone> {
+        self.tone
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.tone.is_some()
+    }
+
+    /// Starts `tone` from the beginning of its cadence at `time`
+    pub fn start(&mut self, tone: Tone, time: Instant) {
+        self.tone = Some(tone);
+        self.started = time;
+        self.phases = [0; 2];
+    }
+
+    pub fn stop(&mut self) {
+        self.tone = None;
+    }
+
+    /// Adds the tone to `pcm`, whose first sample is at `time`. Returns
+    /// false once nothing is playing.
+    pub fn mix(&mut self, time: Instant, pcm: &mut [i16]) -> bool {
+        let tone = match self.tone {
+            Some(t) => t,
+            None => return false,
+        };
+        let spec = self.region.tone(tone);
+        let amplitude = amplitude(spec.level);
+        let elapsed = time.checked_sub(self.started).unwrap_or_default();
+        let first = elapsed.as_micros() as u64 * u64::from(SAMPLE_RATE) / 1_000_000;
+
+        for (n, s) in pcm.iter_mut().enumerate() {
+            let sample = first + n as u64;
+            let at = Duration::from_micros(sample * 1_000_000 / u64::from(SAMPLE_RATE));
+            match spec.cadence.is_on(at) {
+                None => {
+                    self.tone = None;
+                    return false;
+                }
+                // Each burst starts from zero, without a click
+                Some(false) => self.phases = [0; 2],
+                Some(true) => {
+                    let mut value = i32::from(*s);
+                    for (phase, f) in self.phases.iter_mut().zip(spec.frequencies.iter()) {
+                        value += (sine(*phase) * amplitude) >> 15;
+                        *phase = phase.wrapping_add(phase_step(*f));
+                    }
+                    *s = saturate(value);
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Peak of a sine at `level` dBm0
+fn amplitude(level: i8) -> i32 {
+    let attenuation = if level < 0 { -i32::from(level) } else { 0 } as usize;
+    let mut peak = DBM0_PEAK;
+    for _ in 0..attenuation / 20 {
+        peak /= 10;
+    }
+    (peak * ATTENUATION[attenuation % 20]) >> 15
+}
+
+/// Phase increment per sample, a full turn is 2^32
+fn phase_step(frequency: u16) -> u32 {
+    ((u64::from(frequency) << 32) / u64::from(SAMPLE_RATE)) as u32
+}
+
+fn sine(phase: u32) -> i32 {
+    let index = (phase >> 22) as usize;
+    let i = index & 0xFF;
+    let value = match index >> 8 {
+        0 => QUARTER_SINE[i],
+        1 => QUARTER_SINE[256 - i],
+        2 => -QUARTER_SINE[i],
+        _ => -QUARTER_SINE[256 - i],
+    };
+    i32::from(value)
+}
+
+fn saturate(value: i32) -> i16 {
+    if value > i32::from(i16::max_value()) {
+        i16::max_value()
+    } else if value < i32::from(i16::min_value()) {
+        i16::min_value()
+    } else {
+        value as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn zero_crossings(pcm: &[i16]) -> usize {
+        pcm.windows(2).filter(|w| (w[0] < 0) != (w[1] < 0)).count()
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(amplitude(0), DBM0_PEAK);
+        assert_eq!(amplitude(3), DBM0_PEAK);
+        assert_eq!(amplitude(-13), 5072);
+        assert_eq!(amplitude(-20), 2265);
+        assert_eq!(amplitude(-24), 1429);
+        assert_eq!(sine(0), 0);
+        assert_eq!(sine(1 << 30), 32767);
+        assert_eq!(sine(1 << 31), 0);
+        assert_eq!(sine(3 << 30), -32767);
+    }
+
+    #[test]
+    fn cadences() {
+        let busy = Region::NorthAmerica.tone(Tone::Busy).cadence;
+        assert_eq!(busy.is_on(ms(0)), Some(true));
+        assert_eq!(busy.is_on(ms(499)), Some(true));
+        assert_eq!(busy.is_on(ms(500)), Some(false));
+        assert_eq!(busy.is_on(ms(999)), Some(false));
+        assert_eq!(busy.is_on(ms(60_000)), Some(true));
+
+        let ringback = Region::UnitedKingdom.tone(Tone::Ringback).cadence;
+        assert_eq!(ringback.is_on(ms(450)), Some(false));
+        assert_eq!(ringback.is_on(ms(700)), Some(true));
+        assert_eq!(ringback.is_on(ms(1000)), Some(false));
+        assert_eq!(ringback.is_on(ms(3000)), Some(true));
+
+        let call_waiting = Region::NorthAmerica.tone(Tone::CallWaiting).cadence;
+        assert_eq!(call_waiting.is_on(ms(299)), Some(true));
+        assert_eq!(call_waiting.is_on(ms(300)), Some(false));
+        assert_eq!(call_waiting.is_on(ms(10_000)), Some(true));
+        assert_eq!(call_waiting.is_on(ms(10_300)), None);
+
+        assert_eq!(Cadence::CONTINUOUS.is_on(ms(1_000_000)), Some(true));
+    }
+
+    #[test]
+    fn call_waiting_beeps() {
+        let mut tones = ToneGenerator::new(Region::NorthAmerica);
+        let start = Instant::from_secs(5);
+        tones.start(Tone::CallWaiting, start);
+
+        // 440 Hz at -13 dBm0 for 300 ms, then silence
+        let mut pcm = [0; 8000];
+        assert_eq!(tones.mix(start, &mut pcm), true);
+        let (on, off) = pcm.split_at(2400);
+        let crossings = zero_crossings(on) as i32;
+        assert!((crossings - 264).abs() <= 1, "{}", crossings);
+        let peak = on.iter().map(|s| i32::from(*s).abs()).max().unwrap();
+        assert!(peak > 5030 && peak <= 5072, "{}", peak);
+        assert_eq!(off.iter().all(|s| *s == 0), true);
+
+        // Second beep, then done
+        let mut pcm = [0; 2400];
+        assert_eq!(tones.mix(start + ms(10_000), &mut pcm), true);
+        let crossings = zero_crossings(&pcm) as i32;
+        assert!((crossings - 264).abs() <= 1, "{}", crossings);
+        assert_eq!(tones.mix(start + ms(10_300), &mut pcm[..160]), false);
+        assert_eq!(tones.tone(), None);
+    }
+
+    #[test]
+    fn frames_join_up() {
+        let mut tones = ToneGenerator::new(Region::NorthAmerica);
+        let start = Instant::from_secs(1);
+        tones.start(Tone::Dial, start);
+        let mut whole = [0; 800];
+        tones.mix(start, &mut whole);
+
+        tones.start(Tone::Dial, start);
+        let mut frames = [0; 800];
+        for (i, frame) in frames.chunks_mut(160).enumerate() {
+            let time = start + ms(20 * i as u64);
+            assert_eq!(tones.mix(time, frame), true);
+        }
+        assert_eq!(&whole[..], &frames[..]);
+        // Two tones at -13 dBm0 each
+        let peak = whole.iter().map(|s| i32::from(*s).abs()).max().unwrap();
+        assert!(peak > 9000 && peak <= 2 * 5072, "{}", peak);
+    }
+
+    #[test]
+    fn busy_and_mixing() {
+        let mut tones = ToneGenerator::new(Region::Europe);
+        let start = Instant::from_secs(1);
+        tones.start(Tone::Busy, start);
+
+        // 425 Hz for 480 ms, mixed over what's there
+        let mut pcm = [1000; 8000];
+        tones.mix(start, &mut pcm);
+        assert_eq!(pcm[..3840].iter().any(|s| *s < 0), true);
+        assert_eq!(pcm[3840..7680].iter().all(|s| *s == 1000), true);
+        assert_eq!(pcm[7680..].iter().any(|s| *s < 0), true);
+
+        let mut loud = [32000; 160];
+        tones.mix(start, &mut loud);
+        assert_eq!(loud.contains(&i16::max_value()), true);
+
+        tones.stop();
+        let mut pcm = [0; 160];
+        assert_eq!(tones.mix(start, &mut pcm), false);
+        assert_eq!(pcm, [0; 160]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Call-progress tone plans
+//!
+//! Frequencies, levels and cadences per region, after ANSI T1.401 for
+//! North America, SIN 350 for the UK, ETSI TR 101 041 (CEPT) for
+//! continental Europe and TTC JJ-90.10 for Japan.
+
+use crate::tones::{Cadence, Step, Tone};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Region {
+    NorthAmerica,
+    UnitedKingdom,
+    Europe,
+    Japan,
+}
+
+impl Default for Region {
+    fn default() -> Self {
+        Region::NorthAmerica
+    }
+}
+
+/// How one tone sounds in a region
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ToneSpec {
+    /// One or two frequencies, in Hz
+    pub frequencies: &'static [u16],
+    /// Level of each frequency, in dBm0
+    pub level: i8,
+    pub cadence: Cadence,
+}
+
+impl Region {
+    pub fn enumerate() -> &'static [Region] {
+        &[
+            Region::NorthAmerica,
+            Region::UnitedKingdom,
+            Region::Europe,
+            Region::Japan,
+        ]
+    }
+
+    pub fn tone(self, tone: Tone) -> &'static ToneSpec {
+        let plan = match self {
+            Region::NorthAmerica => &NORTH_AMERICA,
+            Region::UnitedKingdom => &UNITED_KINGDOM,
+            Region::Europe => &EUROPE,
+            Region::Japan => &JAPAN,
+        };
+        match tone {
+            Tone::Dial => &plan.dial,
+            Tone::Ringback => &plan.ringback,
+            Tone::Busy => &plan.busy,
+            Tone::Reorder => &plan.reorder,
+            Tone::CallWaiting => &plan.call_waiting,
+        }
+    }
+}
+
+struct Plan {
+    dial: ToneSpec,
+    ringback: ToneSpec,
+    busy: ToneSpec,
+    reorder: ToneSpec,
+    call_waiting: ToneSpec,
+}
+
+const fn spec(frequencies: &'static [u16], level: i8, cadence: Cadence) -> ToneSpec {
+    ToneSpec {
+        frequencies,
+        level,
+        cadence,
+    }
+}
+
+const fn repeat(steps: &'static [Step]) -> Cadence {
+    Cadence {
+        steps,
+        repeat: true,
+    }
+}
+
+const fn once(steps: &'static [Step]) -> Cadence {
+    Cadence {
+        steps,
+        repeat: false,
+    }
+}
+
+const fn step(on_ms: u64, off_ms: u64) -> Step {
+    Step::from_millis(on_ms, off_ms)
+}
+
+const NORTH_AMERICA: Plan = Plan {
+    dial: spec(&[350, 440], -13, Cadence::CONTINUOUS),
+    ringback: spec(&[440, 480], -19, repeat(&[step(2000, 4000)])),
+    busy: spec(&[480, 620], -24, repeat(&[step(500, 500)])),
+    reorder: spec(&[480, 620], -24, repeat(&[step(250, 250)])),
+    // One burst, repeated once after 10 s
+    call_waiting: spec(&[440], -13, once(&[step(300, 9700), step(300, 0)])),
+};
+
+const UNITED_KINGDOM: Plan = Plan {
+    dial: spec(&[350, 450], -13, Cadence::CONTINUOUS),
+    ringback: spec(&[400, 450], -19, repeat(&[step(400, 200), step(400, 2000)])),
+    busy: spec(&[400], -13, repeat(&[step(375, 375)])),
+    reorder: spec(&[400], -13, repeat(&[step(400, 350), step(225, 525)])),
+    call_waiting: spec(&[400], -13, once(&[step(100, 2000), step(100, 0)])),
+};
+
+const EUROPE: Plan = Plan {
+    dial: spec(&[425], -13, Cadence::CONTINUOUS),
+    ringback: spec(&[425], -13, repeat(&[step(1000, 4000)])),
+    busy: spec(&[425], -13, repeat(&[step(480, 480)])),
+    reorder: spec(&[425], -13, repeat(&[step(240, 240)])),
+    call_waiting: spec(&[425], -13, once(&[step(200, 200), step(200, 0)])),
+};
+
+const JAPAN: Plan = Plan {
+    dial: spec(&[400], -13, Cadence::CONTINUOUS),
+    ringback: spec(&[400], -13, repeat(&[step(1000, 2000)])),
+    busy: spec(&[400], -13, repeat(&[step(500, 500)])),
+    reorder: spec(&[400], -13, repeat(&[step(500, 500)])),
+    call_waiting: spec(&[400], -13, once(&[step(100, 100), step(100, 0)])),
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_region_has_every_tone() {
+        for region in Region::enumerate() {
+            for tone in Tone::enumerate() {
+                let spec = region.tone(*tone);
+                assert!(!spec.frequencies.is_empty() && spec.frequencies.len() <= 2);
+                for f in spec.frequencies.iter() {
+                    assert!(*f >= 300 && *f <= 700, "{:?} {:?} {}", region, tone, f);
+                }
+                assert!(spec.level < 0);
+                // Only dial tone is steady
+                assert_eq!(
+                    spec.cadence.steps.is_empty(),
+                    *tone == Tone::Dial,
+                    "{:?} {:?}",
+                    region,
+                    tone
+                );
+            }
+        }
+        assert_eq!(Region::default(), Region::NorthAmerica);
+    }
+}